@@ -1,4 +0,0 @@
-#!/bin/bash
-
-set -euo pipefail
-rm -rv unit-test-temp/*
\ No newline at end of file
@@ -0,0 +1,269 @@
+use std::{
+    path::{Component, Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    error::{ErrorKind, JanitorError},
+    info,
+    inventory::KernelInventory,
+    kernel::{InstalledItemKind, InstalledKernel, KernelVersion},
+    signing,
+    update::RunCmdConfig,
+    utils,
+};
+
+/// Where the archive of `version` is in `archive_dir`
+pub fn archive_path(archive_dir: &Path, version: &KernelVersion) -> PathBuf {
+    archive_dir.join(format!("linux-{}.tar.zst", version))
+}
+
+/// The files of `kernel` that go into its archive, the ones uninstalling it removes except for
+/// the sources, which can be fetched again
+fn archived_paths(
+    cmd_config: &RunCmdConfig,
+    kernel: &InstalledKernel,
+) -> Result<Vec<PathBuf>, JanitorError> {
+    let mut paths: Vec<PathBuf> = [
+        &kernel.vmlinuz_path,
+        &kernel.config_path,
+        &kernel.system_map_path,
+    ]
+    .iter()
+    .copied()
+    .flatten()
+    .cloned()
+    .collect();
+    if let Some(image) = &kernel.vmlinuz_path {
+        let signature = signing::signature_path(image);
+        if cmd_config.fs.disk_usage(&signature).is_ok() {
+            paths.push(signature);
+        }
+    }
+    // Old versions share the modules and device trees with the current one
+    if !kernel.version.is_old() {
+        paths.extend(kernel.module_path.clone());
+        paths.extend(kernel.dtb_path.clone());
+    }
+    paths.extend(
+        kernel
+            .extra_paths
+            .iter()
+            .filter(|(kind, _)| *kind != InstalledItemKind::SourceDir)
+            .map(|(_, path)| path.clone()),
+    );
+    if let Some(initramfs) = &cmd_config.initramfs {
+        paths.extend(initramfs.artifact_paths(cmd_config.fs.as_ref(), &kernel.version)?);
+    }
+    Ok(paths)
+}
+
+/// Pack the image, config, System.map, initramfs and module tree of `kernel` into a zstd tarball
+/// in `archive_dir` before it's removed. The paths are kept absolute so `restore` puts them back
+/// where they were. Replaces an earlier archive of the same version
+pub fn archive(
+    cmd_config: &RunCmdConfig,
+    kernel: &InstalledKernel,
+    archive_dir: &Path,
+) -> Result<PathBuf, JanitorError> {
+    let archive = archive_path(archive_dir, &kernel.version);
+    cmd_config.create_dir_all(archive_dir)?;
+    let mut tar = Command::new("tar");
+    tar.arg("--zstd")
+        .arg("--absolute-names")
+        .arg("-cf")
+        .arg(&archive)
+        .arg("--")
+        .args(archived_paths(cmd_config, kernel)?);
+    utils::exec_and_print_command(
+        &mut cmd_config.privileged(tar),
+        format!("archive {} into {:?}", kernel.version, archive),
+        cmd_config,
+    )?;
+    Ok(archive)
+}
+
+/// Whether `entry` of an archive may be unpacked: absolute, without `..`, and inside one of the
+/// directories kernels are installed to
+fn restorable(entry: &Path, roots: &[PathBuf]) -> bool {
+    entry.is_absolute()
+        && !entry.components().any(|c| c == Component::ParentDir)
+        && roots
+            .iter()
+            .any(|root| entry.starts_with(root) && entry != root)
+}
+
+/// Unpack the archive of `version` in `archive_dir` back to where its files were installed
+/// Refuses an archive with files outside the install, module and device tree paths, and
+/// overwriting anything that's installed. The archive is kept, it's removed like any other file
+/// in `archive_dir`
+pub fn restore(
+    cmd_config: &RunCmdConfig,
+    inventory: &KernelInventory,
+    version: &KernelVersion,
+    archive_dir: &Path,
+) -> Result<(), JanitorError> {
+    if inventory.by_version(version).is_some() {
+        return Err(JanitorError::new(
+            ErrorKind::Config,
+            format!("{} is installed, not restoring it over itself", version),
+        ));
+    }
+    let archive = archive_path(archive_dir, version);
+    if cmd_config.fs.disk_usage(&archive).is_err() {
+        let archived: Vec<String> = cmd_config
+            .fs
+            .read_dir(archive_dir)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|path| utils::paths::filename_from_path(path))
+            .filter(|name| name.ends_with(".tar.zst"))
+            .collect();
+        return Err(JanitorError::new(
+            ErrorKind::Config,
+            format!(
+                "There's no archive of {} in {}, it has {}",
+                version,
+                archive_dir.display(),
+                match archived.is_empty() {
+                    true => "none".to_string(),
+                    false => archived.join(", "),
+                }
+            ),
+        ));
+    }
+    let mut list = Command::new("tar");
+    list.arg("--zstd").arg("-tf").arg(&archive);
+    let entries = cmd_config.runner.output(&mut cmd_config.privileged(list))?;
+    for entry in entries
+        .lines()
+        .map(|line| Path::new(line.trim_end_matches('/')))
+    {
+        if !restorable(entry, &cmd_config.removable_roots) {
+            return Err(JanitorError::new(
+                ErrorKind::Config,
+                format!(
+                    "{} has {}, which isn't in any of {:?}. Not restoring it",
+                    archive.display(),
+                    entry.display(),
+                    cmd_config.removable_roots
+                ),
+            ));
+        }
+        if cmd_config.fs.disk_usage(entry).is_ok() {
+            return Err(JanitorError::new(
+                ErrorKind::Config,
+                format!(
+                    "{} from {} is already there, not overwriting it",
+                    entry.display(),
+                    archive.display()
+                ),
+            ));
+        }
+    }
+    let mut tar = Command::new("tar");
+    tar.arg("--zstd")
+        .arg("--absolute-names")
+        .arg("-xf")
+        .arg(&archive);
+    utils::exec_and_print_command(
+        &mut cmd_config.privileged(tar),
+        format!("restore {} from {:?}", version, archive),
+        cmd_config,
+    )?;
+    info!("Restored {} from {}", version, archive.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        initramfs::InitramfsKind,
+        runner::RecordingRunner,
+        test_utils::TestDir,
+        update::{InteractiveStatus, PretendStatus},
+    };
+
+    #[test]
+    fn archive_and_restore() -> Result<(), JanitorError> {
+        let test_dir = TestDir::new();
+        let kernel = InstalledKernel::create_test_version(&test_dir, "5.4.97", false);
+        let initramfs = test_dir.install_path().join("initramfs-5.4.97-gentoo.img");
+        std::fs::write(&initramfs, b"")?;
+        let archive_dir = test_dir.path().join("archive");
+        let path = |path: &Option<PathBuf>| path.as_ref().unwrap().display().to_string();
+        let listing = format!(
+            "{}\n{}/\n{}/kernel/ext4.ko\n",
+            path(&kernel.vmlinuz_path),
+            path(&kernel.module_path),
+            path(&kernel.module_path)
+        );
+        let runner = RecordingRunner::new().with_output("tar", &listing);
+        let cmd_config = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone())
+            .with_initramfs(InitramfsKind::Dracut.create(&test_dir.install_path()))
+            .with_removable_roots(vec![test_dir.install_path(), test_dir.module_path()]);
+        let installed = KernelInventory::new(
+            vec![InstalledKernel::new(kernel.version.clone())],
+            cmd_config.fs.clone(),
+        );
+        let removed = KernelInventory::new(Vec::new(), cmd_config.fs.clone());
+
+        let err = restore(&cmd_config, &installed, &kernel.version, &archive_dir).unwrap_err();
+        assert!(err.to_string().contains("is installed"), "{}", err);
+        let err = restore(&cmd_config, &removed, &kernel.version, &archive_dir).unwrap_err();
+        assert!(err.to_string().contains("it has none"), "{}", err);
+
+        let archive = archive(&cmd_config, &kernel, &archive_dir)?;
+        assert_eq!(archive, archive_dir.join("linux-5.4.97-gentoo.tar.zst"));
+        // The sources aren't archived, the initramfs is
+        assert_eq!(
+            runner.command_lines(),
+            vec![format!(
+                "tar --zstd --absolute-names -cf {} -- {} {} {} {} {}",
+                archive.display(),
+                path(&kernel.vmlinuz_path),
+                path(&kernel.config_path),
+                path(&kernel.system_map_path),
+                path(&kernel.module_path),
+                initramfs.display(),
+            )]
+        );
+
+        std::fs::write(&archive, b"")?;
+        // The files are still there
+        let err = restore(&cmd_config, &removed, &kernel.version, &archive_dir).unwrap_err();
+        assert!(err.to_string().contains("not overwriting it"), "{}", err);
+
+        std::fs::remove_file(kernel.vmlinuz_path.as_ref().unwrap())?;
+        std::fs::remove_dir_all(kernel.module_path.as_ref().unwrap())?;
+        restore(&cmd_config, &removed, &kernel.version, &archive_dir)?;
+        assert_eq!(
+            runner.command_lines().last().unwrap(),
+            &format!("tar --zstd --absolute-names -xf {}", archive.display())
+        );
+
+        // Nothing is unpacked from an archive with files elsewhere
+        let runner = RecordingRunner::new().with_output("tar", "/etc/cron.d/evil\n");
+        let cmd_config = cmd_config.with_runner(runner.clone());
+        let err = restore(&cmd_config, &removed, &kernel.version, &archive_dir).unwrap_err();
+        assert!(err.to_string().contains("isn't in any of"), "{}", err);
+        assert_eq!(
+            runner.command_lines(),
+            vec![format!("tar --zstd -tf {}", archive.display())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn restorable_entries() {
+        let roots = [PathBuf::from("/boot"), PathBuf::from("/lib/modules")];
+        assert!(restorable(Path::new("/boot/vmlinuz-6.1.0-gentoo"), &roots));
+        assert!(restorable(Path::new("/lib/modules/6.1.0-gentoo"), &roots));
+        assert!(!restorable(Path::new("/boot"), &roots));
+        assert!(!restorable(Path::new("/boot/../etc/passwd"), &roots));
+        assert!(!restorable(Path::new("boot/vmlinuz-6.1.0-gentoo"), &roots));
+        assert!(!restorable(Path::new("/etc/passwd"), &roots));
+    }
+}
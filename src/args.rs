@@ -0,0 +1,234 @@
+use crate::cli::{self, ParseResults};
+use kernel_janitor::{
+    error::{ErrorKind, JanitorError},
+    events, log,
+};
+use std::sync::Arc;
+
+/// Every flag, option and subcommand kernel-janitor takes, parsed from the command line
+pub fn parse() -> ParseResults {
+    cli::FlagParser::new()
+        .with_flag(
+            "manual_edit",
+            "-m",
+            "--manual-edit",
+            "Avoids auto-copying of config file to newest installed source directory",
+        )
+        .with_flag(
+            "clean_only",
+            "-c",
+            "--clean-only",
+            "Deletes extra installations, count is specified in NumVersionsToKeep in config file.",
+        )
+        .with_flag(
+            "interactive",
+            "-i",
+            "--interactive",
+            "Run the commands interactively",
+        )
+        .with_flag(
+            "list",
+            "-l",
+            "--list",
+            "List installed kernels and then exit",
+        )
+        .with_flag(
+            "all_initramfs",
+            "-I",
+            "--all-initramfs",
+            "Also regenerate the initramfs of every kernel kept after cleanup, not just the new one",
+        )
+        .with_flag(
+            "update_check",
+            "-u",
+            "--update-check",
+            "Tell whether there are kernel sources newer than every installed kernel, then exit",
+        )
+        .with_optional_option(
+            "rebuild",
+            "-r",
+            "--rebuild",
+            "Build and install an installed kernel again, the newest one unless a release like 5.11.8-gentoo is given",
+        )
+        .with_flag(
+            "incremental",
+            "-n",
+            "--incremental",
+            "With --rebuild, reuse the objects of the last build instead of running make clean, for when only the config changed",
+        )
+        .with_flag(
+            "build_only",
+            "-b",
+            "--build-only",
+            "Compile the newest kernel but don't install it, doesn't need root",
+        )
+        .with_flag(
+            "install_only",
+            "-B",
+            "--install-only",
+            "Install the kernel --build-only compiled and carry on with the rest of the run",
+        )
+        .with_long_flag(
+            "skip_build",
+            "--skip-build",
+            "Don't copy the config, patch or build, for re-running after a later stage failed",
+        )
+        .with_long_flag(
+            "skip_initramfs",
+            "--skip-initramfs",
+            "Don't generate the initramfs of the new kernel",
+        )
+        .with_long_flag(
+            "skip_bootloader",
+            "--skip-bootloader",
+            "Don't update the bootloader config",
+        )
+        .with_long_flag(
+            "skip_cleanup",
+            "--skip-cleanup",
+            "Don't remove old kernels after installing the new one",
+        )
+        .with_flag(
+            "pretend",
+            "-p",
+            "--pretend",
+            "Don't actually run the commands, just print them out",
+        )
+        .with_flag(
+            "delete_interactive",
+            "-d",
+            "--delete",
+            "Pick kernels to delete from a list with their sizes, then confirm once for all of them",
+        )
+        .with_flag("quiet", "-q", "--quiet", "Only print warnings and errors")
+        .with_flag(
+            "cron",
+            "-C",
+            "--cron",
+            "Print nothing unless the run fails or changes something, for mailed cron jobs",
+        )
+        .with_flag("verbose", "-v", "--verbose", "Print debug messages")
+        .with_flag("trace", "-vv", "--trace", "Print debug and trace messages")
+        .with_option(
+            "output",
+            "-o",
+            "--output",
+            "Output format, either text or json (one event per line)",
+        )
+        .with_option(
+            "sort",
+            "-s",
+            "--sort",
+            "Order of --list, one of version (the default), size or date",
+        )
+        .with_option(
+            "older_than",
+            "-a",
+            "--older-than",
+            "Only list kernels installed longer ago than this, like 90d",
+        )
+        .with_option(
+            "flavor",
+            "-f",
+            "--flavor",
+            "Only list kernels of this flavor, like gentoo-dist",
+        )
+        .with_flag(
+            "missing_files",
+            "-M",
+            "--missing-files",
+            "Only list kernels missing some of their files",
+        )
+        .with_flag(
+            "orphans",
+            "-O",
+            "--orphans",
+            "Only list leftovers of kernels whose image is gone",
+        )
+        .with_subcommand(
+            "doctor",
+            "Check the config, paths and tools a run needs, then exit",
+        )
+        .with_subcommand(
+            "history",
+            "Show every run that changed something, then exit",
+        )
+        .with_subcommand(
+            "verify",
+            "Check that installed kernels are complete and boot entries point at real files",
+        )
+        .with_subcommand(
+            "verify-checksums",
+            "Check the boot files kernel-janitor installed against the checksums it recorded",
+        )
+        .with_subcommand(
+            "prune-sources",
+            "Run make clean in the source trees of old kernels, keeping their .config",
+        )
+        .with_subcommand(
+            "space",
+            "Show the space used by the sources, build artifacts, modules and boot files of each kernel",
+        )
+        .with_subcommand(
+            "install-hook",
+            "Install a Portage postsync hook that runs --update-check after every sync",
+        )
+        .with_subcommand(
+            "suggest",
+            "Print what would free space and about how much, without changing anything",
+        )
+        .with_subcommand(
+            "restore",
+            "Unpack the archive of the given version from the ArchiveDir back into place",
+        )
+        .with_subcommand(
+            "stats",
+            "Show build times and disk usage over time from the history, then exit",
+        )
+        .parse_args_from_env()
+}
+
+/// Whether the run may install or remove kernels, only those mount what InstallPath lives on
+pub fn writes_install_path(parsed_results: &ParseResults) -> bool {
+    let only_looking = ["list", "update_check", "build_only"]
+        .iter()
+        .any(|flag| parsed_results.flag_enabled(flag));
+    let looking_subcommand = matches!(
+        parsed_results.subcommand(),
+        Some(
+            "space" | "suggest" | "verify" | "verify-checksums" | "install-hook" | "prune-sources"
+        )
+    );
+    !only_looking && !looking_subcommand
+}
+
+/// The flags that change how much gets printed and in which format
+pub fn init_output(parsed_results: &ParseResults) -> Result<(), JanitorError> {
+    if parsed_results.flag_enabled("trace") {
+        log::set_max_level(log::Level::Trace);
+    } else if parsed_results.flag_enabled("verbose") {
+        log::set_max_level(log::Level::Debug);
+    } else if parsed_results.flag_enabled("quiet") {
+        log::set_max_level(log::Level::Warn);
+    }
+
+    if parsed_results.flag_enabled("cron") {
+        if parsed_results.flag_enabled("interactive") {
+            return Err(JanitorError::new(
+                ErrorKind::Config,
+                "--cron can't be combined with --interactive, nobody is there to answer",
+            ));
+        }
+        log::hold_output();
+    }
+
+    if let Some(format) = parsed_results.option_value("output") {
+        events::set_format(format.parse::<events::OutputFormat>()?);
+    }
+    // main() still reports the error and the end of the run after this returns
+    events::add_observer(Arc::new(events::ConsoleObserver)).keep();
+    if events::json_enabled() {
+        events::add_observer(Arc::new(events::JsonObserver)).keep();
+    }
+    Ok(())
+}
@@ -0,0 +1,899 @@
+use std::{
+    convert::TryFrom,
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::{
+    conf::Config,
+    error::{Context, ErrorKind, JanitorError},
+    filesystem::FileSystem,
+    info,
+    kernel::KernelVersion,
+    portage,
+    update::RunCmdConfig,
+    utils, JanitorErrorFrom,
+};
+
+/// In the state dir, the kernel `try_once` booted that becomes the default once it booted fine
+const PENDING_DEFAULT: &str = "pending-default";
+/// Next to grub.cfg, where grub-set-default and grub-reboot save the entry to boot
+const GRUBENV: &str = "grubenv";
+
+/// Makes installed kernels bootable, implemented once per bootloader
+pub trait Bootloader: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Add a boot entry for a freshly installed kernel
+    fn install_entry(
+        &self,
+        cmd_config: &RunCmdConfig,
+        version: &KernelVersion,
+    ) -> Result<(), JanitorError>;
+
+    /// Drop the boot entry of a kernel that's about to be uninstalled
+    fn remove_entry(
+        &self,
+        cmd_config: &RunCmdConfig,
+        version: &KernelVersion,
+    ) -> Result<(), JanitorError>;
+
+    /// Rewrite the bootloader's own configuration from the entries
+    fn regenerate(&self, cmd_config: &RunCmdConfig) -> Result<(), JanitorError>;
+
+    /// Check the regenerated configuration is valid and boots `version`
+    fn validate(
+        &self,
+        _cmd_config: &RunCmdConfig,
+        _version: &KernelVersion,
+    ) -> Result<(), JanitorError> {
+        Ok(())
+    }
+
+    /// The kernel booted when nobody picks one, None if it can't be told
+    /// The read-only methods take `fs` so a pretend run sees what it would have changed
+    fn default_entry(&self, fs: &dyn FileSystem) -> Result<Option<KernelVersion>, JanitorError>;
+
+    /// The kernel saved as the default in the bootloader's environment, like by `set_default`
+    /// None if none is saved or the bootloader doesn't read it
+    fn saved_default(&self, _fs: &dyn FileSystem) -> Result<Option<KernelVersion>, JanitorError> {
+        Ok(None)
+    }
+
+    /// The kernel `boot_once` saved for the next boot only, None if there's none
+    fn next_boot(&self, _fs: &dyn FileSystem) -> Result<Option<KernelVersion>, JanitorError> {
+        Ok(None)
+    }
+
+    /// Boot `version` when nobody picks a kernel, from now on
+    fn set_default(
+        &self,
+        _cmd_config: &RunCmdConfig,
+        _version: &KernelVersion,
+    ) -> Result<(), JanitorError> {
+        Err(JanitorError::new(
+            ErrorKind::Bootloader,
+            format!("{} can't be told which kernel to boot", self.name()),
+        ))
+    }
+
+    /// Boot `version` the next time only, the default again after that
+    fn boot_once(
+        &self,
+        _cmd_config: &RunCmdConfig,
+        _version: &KernelVersion,
+    ) -> Result<(), JanitorError> {
+        Err(JanitorError::new(
+            ErrorKind::Bootloader,
+            format!("{} can't be told which kernel to boot", self.name()),
+        ))
+    }
+
+    /// Every kernel that has a boot entry in menu order, None if it can't be told
+    fn entries(&self, fs: &dyn FileSystem) -> Result<Option<Vec<KernelVersion>>, JanitorError>;
+
+    /// Kernel images and initramfs the boot entries load, so they can be checked to exist
+    fn referenced_files(&self, _fs: &dyn FileSystem) -> Result<Vec<PathBuf>, JanitorError> {
+        Ok(Vec::new())
+    }
+
+    /// The command line new entries get and the file it's set in, None if there's no such file
+    fn cmdline_defaults(
+        &self,
+        _fs: &dyn FileSystem,
+    ) -> Result<Option<(PathBuf, String)>, JanitorError> {
+        Ok(None)
+    }
+
+    /// The command line of each boot entry, recovery entries left out
+    fn cmdlines(&self, _fs: &dyn FileSystem) -> Result<Vec<(KernelVersion, String)>, JanitorError> {
+        Ok(Vec::new())
+    }
+}
+
+/// Which `Bootloader` to update, set with `Bootloader` in the config
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum BootloaderKind {
+    None,
+    Grub,
+}
+
+impl FromStr for BootloaderKind {
+    type Err = JanitorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(BootloaderKind::None),
+            "grub" => Ok(BootloaderKind::Grub),
+            _ => Err(JanitorErrorFrom!(
+                "Unknown bootloader {}, expected one of none, grub",
+                s
+            )),
+        }
+    }
+}
+
+impl BootloaderKind {
+    /// `Bootloader` in the config, or grub when the older `RegenerateGrubConfig` is true
+    pub fn from_config(config: &Config) -> Result<BootloaderKind, JanitorError> {
+        match config.contains("Bootloader") {
+            true => config.get_string("Bootloader")?.parse(),
+            false if config.get_bool("RegenerateGrubConfig")? => Ok(BootloaderKind::Grub),
+            false => Ok(BootloaderKind::None),
+        }
+    }
+
+    /// Kernels are installed to `install_path`
+    pub fn create(self, install_path: &Path) -> Option<Arc<dyn Bootloader>> {
+        match self {
+            BootloaderKind::None => None,
+            BootloaderKind::Grub => Some(Arc::new(Grub::new(install_path))),
+        }
+    }
+}
+
+/// Where grub-mkconfig takes GRUB_CMDLINE_LINUX from
+const GRUB_DEFAULTS: &str = "/etc/default/grub";
+
+/// GRUB finds kernels in the install path by itself whenever `grub-mkconfig` runs
+pub struct Grub {
+    install_path: PathBuf,
+    config_path: PathBuf,
+    defaults_path: PathBuf,
+    env_path: PathBuf,
+}
+
+impl Grub {
+    pub fn new(install_path: &Path) -> Grub {
+        Grub {
+            install_path: install_path.to_path_buf(),
+            config_path: install_path.join("grub").join("grub.cfg"),
+            defaults_path: PathBuf::from(GRUB_DEFAULTS),
+            env_path: install_path.join("grub").join(GRUBENV),
+        }
+    }
+}
+
+/// The first argument of every `linux` and `initrd` line, as GRUB sees them
+fn loaded_files(grub_cfg: &str) -> Vec<&str> {
+    grub_cfg
+        .lines()
+        .flat_map(|line| {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("linux") => words.take(1).collect(),
+                // initrd can load several images at once, like microcode and the initramfs
+                Some("initrd") => words.collect(),
+                _ => Vec::new(),
+            }
+        })
+        .collect()
+}
+
+/// GRUB paths are relative to the partition they're on, which is the install path when
+/// /boot is its own partition and the root filesystem when it isn't
+fn resolve_grub_path(install_path: &Path, grub_path: &str) -> PathBuf {
+    let on_boot_partition = install_path.join(grub_path.trim_start_matches('/'));
+    match on_boot_partition.exists() {
+        true => on_boot_partition,
+        false => PathBuf::from(grub_path),
+    }
+}
+
+/// The kernels of every `linux` line, in menu order
+fn linux_entries(grub_cfg: &str) -> Vec<KernelVersion> {
+    grub_cfg
+        .lines()
+        .map(|line| line.split_whitespace())
+        .filter_map(|mut words| match words.next() {
+            Some("linux") => words.next(),
+            _ => None,
+        })
+        .filter_map(|image| {
+            let filename = image.rsplit('/').next()?;
+            KernelVersion::try_from(filename).ok()
+        })
+        .collect()
+}
+
+/// The kernel and the rest of the `linux` line of each entry
+/// The recovery entries boot with `single` and without GRUB_CMDLINE_LINUX_DEFAULT, they're left out
+fn linux_cmdlines(grub_cfg: &str) -> Vec<(KernelVersion, String)> {
+    grub_cfg
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            if words.next() != Some("linux") {
+                return None;
+            }
+            let filename = words.next()?.rsplit('/').next()?;
+            let version = KernelVersion::try_from(filename).ok()?;
+            let parameters: Vec<&str> = words.collect();
+            match parameters.contains(&"single") {
+                true => None,
+                false => Some((version, parameters.join(" "))),
+            }
+        })
+        .collect()
+}
+
+/// What grub-mkconfig puts on the command line of the normal entries
+fn default_cmdline(grub_defaults: &str) -> String {
+    let variables = portage::shell_variables(grub_defaults);
+    ["GRUB_CMDLINE_LINUX", "GRUB_CMDLINE_LINUX_DEFAULT"]
+        .iter()
+        .filter_map(|name| variables.get(*name))
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The first quoted word of `text`
+fn quoted(text: &str) -> Option<String> {
+    let start = text.find('\'')? + 1;
+    let len = text[start..].find('\'')?;
+    Some(text[start..start + len].to_string())
+}
+
+/// The first quoted word after `$menuentry_id_option` on a `menuentry` or `submenu` line, or the
+/// title when there's no id
+fn menu_id(line: &str) -> Option<String> {
+    match line.split_once("$menuentry_id_option") {
+        Some((_, id)) => quoted(id),
+        None => quoted(line),
+    }
+}
+
+/// A `menuentry` of grub.cfg that boots a kernel
+struct MenuEntry {
+    version: KernelVersion,
+    /// With the ids of the submenus it's in, separated by `>`
+    id: String,
+    title: String,
+    /// Boots with `single`
+    recovery: bool,
+}
+
+/// Every entry of grub.cfg that boots a kernel, in the order of the menu
+fn menu_entries(grub_cfg: &str) -> Vec<MenuEntry> {
+    let mut entries = Vec::new();
+    // Each open submenu with the depth it was opened at
+    let mut submenus: Vec<(String, usize)> = Vec::new();
+    let mut entry = None;
+    let mut depth = 0;
+    for line in grub_cfg.lines() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("submenu") => submenus.extend(menu_id(line).map(|id| (id, depth))),
+            Some("menuentry") => {
+                entry = menu_id(line).map(|id| {
+                    let mut path: Vec<&str> = submenus.iter().map(|(s, _)| s.as_str()).collect();
+                    path.push(&id);
+                    (path.join(">"), quoted(line).unwrap_or_default())
+                })
+            }
+            Some("linux") => {
+                let version = words
+                    .next()
+                    .and_then(|image| image.rsplit('/').next())
+                    .and_then(|filename| KernelVersion::try_from(filename).ok());
+                if let (Some(version), Some((id, title))) = (version, &entry) {
+                    entries.push(MenuEntry {
+                        version,
+                        id: id.clone(),
+                        title: title.clone(),
+                        recovery: line.split_whitespace().any(|word| word == "single"),
+                    });
+                }
+            }
+            _ => (),
+        }
+        // `${var}` opens and closes on the same line
+        depth = (depth + line.matches('{').count()).saturating_sub(line.matches('}').count());
+        while submenus.last().is_some_and(|(_, opened)| *opened >= depth) {
+            submenus.pop();
+        }
+    }
+    entries
+}
+
+/// What grub-reboot and grub-set-default call the first normal entry of each kernel, like
+/// `gnulinux-advanced-UUID>gnulinux-5.10.1-gentoo-advanced-UUID` for one in a submenu
+fn entry_ids(grub_cfg: &str) -> Vec<(KernelVersion, String)> {
+    let mut ids: Vec<(KernelVersion, String)> = Vec::new();
+    for entry in menu_entries(grub_cfg) {
+        if !entry.recovery && !ids.iter().any(|(v, _)| v == &entry.version) {
+            ids.push((entry.version, entry.id));
+        }
+    }
+    ids
+}
+
+/// The kernel of the first menu entry, which is booted unless GRUB_DEFAULT says otherwise
+fn first_linux_entry(grub_cfg: &str) -> Option<KernelVersion> {
+    linux_entries(grub_cfg).into_iter().next()
+}
+
+/// The value of `name` in a grubenv, None when it isn't set. The block is padded to 1 KiB with `#`
+fn grubenv_value<'a>(grubenv: &'a str, name: &str) -> Option<&'a str> {
+    grubenv
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+        .filter(|value| !value.is_empty())
+}
+
+/// The kernel of the entry `saved` names, as a path of ids like grub-set-default saves it or only
+/// the id or title of the entry itself
+fn entry_for(grub_cfg: &str, saved: &str) -> Option<KernelVersion> {
+    let last = |path: &str| path.rsplit('>').next().unwrap_or_default().to_string();
+    menu_entries(grub_cfg)
+        .into_iter()
+        .find(|entry| {
+            entry.id == saved || last(&entry.id) == last(saved) || entry.title == last(saved)
+        })
+        .map(|entry| entry.version)
+}
+
+impl Grub {
+    /// None if grub.cfg hasn't been generated yet, `fs` is in memory when pretending
+    fn read_config(&self, fs: &dyn FileSystem) -> Result<Option<String>, JanitorError> {
+        match fs.read_to_string(&self.config_path) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context(format!("failed to read {}", self.config_path.display())),
+        }
+    }
+
+    /// GRUB only looks at what's saved in grubenv with GRUB_DEFAULT=saved
+    fn uses_saved(&self, fs: &dyn FileSystem) -> bool {
+        let defaults = fs.read_to_string(&self.defaults_path).unwrap_or_default();
+        portage::shell_variables(&defaults)
+            .get("GRUB_DEFAULT")
+            .map(String::as_str)
+            == Some("saved")
+    }
+
+    /// The kernel of the entry saved as `name` in grubenv, None if there's none or it's not a kernel
+    fn env_entry(
+        &self,
+        fs: &dyn FileSystem,
+        name: &str,
+    ) -> Result<Option<KernelVersion>, JanitorError> {
+        if !self.uses_saved(fs) {
+            return Ok(None);
+        }
+        let grubenv = match fs.read_to_string(&self.env_path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context(format!("failed to read {}", self.env_path.display())),
+        };
+        let saved = match grubenv_value(&grubenv, name) {
+            Some(saved) => saved,
+            None => return Ok(None),
+        };
+        Ok(self
+            .read_config(fs)?
+            .as_deref()
+            .and_then(|contents| entry_for(contents, saved)))
+    }
+
+    /// Run `program` (grub-set-default or grub-reboot) with the menu entry of `version`
+    /// They only change what's saved in grubenv, which GRUB only looks at with GRUB_DEFAULT=saved
+    fn save_entry(
+        &self,
+        cmd_config: &RunCmdConfig,
+        program: &str,
+        version: &KernelVersion,
+    ) -> Result<(), JanitorError> {
+        if !self.uses_saved(cmd_config.fs.as_ref()) {
+            return Err(JanitorError::new(
+                ErrorKind::Bootloader,
+                format!(
+                    "GRUB ignores {} without GRUB_DEFAULT=saved in {}, set it and run grub-mkconfig",
+                    program,
+                    self.defaults_path.display()
+                ),
+            ));
+        }
+        let contents = self
+            .read_config(cmd_config.fs.as_ref())?
+            .unwrap_or_default();
+        let id = match entry_ids(&contents).into_iter().find(|(v, _)| v == version) {
+            Some((_, id)) => id,
+            // grub.cfg wasn't regenerated
+            None if cmd_config.pretending() => version.to_string(),
+            None => {
+                return Err(JanitorError::new(
+                    ErrorKind::Bootloader,
+                    format!(
+                        "{} has no entry for {}",
+                        self.config_path.display(),
+                        version
+                    ),
+                ))
+            }
+        };
+        let mut cmd = Command::new(program);
+        cmd.arg(&id);
+        utils::exec_and_print_command(
+            &mut cmd_config.privileged(cmd),
+            format!("\'{} {}\'", program, id),
+            cmd_config,
+        )
+    }
+}
+
+impl Bootloader for Grub {
+    fn name(&self) -> &'static str {
+        "grub"
+    }
+
+    fn install_entry(&self, _: &RunCmdConfig, _: &KernelVersion) -> Result<(), JanitorError> {
+        Ok(())
+    }
+
+    fn remove_entry(&self, _: &RunCmdConfig, _: &KernelVersion) -> Result<(), JanitorError> {
+        Ok(())
+    }
+
+    fn regenerate(&self, cmd_config: &RunCmdConfig) -> Result<(), JanitorError> {
+        let mut grub_mkconfig = Command::new("grub-mkconfig");
+        grub_mkconfig.arg("-o").arg(&self.config_path);
+        utils::exec_and_print_command(
+            &mut cmd_config.privileged(grub_mkconfig),
+            format!("\'grub-mkconfig -o {:?}\'", self.config_path),
+            cmd_config,
+        )
+    }
+
+    /// grub-mkconfig happily writes a config that GRUB never reads, or one that's missing the new kernel
+    /// when it looks for kernels somewhere else than where they're installed
+    fn validate(
+        &self,
+        cmd_config: &RunCmdConfig,
+        version: &KernelVersion,
+    ) -> Result<(), JanitorError> {
+        // Nothing was regenerated
+        if cmd_config.pretending() {
+            return Ok(());
+        }
+        let mut script_check = Command::new("grub-script-check");
+        script_check.arg(&self.config_path);
+        cmd_config
+            .run_without_prompt(
+                &mut cmd_config.privileged(script_check),
+                &format!("\'grub-script-check {:?}\'", self.config_path),
+            )
+            .map_err(|e| e.with_kind(ErrorKind::Bootloader))?;
+        let contents = self
+            .read_config(cmd_config.fs.as_ref())?
+            .unwrap_or_default();
+        if !linux_entries(&contents).contains(version) {
+            return Err(JanitorError::new(
+                ErrorKind::Bootloader,
+                format!(
+                    "{} has no entry for {}. grub-mkconfig didn't find the kernel in {}, \
+                     or GRUB reads its config from another path (check the --boot-directory grub-install was run with)",
+                    self.config_path.display(),
+                    version,
+                    self.install_path.display()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The saved one, or else the first entry
+    fn default_entry(&self, fs: &dyn FileSystem) -> Result<Option<KernelVersion>, JanitorError> {
+        if let Some(saved) = self.saved_default(fs)? {
+            return Ok(Some(saved));
+        }
+        Ok(self.read_config(fs)?.as_deref().and_then(first_linux_entry))
+    }
+
+    fn saved_default(&self, fs: &dyn FileSystem) -> Result<Option<KernelVersion>, JanitorError> {
+        self.env_entry(fs, "saved_entry")
+    }
+
+    fn next_boot(&self, fs: &dyn FileSystem) -> Result<Option<KernelVersion>, JanitorError> {
+        self.env_entry(fs, "next_entry")
+    }
+
+    fn entries(&self, fs: &dyn FileSystem) -> Result<Option<Vec<KernelVersion>>, JanitorError> {
+        Ok(self.read_config(fs)?.as_deref().map(linux_entries))
+    }
+
+    fn set_default(
+        &self,
+        cmd_config: &RunCmdConfig,
+        version: &KernelVersion,
+    ) -> Result<(), JanitorError> {
+        self.save_entry(cmd_config, "grub-set-default", version)
+    }
+
+    fn boot_once(
+        &self,
+        cmd_config: &RunCmdConfig,
+        version: &KernelVersion,
+    ) -> Result<(), JanitorError> {
+        self.save_entry(cmd_config, "grub-reboot", version)
+    }
+
+    fn referenced_files(&self, fs: &dyn FileSystem) -> Result<Vec<PathBuf>, JanitorError> {
+        let contents = self.read_config(fs)?.unwrap_or_default();
+        let mut files: Vec<PathBuf> = loaded_files(&contents)
+            .into_iter()
+            .map(|path| resolve_grub_path(&self.install_path, path))
+            .collect();
+        // The same images show up in the normal and the recovery entries
+        files.sort();
+        files.dedup();
+        Ok(files)
+    }
+
+    fn cmdline_defaults(
+        &self,
+        fs: &dyn FileSystem,
+    ) -> Result<Option<(PathBuf, String)>, JanitorError> {
+        match fs.read_to_string(&self.defaults_path) {
+            Ok(contents) => Ok(Some((
+                self.defaults_path.clone(),
+                default_cmdline(&contents),
+            ))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context(format!("failed to read {}", self.defaults_path.display())),
+        }
+    }
+
+    fn cmdlines(&self, fs: &dyn FileSystem) -> Result<Vec<(KernelVersion, String)>, JanitorError> {
+        Ok(self
+            .read_config(fs)?
+            .as_deref()
+            .map(linux_cmdlines)
+            .unwrap_or_default())
+    }
+}
+
+/// Boot `version` the next time only and keep `fallback` the default, so a kernel that doesn't boot
+/// is left behind with a reset. `confirm_boot` makes it the default once it's seen running
+pub fn try_once(
+    cmd_config: &RunCmdConfig,
+    bootloader: &dyn Bootloader,
+    version: &KernelVersion,
+    fallback: &KernelVersion,
+    state_dir: &Path,
+) -> Result<(), JanitorError> {
+    bootloader.set_default(cmd_config, fallback)?;
+    bootloader.boot_once(cmd_config, version)?;
+    cmd_config.write_file(
+        &state_dir.join(PENDING_DEFAULT),
+        &format!("{}\n", version),
+        0o644,
+    )?;
+    info!(
+        "The next boot tries {}, {} stays the default until it booted fine",
+        version, fallback
+    );
+    Ok(())
+}
+
+/// Make the kernel `try_once` tried the default, now that it's `running`
+/// Nothing changes while another kernel runs, there might not have been a reboot yet
+pub fn confirm_boot(
+    cmd_config: &RunCmdConfig,
+    bootloader: &dyn Bootloader,
+    running: &KernelVersion,
+    state_dir: &Path,
+) -> Result<(), JanitorError> {
+    let path = state_dir.join(PENDING_DEFAULT);
+    let pending = match cmd_config.fs.read_to_string(&path) {
+        Ok(contents) => KernelVersion::try_from(contents.trim()).ok(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e).context(format!("failed to read {}", path.display())),
+    };
+    if pending.as_ref() != Some(running) {
+        return Ok(());
+    }
+    bootloader.set_default(cmd_config, running)?;
+    cmd_config.write_file(&path, "", 0o644)?;
+    info!("{} booted fine, it's the default now", running);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filesystem::MemoryFileSystem,
+        runner::RecordingRunner,
+        test_utils::TestDir,
+        update::{InteractiveStatus, PretendStatus},
+    };
+    use std::fs;
+
+    #[test]
+    fn grub_cfg_command() -> Result<(), JanitorError> {
+        let runner = RecordingRunner::new();
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone());
+        let grub = BootloaderKind::Grub.create(Path::new("/boot")).unwrap();
+        grub.install_entry(&cfg, &"5.11.8-gentoo".parse::<KernelVersion>().unwrap())?;
+        grub.regenerate(&cfg)?;
+        assert_eq!(
+            runner.command_lines(),
+            vec!["grub-mkconfig -o /boot/grub/grub.cfg"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn grub_cfg_validation() -> Result<(), JanitorError> {
+        let test_dir = TestDir::new();
+        let runner = RecordingRunner::new();
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone());
+        let grub = Grub::new(&test_dir.install_path());
+        fs::create_dir_all(test_dir.install_path().join("grub")).unwrap();
+        fs::write(
+            &grub.config_path,
+            "menuentry 'Gentoo' {\n\tlinux\t/vmlinuz-5.11.8-gentoo root=/dev/sda2\n}\n",
+        )
+        .unwrap();
+        grub.validate(&cfg, &"5.11.8-gentoo".parse::<KernelVersion>().unwrap())?;
+        assert_eq!(
+            runner.command_lines(),
+            vec![format!("grub-script-check {}", grub.config_path.display())]
+        );
+        let err = grub
+            .validate(&cfg, &"5.12.0-gentoo".parse::<KernelVersion>().unwrap())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Bootloader);
+        Ok(())
+    }
+
+    #[test]
+    fn grub_cfg_validation_reads_through_fs() {
+        let grub = Grub::new(Path::new("/boot"));
+        let memfs = MemoryFileSystem::new().with_contents(
+            &grub.config_path,
+            "menuentry 'Gentoo' {\n\tlinux\t/vmlinuz-5.11.8-gentoo-dist root=/dev/sda2\n}\n",
+        );
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(RecordingRunner::new())
+            .with_fs(memfs);
+        grub.validate(
+            &cfg,
+            &"5.11.8-gentoo-dist".parse::<KernelVersion>().unwrap(),
+        )
+        .unwrap();
+        // Same version, but another flavor's entry doesn't boot this one
+        let err = grub
+            .validate(&cfg, &"5.11.8-gentoo".parse::<KernelVersion>().unwrap())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Bootloader);
+    }
+
+    /// A grub.cfg made by grub-mkconfig, with ids for the entries and a submenu
+    const GRUB_CFG_WITH_IDS: &str = "\
+menuentry 'Gentoo GNU/Linux' --class gentoo $menuentry_id_option 'gnulinux-simple-1234' {
+\tlinux\t/vmlinuz-5.11.8-gentoo root=/dev/sda2 ro
+}
+submenu 'Advanced options for Gentoo GNU/Linux' $menuentry_id_option 'gnulinux-advanced-1234' {
+\tmenuentry 'Gentoo GNU/Linux, with Linux 5.11.8-gentoo' $menuentry_id_option 'gnulinux-5.11.8-gentoo-advanced-1234' {
+\t\tlinux\t/vmlinuz-5.11.8-gentoo root=/dev/sda2 ro
+\t}
+\tmenuentry 'Gentoo GNU/Linux, with Linux 5.10.1-gentoo (recovery mode)' $menuentry_id_option 'gnulinux-5.10.1-gentoo-recovery-1234' {
+\t\tlinux\t/vmlinuz-5.10.1-gentoo root=/dev/sda2 ro single
+\t}
+\tmenuentry 'Gentoo GNU/Linux, with Linux 5.10.1-gentoo' $menuentry_id_option 'gnulinux-5.10.1-gentoo-advanced-1234' {
+\t\tlinux\t/vmlinuz-5.10.1-gentoo root=/dev/sda2 ro
+\t}
+}
+menuentry 'UEFI Firmware Settings' $menuentry_id_option 'uefi-firmware' {
+\tfwsetup
+}
+";
+
+    #[test]
+    fn grub_boot_once() -> Result<(), JanitorError> {
+        let test_dir = TestDir::new();
+        let mut grub = Grub::new(&test_dir.install_path());
+        grub.defaults_path = test_dir.path().join("grub-defaults");
+        fs::create_dir_all(test_dir.install_path().join("grub")).unwrap();
+        fs::write(&grub.config_path, GRUB_CFG_WITH_IDS).unwrap();
+
+        let new = "5.11.8-gentoo".parse::<KernelVersion>().unwrap();
+        let previous = "5.10.1-gentoo".parse::<KernelVersion>().unwrap();
+        let state_dir = test_dir.path().join("state");
+        let runner = RecordingRunner::new();
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone());
+
+        let err = try_once(&cfg, &grub, &new, &previous, &state_dir).unwrap_err();
+        assert!(
+            err.to_string().contains("without GRUB_DEFAULT=saved"),
+            "{}",
+            err
+        );
+        fs::write(&grub.defaults_path, "GRUB_DEFAULT=saved\n").unwrap();
+        try_once(&cfg, &grub, &new, &previous, &state_dir)?;
+        // Nothing changes until the new kernel is seen running
+        confirm_boot(&cfg, &grub, &previous, &state_dir)?;
+        confirm_boot(&cfg, &grub, &new, &state_dir)?;
+        confirm_boot(&cfg, &grub, &new, &state_dir)?;
+        assert_eq!(
+            runner.command_lines(),
+            vec![
+                "grub-set-default gnulinux-advanced-1234>gnulinux-5.10.1-gentoo-advanced-1234",
+                "grub-reboot gnulinux-simple-1234",
+                "grub-set-default gnulinux-simple-1234",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn grub_saved_default() -> Result<(), JanitorError> {
+        let mut grub = Grub::new(Path::new("/boot"));
+        grub.defaults_path = PathBuf::from("/etc/default/grub");
+        let memfs = MemoryFileSystem::new().with_contents(&grub.config_path, GRUB_CFG_WITH_IDS);
+        let new = "5.11.8-gentoo".parse::<KernelVersion>().unwrap();
+        let previous = "5.10.1-gentoo".parse::<KernelVersion>().unwrap();
+        // grub.cfg is only looked for on the filesystem it's given
+        assert_eq!(grub.entries(&MemoryFileSystem::new())?, None);
+        assert!(grub.entries(&memfs)?.unwrap().contains(&previous));
+        // Without a grubenv the first entry boots
+        assert_eq!(grub.saved_default(&memfs)?, None);
+        assert_eq!(grub.default_entry(&memfs)?, Some(new.clone()));
+
+        let memfs = memfs.with_contents(
+            &grub.env_path,
+            &format!(
+                "# GRUB Environment Block\n\
+                 saved_entry=gnulinux-advanced-1234>gnulinux-5.10.1-gentoo-advanced-1234\n\
+                 next_entry=gnulinux-simple-1234\n{}",
+                "#".repeat(64)
+            ),
+        );
+        // Saved, but GRUB doesn't look at it
+        assert_eq!(grub.saved_default(&memfs)?, None);
+        let memfs = memfs.with_contents(&grub.defaults_path, "GRUB_DEFAULT=saved\n");
+        assert_eq!(grub.saved_default(&memfs)?, Some(previous.clone()));
+        assert_eq!(grub.default_entry(&memfs)?, Some(previous.clone()));
+        assert_eq!(grub.next_boot(&memfs)?, Some(new));
+
+        // grub-reboot clears next_entry once it booted, and a title works as well as an id
+        let memfs = memfs.with_contents(
+            &grub.env_path,
+            "saved_entry=Gentoo GNU/Linux, with Linux 5.10.1-gentoo\nnext_entry=\n",
+        );
+        assert_eq!(grub.saved_default(&memfs)?, Some(previous));
+        assert_eq!(grub.next_boot(&memfs)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn grub_entry_ids_per_flavor() {
+        let grub_cfg = "\
+submenu 'Advanced options' $menuentry_id_option 'gnulinux-advanced-1234' {
+\tmenuentry 'Linux 5.11.8-gentoo-dist' $menuentry_id_option 'gnulinux-5.11.8-gentoo-dist-advanced-1234' {
+\t\tlinux\t/vmlinuz-5.11.8-gentoo-dist root=/dev/sda2 ro
+\t}
+\tmenuentry 'Linux 5.11.8-gentoo' $menuentry_id_option 'gnulinux-5.11.8-gentoo-advanced-1234' {
+\t\tlinux\t/vmlinuz-5.11.8-gentoo root=/dev/sda2 ro
+\t}
+}
+";
+        let id = |release: &str| {
+            let version = release.parse::<KernelVersion>().unwrap();
+            entry_ids(grub_cfg)
+                .into_iter()
+                .find(|(v, _)| v == &version)
+                .map(|(_, id)| id)
+        };
+        assert_eq!(
+            id("5.11.8-gentoo").as_deref(),
+            Some("gnulinux-advanced-1234>gnulinux-5.11.8-gentoo-advanced-1234")
+        );
+        assert_eq!(
+            id("5.11.8-gentoo-dist").as_deref(),
+            Some("gnulinux-advanced-1234>gnulinux-5.11.8-gentoo-dist-advanced-1234")
+        );
+        assert_eq!(id("5.11.8-zen1"), None);
+    }
+
+    #[test]
+    fn grub_default_entry() {
+        let grub_cfg = "\
+menuentry 'Gentoo GNU/Linux' --class gentoo {
+\tload_video
+\tlinux\t/vmlinuz-5.11.8-gentoo root=/dev/sda2 ro
+\tinitrd\t/initramfs-5.11.8-gentoo.img
+}
+submenu 'Advanced options for Gentoo GNU/Linux' {
+\tmenuentry 'Gentoo GNU/Linux, with Linux 5.10.1-gentoo' {
+\t\tlinux\t/vmlinuz-5.10.1-gentoo root=/dev/sda2 ro
+\t}
+}
+";
+        assert_eq!(
+            first_linux_entry(grub_cfg),
+            Some("5.11.8-gentoo".parse::<KernelVersion>().unwrap())
+        );
+        assert_eq!(
+            linux_entries(grub_cfg),
+            vec![
+                "5.11.8-gentoo".parse::<KernelVersion>().unwrap(),
+                "5.10.1-gentoo".parse::<KernelVersion>().unwrap()
+            ]
+        );
+        assert_eq!(
+            loaded_files(grub_cfg),
+            vec![
+                "/vmlinuz-5.11.8-gentoo",
+                "/initramfs-5.11.8-gentoo.img",
+                "/vmlinuz-5.10.1-gentoo"
+            ]
+        );
+        assert_eq!(first_linux_entry("set timeout=5\n"), None);
+        assert_eq!(
+            "GRUB".parse::<BootloaderKind>().unwrap(),
+            BootloaderKind::Grub
+        );
+        assert!("lilo".parse::<BootloaderKind>().is_err());
+    }
+
+    #[test]
+    fn grub_cmdlines() {
+        let grub_cfg = "\
+menuentry 'Gentoo GNU/Linux' --class gentoo {
+\tlinux\t/vmlinuz-5.11.8-gentoo root=/dev/sda2 ro quiet
+}
+submenu 'Advanced options for Gentoo GNU/Linux' {
+\tmenuentry 'Gentoo GNU/Linux, with Linux 5.11.8-gentoo (recovery mode)' {
+\t\tlinux\t/vmlinuz-5.11.8-gentoo root=/dev/sda2 ro single
+\t}
+}
+";
+        assert_eq!(
+            linux_cmdlines(grub_cfg),
+            vec![(
+                "5.11.8-gentoo".parse::<KernelVersion>().unwrap(),
+                "root=/dev/sda2 ro quiet".to_string()
+            )]
+        );
+        let grub_defaults = "\
+GRUB_DISTRIBUTOR=\"Gentoo\"
+GRUB_CMDLINE_LINUX=\"root=/dev/sda2\"
+#GRUB_CMDLINE_LINUX_DEFAULT=\"nomodeset\"
+GRUB_CMDLINE_LINUX_DEFAULT=\"quiet\"
+";
+        assert_eq!(default_cmdline(grub_defaults), "root=/dev/sda2 quiet");
+    }
+}
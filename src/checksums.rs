@@ -0,0 +1,235 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    doctor::Check,
+    error::{Context, JanitorError},
+    filesystem::FileSystem,
+    info,
+    initramfs::InitramfsGenerator,
+    kernel::InstalledKernel,
+    update::RunCmdConfig,
+};
+
+/// Kept in the state dir rather than next to the files, so whoever can change /boot can't fix it up
+/// too. It's in `sha256sum` format, `sha256sum -c` can check it as well
+const MANIFEST: &str = "sha256sums";
+
+pub fn manifest_path(state_dir: &Path) -> PathBuf {
+    state_dir.join(MANIFEST)
+}
+
+/// `<sha256>  <path>` lines, like `sha256sum` prints them
+fn parse(contents: &str) -> BTreeMap<PathBuf, String> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (hash, path) = line.split_once(' ')?;
+            // Binary mode marks the path with a `*` instead of a second space
+            let path = path.strip_prefix([' ', '*'])?;
+            Some((PathBuf::from(path), hash.to_string()))
+        })
+        .collect()
+}
+
+fn render(entries: &BTreeMap<PathBuf, String>) -> String {
+    entries
+        .iter()
+        .map(|(path, hash)| format!("{}  {}\n", hash, path.display()))
+        .collect()
+}
+
+fn read(state_dir: &Path) -> Result<BTreeMap<PathBuf, String>, JanitorError> {
+    let path = manifest_path(state_dir);
+    match fs::read_to_string(&path) {
+        Ok(contents) => Ok(parse(&contents)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e).context(format!("failed to read {}", path.display())),
+    }
+}
+
+/// The sha256 of each of `files`, through `sha256sum` and the escalation command since an
+/// initramfs is usually only readable by root
+fn hash(
+    config: &RunCmdConfig,
+    files: &[PathBuf],
+) -> Result<BTreeMap<PathBuf, String>, JanitorError> {
+    if files.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    let mut sha256sum = Command::new("sha256sum");
+    sha256sum.arg("--").args(files);
+    let output = config
+        .runner
+        .output(&mut config.privileged(sha256sum))
+        .context("failed to compute checksums")?;
+    Ok(parse(&output))
+}
+
+/// The files in the install path that make up `kernel`, its initramfs included
+pub fn boot_files(
+    fs: &dyn FileSystem,
+    kernel: &InstalledKernel,
+    initramfs: Option<&dyn InitramfsGenerator>,
+) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = [
+        &kernel.vmlinuz_path,
+        &kernel.config_path,
+        &kernel.system_map_path,
+    ]
+    .iter()
+    .filter_map(|path| path.as_ref().cloned())
+    .collect();
+    if let Some(initramfs) = initramfs {
+        files.extend(
+            initramfs
+                .artifact_paths(fs, &kernel.version)
+                .unwrap_or_default(),
+        );
+    }
+    files
+}
+
+/// Add the checksums of `files` to the manifest in `state_dir`, replacing the ones they had, and
+/// forget the files that are gone, like the ones of removed kernels
+pub fn record(
+    config: &RunCmdConfig,
+    state_dir: &Path,
+    files: &[PathBuf],
+) -> Result<(), JanitorError> {
+    let path = manifest_path(state_dir);
+    if config.pretending() {
+        info!(
+            "Would record the checksums of {} files in {}",
+            files.len(),
+            path.display()
+        );
+        return Ok(());
+    }
+    let mut entries = read(state_dir)?;
+    entries.retain(|file, _| file.exists());
+    entries.extend(hash(config, files)?);
+    config.write_file(&path, &render(&entries), 0o644)
+}
+
+/// Compare every file in the manifest in `state_dir` to its recorded checksum
+pub fn verify(config: &RunCmdConfig, state_dir: &Path) -> Result<Vec<Check>, JanitorError> {
+    let recorded = read(state_dir)?;
+    if recorded.is_empty() {
+        return Ok(vec![Check::warn(
+            "checksums",
+            format!("none recorded in {}", manifest_path(state_dir).display()),
+            "they're recorded for every kernel installed from now on",
+        )]);
+    }
+    let (present, gone): (Vec<&PathBuf>, Vec<&PathBuf>) =
+        recorded.keys().partition(|file| file.exists());
+    let actual = hash(
+        config,
+        &present
+            .iter()
+            .map(|file| file.to_path_buf())
+            .collect::<Vec<_>>(),
+    )?;
+    let mut checks: Vec<Check> = gone
+        .iter()
+        .map(|file| {
+            Check::warn(
+                &file.display().to_string(),
+                "recorded but gone".to_string(),
+                "it was removed without kernel-janitor, the next run forgets it",
+            )
+        })
+        .collect();
+    for file in present {
+        let name = file.display().to_string();
+        checks.push(match (actual.get(file), recorded.get(file)) {
+            (Some(actual), Some(expected)) if actual == expected => {
+                Check::pass(&name, "sha256 matches".to_string())
+            }
+            _ => Check::fail(
+                &name,
+                "sha256 differs from the one recorded when it was installed".to_string(),
+                "the boot partition may be corrupted or tampered with, reinstall the kernel with --rebuild",
+            ),
+        });
+    }
+    Ok(checks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        doctor::CheckStatus,
+        runner::RecordingRunner,
+        test_utils::TestDir,
+        update::{InteractiveStatus, PretendStatus},
+    };
+
+    #[test]
+    fn manifest() -> Result<(), JanitorError> {
+        let test_dir = TestDir::new();
+        let boot = test_dir.install_path();
+        let vmlinuz = boot.join("vmlinuz-5.11.8-gentoo");
+        let config = boot.join("config-5.11.8-gentoo");
+        let removed = boot.join("vmlinuz-5.4.97-gentoo");
+        fs::write(&vmlinuz, b"kernel").unwrap();
+        fs::write(&config, b"config").unwrap();
+        let state_dir = test_dir.path().join("state");
+        fs::create_dir_all(&state_dir).unwrap();
+        fs::write(
+            manifest_path(&state_dir),
+            format!("{}  {}\n", "0".repeat(64), removed.display()),
+        )
+        .unwrap();
+
+        let sums = format!(
+            "{}  {}\n{} *{}\n",
+            "a".repeat(64),
+            vmlinuz.display(),
+            "b".repeat(64),
+            config.display()
+        );
+        let runner = RecordingRunner::new().with_output("sha256sum", &sums);
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone());
+        record(&cfg, &state_dir, &[vmlinuz.clone(), config.clone()])?;
+        let recorded = read(&state_dir)?;
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded.get(&config), Some(&"b".repeat(64)));
+        assert_eq!(
+            runner.command_lines(),
+            vec![format!(
+                "sha256sum -- {} {}",
+                vmlinuz.display(),
+                config.display()
+            )]
+        );
+
+        // The image changed since
+        let changed = format!(
+            "{}  {}\n{}  {}\n",
+            "c".repeat(64),
+            vmlinuz.display(),
+            "b".repeat(64),
+            config.display()
+        );
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(RecordingRunner::new().with_output("sha256sum", &changed));
+        let checks = verify(&cfg, &state_dir)?;
+        let status = |file: &Path| {
+            checks
+                .iter()
+                .find(|c| c.name == file.display().to_string())
+                .map(|c| c.status)
+        };
+        assert_eq!(status(&config), Some(CheckStatus::Pass));
+        assert_eq!(status(&vmlinuz), Some(CheckStatus::Fail));
+        Ok(())
+    }
+}
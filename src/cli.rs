@@ -1,7 +1,7 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 type FlagName = String;
-/// Only boolean flags for now
+/// Boolean flags or options that take the next argument as their value
 /// `name` is used by the programmer to refer to the Flag
 #[derive(PartialEq, Eq, Hash)]
 struct Flag {
@@ -9,18 +9,31 @@ struct Flag {
     short_form: String,
     long_form: String,
     description: String,
+    takes_value: bool,
+    /// The value can be left out, the next argument is only taken if it isn't a flag or subcommand
+    value_optional: bool,
 }
 
 /// The parser should return a HashSet that contains the list of found flags
 /// The HashSet will be indexed by the `Flag::name` member
 pub struct FlagParser {
     flags: Vec<Flag>,
+    /// Name and description of each subcommand
+    subcommands: Vec<(String, String)>,
     found_flags: HashSet<FlagName>,
+    found_values: HashMap<FlagName, String>,
+    /// Arguments that aren't flags, in order
+    found_positionals: Vec<String>,
+    /// Long forms of the options that were passed without the value they need
+    missing_values: Vec<String>,
 }
 
 pub struct ParseResults {
     help_message: String,
     found_flags: HashSet<FlagName>,
+    found_values: HashMap<FlagName, String>,
+    found_positionals: Vec<String>,
+    missing_values: Vec<String>,
 }
 
 impl Flag {
@@ -30,15 +43,20 @@ impl Flag {
             short_form: short_form.to_owned(),
             long_form: long_form.to_owned(),
             description: description.to_owned(),
+            takes_value: false,
+            value_optional: false,
         }
     }
 
     pub fn matches(&self, other: &str) -> bool {
-        if other == self.short_form || other == self.long_form {
-            true
-        } else {
-            false
-        }
+        (!self.short_form.is_empty() && other == self.short_form) || other == self.long_form
+    }
+
+    /// Grabs the value out of `--long-form=value`
+    pub fn inline_value<'a>(&self, other: &'a str) -> Option<&'a str> {
+        other
+            .strip_prefix(self.long_form.as_str())
+            .and_then(|rest| rest.strip_prefix('='))
     }
 }
 
@@ -46,7 +64,11 @@ impl FlagParser {
     pub fn new() -> FlagParser {
         FlagParser {
             flags: Vec::new(),
+            subcommands: Vec::new(),
             found_flags: HashSet::new(),
+            found_values: HashMap::new(),
+            found_positionals: Vec::new(),
+            missing_values: Vec::new(),
         }
     }
 
@@ -63,6 +85,48 @@ impl FlagParser {
         self
     }
 
+    /// Like `with_flag` but only the long form can be passed, for flags that aren't worth a letter
+    pub fn with_long_flag(mut self, name: &str, long_form: &str, description: &str) -> FlagParser {
+        self.flags.push(Flag::new(name, "", long_form, description));
+        self
+    }
+
+    /// A word like `doctor` that picks what to do instead of the usual run
+    pub fn with_subcommand(mut self, name: &str, description: &str) -> FlagParser {
+        self.subcommands
+            .push((name.to_owned(), description.to_owned()));
+        self
+    }
+
+    /// Like `with_flag` but the argument after the flag is stored as its value
+    pub fn with_option(
+        mut self,
+        name: &str,
+        short_form: &str,
+        long_form: &str,
+        description: &str,
+    ) -> FlagParser {
+        let mut flag = Flag::new(name, short_form, long_form, description);
+        flag.takes_value = true;
+        self.flags.push(flag);
+        self
+    }
+
+    /// Like `with_option` but the value can be left out, `flag_enabled` tells if it was passed
+    pub fn with_optional_option(
+        mut self,
+        name: &str,
+        short_form: &str,
+        long_form: &str,
+        description: &str,
+    ) -> FlagParser {
+        let mut flag = Flag::new(name, short_form, long_form, description);
+        flag.takes_value = true;
+        flag.value_optional = true;
+        self.flags.push(flag);
+        self
+    }
+
     fn add_help_flag(&mut self) {
         self.flags.push(Flag::new(
             "help",
@@ -73,23 +137,38 @@ impl FlagParser {
     }
 
     pub fn help_message(&self) -> String {
+        let subcommands = self
+            .subcommands
+            .iter()
+            .map(|(name, description)| format!("{:width$}{:}", name, description, width = 29));
         self.flags
             .iter()
             .map(|flag| {
+                let long_form = match (flag.takes_value, flag.value_optional) {
+                    (true, true) => format!("{} [value]", flag.long_form),
+                    (true, false) => format!("{} <value>", flag.long_form),
+                    (false, _) => flag.long_form.clone(),
+                };
+                let short_form = match flag.short_form.is_empty() {
+                    true => "   ".to_string(),
+                    false => format!("{},", flag.short_form),
+                };
                 format!(
-                    "{}, {:width$}{:}",
-                    flag.short_form,
-                    flag.long_form,
+                    "{} {:width$}{:}",
+                    short_form,
+                    long_form,
                     flag.description,
                     width = 25
                 )
             })
+            .chain(subcommands)
             .collect::<Vec<_>>()
             .join("\n")
     }
 
     pub fn parse_args_from_env(self) -> ParseResults {
-        let args = std::env::args().collect::<Vec<_>>();
+        // Skip the program name, it'd be taken for a subcommand
+        let args = std::env::args().skip(1).collect::<Vec<_>>();
         self.parse_args(args)
     }
 
@@ -97,13 +176,50 @@ impl FlagParser {
     pub fn parse_args(mut self, args: Vec<String>) -> ParseResults {
         // Create the help flag at the last possible moment
         self.add_help_flag();
+        let mut args = args.into_iter().peekable();
         // nested `for` loops, yuck
-        for arg in args {
+        while let Some(arg) = args.next() {
+            let mut matched = false;
             for flag in &self.flags {
-                if flag.matches(&arg) {
+                if flag.takes_value {
+                    let value = match flag.inline_value(&arg) {
+                        Some(v) => Some(v.to_string()),
+                        None if flag.matches(&arg) && flag.value_optional => {
+                            self.found_flags.insert(flag.name.clone());
+                            matched = true;
+                            let is_subcommand = |next: &String| {
+                                self.subcommands.iter().any(|(name, _)| name == next)
+                            };
+                            args.next_if(|next| !next.starts_with('-') && !is_subcommand(next))
+                        }
+                        None if flag.matches(&arg) => {
+                            // `--output --pretend` is missing the value, not outputting pretend
+                            let value = args.next_if(|next| !next.starts_with('-'));
+                            if value.is_none() {
+                                self.missing_values.push(flag.long_form.clone());
+                                matched = true;
+                            }
+                            value
+                        }
+                        None => None,
+                    };
+                    if matched && value.is_none() {
+                        break;
+                    }
+                    if let Some(v) = value {
+                        self.found_flags.insert(flag.name.clone());
+                        self.found_values.insert(flag.name.clone(), v);
+                        matched = true;
+                        break;
+                    }
+                } else if flag.matches(&arg) {
                     self.found_flags.insert(flag.name.clone());
+                    matched = true;
                 }
             }
+            if !matched && !arg.starts_with('-') {
+                self.found_positionals.push(arg);
+            }
         }
         ParseResults::from(self)
     }
@@ -113,19 +229,39 @@ impl ParseResults {
     pub fn flag_enabled(&self, name: &str) -> bool {
         self.found_flags.contains(name)
     }
+    /// The value given to an option, None if the option wasn't passed
+    pub fn option_value(&self, name: &str) -> Option<&str> {
+        self.found_values.get(name).map(|v| v.as_str())
+    }
     pub fn help_message(&self) -> String {
         self.help_message.clone()
     }
+    /// The first argument that isn't a flag, like `doctor`
+    pub fn subcommand(&self) -> Option<&str> {
+        self.found_positionals.first().map(|s| s.as_str())
+    }
+    /// The arguments after the subcommand, like the version in `restore 6.1.0-gentoo`
+    pub fn subcommand_args(&self) -> &[String] {
+        self.found_positionals.get(1..).unwrap_or(&[])
+    }
+    /// The first option that needs a value but was passed without one, like `--output` at the end
+    pub fn missing_value(&self) -> Option<&str> {
+        self.missing_values.first().map(|s| s.as_str())
+    }
 }
 impl From<FlagParser> for ParseResults {
     fn from(parser: FlagParser) -> Self {
         ParseResults {
             help_message: parser.help_message(),
             found_flags: parser.found_flags,
+            found_values: parser.found_values,
+            found_positionals: parser.found_positionals,
+            missing_values: parser.missing_values,
         }
     }
 }
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
     #[test]
@@ -155,4 +291,94 @@ mod tests {
         assert_eq!(parse_results.flag_enabled(disabled_flag_0_name), false);
         assert_eq!(parse_results.flag_enabled(disabled_flag_1_name), false);
     }
+
+    #[test]
+    fn long_flag_parse() {
+        let parser = || {
+            FlagParser::new()
+                .with_long_flag("skip_build", "--skip-build", "don't build")
+                .with_flag("test", "-t", "--test", "enabled")
+        };
+        let results = parser().parse_args(vec!["--skip-build".to_string()]);
+        assert!(results.flag_enabled("skip_build"));
+        let results = parser().parse_args(vec!["".to_string(), "-t".to_string()]);
+        assert!(!results.flag_enabled("skip_build"));
+        let help = results.help_message();
+        let lines = help.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], format!("    {:25}don't build", "--skip-build"));
+        assert!(lines[1].starts_with("-t, --test"));
+    }
+
+    #[test]
+    fn option_parse() {
+        let args = vec![
+            "--output".to_string(),
+            "json".to_string(),
+            "--sort=size".to_string(),
+            "-t".to_string(),
+            "doctor".to_string(),
+        ];
+        let parse_results = FlagParser::new()
+            .with_subcommand("doctor", "check the environment")
+            .with_option("output", "-o", "--output", "output format")
+            .with_option("sort", "-s", "--sort", "sort order")
+            .with_option("missing", "-x", "--missing", "not passed")
+            .with_flag("test", "-t", "--test", "enabled")
+            .parse_args(args);
+
+        assert_eq!(parse_results.option_value("output"), Some("json"));
+        assert_eq!(parse_results.option_value("sort"), Some("size"));
+        assert_eq!(parse_results.option_value("missing"), None);
+        assert!(parse_results.flag_enabled("test"));
+        assert_eq!(parse_results.subcommand(), Some("doctor"));
+        assert_eq!(parse_results.missing_value(), None);
+    }
+
+    #[test]
+    fn option_without_value() {
+        let parse = |args: &[&str]| {
+            FlagParser::new()
+                .with_option("output", "-o", "--output", "output format")
+                .with_flag("test", "-t", "--test", "enabled")
+                .parse_args(args.iter().map(|a| a.to_string()).collect())
+        };
+        let results = parse(&["-t", "--output"]);
+        assert_eq!(results.missing_value(), Some("--output"));
+        assert_eq!(results.option_value("output"), None);
+
+        let results = parse(&["-o", "-t"]);
+        assert_eq!(results.missing_value(), Some("--output"));
+        assert!(results.flag_enabled("test"));
+    }
+
+    #[test]
+    fn optional_option_parse() {
+        let parse = |args: &[&str]| {
+            FlagParser::new()
+                .with_subcommand("doctor", "check the environment")
+                .with_optional_option("rebuild", "-r", "--rebuild", "rebuild a kernel")
+                .with_flag("test", "-t", "--test", "enabled")
+                .parse_args(args.iter().map(|a| a.to_string()).collect())
+        };
+        let results = parse(&["--rebuild", "5.11.8-gentoo", "-t"]);
+        assert!(results.flag_enabled("rebuild"));
+        assert_eq!(results.option_value("rebuild"), Some("5.11.8-gentoo"));
+        assert!(results.flag_enabled("test"));
+
+        let results = parse(&["-r", "-t"]);
+        assert!(results.flag_enabled("rebuild"));
+        assert_eq!(results.option_value("rebuild"), None);
+        assert!(results.flag_enabled("test"));
+
+        let results = parse(&["-r", "doctor"]);
+        assert_eq!(results.option_value("rebuild"), None);
+        assert_eq!(results.subcommand(), Some("doctor"));
+        assert!(results.subcommand_args().is_empty());
+        let results = parse(&["doctor", "5.11.8-gentoo", "-t"]);
+        assert_eq!(results.subcommand_args(), ["5.11.8-gentoo"]);
+
+        let results = parse(&["--rebuild=5.10.27"]);
+        assert_eq!(results.option_value("rebuild"), Some("5.10.27"));
+        assert!(!parse(&["-t"]).flag_enabled("rebuild"));
+    }
 }
@@ -0,0 +1,237 @@
+use std::convert::TryFrom;
+
+use crate::{
+    bootloader::Bootloader,
+    conf::Config,
+    doctor::Check,
+    error::{ErrorKind, JanitorError},
+    filesystem::FileSystem,
+    kernel::KernelVersion,
+};
+
+/// The config key, per-kernel overrides are `KernelCmdline.<release>`
+const KEY: &str = "KernelCmdline";
+
+/// The command line the kernels should boot with, set with `KernelCmdline` in the config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelCmdline {
+    default: Vec<String>,
+    /// Kernels that boot with a command line of their own, like one that needs `nomodeset`
+    overrides: Vec<(KernelVersion, Vec<String>)>,
+}
+
+fn parameters(cmdline: &str) -> Vec<String> {
+    cmdline.split_whitespace().map(str::to_string).collect()
+}
+
+impl KernelCmdline {
+    pub fn new(default: &str) -> KernelCmdline {
+        KernelCmdline {
+            default: parameters(default),
+            overrides: Vec::new(),
+        }
+    }
+
+    /// `version` boots with `cmdline` instead of the default
+    pub fn with_override(mut self, version: KernelVersion, cmdline: &str) -> KernelCmdline {
+        self.overrides.push((version, parameters(cmdline)));
+        self
+    }
+
+    /// None when `KernelCmdline` isn't set, overrides without it still count
+    pub fn from_config(config: &Config) -> Result<Option<KernelCmdline>, JanitorError> {
+        let override_prefix = format!("{}.", KEY);
+        let overrides = config.names_with_prefix(&override_prefix);
+        if !config.contains(KEY) && overrides.is_empty() {
+            return Ok(None);
+        }
+        let mut cmdline = match config.contains(KEY) {
+            true => KernelCmdline::new(&config.get_string(KEY)?),
+            false => KernelCmdline::new(""),
+        };
+        for name in overrides {
+            let release = &name[override_prefix.len()..];
+            let version = KernelVersion::try_from(release).map_err(|_| {
+                JanitorError::new(
+                    ErrorKind::Config,
+                    format!("{} should name a release like {}.5.11.8-gentoo", name, KEY),
+                )
+            })?;
+            cmdline = cmdline.with_override(version, &config.get_string(name)?);
+        }
+        Ok(Some(cmdline))
+    }
+
+    /// The parameters `version` should boot with, overrides are for one release and its .old image
+    /// but not for other flavors of the same version
+    pub fn for_version(&self, version: &KernelVersion) -> &[String] {
+        self.overrides
+            .iter()
+            .find(|(overridden, _)| overridden.release() == version.release())
+            .map_or(&self.default, |(_, parameters)| parameters)
+    }
+
+    /// The parameters of `expected` that aren't in `cmdline`, `root=/dev/sda2` is missing when
+    /// it's `root=/dev/sda1` instead
+    fn missing<'a>(expected: &'a [String], cmdline: &str) -> Vec<&'a str> {
+        let actual: Vec<&str> = cmdline.split_whitespace().collect();
+        expected
+            .iter()
+            .map(String::as_str)
+            .filter(|parameter| !actual.contains(parameter))
+            .collect()
+    }
+}
+
+/// Every boot entry has the command line its kernel should boot with, and the bootloader's defaults
+/// have the default one, reading the bootloader's files on `fs`
+pub fn check(
+    fs: &dyn FileSystem,
+    bootloader: &dyn Bootloader,
+    cmdline: &KernelCmdline,
+) -> Vec<Check> {
+    let name = format!("{} command line", bootloader.name());
+    let mut checks = Vec::new();
+    match bootloader.cmdline_defaults(fs) {
+        Ok(Some((source, defaults))) => {
+            let missing = KernelCmdline::missing(&cmdline.default, &defaults);
+            checks.push(match missing.is_empty() {
+                true => Check::pass(
+                    &format!("{} defaults", name),
+                    format!("{} has it", source.display()),
+                ),
+                false => Check::warn(
+                    &format!("{} defaults", name),
+                    format!("{} doesn't have {}", source.display(), missing.join(" ")),
+                    "add them there so new entries get them, or change KernelCmdline",
+                ),
+            });
+        }
+        Ok(None) => (),
+        Err(e) => checks.push(Check::fail(
+            &format!("{} defaults", name),
+            e.to_string(),
+            "check that the bootloader's defaults can be read",
+        )),
+    }
+    let entries = match bootloader.cmdlines(fs) {
+        Ok(entries) => entries,
+        Err(e) => {
+            checks.push(Check::fail(
+                &name,
+                e.to_string(),
+                "check that /boot is mounted",
+            ));
+            return checks;
+        }
+    };
+    let wrong: Vec<String> = entries
+        .iter()
+        .filter_map(|(version, entry)| {
+            let missing = KernelCmdline::missing(cmdline.for_version(version), entry);
+            match missing.is_empty() {
+                true => None,
+                false => Some(format!("{} lacks {}", version, missing.join(" "))),
+            }
+        })
+        .collect();
+    checks.push(match wrong.is_empty() {
+        true => Check::pass(
+            &name,
+            format!("all {} entries have KernelCmdline", entries.len()),
+        ),
+        false => Check::fail(
+            &name,
+            wrong.join(", "),
+            "regenerate the bootloader config, or fix the entries by hand",
+        ),
+    });
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{doctor::CheckStatus, filesystem::MemoryFileSystem};
+    use std::path::PathBuf;
+
+    struct FakeBootloader;
+
+    impl Bootloader for FakeBootloader {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+        fn install_entry(
+            &self,
+            _: &crate::update::RunCmdConfig,
+            _: &KernelVersion,
+        ) -> Result<(), JanitorError> {
+            Ok(())
+        }
+        fn remove_entry(
+            &self,
+            _: &crate::update::RunCmdConfig,
+            _: &KernelVersion,
+        ) -> Result<(), JanitorError> {
+            Ok(())
+        }
+        fn regenerate(&self, _: &crate::update::RunCmdConfig) -> Result<(), JanitorError> {
+            Ok(())
+        }
+        fn default_entry(&self, _: &dyn FileSystem) -> Result<Option<KernelVersion>, JanitorError> {
+            Ok(None)
+        }
+        fn entries(&self, _: &dyn FileSystem) -> Result<Option<Vec<KernelVersion>>, JanitorError> {
+            Ok(None)
+        }
+        fn cmdline_defaults(
+            &self,
+            _: &dyn FileSystem,
+        ) -> Result<Option<(PathBuf, String)>, JanitorError> {
+            Ok(Some((
+                PathBuf::from("/etc/default/fake"),
+                "root=/dev/sda2".to_string(),
+            )))
+        }
+        fn cmdlines(
+            &self,
+            _: &dyn FileSystem,
+        ) -> Result<Vec<(KernelVersion, String)>, JanitorError> {
+            Ok(vec![
+                (version("5.10.1-gentoo"), "root=/dev/sda2 quiet".to_string()),
+                (
+                    version("5.11.8-gentoo"),
+                    "root=/dev/sda2 nomodeset".to_string(),
+                ),
+                (version("5.12.0-gentoo"), "root=/dev/sda1 quiet".to_string()),
+            ])
+        }
+    }
+
+    fn version(release: &str) -> KernelVersion {
+        KernelVersion::try_from(release).unwrap()
+    }
+
+    #[test]
+    fn cmdline_checks() {
+        let cmdline = KernelCmdline::new("root=/dev/sda2 quiet")
+            .with_override(version("5.11.8-gentoo"), "root=/dev/sda2 nomodeset");
+        assert_eq!(
+            cmdline.for_version(&version("5.11.8-gentoo.old")),
+            &["root=/dev/sda2", "nomodeset"]
+        );
+        assert_eq!(
+            cmdline.for_version(&version("5.11.8-gentoo-dist")),
+            &["root=/dev/sda2", "quiet"]
+        );
+        let checks = check(&MemoryFileSystem::new(), &FakeBootloader, &cmdline);
+        let statuses: Vec<_> = checks.iter().map(|check| check.status).collect();
+        assert_eq!(statuses, vec![CheckStatus::Warn, CheckStatus::Fail]);
+        assert!(
+            checks[0].detail.contains("doesn't have quiet"),
+            "{:?}",
+            checks[0]
+        );
+        assert_eq!(checks[1].detail, "5.12.0-gentoo lacks root=/dev/sda2");
+    }
+}
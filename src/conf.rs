@@ -1,4 +1,7 @@
-use crate::{error::JanitorError, JanitorErrorFrom};
+use crate::{
+    error::{Context, ErrorKind, JanitorError},
+    info, JanitorErrorFrom,
+};
 use std::{
     collections::HashMap,
     path::{Path, PathBuf},
@@ -19,8 +22,16 @@ pub enum ConfigLineKind {
 }
 
 type EntryName = String;
+/// Sections whose entries are lists in their own right instead of settings, read with `section`
+/// Their names can be anything without clashing with a setting
+const LIST_SECTIONS: &[&str] = &["pipeline"];
+
 pub struct Config {
     entries: HashMap<EntryName, ConfigEntry>,
+    /// The entries of the `LIST_SECTIONS`, in the order they're in the file
+    sections: HashMap<String, Vec<ConfigEntry>>,
+    /// Where the entries were read from, for error messages
+    path: PathBuf,
 }
 
 fn strip_comment(text: &str) -> &str {
@@ -37,6 +48,29 @@ fn strip_comment(text: &str) -> &str {
     }
 }
 
+/// Number of single character edits to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Probably a typo of `name`, e.g. `versionstokeep` or `VersionToKeep` for `VersionsToKeep`
+fn is_similar(name: &str, candidate: &str) -> bool {
+    let name = name.to_lowercase();
+    let candidate = candidate.to_lowercase();
+    edit_distance(&name, &candidate) <= 2
+        || (candidate.len() >= 4 && (name.contains(&candidate) || candidate.contains(&name)))
+}
+
 impl ConfigLineKind {
     pub fn parse(line: &str) -> ConfigLineKind {
         if strip_comment(line).is_empty() {
@@ -85,26 +119,47 @@ impl ConfigEntry {
 
 impl Config {
     pub fn new(path: &Path) -> Result<Config, JanitorError> {
-        let contents = std::fs::read(path)?;
+        let contents = std::fs::read(path)
+            .with_context(|| format!("failed to read {}", path.display()))
+            .map_err(|e| e.with_kind(ErrorKind::Config))?;
 
         let file_str = String::from_utf8_lossy(&contents);
         let lines = file_str.lines();
 
         let mut entries = HashMap::<EntryName, ConfigEntry>::new();
-        for line in lines {
+        let mut sections = HashMap::<String, Vec<ConfigEntry>>::new();
+        // Settings can be in any section, it's only there to group them
+        let mut list_section = None;
+        for (line_num, line) in lines.enumerate() {
             match ConfigLineKind::parse(line) {
-                ConfigLineKind::Section(_) => (), // Ignore sections for now
-                ConfigLineKind::Entry(e) => {
-                    entries.insert(e.name.clone(), e);
-                    ()
+                ConfigLineKind::Section(name) => {
+                    list_section = LIST_SECTIONS.contains(&name.as_str()).then_some(name);
                 }
+                ConfigLineKind::Entry(e) => match &list_section {
+                    Some(section) => sections.entry(section.clone()).or_default().push(e),
+                    None => {
+                        entries.insert(e.name.clone(), e);
+                    }
+                },
                 ConfigLineKind::ParseError(e) => {
-                    return Err(JanitorError::from(e));
+                    return Err(JanitorError::new(
+                        ErrorKind::Config,
+                        format!(
+                            "failed to parse line {} of {}: {}",
+                            line_num + 1,
+                            path.display(),
+                            e
+                        ),
+                    ));
                 }
                 ConfigLineKind::Comment => (),
             }
         }
-        Ok(Config { entries })
+        Ok(Config {
+            entries,
+            sections,
+            path: path.to_path_buf(),
+        })
     }
 
     /// Eh, this isn't needed. Kernel installation configuratino isn't per-user
@@ -122,6 +177,11 @@ impl Config {
         Some(Path::new(home_dir).join(".config"))
     }
 
+    /// The file the config was read from
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     /// Find a Config in the filesystem
     pub fn find_in_fs() -> Result<Config, JanitorError> {
         let path_list: Vec<PathBuf> = vec![
@@ -131,8 +191,8 @@ impl Config {
 
         for conf_path in &path_list {
             if conf_path.exists() {
-                println!("Found config at {:?}", &conf_path);
-                return Config::new(&conf_path);
+                info!("Found config at {:?}", &conf_path);
+                return Config::new(conf_path);
             }
         }
 
@@ -142,37 +202,115 @@ impl Config {
             .map(|p| p.to_string_lossy().to_string())
             .collect::<Vec<String>>()
             .join(", ");
-        Err(JanitorError::from(format!(
-            "No config files found at {}",
-            path_strings
-        )))
+        Err(JanitorError::new(
+            ErrorKind::Config,
+            format!("No config files found at {}", path_strings),
+        ))
+    }
+
+    /// Says where the key was looked for, how to add it and what was probably meant instead
+    /// `example` is a plausible value for the key
+    fn not_found(&self, name: &str, example: &str) -> JanitorError {
+        let mut similar: Vec<&str> = self
+            .entries
+            .keys()
+            .filter(|key| is_similar(name, key))
+            .map(|key| key.as_str())
+            .collect();
+        similar.sort_unstable();
+        let mut message = format!(
+            "{} is missing from {}, add a line like `{} = {}`",
+            name,
+            self.path.display(),
+            name,
+            example
+        );
+        if !similar.is_empty() {
+            message += &format!(". Found similar keys: {}", similar.join(", "));
+        }
+        JanitorError::new(ErrorKind::Config, message)
     }
 
     // TODO use generics but they must be restricted
     pub fn get_usize(&self, name: &str) -> Result<usize, JanitorError> {
         match self.entries.get(name) {
-            Some(e) => e.value.parse::<usize>().map_err(|e| JanitorError::from(e)),
-            None => Err(format!("Config value with name {} was not found!", name).into()),
+            Some(e) => e
+                .value
+                .parse::<usize>()
+                .with_context(|| format!("invalid number {} for {}", e.value, name))
+                .map_err(|e| e.with_kind(ErrorKind::Config)),
+            None => Err(self.not_found(name, "3")),
         }
     }
     pub fn get_bool(&self, name: &str) -> Result<bool, JanitorError> {
         match self.entries.get(name) {
-            Some(e) => e.value.parse::<bool>().map_err(|e| JanitorError::from(e)),
-            None => Err(format!("Config value with name {} was not found!", name).into()),
+            Some(e) => e
+                .value
+                .parse::<bool>()
+                .with_context(|| format!("invalid true/false value {} for {}", e.value, name))
+                .map_err(|e| e.with_kind(ErrorKind::Config)),
+            None => Err(self.not_found(name, "true")),
         }
     }
+    pub fn get_string(&self, name: &str) -> Result<String, JanitorError> {
+        match self.entries.get(name) {
+            Some(e) => Ok(e.value.clone()),
+            None => Err(self.not_found(name, "value")),
+        }
+    }
+    /// A list of values separated by commas and/or whitespace
+    pub fn get_list(&self, name: &str) -> Result<Vec<String>, JanitorError> {
+        Ok(self
+            .get_string(name)?
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|v| !v.is_empty())
+            .map(|v| v.to_string())
+            .collect())
+    }
+    /// The entries of a section like `[pipeline]` in the order they're in, empty when it isn't there
+    pub fn section(&self, name: &str) -> &[ConfigEntry] {
+        self.sections.get(name).map_or(&[], Vec::as_slice)
+    }
+    /// Newer settings are optional so that existing config files keep working
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
     pub fn get_path(&self, name: &str) -> Result<PathBuf, JanitorError> {
         match self.entries.get(name) {
             Some(e) => Ok(PathBuf::from(e.value.clone())),
-            None => Err(JanitorError::from(format!(
-                "Config value with name {} was not found!",
-                name
-            ))),
+            None => Err(self.not_found(name, "/path/to/dir")),
+        }
+    }
+    /// The names of the entries that start with `prefix`, sorted
+    pub fn names_with_prefix(&self, prefix: &str) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .entries
+            .keys()
+            .filter(|name| name.starts_with(prefix))
+            .map(String::as_str)
+            .collect();
+        names.sort_unstable();
+        names
+    }
+    /// Paths separated by commas, paths can have spaces in them
+    pub fn get_paths(&self, name: &str) -> Result<Vec<PathBuf>, JanitorError> {
+        let paths: Vec<_> = self
+            .get_path(name)?
+            .to_string_lossy()
+            .split(',')
+            .map(str::trim)
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .collect();
+        match paths.is_empty() {
+            true => Err(self.not_found(name, "/path/to/dir")),
+            false => Ok(paths),
         }
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison, clippy::unnecessary_cast)]
 mod tests {
     use super::*;
     #[test]
@@ -225,6 +363,51 @@ mod tests {
         assert_eq!(entry.value, "value2");
     }
 
+    #[test]
+    fn invalid_value_names_the_key() {
+        let entry = ConfigEntry::new("VersionsToKeep = three").unwrap();
+        let conf = Config {
+            entries: HashMap::from([(entry.name.clone(), entry)]),
+            sections: HashMap::new(),
+            path: PathBuf::from("/etc/kernel-janitor.conf"),
+        };
+        let message = conf.get_usize("VersionsToKeep").unwrap_err().to_string();
+        assert!(
+            message.contains("invalid number three for VersionsToKeep: invalid digit"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn missing_key_suggests_fix() {
+        let entries = [
+            "versionstokeep = 3",
+            "InstallPath = /boot",
+            "Pretend = true",
+        ]
+        .iter()
+        .map(|line| ConfigEntry::new(line).unwrap())
+        .map(|e| (e.name.clone(), e))
+        .collect();
+        let conf = Config {
+            entries,
+            sections: HashMap::new(),
+            path: PathBuf::from("/etc/kernel-janitor.conf"),
+        };
+        let message = conf.get_usize("VersionsToKeep").unwrap_err().to_string();
+        assert!(
+            message.ends_with(
+                "VersionsToKeep is missing from /etc/kernel-janitor.conf, \
+                 add a line like `VersionsToKeep = 3`. Found similar keys: versionstokeep"
+            ),
+            "{}",
+            message
+        );
+        let message = conf.get_path("SourcePath").unwrap_err().to_string();
+        assert!(!message.contains("similar"), "{}", message);
+    }
+
     #[test]
     fn parse_conf_file() {
         let example_conf = PathBuf::from("kernel-janitor-example.conf");
@@ -236,6 +419,10 @@ mod tests {
         println!("{:?}", path_value);
         assert!(path_value.is_ok());
         assert_eq!(path_value.unwrap(), PathBuf::from("/boot"));
+        assert_eq!(
+            conf.get_paths("KernelSourcePath").unwrap(),
+            vec![PathBuf::from("/usr/src")]
+        );
 
         let usize_value = conf.get_usize("VersionsToKeep");
         println!("{:?}", usize_value);
@@ -0,0 +1,619 @@
+use std::{
+    convert::TryFrom,
+    env,
+    ffi::{CString, OsString},
+    fs,
+    os::{
+        raw::c_int,
+        unix::{ffi::OsStrExt, fs::PermissionsExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    bootloader::BootloaderKind,
+    conf::Config,
+    error::{ErrorKind, JanitorError},
+    history,
+    initramfs::InitramfsKind,
+    inventory,
+    kernel::{self, KernelSearch, KernelVersion},
+    mount,
+    pipeline::Pipeline,
+    policy::{self, CleanupPolicyKind},
+    portage,
+    search_cache::{self, CacheUse},
+    snapshot::SnapshotKind,
+    sys::access,
+    update::Stage,
+    utils,
+};
+
+const W_OK: c_int = 2;
+
+/// How a check went, only failures make `doctor` exit with an error
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CheckStatus {
+    Pass,
+    /// Works, but probably not the way it was meant to
+    Warn,
+    Fail,
+}
+
+impl CheckStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CheckStatus::Pass => "pass",
+            CheckStatus::Warn => "warn",
+            CheckStatus::Fail => "fail",
+        }
+    }
+}
+
+/// The result of one check, with what to do about it when it didn't pass
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    pub(crate) fn pass(name: &str, detail: String) -> Check {
+        Check {
+            name: name.to_string(),
+            status: CheckStatus::Pass,
+            detail,
+            hint: None,
+        }
+    }
+
+    pub(crate) fn warn(name: &str, detail: String, hint: &str) -> Check {
+        Check {
+            name: name.to_string(),
+            status: CheckStatus::Warn,
+            detail,
+            hint: Some(hint.to_string()),
+        }
+    }
+
+    pub(crate) fn fail(name: &str, detail: String, hint: &str) -> Check {
+        Check {
+            name: name.to_string(),
+            status: CheckStatus::Fail,
+            detail,
+            hint: Some(hint.to_string()),
+        }
+    }
+}
+
+/// Whether this process may write to `path`, which root always may
+fn writable(path: &Path) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(c_path) => unsafe { access(c_path.as_ptr(), W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+/// The values a run reads, checked the same way a run would
+fn check_values(config: &Config) -> Check {
+    let results = [
+        config.get_usize("VersionsToKeep").map(|_| ()),
+        config.get_bool("RebuildPortageModules").map(|_| ()),
+        BootloaderKind::from_config(config).map(|_| ()),
+        InitramfsKind::from_config(config).map(|_| ()),
+        kernel::optional_files(config).map(|_| ()),
+        match config.contains("CleanupPolicy") {
+            true => config
+                .get_string("CleanupPolicy")
+                .and_then(|policy| policy.parse::<CleanupPolicyKind>())
+                .map(|_| ()),
+            false => Ok(()),
+        },
+    ];
+    let errors: Vec<String> = results
+        .iter()
+        .filter_map(|r| r.as_ref().err())
+        .map(|e| e.to_string())
+        .collect();
+    match errors.is_empty() {
+        true => Check::pass("config values", "all valid".to_string()),
+        false => Check::fail(
+            "config values",
+            errors.join("; "),
+            "fix these in the config file, kernel-janitor-example.conf has every key",
+        ),
+    }
+}
+
+/// One check for each of the directories `key` lists
+fn check_paths(config: &Config, key: &str) -> Vec<Check> {
+    match config.get_paths(key) {
+        Ok(paths) => paths
+            .iter()
+            .map(|path| check_path(config, key, path))
+            .collect(),
+        Err(e) => vec![Check::fail(
+            key,
+            e.to_string(),
+            "fix it in the config file, kernel-janitor-example.conf has every key",
+        )],
+    }
+}
+
+fn check_path(config: &Config, key: &str, path: &Path) -> Check {
+    if !path.is_dir() {
+        return Check::fail(
+            key,
+            format!("{} is not a directory", path.display()),
+            "create it or point the config at the right directory",
+        );
+    }
+    if writable(path) {
+        return Check::pass(key, format!("{} is writable", path.display()));
+    }
+    match config.contains("EscalationCommand") {
+        true => Check::warn(
+            key,
+            format!(
+                "{} is only writable through EscalationCommand",
+                path.display()
+            ),
+            "make sure EscalationCommand works without a password when running unattended",
+        ),
+        false => Check::fail(
+            key,
+            format!("{} is not writable", path.display()),
+            "run as root, or set EscalationCommand in the config",
+        ),
+    }
+}
+
+/// Whether the mount `install_path` lives on is mounted, if fstab has one for it
+fn check_mounted(install_path: &Path, fstab: &str, mounts: &str) -> Check {
+    match mount::fstab_mount_point(install_path, fstab) {
+        None => Check::pass(
+            "boot mount",
+            format!("{} is not a separate mount", install_path.display()),
+        ),
+        Some(point) if mount::mount_points(mounts).contains(&point) => {
+            Check::pass("boot mount", format!("{} is mounted", point.display()))
+        }
+        Some(point) => Check::fail(
+            "boot mount",
+            format!("{} is in /etc/fstab but not mounted", point.display()),
+            &format!("mount {}, or set MountBoot = true", point.display()),
+        ),
+    }
+}
+
+/// `tool` in one of the directories of `path_var`, like a shell looks for it
+fn find_tool(tool: &str, path_var: &OsString) -> Option<PathBuf> {
+    env::split_paths(path_var)
+        .map(|dir| dir.join(tool))
+        .find(|candidate| {
+            fs::metadata(candidate)
+                .is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        })
+}
+
+/// Commands are run with CommandPath when it's set
+fn command_path(config: &Config) -> OsString {
+    match config.contains("CommandPath") {
+        true => config
+            .get_string("CommandPath")
+            .map(OsString::from)
+            .unwrap_or_default(),
+        false => env::var_os("PATH").unwrap_or_default(),
+    }
+}
+
+/// What a run is going to do, which decides the tools it needs
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum RunKind {
+    /// Build and install the newest kernel, then clean up
+    Update,
+    /// `--build-only`, nothing after the compile
+    BuildOnly,
+    /// `--install-only`, everything after the compile
+    InstallOnly,
+    /// `--delete`, no kernel is built
+    Delete,
+    /// `--clean-only`, only the cleanup stage
+    Clean,
+}
+
+/// The tools the configured stages of `run` run, with the stage that runs them
+/// Any one of a group will do, like gcc or clang. Snapshots and signing aren't part of a stage
+fn required_tools(config: &Config, run: RunKind) -> Vec<(Option<Stage>, Vec<String>)> {
+    let compiles = run == RunKind::Update || run == RunKind::BuildOnly;
+    let installs = run == RunKind::Update || run == RunKind::InstallOnly;
+    let mut tools: Vec<(Option<Stage>, &'static [&'static str])> = Vec::new();
+    // Configured, unlike the others
+    let mut module_rebuild = None;
+    if compiles || installs {
+        tools.push((Some(Stage::Build), &["make"]));
+    }
+    if compiles {
+        tools.push((Some(Stage::Build), &["gcc", "clang"]));
+        let patching =
+            config.contains("PatchesDir") || config.get_bool("PortagePatches").unwrap_or(false);
+        if patching {
+            tools.push((Some(Stage::Patch), &["patch"]));
+        }
+    }
+    if installs {
+        let initramfs: Option<&'static [&'static str]> = match InitramfsKind::from_config(config) {
+            Ok(InitramfsKind::Dracut) => Some(&["dracut"]),
+            Ok(InitramfsKind::Genkernel) => Some(&["genkernel"]),
+            Ok(InitramfsKind::Booster) => Some(&["booster"]),
+            Ok(InitramfsKind::Mkinitcpio) => Some(&["mkinitcpio"]),
+            _ => None,
+        };
+        tools.extend(initramfs.map(|tool| (Some(Stage::Initramfs), tool)));
+        if config.get_bool("RebuildPortageModules").unwrap_or(false) {
+            module_rebuild = portage::module_rebuild_command(config)
+                .ok()
+                .map(|command| command[0].clone());
+        }
+        // Removing a kernel doesn't regenerate grub.cfg
+        if let Ok(BootloaderKind::Grub) = BootloaderKind::from_config(config) {
+            tools.push((Some(Stage::Bootloader), &["grub-mkconfig"]));
+            tools.push((Some(Stage::Bootloader), &["grub-script-check"]));
+            if config.contains("GrubRebootFallback")
+                && config.get_bool("GrubRebootFallback").unwrap_or(false)
+            {
+                tools.push((Some(Stage::Bootloader), &["grub-set-default"]));
+                tools.push((Some(Stage::Bootloader), &["grub-reboot"]));
+            }
+        }
+    }
+    if run != RunKind::BuildOnly {
+        match config
+            .get_string("Snapshot")
+            .and_then(|kind| kind.parse::<SnapshotKind>())
+        {
+            Ok(SnapshotKind::Snapper) => tools.push((None, &["snapper"])),
+            Ok(SnapshotKind::Btrfs) => tools.push((None, &["btrfs"])),
+            Ok(SnapshotKind::Zfs) => tools.push((None, &["zfs"])),
+            Err(_) => (),
+        }
+        if config.contains("SigningKey") {
+            tools.push((None, &["gpg"]));
+        }
+        // tar runs zstd for --zstd
+        if config.contains("ArchiveDir") {
+            tools.push((Some(Stage::Cleanup), &["tar"]));
+            tools.push((Some(Stage::Cleanup), &["zstd"]));
+        }
+    }
+    let mut tools: Vec<_> = tools
+        .into_iter()
+        .map(|(stage, group)| (stage, group.iter().map(|tool| tool.to_string()).collect()))
+        .collect();
+    tools.extend(module_rebuild.map(|program| (Some(Stage::ModuleRebuild), vec![program])));
+    // Stages the pipeline leaves out or replaces don't run their tools, the commands run theirs
+    let pipeline = Pipeline::from_config(config).unwrap_or_default();
+    tools.retain(|(stage, _)| stage.is_none_or(|stage| pipeline.runs_builtin(stage)));
+    if installs {
+        tools.extend(
+            pipeline
+                .programs()
+                .into_iter()
+                .map(|(stage, program)| (Some(stage), vec![program])),
+        );
+    }
+    tools
+}
+
+/// Each group of `tools` with where its first one found is, or None when none of them are
+fn find_tools(tools: &[Vec<String>], path_var: &OsString) -> Vec<(String, Option<PathBuf>)> {
+    tools
+        .iter()
+        .map(|group| {
+            let found = group.iter().find_map(|tool| find_tool(tool, path_var));
+            (group.join(" or "), found)
+        })
+        .collect()
+}
+
+/// The tools the configured stages run
+fn check_tools(config: &Config) -> Vec<Check> {
+    let tools: Vec<_> = required_tools(config, RunKind::Update)
+        .into_iter()
+        .map(|(_, group)| group)
+        .collect();
+    find_tools(&tools, &command_path(config))
+        .into_iter()
+        .map(|(tool, found)| match found {
+            Some(found) => Check::pass(&tool, format!("found at {}", found.display())),
+            None => Check::fail(
+                &tool,
+                format!("{} is not in PATH", tool),
+                "install it, or set CommandPath in the config to where it is",
+            ),
+        })
+        .collect()
+}
+
+/// Fails with every tool the run would need that isn't in PATH, before anything is started
+/// instead of with the first one halfway through. The tools of `skipped` stages aren't needed
+pub fn preflight(config: &Config, run: RunKind, skipped: &[Stage]) -> Result<(), JanitorError> {
+    let tools: Vec<_> = required_tools(config, run)
+        .into_iter()
+        .filter(|(stage, _)| stage.is_none_or(|stage| !skipped.contains(&stage)))
+        .map(|(_, group)| group)
+        .collect();
+    let missing: Vec<String> = find_tools(&tools, &command_path(config))
+        .into_iter()
+        .filter(|(_, found)| found.is_none())
+        .map(|(tool, _)| tool)
+        .collect();
+    match missing.is_empty() {
+        true => Ok(()),
+        false => Err(JanitorError::new(
+            ErrorKind::Config,
+            format!(
+                "Not in PATH: {}. Install them, or set CommandPath in the config to where they are",
+                missing.join(", ")
+            ),
+        )),
+    }
+}
+
+/// Compares the running kernel with the newest sources, `newest_source` is their release
+fn check_source_family(running: Option<&str>, newest_source: Option<&str>) -> Check {
+    let name = "kernel sources";
+    let newest_source = match newest_source {
+        Some(release) => release,
+        None => return Check::fail(
+            name,
+            "no kernel sources found".to_string(),
+            "emerge the kernel sources, like sys-kernel/gentoo-sources, or fix KernelSourcePath",
+        ),
+    };
+    let running = match running {
+        Some(release) => release,
+        None => {
+            return Check::warn(
+                name,
+                "the running kernel is unknown".to_string(),
+                "check that /proc is mounted",
+            )
+        }
+    };
+    let (running_flavor, source_flavor) = (
+        policy::release_flavor(running),
+        policy::release_flavor(newest_source),
+    );
+    if running_flavor != source_flavor {
+        return Check::warn(
+            name,
+            format!(
+                "running {} but the newest sources are {}",
+                running, newest_source
+            ),
+            "emerge the sources of the flavor you run, or ignore this if you're switching",
+        );
+    }
+    let versions = (
+        KernelVersion::try_from(running),
+        KernelVersion::try_from(newest_source),
+    );
+    if let (Ok(running_version), Ok(source_version)) = versions {
+        if running_version > source_version {
+            return Check::warn(
+                name,
+                format!(
+                    "running {} is newer than the newest sources {}",
+                    running, newest_source
+                ),
+                "emerge newer kernel sources",
+            );
+        }
+    }
+    Check::pass(name, format!("newest sources are {}", newest_source))
+}
+
+fn newest_source(config: &Config) -> Result<Option<String>, JanitorError> {
+    let source_paths = config.get_paths("KernelSourcePath")?;
+    let inventory = KernelSearch::new(
+        &config.get_path("InstallPath")?,
+        &source_paths,
+        &config.get_path("KernelModulesPath")?,
+    )
+    .with_cache(
+        search_cache::cache_path(&history::state_dir(config)?),
+        CacheUse::ReadOnly,
+    )
+    .inventory()?;
+    Ok(inventory
+        .kernels()
+        .iter()
+        .rev()
+        .find_map(|k| k.source_path.as_deref())
+        .and_then(utils::paths::filename_from_path)
+        .map(|name| name.trim_start_matches("linux-").to_string()))
+}
+
+/// Check everything a run needs, without changing anything
+pub fn run() -> Vec<Check> {
+    let config = match Config::find_in_fs() {
+        Ok(config) => config,
+        Err(e) => {
+            return vec![Check::fail(
+                "config file",
+                e.to_string(),
+                "copy kernel-janitor-example.conf to /etc/kernel-janitor.conf and adjust it",
+            )]
+        }
+    };
+    let mut checks = vec![
+        Check::pass(
+            "config file",
+            format!("found at {}", config.path().display()),
+        ),
+        check_values(&config),
+    ];
+    for key in &["InstallPath", "KernelModulesPath", "KernelSourcePath"] {
+        checks.extend(check_paths(&config, key));
+    }
+    if let Ok(install_path) = config.get_path("InstallPath") {
+        let fstab = fs::read_to_string("/etc/fstab").unwrap_or_default();
+        let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+        checks.push(check_mounted(&install_path, &fstab, &mounts));
+    }
+    checks.extend(check_tools(&config));
+    match newest_source(&config) {
+        Ok(newest) => checks.push(check_source_family(
+            inventory::running_release().as_deref(),
+            newest.as_deref(),
+        )),
+        Err(e) => checks.push(Check::fail(
+            "kernel sources",
+            e.to_string(),
+            "fix the paths in the config file",
+        )),
+    }
+    checks
+}
+
+/// Fails when any check failed, warnings are fine
+pub fn result(checks: &[Check]) -> Result<(), JanitorError> {
+    let failed = checks
+        .iter()
+        .filter(|c| c.status == CheckStatus::Fail)
+        .count();
+    match failed {
+        0 => Ok(()),
+        n => Err(JanitorError::new(
+            ErrorKind::Config,
+            format!("{} of {} checks failed", n, checks.len()),
+        )),
+    }
+}
+
+/// One line per check, and the hint below it if there is one
+pub fn render(checks: &[Check]) -> String {
+    let mut lines = Vec::new();
+    for check in checks {
+        let status = match check.status {
+            CheckStatus::Pass => "[pass]",
+            CheckStatus::Warn => "[warn]",
+            CheckStatus::Fail => "[FAIL]",
+        };
+        lines.push(format!("{} {}: {}", status, check.name, check.detail));
+        if let Some(hint) = &check.hint {
+            lines.push(format!("       hint: {}", hint));
+        }
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestDir;
+
+    #[test]
+    fn boot_mount() {
+        let fstab = "\
+# <fs>      <mountpoint> <type> <opts>         <dump/pass>
+/dev/sda1   /boot        vfat   noauto,noatime 1 2
+/dev/sda2   /            ext4   noatime        0 1
+";
+        let mounts = "/dev/sda2 / ext4 rw,noatime 0 0\n";
+        let check = check_mounted(Path::new("/boot"), fstab, mounts);
+        assert_eq!(check.status, CheckStatus::Fail);
+        assert_eq!(
+            check.hint.as_deref(),
+            Some("mount /boot, or set MountBoot = true")
+        );
+
+        let mounts = "/dev/sda2 / ext4 rw,noatime 0 0\n/dev/sda1 /boot vfat rw 0 0\n";
+        let check = check_mounted(Path::new("/boot"), fstab, mounts);
+        assert_eq!(check.status, CheckStatus::Pass);
+        // Only the root filesystem, /boot is a plain directory
+        let check = check_mounted(Path::new("/boot"), "/dev/sda2 / ext4 noatime 0 1\n", "");
+        assert_eq!(check.status, CheckStatus::Pass);
+    }
+
+    #[test]
+    fn source_family() {
+        let status = |running, newest| check_source_family(running, newest).status;
+        assert_eq!(
+            status(Some("5.10.1-gentoo"), Some("5.11.8-gentoo")),
+            CheckStatus::Pass
+        );
+        assert_eq!(
+            status(Some("5.10.1-gentoo-dist"), Some("5.11.8-gentoo")),
+            CheckStatus::Warn
+        );
+        assert_eq!(
+            status(Some("5.12.0-gentoo"), Some("5.11.8-gentoo")),
+            CheckStatus::Warn
+        );
+        assert_eq!(status(Some("5.10.1-gentoo"), None), CheckStatus::Fail);
+    }
+
+    #[test]
+    fn preflight_lists_every_missing_tool() -> Result<(), JanitorError> {
+        let test_dir = TestDir::new();
+        let bin = test_dir.path().join("bin");
+        fs::create_dir_all(&bin)?;
+        for tool in &["make", "clang", "grub-mkconfig"] {
+            fs::write(bin.join(tool), "#!/bin/sh\n")?;
+            fs::set_permissions(bin.join(tool), fs::Permissions::from_mode(0o755))?;
+        }
+        // Not executable, so it doesn't count
+        fs::write(bin.join("dracut"), "#!/bin/sh\n")?;
+        let conf_path = test_dir.path().join("kernel-janitor.conf");
+        fs::write(
+            &conf_path,
+            format!(
+                "CommandPath = {}\nBootloader = grub\nInitramfsGenerator = dracut\n\
+                 Snapshot = snapper\n",
+                bin.display()
+            ),
+        )?;
+        let config = Config::new(&conf_path)?;
+
+        let message = preflight(&config, RunKind::Update, &[])
+            .unwrap_err()
+            .to_string();
+        assert!(
+            message.contains("Not in PATH: dracut, grub-script-check, snapper."),
+            "{}",
+            message
+        );
+        // Deleting a kernel doesn't build an initramfs
+        let message = preflight(&config, RunKind::Delete, &[])
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("Not in PATH: snapper."), "{}", message);
+        // Nor does a build on its own install anything
+        assert!(preflight(&config, RunKind::BuildOnly, &[]).is_ok());
+        // Only cleaning up doesn't need make or a compiler
+        fs::remove_file(bin.join("make"))?;
+        fs::remove_file(bin.join("clang"))?;
+        let message = preflight(&config, RunKind::Clean, &[])
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("Not in PATH: snapper."), "{}", message);
+        fs::write(bin.join("make"), "#!/bin/sh\n")?;
+        fs::write(bin.join("clang"), "#!/bin/sh\n")?;
+        for tool in &["make", "clang"] {
+            fs::set_permissions(bin.join(tool), fs::Permissions::from_mode(0o755))?;
+        }
+        let skipped = [Stage::Initramfs, Stage::Bootloader];
+        let message = preflight(&config, RunKind::Update, &skipped)
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains("Not in PATH: snapper."), "{}", message);
+        let checks = check_tools(&config);
+        let compiler = checks.iter().find(|c| c.name == "gcc or clang").unwrap();
+        assert_eq!(compiler.status, CheckStatus::Pass);
+        Ok(())
+    }
+}
@@ -1,26 +1,86 @@
-use std::{fmt, io, num, str};
+use std::{error::Error, fmt, io, num, str};
 // Reference: https://learning-rust.github.io/docs/e7.custom_error_types.html
+/// What went wrong, so callers can decide what to do without parsing messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Missing or invalid config file or values
+    Config,
+    /// Looking for installed kernels failed
+    Search,
+    /// Preparing, compiling or installing the new kernel
+    Build,
+    /// Regenerating the bootloader config
+    Bootloader,
+    /// Removing old kernels
+    Cleanup,
+    /// Root is needed but neither running as root nor configured to escalate
+    NotRoot,
+    /// The user asked the janitor to stop
+    Interrupted,
+    Io,
+    Parse,
+    Other,
+}
+
 #[derive(Debug)]
 pub struct JanitorError {
-    kind: String,
+    kind: ErrorKind,
     message: String,
+    /// The error this one was converted from, if any
+    source: Option<Box<dyn Error + Send + Sync + 'static>>,
 }
 
 /// Use like `format!` but it will create a generic JanitorError instead
 #[macro_export]
 macro_rules! JanitorErrorFrom {
     ($($arg:tt)*) => {{
-        JanitorError::from(format!($($arg)*))
+        $crate::error::JanitorError::from(format!($($arg)*))
     }}
 }
 /// Use like `format!` but it will create a generic JanitorError wrapped in an Err
 #[macro_export]
 macro_rules! JanitorResultErr {
     ($($arg:tt)*) => {{
-        Err(JanitorError::from(format!($($arg)*)))
+        Err($crate::error::JanitorError::from(format!($($arg)*)))
     }}
 }
 
+impl ErrorKind {
+    /// Process exit code for a run that failed with this kind of error
+    /// These are documented in the README, don't renumber them
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorKind::Other | ErrorKind::Io | ErrorKind::Parse => 1,
+            ErrorKind::Config => 2,
+            ErrorKind::NotRoot => 3,
+            ErrorKind::Search => 4,
+            ErrorKind::Build => 5,
+            ErrorKind::Bootloader => 6,
+            ErrorKind::Cleanup => 7,
+            // Same convention as shells, 128 + SIGINT
+            ErrorKind::Interrupted => 130,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ErrorKind::Config => "config",
+            ErrorKind::Search => "search",
+            ErrorKind::Build => "build",
+            ErrorKind::Bootloader => "bootloader",
+            ErrorKind::Cleanup => "cleanup",
+            ErrorKind::NotRoot => "not-root",
+            ErrorKind::Interrupted => "interrupted",
+            ErrorKind::Io => "io",
+            ErrorKind::Parse => "parse",
+            ErrorKind::Other => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 impl fmt::Display for JanitorError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -31,60 +91,136 @@ impl fmt::Display for JanitorError {
     }
 }
 
-impl From<&str> for JanitorError {
-    fn from(msg: &str) -> Self {
+impl JanitorError {
+    pub fn new<S: Into<String>>(kind: ErrorKind, message: S) -> JanitorError {
         JanitorError {
-            kind: "unknown".to_string(),
-            message: msg.to_string(),
+            kind,
+            message: message.into(),
+            source: None,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Recategorize, e.g. an io error that happened while removing old kernels
+    pub fn with_kind(mut self, kind: ErrorKind) -> JanitorError {
+        self.kind = kind;
+        self
+    }
+
+    /// The `io::ErrorKind` of the io error at the bottom of this error's chain, if there is one
+    pub fn io_kind(&self) -> Option<io::ErrorKind> {
+        let mut source = self.source();
+        while let Some(error) = source {
+            if let Some(io_err) = error.downcast_ref::<io::Error>() {
+                return Some(io_err.kind());
+            }
+            source = error.source();
         }
+        None
+    }
+
+    /// What the user could try next, based on the underlying io error
+    pub fn hint(&self) -> Option<&'static str> {
+        match self.io_kind()? {
+            io::ErrorKind::NotFound => Some("Check that /boot is mounted and the configured paths exist"),
+            io::ErrorKind::PermissionDenied => {
+                Some("Run as root (e.g. with sudo) or set EscalationCommand in the config")
+            }
+            io::ErrorKind::StorageFull => {
+                Some("Free up space, e.g. lower VersionsToKeep or run with --delete to remove old kernels")
+            }
+            _ => None,
+        }
+    }
+
+    /// Keeps `error` around as the `source()`
+    fn wrap<E: Error + Send + Sync + 'static>(kind: ErrorKind, error: E) -> JanitorError {
+        JanitorError {
+            kind,
+            message: error.to_string(),
+            source: Some(Box::new(error)),
+        }
+    }
+}
+
+/// Add what was being done when an error happened, like `failed to read /boot`
+/// The message already says what the wrapped error said, so only its cause is kept as the `source()`
+pub trait Context<T> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T, JanitorError>;
+
+    /// Like `context` but only builds the message when there is an error
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T, JanitorError>;
+}
+
+impl<T, E: Into<JanitorError>> Context<T> for Result<T, E> {
+    fn context<C: fmt::Display>(self, context: C) -> Result<T, JanitorError> {
+        self.with_context(|| context)
+    }
+
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T, JanitorError> {
+        self.map_err(|e| {
+            let inner: JanitorError = e.into();
+            JanitorError {
+                kind: inner.kind,
+                message: format!("{}: {}", f(), inner.message),
+                source: inner.source,
+            }
+        })
+    }
+}
+
+impl Error for JanitorError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_deref().map(|e| e as &(dyn Error + 'static))
+    }
+}
+
+impl From<&str> for JanitorError {
+    fn from(msg: &str) -> Self {
+        JanitorError::new(ErrorKind::Other, msg)
     }
 }
 
 impl From<String> for JanitorError {
     fn from(msg: String) -> Self {
-        JanitorError {
-            kind: "unknown".to_string(),
-            message: msg,
-        }
+        JanitorError::new(ErrorKind::Other, msg)
     }
 }
 
 impl From<io::Error> for JanitorError {
     fn from(error: io::Error) -> Self {
-        JanitorError {
-            kind: String::from("std::io::error"),
-            message: error.to_string(),
-        }
+        JanitorError::wrap(ErrorKind::Io, error)
     }
 }
 
 impl From<num::ParseIntError> for JanitorError {
     fn from(error: num::ParseIntError) -> Self {
-        JanitorError {
-            kind: String::from("std::num::ParseIntError"),
-            message: error.to_string(),
-        }
+        JanitorError::wrap(ErrorKind::Parse, error)
     }
 }
 impl From<str::ParseBoolError> for JanitorError {
     fn from(error: str::ParseBoolError) -> Self {
-        JanitorError {
-            kind: String::from("std::str::ParseBoolError"),
-            message: error.to_string(),
-        }
+        JanitorError::wrap(ErrorKind::Parse, error)
     }
 }
 
 impl From<str::Utf8Error> for JanitorError {
     fn from(error: str::Utf8Error) -> Self {
-        JanitorError {
-            kind: String::from("std::str::Utf8Error"),
-            message: error.to_string(),
-        }
+        JanitorError::wrap(ErrorKind::Parse, error)
+    }
+}
+
+impl From<crate::kernel::VersionParseError> for JanitorError {
+    fn from(error: crate::kernel::VersionParseError) -> Self {
+        JanitorError::wrap(ErrorKind::Parse, error)
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::io_other_error)]
 mod tests {
     use super::*;
     use std::io;
@@ -94,6 +230,98 @@ mod tests {
         let _ = JanitorError::from(io_err);
     }
 
+    #[test]
+    fn keeps_source() {
+        let err = JanitorError::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        let source = err
+            .source()
+            .expect("io errors should be kept as the source");
+        let io_err = source.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(io_err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(JanitorError::from("no cause").source().is_none());
+    }
+
+    #[test]
+    fn context_message() {
+        let res: Result<(), io::Error> = Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        let err = res
+            .with_context(|| format!("failed to copy {} to {}", "/boot/a", "/usr/src/b"))
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("failed to copy /boot/a to /usr/src/b: permission denied"),
+            "{}",
+            err
+        );
+        // The io error is still at the bottom of the chain, right below the message that has it
+        let root = err.source().unwrap();
+        assert!(root.downcast_ref::<io::Error>().is_some());
+        assert!(root.source().is_none());
+
+        // Walking the chain says every context once
+        let err = Err::<(), _>(err).context("failed to install").unwrap_err();
+        let mut chain = vec![err.to_string()];
+        let mut source = err.source();
+        while let Some(error) = source {
+            chain.push(error.to_string());
+            source = error.source();
+        }
+        assert_eq!(
+            chain
+                .iter()
+                .filter(|m| m.contains("failed to copy"))
+                .count(),
+            1,
+            "{:?}",
+            chain
+        );
+    }
+
+    #[test]
+    fn io_kind_through_context() {
+        let res: Result<(), io::Error> = Err(io::Error::from(io::ErrorKind::StorageFull));
+        let err = res
+            .context("failed to copy")
+            .context("failed to install")
+            .unwrap_err()
+            .with_kind(ErrorKind::Build);
+        assert_eq!(err.io_kind(), Some(io::ErrorKind::StorageFull));
+        assert!(err.hint().is_some());
+        assert_eq!(JanitorError::from("no io").io_kind(), None);
+        let other = JanitorError::from(io::Error::other("x"));
+        assert_eq!(other.io_kind(), Some(io::ErrorKind::Other));
+        assert_eq!(other.hint(), None);
+    }
+
+    #[test]
+    fn kinds() {
+        assert_eq!(JanitorError::from("x").kind(), ErrorKind::Other);
+        let parse_err = "x".parse::<usize>().unwrap_err();
+        assert_eq!(JanitorError::from(parse_err).kind(), ErrorKind::Parse);
+        let res: Result<(), io::Error> = Err(io::Error::other("x"));
+        let err = res.context("while cleaning").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Io);
+        assert_eq!(err.with_kind(ErrorKind::Cleanup).kind(), ErrorKind::Cleanup);
+    }
+
+    #[test]
+    fn stable_exit_codes() {
+        let codes = [
+            ErrorKind::Other,
+            ErrorKind::Config,
+            ErrorKind::NotRoot,
+            ErrorKind::Search,
+            ErrorKind::Build,
+            ErrorKind::Bootloader,
+            ErrorKind::Cleanup,
+            ErrorKind::Interrupted,
+        ]
+        .iter()
+        .map(ErrorKind::exit_code)
+        .collect::<Vec<_>>();
+        assert_eq!(codes, vec![1, 2, 3, 4, 5, 6, 7, 130]);
+    }
+
     #[test]
     fn macro_test() {
         let err_str = "error";
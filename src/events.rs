@@ -0,0 +1,390 @@
+use std::{
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::JanitorError,
+    info,
+    json::{self, JsonObject},
+    kernel::KernelVersion,
+    log, schema, summary,
+    update::Stage,
+    JanitorErrorFrom,
+};
+
+/// How progress is reported on stdout
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// Something that happened during a run, emitted as one JSON object per line
+pub enum Event<'a> {
+    StageStarted {
+        stage: Stage,
+        version: Option<&'a KernelVersion>,
+    },
+    StageFinished {
+        stage: Stage,
+        version: Option<&'a KernelVersion>,
+        error: Option<&'a JanitorError>,
+    },
+    CommandRun {
+        description: &'a str,
+        pretend: bool,
+    },
+    FileDeleted {
+        path: &'a Path,
+        pretend: bool,
+    },
+    Warning {
+        message: &'a str,
+    },
+    /// The kernels cleanup is about to remove, before asking for confirmation
+    CleanupPlanned {
+        policy: &'a str,
+        versions: &'a [KernelVersion],
+    },
+    Error {
+        error: &'a JanitorError,
+    },
+    /// The last event of a run that attempted anything, carries the summary
+    RunFinished {
+        error: Option<&'a JanitorError>,
+    },
+}
+
+/// Gets told about everything a run does, implement it to embed the janitor in a GUI or bot
+/// and register it with `add_observer`. Every method does nothing by default
+pub trait Observer: Send + Sync {
+    fn on_stage_start(&self, _stage: Stage, _version: Option<&KernelVersion>) {}
+
+    fn on_stage_finish(
+        &self,
+        _stage: Stage,
+        _version: Option<&KernelVersion>,
+        _error: Option<&JanitorError>,
+    ) {
+    }
+
+    /// Called before a command runs, or instead of running it when pretending
+    fn on_command(&self, _description: &str, _pretend: bool) {}
+
+    fn on_file_removed(&self, _path: &Path, _pretend: bool) {}
+
+    /// Don't log a warning from in here, it would come right back
+    fn on_warning(&self, _message: &str) {}
+
+    /// `policy` is the policy's description, `versions` are oldest first
+    fn on_cleanup_planned(&self, _policy: &str, _versions: &[KernelVersion]) {}
+
+    /// The error that ended the run
+    fn on_error(&self, _error: &JanitorError) {}
+
+    /// Everything is done, `summary` has the results
+    fn on_run_finished(&self, _error: Option<&JanitorError>) {}
+}
+
+/// What the command line prints about what a pretend run would have done
+pub struct ConsoleObserver;
+
+impl Observer for ConsoleObserver {
+    fn on_command(&self, description: &str, pretend: bool) {
+        if pretend {
+            info!("Pretending to run {}", description);
+        }
+    }
+
+    fn on_file_removed(&self, path: &Path, pretend: bool) {
+        if pretend {
+            info!("Pretending to delete {:?}", path);
+        }
+    }
+}
+
+/// Writes every event to stdout as one JSON object per line, see `set_format`
+pub struct JsonObserver;
+
+impl JsonObserver {
+    fn write(&self, event: Event) {
+        log::write_stdout(&event.to_json().to_string());
+    }
+}
+
+impl Observer for JsonObserver {
+    fn on_stage_start(&self, stage: Stage, version: Option<&KernelVersion>) {
+        self.write(Event::StageStarted { stage, version });
+    }
+
+    fn on_stage_finish(
+        &self,
+        stage: Stage,
+        version: Option<&KernelVersion>,
+        error: Option<&JanitorError>,
+    ) {
+        self.write(Event::StageFinished {
+            stage,
+            version,
+            error,
+        });
+    }
+
+    fn on_command(&self, description: &str, pretend: bool) {
+        self.write(Event::CommandRun {
+            description,
+            pretend,
+        });
+    }
+
+    fn on_file_removed(&self, path: &Path, pretend: bool) {
+        self.write(Event::FileDeleted { path, pretend });
+    }
+
+    fn on_warning(&self, message: &str) {
+        self.write(Event::Warning { message });
+    }
+
+    fn on_cleanup_planned(&self, policy: &str, versions: &[KernelVersion]) {
+        self.write(Event::CleanupPlanned { policy, versions });
+    }
+
+    fn on_error(&self, error: &JanitorError) {
+        self.write(Event::Error { error });
+    }
+
+    fn on_run_finished(&self, error: Option<&JanitorError>) {
+        self.write(Event::RunFinished { error });
+    }
+}
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+static OBSERVERS: Mutex<Vec<Arc<dyn Observer>>> = Mutex::new(Vec::new());
+
+impl FromStr for OutputFormat {
+    type Err = JanitorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(JanitorErrorFrom!(
+                "Unknown output format {}, expected text or json",
+                s
+            )),
+        }
+    }
+}
+
+impl Event<'_> {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::StageStarted { .. } => "stage_started",
+            Event::StageFinished { .. } => "stage_finished",
+            Event::CommandRun { .. } => "command_run",
+            Event::FileDeleted { .. } => "file_deleted",
+            Event::Warning { .. } => "warning",
+            Event::CleanupPlanned { .. } => "cleanup_planned",
+            Event::Error { .. } => "error",
+            Event::RunFinished { .. } => "run_finished",
+        }
+    }
+
+    pub fn to_json(&self) -> JsonObject {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let obj = schema::document()
+            .string("event", self.name())
+            .number("timestamp", timestamp);
+        match self {
+            Event::StageStarted { stage, version } => obj
+                .string("stage", &stage.to_string())
+                .optional_string("version", version.map(|v| v.to_string()).as_deref()),
+            Event::StageFinished {
+                stage,
+                version,
+                error,
+            } => obj
+                .string("stage", &stage.to_string())
+                .optional_string("version", version.map(|v| v.to_string()).as_deref())
+                .boolean("success", error.is_none())
+                .optional_string("error", error.map(|e| e.to_string()).as_deref()),
+            Event::CommandRun {
+                description,
+                pretend,
+            } => obj
+                .string("command", description)
+                .boolean("pretend", *pretend),
+            Event::FileDeleted { path, pretend } => obj
+                .string("path", &path.to_string_lossy())
+                .boolean("pretend", *pretend),
+            Event::Warning { message } => obj.string("message", message),
+            Event::CleanupPlanned { policy, versions } => obj.string("policy", policy).raw(
+                "versions",
+                json::array(versions.iter().map(|v| json::string(&v.to_string()))),
+            ),
+            Event::Error { error } => obj
+                .string("kind", &error.kind().to_string())
+                .string("message", &error.to_string()),
+            Event::RunFinished { error } => obj
+                .boolean("success", error.is_none())
+                .optional_string("error", error.map(|e| e.to_string()).as_deref())
+                .raw("summary", summary::to_json().to_string()),
+        }
+    }
+}
+
+/// Returned by `add_observer`, the observer stops being told once this is dropped
+#[must_use = "the observer is removed again when the guard is dropped"]
+pub struct ObserverGuard {
+    observer: Arc<dyn Observer>,
+}
+
+impl ObserverGuard {
+    /// Leave the observer registered until the janitor exits
+    pub fn keep(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl Drop for ObserverGuard {
+    fn drop(&mut self) {
+        OBSERVERS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|observer| !Arc::ptr_eq(observer, &self.observer));
+    }
+}
+
+/// `observer` is told about everything from now on, until the guard is dropped
+pub fn add_observer(observer: Arc<dyn Observer>) -> ObserverGuard {
+    OBSERVERS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(observer.clone());
+    ObserverGuard { observer }
+}
+
+/// JSON output takes over stdout, so regular messages are moved to stderr
+/// Only picks the format, a `JsonObserver` still has to be added to write the events
+pub fn set_format(format: OutputFormat) {
+    let json = format == OutputFormat::Json;
+    JSON_OUTPUT.store(json, Ordering::Relaxed);
+    log::reserve_stdout(json);
+}
+
+pub fn json_enabled() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// Tells every observer about the event
+pub fn emit(event: Event) {
+    // Observers may log, which emits again, so don't hold the lock while calling them
+    let observers = OBSERVERS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    for observer in observers {
+        match event {
+            Event::StageStarted { stage, version } => observer.on_stage_start(stage, version),
+            Event::StageFinished {
+                stage,
+                version,
+                error,
+            } => observer.on_stage_finish(stage, version, error),
+            Event::CommandRun {
+                description,
+                pretend,
+            } => observer.on_command(description, pretend),
+            Event::FileDeleted { path, pretend } => observer.on_file_removed(path, pretend),
+            Event::Warning { message } => observer.on_warning(message),
+            Event::CleanupPlanned { policy, versions } => {
+                observer.on_cleanup_planned(policy, versions)
+            }
+            Event::Error { error } => observer.on_error(error),
+            Event::RunFinished { error } => observer.on_run_finished(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn stage_finished_json() {
+        let err = JanitorError::from("boom");
+        let event = Event::StageFinished {
+            stage: Stage::Build,
+            version: None,
+            error: Some(&err),
+        };
+        let json = event.to_json().to_string();
+        assert!(json.starts_with(r#"{"schema_version":1,"event":"stage_finished","timestamp":"#));
+        assert!(json.contains(r#""stage":"build","version":null,"success":false"#));
+    }
+
+    #[test]
+    fn cleanup_planned_json() {
+        let versions = [KernelVersion::new(5, 4, 97, None, false)];
+        let event = Event::CleanupPlanned {
+            policy: "keep the newest 3",
+            versions: &versions,
+        };
+        assert!(event
+            .to_json()
+            .to_string()
+            .ends_with(r#""policy":"keep the newest 3","versions":["5.4.97"]}"#));
+    }
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        seen: Mutex<Vec<String>>,
+    }
+
+    impl Observer for RecordingObserver {
+        fn on_command(&self, description: &str, _pretend: bool) {
+            self.seen.lock().unwrap().push(description.to_string());
+        }
+
+        fn on_warning(&self, message: &str) {
+            self.seen.lock().unwrap().push(message.to_string());
+        }
+    }
+
+    #[test]
+    fn observers_are_told() {
+        let observer = Arc::new(RecordingObserver::default());
+        let guard = add_observer(observer.clone());
+        emit(Event::CommandRun {
+            description: "make -j 8 in observers_are_told",
+            pretend: true,
+        });
+        crate::warn!("warning from observers_are_told");
+        drop(guard);
+        crate::warn!("removed before observers_are_told");
+        // Other tests emit too
+        let seen = observer.seen.lock().unwrap();
+        let ours: Vec<_> = seen
+            .iter()
+            .filter(|s| s.ends_with("observers_are_told"))
+            .collect();
+        assert_eq!(
+            ours,
+            vec![
+                "make -j 8 in observers_are_told",
+                "warning from observers_are_told"
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_format() {
+        assert_eq!("JSON".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+}
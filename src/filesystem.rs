@@ -0,0 +1,484 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use crate::utils;
+
+/// The filesystem operations the janitor performs on installed kernels
+/// Pretend mode swaps in a `MemoryFileSystem` so it runs the same code as a real run
+pub trait FileSystem {
+    /// Every entry directly inside `dir`
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Entries directly inside `dir` whose file name starts with `prefix`
+    fn read_dir_with_prefix(&self, prefix: &str, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self
+            .read_dir(dir)?
+            .into_iter()
+            .filter(|path| {
+                utils::paths::filename_from_path(path)
+                    .unwrap_or_default()
+                    .starts_with(prefix)
+            })
+            .collect())
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    fn is_symlink(&self, path: &Path) -> bool;
+
+    /// Total size of a file or everything under a directory
+    fn disk_usage(&self, path: &Path) -> io::Result<u64>;
+
+    /// Copy a single file, returns the number of bytes copied
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64>;
+
+    /// Remove a file or directory tree that's inside one of `roots`, see `utils::paths::safe_rm`
+    fn remove(&self, path: &Path, roots: &[PathBuf]) -> io::Result<()>;
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// When a file was last written
+    fn modified(&self, path: &Path) -> io::Result<SystemTime>;
+
+    /// The contents of a text file, like a bootloader config
+    fn read_to_string(&self, path: &Path) -> io::Result<String>;
+
+    /// The contents of any file, like a kernel image
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+}
+
+/// Passes everything through to the real filesystem
+#[derive(Default)]
+pub struct RealFileSystem;
+
+impl FileSystem for RealFileSystem {
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        utils::paths::all_paths(dir)
+    }
+
+    fn read_dir_with_prefix(&self, prefix: &str, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        utils::paths::all_paths_with_prefix(prefix, dir)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        path.is_symlink()
+    }
+
+    fn disk_usage(&self, path: &Path) -> io::Result<u64> {
+        utils::paths::disk_usage(path)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        fs::copy(from, to)
+    }
+
+    fn remove(&self, path: &Path, roots: &[PathBuf]) -> io::Result<()> {
+        utils::paths::safe_rm(path, roots)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        fs::metadata(path)?.modified()
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Node {
+    File {
+        size: u64,
+    },
+    Dir,
+    /// Hides whatever is at this path (and below it) on the real filesystem
+    Removed,
+}
+
+/// Keeps every change in memory
+/// When created with `over_real` reads fall through to the real filesystem for anything
+/// that wasn't changed, so it can stand in for it during a pretend run
+/// Clones share the same state
+#[derive(Default, Clone)]
+pub struct MemoryFileSystem {
+    nodes: Arc<Mutex<BTreeMap<PathBuf, Node>>>,
+    /// What's in the files that were added with their contents, the others only have a size
+    contents: Arc<Mutex<BTreeMap<PathBuf, String>>>,
+    over_real: bool,
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{:?} does not exist", path),
+    )
+}
+
+/// Resolve `.` and `..` without touching the filesystem
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+impl MemoryFileSystem {
+    /// Starts out looking exactly like the real filesystem
+    pub fn over_real() -> MemoryFileSystem {
+        MemoryFileSystem {
+            over_real: true,
+            ..MemoryFileSystem::default()
+        }
+    }
+
+    fn insert(&self, path: &Path, node: Node) {
+        let path = normalize(path);
+        let mut nodes = self.nodes.lock().unwrap_or_else(|e| e.into_inner());
+        for ancestor in path.ancestors().skip(1) {
+            if ancestor.as_os_str().is_empty() {
+                break;
+            }
+            match nodes.get(ancestor) {
+                Some(Node::Dir) => break,
+                _ => nodes.insert(ancestor.to_path_buf(), Node::Dir),
+            };
+        }
+        nodes.insert(path, node);
+    }
+
+    /// `to` has what `from` has, if its contents are known
+    fn copy_contents(&self, from: &Path, to: &Path) {
+        let mut contents = self.contents.lock().unwrap_or_else(|e| e.into_inner());
+        match contents.get(&normalize(from)).cloned() {
+            Some(copied) => contents.insert(normalize(to), copied),
+            None => contents.remove(&normalize(to)),
+        };
+    }
+
+    /// What's at `path`, looking at the real filesystem for anything untouched
+    fn node(&self, path: &Path) -> Option<Node> {
+        let path = normalize(path);
+        let nodes = self.nodes.lock().unwrap_or_else(|e| e.into_inner());
+        // The closest change wins, a removed parent hides everything below it
+        for ancestor in path.ancestors() {
+            match nodes.get(ancestor) {
+                Some(Node::Removed) => return None,
+                Some(node) if ancestor == path => return Some(*node),
+                _ => (),
+            }
+        }
+        if !self.over_real {
+            return None;
+        }
+        fs::symlink_metadata(&path).ok().map(|m| match m.is_dir() {
+            true => Node::Dir,
+            false => Node::File { size: m.len() },
+        })
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        match self.node(dir) {
+            Some(Node::Dir) => (),
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{:?} is not a directory", dir),
+                ))
+            }
+            None => return Err(not_found(dir)),
+        }
+        let normalized = normalize(dir);
+        let mut names: Vec<PathBuf> = self
+            .nodes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .keys()
+            .filter(|path| path.parent() == Some(normalized.as_path()))
+            .map(|path| dir.join(path.file_name().unwrap_or_default()))
+            .collect();
+        if self.over_real {
+            if let Ok(real) = utils::paths::all_paths(dir) {
+                names.extend(real);
+            }
+        }
+        names.sort();
+        names.dedup();
+        names.retain(|path| self.node(path).is_some());
+        Ok(names)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        if self.node(path).is_none() {
+            return Err(not_found(path));
+        }
+        match self.over_real {
+            true => fs::canonicalize(path).or_else(|_| Ok(normalize(path))),
+            false => Ok(normalize(path)),
+        }
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        self.over_real && self.node(path).is_some() && path.is_symlink()
+    }
+
+    fn disk_usage(&self, path: &Path) -> io::Result<u64> {
+        match self.node(path) {
+            Some(Node::File { size }) => Ok(size),
+            Some(Node::Dir) => self
+                .read_dir(path)?
+                .iter()
+                .try_fold(0, |total, child| Ok(total + self.disk_usage(child)?)),
+            _ => Err(not_found(path)),
+        }
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        match self.node(from) {
+            Some(Node::File { size }) => {
+                self.insert(to, Node::File { size });
+                self.copy_contents(from, to);
+                Ok(size)
+            }
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a file", from),
+            )),
+            None => Err(not_found(from)),
+        }
+    }
+
+    fn remove(&self, path: &Path, roots: &[PathBuf]) -> io::Result<()> {
+        if self.node(path).is_none() {
+            return Err(not_found(path));
+        }
+        // Still refuse what a real run would refuse
+        if self.over_real && fs::symlink_metadata(path).is_ok() {
+            utils::paths::check_removable(path, roots)?;
+        } else if !roots.iter().any(|root| {
+            normalize(path).starts_with(normalize(root)) && normalize(path) != normalize(root)
+        }) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Refusing to delete {:?}: not inside any of the configured directories {:?}",
+                    path, roots
+                ),
+            ));
+        }
+        let normalized = normalize(path);
+        let mut nodes = self.nodes.lock().unwrap_or_else(|e| e.into_inner());
+        nodes.retain(|p, _| !p.starts_with(&normalized));
+        nodes.insert(normalized, Node::Removed);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        match self.node(from) {
+            Some(Node::File { size }) => {
+                self.insert(to, Node::File { size });
+                self.copy_contents(from, to);
+            }
+            // Everything below a directory moves along with it
+            Some(Node::Dir) => {
+                self.insert(to, Node::Dir);
+                for child in self.read_dir(from)? {
+                    self.rename(&child, &to.join(child.file_name().unwrap_or_default()))?;
+                }
+            }
+            _ => return Err(not_found(from)),
+        }
+        self.nodes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(normalize(from), Node::Removed);
+        Ok(())
+    }
+
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        if self.node(path).is_none() {
+            return Err(not_found(path));
+        }
+        // Only files that are still untouched on disk have a time
+        match self.over_real {
+            true => fs::metadata(path)?.modified(),
+            false => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{:?} only exists in memory and has no modification time",
+                    path
+                ),
+            )),
+        }
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.node(path) {
+            Some(Node::File { .. }) => (),
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{:?} is not a file", path),
+                ))
+            }
+            None => return Err(not_found(path)),
+        }
+        let normalized = normalize(path);
+        if let Some(contents) = self
+            .contents
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&normalized)
+        {
+            return Ok(contents.clone().into_bytes());
+        }
+        // Only files that are still untouched on disk can be read
+        let untouched = !self
+            .nodes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains_key(&normalized);
+        match self.over_real && untouched {
+            true => fs::read(path),
+            false => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{:?} only exists in memory and has no contents", path),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    impl MemoryFileSystem {
+        /// Empty, nothing exists until it's added
+        pub fn new() -> MemoryFileSystem {
+            MemoryFileSystem::default()
+        }
+
+        /// Add a file of `size` bytes, parent directories are created as needed
+        pub fn with_file(self, path: &Path, size: u64) -> MemoryFileSystem {
+            self.insert(path, Node::File { size });
+            self
+        }
+
+        /// Add a text file with `contents`
+        pub fn with_contents(self, path: &Path, contents: &str) -> MemoryFileSystem {
+            self.insert(
+                path,
+                Node::File {
+                    size: contents.len() as u64,
+                },
+            );
+            self.contents
+                .lock()
+                .unwrap()
+                .insert(normalize(path), contents.to_string());
+            self
+        }
+
+        /// Add a directory and its parents
+        pub fn with_dir(self, path: &Path) -> MemoryFileSystem {
+            self.insert(path, Node::Dir);
+            self
+        }
+
+        pub fn exists(&self, path: &Path) -> bool {
+            self.node(path).is_some()
+        }
+    }
+
+    #[test]
+    fn memory_only() {
+        let memfs = MemoryFileSystem::new()
+            .with_file(Path::new("/boot/vmlinuz-5.4.97-gentoo"), 100)
+            .with_file(Path::new("/boot/config-5.4.97-gentoo"), 28)
+            .with_dir(Path::new("/lib/modules/5.4.97-gentoo"));
+        let roots = vec![PathBuf::from("/boot")];
+
+        assert_eq!(memfs.disk_usage(Path::new("/boot")).unwrap(), 128);
+        assert_eq!(
+            memfs
+                .read_dir_with_prefix("vmlinuz-", Path::new("/boot"))
+                .unwrap(),
+            vec![PathBuf::from("/boot/vmlinuz-5.4.97-gentoo")]
+        );
+        assert!(memfs.remove(Path::new("/boot"), &roots).is_err());
+        assert!(memfs
+            .remove(Path::new("/lib/modules/5.4.97-gentoo"), &roots)
+            .is_err());
+
+        memfs
+            .remove(Path::new("/boot/vmlinuz-5.4.97-gentoo"), &roots)
+            .unwrap();
+        memfs
+            .rename(
+                Path::new("/boot/config-5.4.97-gentoo"),
+                Path::new("/boot/config-5.4.97-gentoo.old"),
+            )
+            .unwrap();
+        assert_eq!(
+            memfs.read_dir(Path::new("/boot")).unwrap(),
+            vec![PathBuf::from("/boot/config-5.4.97-gentoo.old")]
+        );
+    }
+
+    #[test]
+    fn over_real_leaves_disk_alone() {
+        let test_dir = TestDir::new();
+        let root = test_dir.install_path();
+        fs::create_dir_all(root.join("kernel")).unwrap();
+        fs::write(root.join("kernel/vmlinuz"), [0u8; 10]).unwrap();
+        fs::write(root.join("config"), [0u8; 5]).unwrap();
+        let memfs = MemoryFileSystem::over_real();
+        let roots = vec![root.clone()];
+
+        assert_eq!(memfs.disk_usage(&root.join("kernel")).unwrap(), 10);
+        memfs.remove(&root.join("kernel"), &roots).unwrap();
+        memfs
+            .copy(&root.join("config"), &root.join(".config"))
+            .unwrap();
+
+        assert!(!memfs.exists(&root.join("kernel/vmlinuz")));
+        assert_eq!(
+            memfs.read_dir(&root).unwrap(),
+            vec![root.join(".config"), root.join("config")]
+        );
+        // Nothing actually happened
+        assert!(root.join("kernel/vmlinuz").exists());
+        assert!(!root.join(".config").exists());
+    }
+}
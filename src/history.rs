@@ -0,0 +1,439 @@
+use std::{
+    convert::TryFrom,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use crate::{
+    conf::Config,
+    debug,
+    error::{Context, JanitorError},
+    kernel::KernelVersion,
+    summary,
+    update::RunCmdConfig,
+    utils, warn,
+};
+
+/// Where the state is kept when `StateDir` isn't set
+pub const DEFAULT_STATE_DIR: &str = "/var/lib/kernel-janitor";
+const HISTORY_FILE: &str = "history.tsv";
+/// Every kernel that was seen running, one per line
+const BOOT_LOG: &str = "booted";
+
+/// One finished run, a line of the history file
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct RunRecord {
+    /// Seconds since the epoch when the run finished
+    pub timestamp: u64,
+    /// `success`, `degraded`, or the kind of error that ended the run
+    pub outcome: String,
+    pub built: Option<KernelVersion>,
+    pub build_time: Option<Duration>,
+    pub removed: Vec<KernelVersion>,
+    pub bytes_freed: u64,
+    /// What every installed kernel took up after the run
+    pub installed_bytes: Option<u64>,
+    /// Taken before installing and cleaning up, see `snapshot::take`
+    pub snapshots: Vec<String>,
+    /// The kernel the run ran on, which booted fine
+    pub booted: Option<KernelVersion>,
+}
+
+static STATE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+fn parse_optional<T, F: FnOnce(&str) -> Option<T>>(field: &str, parse: F) -> Option<Option<T>> {
+    match field {
+        "-" => Some(None),
+        _ => parse(field).map(Some),
+    }
+}
+
+impl RunRecord {
+    /// Tab separated, `-` for missing values
+    pub fn to_line(&self) -> String {
+        let removed = match self.removed.is_empty() {
+            true => "-".to_string(),
+            false => self
+                .removed
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+        };
+        [
+            self.timestamp.to_string(),
+            self.outcome.clone(),
+            optional(self.built.as_ref()),
+            optional(self.build_time.map(|d| d.as_secs())),
+            removed,
+            self.bytes_freed.to_string(),
+            optional(self.installed_bytes),
+            match self.snapshots.is_empty() {
+                true => "-".to_string(),
+                false => self.snapshots.join(","),
+            },
+            optional(self.booted.as_ref()),
+        ]
+        .join("\t")
+    }
+
+    /// None if the line is malformed
+    pub fn from_line(line: &str) -> Option<RunRecord> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        // Lines written before snapshots and the booted kernel were recorded have fewer fields
+        if !(7..=9).contains(&fields.len()) {
+            return None;
+        }
+        let snapshots = match fields.get(7) {
+            None | Some(&"-") => Vec::new(),
+            Some(list) => list.split(',').map(str::to_string).collect(),
+        };
+        let version = |s: &str| KernelVersion::try_from(s).ok();
+        let booted = match fields.get(8) {
+            Some(field) => parse_optional(field, version)?,
+            None => None,
+        };
+        let removed = match fields[4] {
+            "-" => Vec::new(),
+            list => list.split(',').map(version).collect::<Option<Vec<_>>>()?,
+        };
+        Some(RunRecord {
+            timestamp: fields[0].parse().ok()?,
+            outcome: fields[1].to_string(),
+            built: parse_optional(fields[2], version)?,
+            build_time: parse_optional(fields[3], |s| s.parse().ok().map(Duration::from_secs))?,
+            removed,
+            bytes_freed: fields[5].parse().ok()?,
+            installed_bytes: parse_optional(fields[6], |s| s.parse().ok())?,
+            snapshots,
+            booted,
+        })
+    }
+}
+
+/// `StateDir`, or the default when it isn't set
+pub fn state_dir(config: &Config) -> Result<PathBuf, JanitorError> {
+    match config.contains("StateDir") {
+        true => config.get_path("StateDir"),
+        false => Ok(PathBuf::from(DEFAULT_STATE_DIR)),
+    }
+}
+
+pub fn history_path(state_dir: &Path) -> PathBuf {
+    state_dir.join(HISTORY_FILE)
+}
+
+/// Runs are recorded to `state_dir` from now on
+pub fn init(state_dir: &Path) {
+    let _ = STATE_DIR.set(state_dir.to_path_buf());
+}
+
+pub fn append(state_dir: &Path, record: &RunRecord) -> Result<(), JanitorError> {
+    let path = history_path(state_dir);
+    fs::create_dir_all(state_dir)
+        .with_context(|| format!("failed to create {}", state_dir.display()))?;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    writeln!(file, "{}", record.to_line())
+        .with_context(|| format!("failed to write to {}", path.display()))
+}
+
+/// Every recorded run, oldest first. Lines that can't be parsed are skipped
+pub fn read(state_dir: &Path) -> Result<Vec<RunRecord>, JanitorError> {
+    let path = history_path(state_dir);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).context(format!("failed to read {}", path.display())),
+    };
+    Ok(contents
+        .lines()
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| {
+            let record = RunRecord::from_line(line);
+            if record.is_none() {
+                debug!("Skipping malformed history line {:?}", line);
+            }
+            record
+        })
+        .collect())
+}
+
+/// Every kernel that was seen running, oldest first
+/// Runs that change nothing aren't in the history, but they still tell what booted
+pub fn read_booted(state_dir: &Path) -> Result<Vec<KernelVersion>, JanitorError> {
+    let path = state_dir.join(BOOT_LOG);
+    match fs::read_to_string(&path) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .filter_map(|line| KernelVersion::try_from(line).ok())
+            .collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).context(format!("failed to read {}", path.display())),
+    }
+}
+
+/// Add `version` to the boot log, unless it's in it already
+pub fn record_booted(
+    cmd_config: &RunCmdConfig,
+    state_dir: &Path,
+    version: &KernelVersion,
+) -> Result<(), JanitorError> {
+    let mut booted = read_booted(state_dir)?;
+    if booted.contains(version) {
+        return Ok(());
+    }
+    booted.push(version.clone());
+    let contents: String = booted.iter().map(|v| format!("{}\n", v)).collect();
+    cmd_config.write_file(&state_dir.join(BOOT_LOG), &contents, 0o644)
+}
+
+/// Add the run that just finished to the history, if it changed anything
+/// Called once at the very end, after `init`
+pub fn record(error: Option<&JanitorError>) {
+    let (state_dir, record) = match (STATE_DIR.get(), summary::run_record(error)) {
+        (Some(state_dir), Some(record)) => (state_dir, record),
+        _ => return,
+    };
+    if let Err(e) = append(state_dir, &record) {
+        warn!("Could not record this run in the history: {}", e);
+    }
+}
+
+/// A table of the runs, oldest first
+pub fn render(records: &[RunRecord]) -> String {
+    if records.is_empty() {
+        return "No runs recorded yet".to_string();
+    }
+    let mut lines = vec![format!(
+        "{:<18}{:<13}{:<10}{:<12}{:<10}{}",
+        "Date (UTC)", "Outcome", "Built", "Build time", "Freed", "Removed"
+    )];
+    for record in records {
+        let removed = record
+            .removed
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let line = format!(
+            "{:<18}{:<13}{:<10}{:<12}{:<10}{}",
+            utils::format_timestamp(record.timestamp),
+            record.outcome,
+            optional(record.built.as_ref()),
+            record
+                .build_time
+                .map(utils::format_duration)
+                .unwrap_or_else(|| "-".to_string()),
+            utils::format_bytes(record.bytes_freed),
+            removed
+        );
+        lines.push(line.trim_end().to_string());
+        if !record.snapshots.is_empty() {
+            lines.push(format!("  snapshots: {}", record.snapshots.join(", ")));
+        }
+    }
+    lines.join("\n")
+}
+
+/// Signed difference like `+1.5 GiB` or `-200 B`
+fn format_change(from: u64, to: u64) -> String {
+    match to >= from {
+        true => format!("+{}", utils::format_bytes(to - from)),
+        false => format!("-{}", utils::format_bytes(from - to)),
+    }
+}
+
+/// Build times per version, how they compare to the average and the previous build,
+/// and how the space used by installed kernels changed
+pub fn render_stats(records: &[RunRecord]) -> String {
+    let builds: Vec<(&RunRecord, KernelVersion, Duration)> = records
+        .iter()
+        .filter_map(|r| Some((r, r.built.clone()?, r.build_time?)))
+        .collect();
+    let mut lines = Vec::new();
+    match builds.len() {
+        0 => lines.push("No builds recorded yet".to_string()),
+        n => {
+            let total: Duration = builds.iter().map(|(_, _, time)| *time).sum();
+            lines.push(format!(
+                "{} builds, {} on average",
+                n,
+                utils::format_duration(total / n as u32)
+            ));
+            lines.push(format!(
+                "  {:<18}{:<10}{:<14}{}",
+                "Date (UTC)", "Version", "Build time", "Change"
+            ));
+            let mut previous: Option<Duration> = None;
+            for (record, version, time) in &builds {
+                // Shows the effect of things like ccache or LTO from one build to the next
+                let change = match previous {
+                    Some(prev) if *time >= prev => {
+                        format!("+{}", utils::format_duration(*time - prev))
+                    }
+                    Some(prev) => format!("-{}", utils::format_duration(prev - *time)),
+                    None => String::new(),
+                };
+                let line = format!(
+                    "  {:<18}{:<10}{:<14}{}",
+                    utils::format_timestamp(record.timestamp),
+                    version.to_string(),
+                    utils::format_duration(*time),
+                    change
+                );
+                lines.push(line.trim_end().to_string());
+                previous = Some(*time);
+            }
+        }
+    }
+
+    let usage: Vec<(u64, u64)> = records
+        .iter()
+        .filter_map(|r| Some((r.timestamp, r.installed_bytes?)))
+        .collect();
+    if let (Some(first), Some(last)) = (usage.first(), usage.last()) {
+        lines.push(String::new());
+        lines.push(format!(
+            "Installed kernels use {}, {} since {}",
+            utils::format_bytes(last.1),
+            format_change(first.1, last.1),
+            utils::format_timestamp(first.0)
+        ));
+        lines.push(format!(
+            "  {:<18}{:<12}{}",
+            "Date (UTC)", "Installed", "Change"
+        ));
+        let mut previous: Option<u64> = None;
+        for (timestamp, bytes) in &usage {
+            let change = previous
+                .map(|prev| format_change(prev, *bytes))
+                .unwrap_or_default();
+            let line = format!(
+                "  {:<18}{:<12}{}",
+                utils::format_timestamp(*timestamp),
+                utils::format_bytes(*bytes),
+                change
+            );
+            lines.push(line.trim_end().to_string());
+            previous = Some(*bytes);
+        }
+    }
+    lines.push(format!(
+        "Freed by cleanup: {} over {} runs",
+        utils::format_bytes(records.iter().map(|r| r.bytes_freed).sum()),
+        records.len()
+    ));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::TestDir,
+        update::{InteractiveStatus, PretendStatus},
+    };
+
+    #[test]
+    fn append_and_read() -> Result<(), JanitorError> {
+        let test_dir = TestDir::new();
+        let state_dir = test_dir.path().join("state");
+        let release = |release: &str| KernelVersion::try_from(release).unwrap();
+        // Recorded by full release, another flavor of the same version is another kernel
+        let record = RunRecord {
+            timestamp: 1_616_249_100,
+            outcome: "success".to_string(),
+            built: Some(release("5.11.8-gentoo")),
+            build_time: Some(Duration::from_secs(1800)),
+            removed: vec![release("5.4.97-gentoo"), release("5.10.1-gentoo-dist")],
+            bytes_freed: 4096,
+            installed_bytes: None,
+            snapshots: vec!["snapper:41".to_string(), "snapper:42".to_string()],
+            booted: Some(release("5.10.1-gentoo")),
+        };
+        assert_eq!(
+            record.to_line(),
+            "1616249100\tsuccess\t5.11.8-gentoo\t1800\t5.4.97-gentoo,5.10.1-gentoo-dist\t4096\t-\tsnapper:41,snapper:42\t5.10.1-gentoo"
+        );
+        // Written before snapshots were recorded
+        let old = RunRecord::from_line("1616249100\tsuccess\t5.11.8\t1800\t-\t0\t-").unwrap();
+        assert!(old.snapshots.is_empty());
+        assert_eq!(old.booted, None);
+        fs::create_dir_all(&state_dir).unwrap();
+        fs::write(
+            history_path(&state_dir),
+            format!("{}\nnot a record\n", record.to_line()),
+        )
+        .unwrap();
+        append(&state_dir, &record)?;
+        assert_eq!(read(&state_dir)?, vec![record.clone(), record]);
+        assert!(read(&test_dir.path().join("missing"))?.is_empty());
+
+        let booted = release("5.11.8-gentoo");
+        let cmd_config = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off);
+        record_booted(&cmd_config, &state_dir, &booted)?;
+        record_booted(&cmd_config, &state_dir, &booted)?;
+        record_booted(&cmd_config, &state_dir, &release("5.11.8-gentoo-dist"))?;
+        assert_eq!(
+            read_booted(&state_dir)?,
+            vec![booted.clone(), release("5.11.8-gentoo-dist")]
+        );
+        // Pretending leaves the boot log alone
+        let pretend = RunCmdConfig::new(PretendStatus::Pretend, InteractiveStatus::Off);
+        record_booted(&pretend, &state_dir, &release("5.12.0-gentoo"))?;
+        assert_eq!(
+            read_booted(&state_dir)?,
+            vec![booted, release("5.11.8-gentoo-dist")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stats() {
+        let record = |timestamp, minor, mins: u64, installed| RunRecord {
+            timestamp,
+            outcome: "success".to_string(),
+            built: Some(KernelVersion::new(5, minor, 0, None, false)),
+            build_time: Some(Duration::from_secs(mins * 60)),
+            removed: Vec::new(),
+            bytes_freed: 1024,
+            installed_bytes: Some(installed),
+            snapshots: Vec::new(),
+            booted: None,
+        };
+        let stats = render_stats(&[record(0, 10, 30, 2048), record(86400, 11, 20, 1024)]);
+        assert!(
+            stats.starts_with("2 builds, 25m 0s on average\n"),
+            "{}",
+            stats
+        );
+        assert!(
+            stats.contains("  1970-01-02 00:00  5.11.0    20m 0s        -10m 0s\n"),
+            "{}",
+            stats
+        );
+        assert!(
+            stats.contains("Installed kernels use 1.0 KiB, -1.0 KiB since 1970-01-01 00:00"),
+            "{}",
+            stats
+        );
+        assert!(
+            stats.ends_with("Freed by cleanup: 2.0 KiB over 2 runs"),
+            "{}",
+            stats
+        );
+    }
+}
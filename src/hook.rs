@@ -0,0 +1,103 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::{Context, JanitorError},
+    info,
+    update::RunCmdConfig,
+};
+
+/// Portage runs every executable in here after `emerge --sync`
+pub const POSTSYNC_DIR: &str = "/etc/portage/postsync.d";
+const HOOK_NAME: &str = "kernel-janitor";
+
+/// Runs the update check from the directory the config was found in, so the same config is used
+pub fn hook_script(binary: &Path, config_dir: &Path) -> String {
+    format!(
+        "#!/bin/sh\n\
+         # Installed by 'kernel-janitor install-hook', tells about new kernel sources after a sync\n\
+         cd {} && exec {} --pretend --update-check\n",
+        shell_quote(config_dir),
+        shell_quote(binary)
+    )
+}
+
+/// Single quoted for sh, a quote in the path ends the quoting, is escaped and starts it again
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.display().to_string().replace('\'', "'\\''"))
+}
+
+/// Write the postsync hook into `hook_dir` for the running binary and the config at `config_path`
+/// Returns where it was written
+pub fn install(
+    cmd_config: &RunCmdConfig,
+    hook_dir: &Path,
+    config_path: &Path,
+) -> Result<PathBuf, JanitorError> {
+    let binary = env::current_exe().context("failed to find the kernel-janitor binary")?;
+    let config_dir = fs::canonicalize(config_path.parent().unwrap_or(Path::new(".")))
+        .with_context(|| format!("failed to resolve {}", config_path.display()))?;
+    let script = hook_script(&binary, &config_dir);
+    let hook = hook_dir.join(HOOK_NAME);
+    // /etc/portage is root's, with an escalation command the script is put in place with `install`
+    cmd_config.write_file(&hook, &script, 0o755)?;
+    match cmd_config.pretending() {
+        true => info!("Would write {}:\n{}", hook.display(), script),
+        false => info!(
+            "Installed {}, new kernel sources are reported after every sync",
+            hook.display()
+        ),
+    }
+    Ok(hook)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TestDir;
+    use crate::update::{InteractiveStatus, PretendStatus};
+    use std::{os::unix::fs::PermissionsExt, process::Command};
+
+    #[test]
+    fn install_postsync_hook() -> Result<(), JanitorError> {
+        let test_dir = TestDir::new();
+        let config_path = test_dir.path().join("kernel-janitor.conf");
+        fs::write(&config_path, b"").unwrap();
+        let hook_dir = test_dir.path().join("postsync.d");
+        let cmd_config = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off);
+        let hook = install(&cmd_config, &hook_dir, &config_path)?;
+        let script = fs::read_to_string(&hook).unwrap();
+        assert!(script.starts_with("#!/bin/sh\n"), "{}", script);
+        assert!(
+            script.contains(&format!("cd '{}' && exec '", test_dir.path().display())),
+            "{}",
+            script
+        );
+        assert!(
+            script.ends_with("' --pretend --update-check\n"),
+            "{}",
+            script
+        );
+        assert_eq!(
+            fs::metadata(&hook).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn hook_script_quotes_paths() {
+        let test_dir = TestDir::new();
+        let config_dir = test_dir.path().join("it's here");
+        fs::create_dir_all(&config_dir).unwrap();
+        let script = hook_script(Path::new("/bin/echo"), &config_dir);
+        let out = Command::new("sh").arg("-c").arg(&script).output().unwrap();
+        assert!(out.status.success(), "{}", script);
+        assert_eq!(
+            String::from_utf8_lossy(&out.stdout),
+            "--pretend --update-check\n"
+        );
+    }
+}
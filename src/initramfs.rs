@@ -0,0 +1,372 @@
+use std::{
+    convert::TryFrom,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::{
+    conf::Config,
+    error::{Context, JanitorError},
+    filesystem::FileSystem,
+    kernel::{self, KernelVersion},
+    update::RunCmdConfig,
+    utils, JanitorErrorFrom,
+};
+
+/// Builds initramfs images and knows which files it left in the install path
+pub trait InitramfsGenerator: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Build the initramfs for `release`, the kernel's `uname -r` like `5.11.8-gentoo`
+    /// `src_dir` is the kernel source it was built from
+    fn generate(
+        &self,
+        cmd_config: &RunCmdConfig,
+        release: &str,
+        src_dir: &Path,
+    ) -> Result<(), JanitorError>;
+
+    /// Whether `generate` builds from the kernel sources, so kernels without them are skipped
+    fn needs_sources(&self) -> bool {
+        false
+    }
+
+    /// Every file this generator made for `version`
+    fn artifact_paths(
+        &self,
+        fs: &dyn FileSystem,
+        version: &KernelVersion,
+    ) -> Result<Vec<PathBuf>, JanitorError>;
+
+    /// Delete everything `artifact_paths` finds, returns the bytes freed
+    fn remove(
+        &self,
+        cmd_config: &RunCmdConfig,
+        version: &KernelVersion,
+    ) -> Result<u64, JanitorError> {
+        self.artifact_paths(cmd_config.fs.as_ref(), version)?
+            .iter()
+            .try_fold(0, |total, path| {
+                Ok(total + kernel::remove_path(path, cmd_config)?)
+            })
+    }
+}
+
+/// Which `InitramfsGenerator` to use, set with `InitramfsGenerator` in the config
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum InitramfsKind {
+    None,
+    Dracut,
+    Genkernel,
+    Booster,
+    Mkinitcpio,
+}
+
+impl FromStr for InitramfsKind {
+    type Err = JanitorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(InitramfsKind::None),
+            "dracut" => Ok(InitramfsKind::Dracut),
+            "genkernel" => Ok(InitramfsKind::Genkernel),
+            "booster" => Ok(InitramfsKind::Booster),
+            "mkinitcpio" => Ok(InitramfsKind::Mkinitcpio),
+            _ => Err(JanitorErrorFrom!(
+                "Unknown initramfs generator {}, expected one of none, dracut, genkernel, booster, mkinitcpio",
+                s
+            )),
+        }
+    }
+}
+
+impl InitramfsKind {
+    /// `InitramfsGenerator` in the config, none when it's missing
+    pub fn from_config(config: &Config) -> Result<InitramfsKind, JanitorError> {
+        match config.contains("InitramfsGenerator") {
+            true => config.get_string("InitramfsGenerator")?.parse(),
+            false => Ok(InitramfsKind::None),
+        }
+    }
+
+    /// Images are written to and looked for in `install_path`
+    pub fn create(self, install_path: &Path) -> Option<Arc<dyn InitramfsGenerator>> {
+        let install_path = install_path.to_path_buf();
+        match self {
+            InitramfsKind::None => None,
+            InitramfsKind::Dracut => Some(Arc::new(Dracut { install_path })),
+            InitramfsKind::Genkernel => Some(Arc::new(Genkernel { install_path })),
+            InitramfsKind::Booster => Some(Arc::new(Booster { install_path })),
+            InitramfsKind::Mkinitcpio => Some(Arc::new(Mkinitcpio {
+                install_path,
+                preset_dir: PathBuf::from(MKINITCPIO_PRESETS),
+            })),
+        }
+    }
+}
+
+/// Files in `dir` named `<prefix><release>...` that belong to `version`
+fn artifacts_with_prefix(
+    fs: &dyn FileSystem,
+    dir: &Path,
+    prefix: &str,
+    version: &KernelVersion,
+) -> Result<Vec<PathBuf>, JanitorError> {
+    let candidates = fs
+        .read_dir_with_prefix(prefix, dir)
+        .with_context(|| format!("failed to read {}", dir.display()))?;
+    Ok(candidates
+        .into_iter()
+        .filter(|path| {
+            // The extension isn't part of the release
+            utils::paths::filename_from_path(path)
+                .and_then(|name| KernelVersion::try_from(name.trim_end_matches(".img")).ok())
+                .is_some_and(|v| &v == version)
+        })
+        .collect())
+}
+
+/// Writes `initramfs-<release>.img`
+pub struct Dracut {
+    install_path: PathBuf,
+}
+
+impl InitramfsGenerator for Dracut {
+    fn name(&self) -> &'static str {
+        "dracut"
+    }
+
+    fn generate(
+        &self,
+        cmd_config: &RunCmdConfig,
+        release: &str,
+        _: &Path,
+    ) -> Result<(), JanitorError> {
+        let image = self.install_path.join(format!("initramfs-{}.img", release));
+        let mut dracut = Command::new("dracut");
+        dracut.arg("--force").arg("--kver").arg(release).arg(&image);
+        utils::exec_and_print_command(
+            &mut cmd_config.privileged(dracut),
+            format!("\'dracut --force --kver {} {:?}\'", release, image),
+            cmd_config,
+        )
+    }
+
+    fn artifact_paths(
+        &self,
+        fs: &dyn FileSystem,
+        version: &KernelVersion,
+    ) -> Result<Vec<PathBuf>, JanitorError> {
+        artifacts_with_prefix(fs, &self.install_path, "initramfs-", version)
+    }
+}
+
+/// Also writes `initramfs-<release>.img`, but works out the release from the source tree
+pub struct Genkernel {
+    install_path: PathBuf,
+}
+
+impl InitramfsGenerator for Genkernel {
+    fn name(&self) -> &'static str {
+        "genkernel"
+    }
+
+    fn needs_sources(&self) -> bool {
+        true
+    }
+
+    fn generate(
+        &self,
+        cmd_config: &RunCmdConfig,
+        _: &str,
+        src_dir: &Path,
+    ) -> Result<(), JanitorError> {
+        let mut genkernel = Command::new("genkernel");
+        genkernel
+            .arg(format!("--bootdir={}", self.install_path.display()))
+            .arg(format!("--kerneldir={}", src_dir.display()))
+            .arg("initramfs");
+        utils::exec_and_print_command(
+            &mut cmd_config.privileged(genkernel),
+            format!("\'genkernel initramfs\' for {:?}", src_dir),
+            cmd_config,
+        )
+    }
+
+    fn artifact_paths(
+        &self,
+        fs: &dyn FileSystem,
+        version: &KernelVersion,
+    ) -> Result<Vec<PathBuf>, JanitorError> {
+        artifacts_with_prefix(fs, &self.install_path, "initramfs-", version)
+    }
+}
+
+/// Writes `booster-<release>.img`
+pub struct Booster {
+    install_path: PathBuf,
+}
+
+impl InitramfsGenerator for Booster {
+    fn name(&self) -> &'static str {
+        "booster"
+    }
+
+    fn generate(
+        &self,
+        cmd_config: &RunCmdConfig,
+        release: &str,
+        _: &Path,
+    ) -> Result<(), JanitorError> {
+        let image = self.install_path.join(format!("booster-{}.img", release));
+        let mut booster = Command::new("booster");
+        booster
+            .arg("build")
+            .arg("--force")
+            .arg("--kernel-version")
+            .arg(release)
+            .arg(&image);
+        utils::exec_and_print_command(
+            &mut cmd_config.privileged(booster),
+            format!("\'booster build --kernel-version {} {:?}\'", release, image),
+            cmd_config,
+        )
+    }
+
+    fn artifact_paths(
+        &self,
+        fs: &dyn FileSystem,
+        version: &KernelVersion,
+    ) -> Result<Vec<PathBuf>, JanitorError> {
+        artifacts_with_prefix(fs, &self.install_path, "booster-", version)
+    }
+}
+
+/// Where mkinitcpio's presets are, one `<pkgbase>.preset` per kernel
+const MKINITCPIO_PRESETS: &str = "/etc/mkinitcpio.d";
+
+/// Arch's generator. Uses the preset of the configured Pkgbase when there is one, so the images
+/// are the ones it names (`initramfs-<pkgbase>.img` and its fallback), `initramfs-<release>.img` otherwise
+pub struct Mkinitcpio {
+    install_path: PathBuf,
+    preset_dir: PathBuf,
+}
+
+impl InitramfsGenerator for Mkinitcpio {
+    fn name(&self) -> &'static str {
+        "mkinitcpio"
+    }
+
+    fn generate(
+        &self,
+        cmd_config: &RunCmdConfig,
+        release: &str,
+        _: &Path,
+    ) -> Result<(), JanitorError> {
+        let mut mkinitcpio = Command::new("mkinitcpio");
+        let preset = cmd_config.naming.pkgbase.as_ref().filter(|pkgbase| {
+            let preset = self.preset_dir.join(format!("{}.preset", pkgbase));
+            cmd_config.fs.disk_usage(&preset).is_ok()
+        });
+        let cmd_desc = match preset {
+            Some(pkgbase) => {
+                mkinitcpio.arg("-p").arg(pkgbase);
+                format!("\'mkinitcpio -p {}\'", pkgbase)
+            }
+            None => {
+                let image = self.install_path.join(format!("initramfs-{}.img", release));
+                mkinitcpio.arg("-k").arg(release).arg("-g").arg(&image);
+                format!("\'mkinitcpio -k {} -g {:?}\'", release, image)
+            }
+        };
+        utils::exec_and_print_command(&mut cmd_config.privileged(mkinitcpio), cmd_desc, cmd_config)
+    }
+
+    /// The preset's images are named after the pkgbase and always belong to the installed kernel
+    fn artifact_paths(
+        &self,
+        fs: &dyn FileSystem,
+        version: &KernelVersion,
+    ) -> Result<Vec<PathBuf>, JanitorError> {
+        artifacts_with_prefix(fs, &self.install_path, "initramfs-", version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filesystem::MemoryFileSystem,
+        naming::Naming,
+        runner::RecordingRunner,
+        test_utils::TestDir,
+        update::{InteractiveStatus, PretendStatus},
+    };
+
+    #[test]
+    fn generate_commands() -> Result<(), JanitorError> {
+        let runner = RecordingRunner::new();
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone());
+        let src_dir = Path::new("/usr/src/linux-5.11.8-gentoo");
+        for kind in &[
+            InitramfsKind::Dracut,
+            InitramfsKind::Genkernel,
+            InitramfsKind::Booster,
+            InitramfsKind::Mkinitcpio,
+        ] {
+            kind.create(Path::new("/boot"))
+                .unwrap()
+                .generate(&cfg, "5.11.8-gentoo", src_dir)?;
+        }
+        assert_eq!(
+            runner.command_lines(),
+            vec![
+                "dracut --force --kver 5.11.8-gentoo /boot/initramfs-5.11.8-gentoo.img",
+                "genkernel --bootdir=/boot --kerneldir=/usr/src/linux-5.11.8-gentoo initramfs",
+                "booster build --force --kernel-version 5.11.8-gentoo /boot/booster-5.11.8-gentoo.img",
+                "mkinitcpio -k 5.11.8-gentoo -g /boot/initramfs-5.11.8-gentoo.img",
+            ]
+        );
+
+        // With a preset for the pkgbase, it names the images
+        let test_dir = TestDir::new();
+        std::fs::write(test_dir.path().join("linux-custom.preset"), b"").unwrap();
+        let mkinitcpio = Mkinitcpio {
+            install_path: PathBuf::from("/boot"),
+            preset_dir: test_dir.path().to_path_buf(),
+        };
+        let runner = RecordingRunner::new();
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone())
+            .with_naming(Naming::default().with_pkgbase(Some("linux-custom".to_string())));
+        mkinitcpio.generate(&cfg, "5.11.8-gentoo", src_dir)?;
+        assert_eq!(runner.command_lines(), vec!["mkinitcpio -p linux-custom"]);
+        Ok(())
+    }
+
+    #[test]
+    fn remove_artifacts() -> Result<(), JanitorError> {
+        let memfs = MemoryFileSystem::new()
+            .with_file(Path::new("/boot/initramfs-5.10.1-gentoo.img"), 30)
+            .with_file(Path::new("/boot/initramfs-5.11.8-gentoo.img"), 30)
+            .with_file(Path::new("/boot/booster-5.10.1-gentoo.img"), 20);
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_fs(memfs.clone())
+            .with_removable_roots(vec![PathBuf::from("/boot")]);
+        let version = KernelVersion::try_from("5.10.1-gentoo").unwrap();
+
+        let dracut = InitramfsKind::Dracut.create(Path::new("/boot")).unwrap();
+        assert_eq!(
+            dracut.artifact_paths(&memfs, &version)?,
+            vec![PathBuf::from("/boot/initramfs-5.10.1-gentoo.img")]
+        );
+        assert_eq!(dracut.remove(&cfg, &version)?, 30);
+        assert!(!memfs.exists(Path::new("/boot/initramfs-5.10.1-gentoo.img")));
+        assert!(memfs.exists(Path::new("/boot/initramfs-5.11.8-gentoo.img")));
+        assert!(memfs.exists(Path::new("/boot/booster-5.10.1-gentoo.img")));
+        Ok(())
+    }
+}
@@ -0,0 +1,1050 @@
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    archive,
+    error::{Context, ErrorKind, JanitorError},
+    filesystem::FileSystem,
+    history::RunRecord,
+    initramfs::InitramfsGenerator,
+    kernel::{InstalledItemKind, InstalledKernel, KernelSearch, KernelVersion},
+    policy,
+    update::RunCmdConfig,
+    utils, JanitorErrorFrom,
+};
+
+/// Every installed kernel, oldest first, and what can be asked of or done to them
+pub struct KernelInventory {
+    kernels: Vec<InstalledKernel>,
+    /// Never removed by `uninstall` or offered by `removable`
+    pinned: HashSet<KernelVersion>,
+    /// The kernel that last booted fine, kept like a pinned one to have something to go back to
+    fallback: Option<KernelVersion>,
+    fs: Arc<dyn FileSystem>,
+    /// Its images are counted in the size of each kernel
+    initramfs: Option<Arc<dyn InitramfsGenerator>>,
+    /// What the bootloader boots when nobody picks a kernel
+    default_boot: Option<KernelVersion>,
+    /// The default saved in the bootloader's environment, like GRUB's `saved_entry`
+    saved_default: Option<KernelVersion>,
+    /// What the bootloader boots the next time only, like after `grub-reboot`
+    next_boot: Option<KernelVersion>,
+    /// Kernels the bootloader has an entry for, None without a bootloader
+    boot_entries: Option<Vec<KernelVersion>>,
+    /// The newest kernel that was ever seen running, None when none was
+    newest_booted: Option<KernelVersion>,
+    /// The release of the kernel that's running, `running_release` unless told otherwise
+    running_release: Option<String>,
+}
+
+/// Whether a kernel has what it needs to boot, None when there's nothing to check against
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct BootStatus {
+    /// Only checked when an `InitramfsGenerator` is configured
+    pub initramfs: Option<bool>,
+    /// Only checked when a `Bootloader` is configured
+    pub boot_entry: Option<bool>,
+}
+
+impl BootStatus {
+    pub fn bootable(&self) -> bool {
+        self.initramfs != Some(false) && self.boot_entry != Some(false)
+    }
+}
+
+/// Where the space of a kernel goes, see `KernelInventory::space`
+#[derive(Default, PartialEq, Eq, Debug, Clone, Copy)]
+pub struct SpaceUsage {
+    /// The whole source tree, build artifacts included
+    pub sources: u64,
+    /// The part of `sources` that `make clean` would free
+    pub build_artifacts: u64,
+    pub modules: u64,
+    /// Kernel image, config, System.map and initramfs
+    pub boot: u64,
+}
+
+impl SpaceUsage {
+    /// The same as `KernelInventory::size`
+    pub fn total(&self) -> u64 {
+        self.sources + self.modules + self.boot
+    }
+}
+
+/// Files kbuild generates in the source tree, roughly what `make clean` removes
+fn is_build_artifact(name: &str) -> bool {
+    const SUFFIXES: [&str; 8] = [
+        ".o", ".ko", ".a", ".cmd", ".mod", ".mod.c", ".order", ".dtb",
+    ];
+    name == "vmlinux"
+        || name.starts_with("vmlinux.")
+        || name == "bzImage"
+        || name == "Image"
+        || SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+}
+
+/// The order of `--list`, set with `--sort`
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ListOrder {
+    /// Oldest first
+    Version,
+    /// Largest first
+    Size,
+    /// Installed first, kernels without an image last
+    Date,
+}
+
+/// Which kernels `--list` shows, every kernel when nothing is set
+#[derive(Default, Debug, Clone)]
+pub struct ListFilter {
+    /// Installed longer ago than this
+    pub older_than: Option<Duration>,
+    /// Like `gentoo-dist`, see `policy::flavor`
+    pub flavor: Option<String>,
+    /// Missing some of the files every kernel should have
+    pub missing_files: bool,
+    /// Only the leftovers in `KernelInventory::orphans`
+    pub orphans: bool,
+}
+
+impl FromStr for ListOrder {
+    type Err = JanitorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "version" => Ok(ListOrder::Version),
+            "size" => Ok(ListOrder::Size),
+            "date" => Ok(ListOrder::Date),
+            _ => Err(JanitorErrorFrom!(
+                "Unknown sort order {}, expected one of version, size, date",
+                s
+            )),
+        }
+    }
+}
+
+impl KernelSearch {
+    /// Run the search and wrap the results up
+    pub fn inventory(&self) -> Result<KernelInventory, JanitorError> {
+        Ok(KernelInventory::new(self.execute()?, self.filesystem()))
+    }
+}
+
+/// The release of the kernel that's running right now, like `uname -r`
+pub(crate) fn running_release() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/osrelease")
+        .ok()
+        .map(|release| release.trim().to_string())
+}
+
+impl KernelInventory {
+    /// Sizes are measured on `fs`, which should be the one the kernels were found on
+    pub fn new(mut kernels: Vec<InstalledKernel>, fs: Arc<dyn FileSystem>) -> KernelInventory {
+        kernels.sort();
+        KernelInventory {
+            kernels,
+            pinned: HashSet::new(),
+            fallback: None,
+            fs,
+            initramfs: None,
+            default_boot: None,
+            saved_default: None,
+            next_boot: None,
+            boot_entries: None,
+            newest_booted: None,
+            running_release: running_release(),
+        }
+    }
+
+    /// Run on a kernel of `release` instead of the one that's actually running
+    pub fn with_running_release(mut self, release: Option<String>) -> KernelInventory {
+        self.running_release = release;
+        self
+    }
+
+    /// Count the images `initramfs` made as part of each kernel
+    pub fn with_initramfs(
+        mut self,
+        initramfs: Option<Arc<dyn InitramfsGenerator>>,
+    ) -> KernelInventory {
+        self.initramfs = initramfs;
+        self
+    }
+
+    /// Oldest first, newest last
+    pub fn kernels(&self) -> &[InstalledKernel] {
+        &self.kernels
+    }
+
+    pub fn len(&self) -> usize {
+        self.kernels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kernels.is_empty()
+    }
+
+    pub fn newest(&self) -> Option<&InstalledKernel> {
+        self.kernels.last()
+    }
+
+    /// The installed kernel matching the running one, if it can be told which one that is
+    pub fn running(&self) -> Option<&InstalledKernel> {
+        self.by_release(self.running_release.as_deref()?)
+    }
+
+    /// `version` is what `Bootloader::default_entry` found
+    pub fn with_default_boot(mut self, version: Option<KernelVersion>) -> KernelInventory {
+        self.default_boot = version;
+        self
+    }
+
+    /// `version` is what `Bootloader::saved_default` found
+    pub fn with_saved_default(mut self, version: Option<KernelVersion>) -> KernelInventory {
+        self.saved_default = version;
+        self
+    }
+
+    /// `version` is what `Bootloader::next_boot` found
+    pub fn with_next_boot(mut self, version: Option<KernelVersion>) -> KernelInventory {
+        self.next_boot = version;
+        self
+    }
+
+    /// `entries` are what `Bootloader::entries` found
+    pub fn with_boot_entries(mut self, entries: Option<Vec<KernelVersion>>) -> KernelInventory {
+        self.boot_entries = entries;
+        self
+    }
+
+    /// None for kernels without an image, there's nothing to boot
+    pub fn boot_status(
+        &self,
+        kernel: &InstalledKernel,
+    ) -> Result<Option<BootStatus>, JanitorError> {
+        if kernel.vmlinuz_path.is_none() {
+            return Ok(None);
+        }
+        let initramfs = match &self.initramfs {
+            // Old kernels share the initramfs of the release they were replaced by
+            Some(_) if kernel.version.is_old() => None,
+            Some(initramfs) => Some(
+                !initramfs
+                    .artifact_paths(self.fs.as_ref(), &kernel.version)?
+                    .is_empty(),
+            ),
+            None => None,
+        };
+        let boot_entry = self
+            .boot_entries
+            .as_ref()
+            .map(|entries| entries.contains(&kernel.version));
+        Ok(Some(BootStatus {
+            initramfs,
+            boot_entry,
+        }))
+    }
+
+    /// The running kernel, or else the one the latest run in `history` ran on that's still installed
+    pub fn last_booted(&self, history: &[RunRecord]) -> Option<KernelVersion> {
+        self.running().map(|k| k.version.clone()).or_else(|| {
+            history
+                .iter()
+                .rev()
+                .filter_map(|record| record.booted.clone())
+                .find(|booted| self.by_version(booted).is_some())
+        })
+    }
+
+    /// `version` is kept whatever the cleanup policy says, see `last_booted`
+    pub fn with_fallback(mut self, version: Option<KernelVersion>) -> KernelInventory {
+        self.fallback = version;
+        self
+    }
+
+    /// `booted` are the kernels the history and the boot log saw running, see `awaiting_boot`
+    pub fn with_booted(mut self, booted: &[KernelVersion]) -> KernelInventory {
+        let running = self.running().map(|k| k.version.clone());
+        self.newest_booted = booted.iter().cloned().chain(running).max();
+        self
+    }
+
+    /// Installed kernels newer than any that was ever seen running, the update isn't finished
+    /// until one of them is booted. Nothing when no kernel was seen running yet
+    pub fn awaiting_boot(&self) -> Vec<&InstalledKernel> {
+        let newest_booted = match &self.newest_booted {
+            Some(version) => version,
+            None => return Vec::new(),
+        };
+        self.kernels
+            .iter()
+            .filter(|k| k.vmlinuz_path.is_some() && !k.version.is_old())
+            .filter(|k| &k.version > newest_booted)
+            .collect()
+    }
+
+    pub fn fallback(&self) -> Option<&InstalledKernel> {
+        self.by_version(self.fallback.as_ref()?)
+    }
+
+    /// The installed kernel booted by default, if the bootloader could tell
+    pub fn default_boot(&self) -> Option<&InstalledKernel> {
+        self.by_version(self.default_boot.as_ref()?)
+    }
+
+    /// Whether the bootloader's environment saved `version` as the default, removing it leaves
+    /// the bootloader without one
+    pub fn is_saved_default(&self, version: &KernelVersion) -> bool {
+        self.saved_default.as_ref() == Some(version)
+    }
+
+    /// The installed kernel the bootloader boots the next time only, if there's one
+    pub fn next_boot(&self) -> Option<&InstalledKernel> {
+        self.by_version(self.next_boot.as_ref()?)
+    }
+
+    /// Annotations for a listing, like `running` and `default boot`
+    pub fn markers(&self, kernel: &InstalledKernel) -> Result<Vec<&'static str>, JanitorError> {
+        let mut markers = Vec::new();
+        if self.running().is_some_and(|k| k.version == kernel.version) {
+            markers.push("running");
+        }
+        if self.is_saved_default(&kernel.version) {
+            markers.push("saved default");
+        } else if self.default_boot.as_ref() == Some(&kernel.version) {
+            markers.push("default boot");
+        }
+        if self.next_boot.as_ref() == Some(&kernel.version) {
+            markers.push("boots next");
+        }
+        if self.is_pinned(&kernel.version) {
+            markers.push("pinned");
+        }
+        if self.fallback.as_ref() == Some(&kernel.version) {
+            markers.push("fallback");
+        }
+        if self.awaiting_boot().contains(&kernel) {
+            markers.push("not booted yet");
+        }
+        if self
+            .boot_status(kernel)?
+            .is_some_and(|status| !status.bootable())
+        {
+            markers.push("unbootable");
+        }
+        if kernel.source_path.is_some() && !self.source_complete(kernel) {
+            markers.push("incomplete sources");
+        }
+        Ok(markers)
+    }
+
+    /// Find a kernel by a release string like `5.4.97-gentoo`
+    pub fn by_release(&self, release: &str) -> Option<&InstalledKernel> {
+        self.by_version(&KernelVersion::try_from(release).ok()?)
+    }
+
+    pub fn by_version(&self, version: &KernelVersion) -> Option<&InstalledKernel> {
+        self.kernels.iter().find(|k| &k.version == version)
+    }
+
+    /// Leftovers of kernels that can't be booted anymore because their image is gone
+    /// A source directory newer than every kernel image is a build that didn't happen yet,
+    /// not an orphan
+    pub fn orphans(&self) -> Vec<&InstalledKernel> {
+        let newest_image = self
+            .kernels
+            .iter()
+            .rev()
+            .find(|k| k.vmlinuz_path.is_some())
+            .map(|k| k.version.clone());
+        self.kernels
+            .iter()
+            .filter(|k| k.vmlinuz_path.is_none())
+            .filter(|k| {
+                newest_image
+                    .as_ref()
+                    .is_some_and(|newest| &k.version < newest)
+            })
+            .collect()
+    }
+
+    /// Whether the source directory of `kernel` was unpacked all the way
+    /// An interrupted emerge or unpack leaves it empty or without its top-level Makefile
+    pub fn source_complete(&self, kernel: &InstalledKernel) -> bool {
+        kernel.source_path.as_deref().is_some_and(|src| {
+            self.fs
+                .read_dir(src)
+                .is_ok_and(|entries| entries.iter().any(|e| e.ends_with("Makefile")))
+        })
+    }
+
+    /// Source directories that can't be built, oldest first
+    pub fn incomplete_sources(&self) -> Vec<&InstalledKernel> {
+        self.kernels
+            .iter()
+            // Old versions share the source tree with the current one
+            .filter(|k| !k.version.is_old())
+            .filter(|k| k.source_path.is_some() && !self.source_complete(k))
+            .collect()
+    }
+
+    /// The kernel the next run builds, the newest one that doesn't have incomplete sources
+    pub fn newest_buildable(&self) -> Option<&InstalledKernel> {
+        self.kernels
+            .iter()
+            .rev()
+            .find(|k| k.source_path.is_none() || self.source_complete(k))
+    }
+
+    /// Source trees newer than every installed kernel image, what the next run builds
+    pub fn pending_sources(&self) -> Vec<&InstalledKernel> {
+        let newest_image = self
+            .kernels
+            .iter()
+            .rev()
+            .find(|k| k.vmlinuz_path.is_some())
+            .map(|k| k.version.clone());
+        self.kernels
+            .iter()
+            .filter(|k| k.vmlinuz_path.is_none() && self.source_complete(k))
+            .filter(|k| {
+                newest_image
+                    .as_ref()
+                    .is_none_or(|newest| &k.version > newest)
+            })
+            .collect()
+    }
+
+    /// Source trees that can be cleaned without getting in the way of the next build
+    /// The newest one is what gets built next and the running kernel's is kept for
+    /// building out-of-tree modules against it
+    pub fn prunable_sources(&self) -> Vec<&InstalledKernel> {
+        let newest_source = self
+            .kernels
+            .iter()
+            .rev()
+            .find(|k| k.source_path.is_some())
+            .map(|k| k.version.clone());
+        let running = self.running().map(|k| k.version.clone());
+        self.kernels
+            .iter()
+            // Old versions share the source tree with the current one
+            .filter(|k| k.source_path.is_some() && !k.version.is_old())
+            .filter(|k| {
+                newest_source.as_ref() != Some(&k.version) && running.as_ref() != Some(&k.version)
+            })
+            .collect()
+    }
+
+    fn measure<'a, I: IntoIterator<Item = &'a PathBuf>>(
+        &self,
+        paths: I,
+    ) -> Result<u64, JanitorError> {
+        paths.into_iter().try_fold(0, |total, path| {
+            let bytes = self
+                .fs
+                .disk_usage(path)
+                .with_context(|| format!("failed to measure {}", path.display()))?;
+            Ok(total + bytes)
+        })
+    }
+
+    /// Bytes of the files and directories under `dir` whose name `matches`, symlinks aren't followed
+    fn measure_matching(
+        &self,
+        dir: &Path,
+        matches: &dyn Fn(&str) -> bool,
+    ) -> Result<u64, JanitorError> {
+        let entries = self
+            .fs
+            .read_dir(dir)
+            .with_context(|| format!("failed to read {}", dir.display()))?;
+        entries.iter().try_fold(0, |total, path| {
+            if self.fs.is_symlink(path) {
+                return Ok(total);
+            }
+            let name = utils::paths::filename_from_path(path).unwrap_or_default();
+            let bytes = match matches(&name) {
+                true => self.measure(Some(path))?,
+                // Plain files can't be read as a directory
+                false => match self.fs.read_dir(path) {
+                    Ok(_) => self.measure_matching(path, matches)?,
+                    Err(_) => 0,
+                },
+            };
+            Ok(total + bytes)
+        })
+    }
+
+    /// Kernel image, config, System.map, device trees and initramfs of a kernel
+    fn boot_files(&self, kernel: &InstalledKernel) -> Result<Vec<PathBuf>, JanitorError> {
+        let mut paths: Vec<PathBuf> = [
+            &kernel.vmlinuz_path,
+            &kernel.config_path,
+            &kernel.system_map_path,
+            &kernel.dtb_path,
+        ]
+        .iter()
+        .copied()
+        .flatten()
+        .cloned()
+        .collect();
+        if let Some(initramfs) = &self.initramfs {
+            paths.extend(initramfs.artifact_paths(self.fs.as_ref(), &kernel.version)?);
+        }
+        Ok(paths)
+    }
+
+    /// Bytes used by a single kernel, the same that uninstalling it would free
+    pub fn size(&self, kernel: &InstalledKernel) -> Result<u64, JanitorError> {
+        let mut paths = self.boot_files(kernel)?;
+        // Old versions share these with the non-old version
+        if !kernel.version.is_old() {
+            paths.extend(kernel.module_path.iter().cloned());
+            paths.extend(kernel.source_path.iter().cloned());
+        }
+        paths.extend(kernel.extra_paths.iter().map(|(_, path)| path.clone()));
+        self.measure(&paths)
+    }
+
+    /// `size` split up by where the bytes are, which tells whether pruning the sources
+    /// is enough or the whole kernel has to go. Walks the whole source tree, so it's slow
+    pub fn space(&self, kernel: &InstalledKernel) -> Result<SpaceUsage, JanitorError> {
+        let mut usage = SpaceUsage {
+            boot: self.measure(&self.boot_files(kernel)?)?,
+            ..SpaceUsage::default()
+        };
+        for (kind, path) in &kernel.extra_paths {
+            let bytes = self.measure(Some(path))?;
+            match kind {
+                InstalledItemKind::SourceDir => usage.sources += bytes,
+                InstalledItemKind::ModuleDir => usage.modules += bytes,
+                _ => usage.boot += bytes,
+            }
+        }
+        // Old versions share these with the non-old version
+        if kernel.version.is_old() {
+            return Ok(usage);
+        }
+        usage.modules += self.measure(&kernel.module_path)?;
+        if let Some(source) = &kernel.source_path {
+            usage.sources += self.measure(Some(source))?;
+            usage.build_artifacts = self.measure_matching(source, &is_build_artifact)?;
+        }
+        Ok(usage)
+    }
+
+    /// Bytes of the modules of `kernel` that were installed uncompressed, which building it with
+    /// CONFIG_MODULE_COMPRESS_ZSTD would shrink. Zero for old versions, they share the modules
+    pub fn uncompressed_modules(&self, kernel: &InstalledKernel) -> Result<u64, JanitorError> {
+        match (&kernel.module_path, kernel.version.is_old()) {
+            (Some(module_path), false) => {
+                self.measure_matching(module_path, &|name| name.ends_with(".ko"))
+            }
+            _ => Ok(0),
+        }
+    }
+
+    /// When the kernel image was written, which is when it was installed
+    pub fn installed_at(&self, kernel: &InstalledKernel) -> Result<SystemTime, JanitorError> {
+        let image = kernel.vmlinuz_path.as_ref().ok_or_else(|| {
+            JanitorError::new(
+                ErrorKind::Other,
+                format!("Kernel {} has no kernel image", kernel.version),
+            )
+        })?;
+        self.fs.modified(image).with_context(|| {
+            format!(
+                "failed to read the modification time of {}",
+                image.display()
+            )
+        })
+    }
+
+    fn matches(&self, kernel: &InstalledKernel, filter: &ListFilter, now: SystemTime) -> bool {
+        if let Some(older_than) = filter.older_than {
+            let old_enough = self
+                .installed_at(kernel)
+                .ok()
+                .and_then(|installed| now.duration_since(installed).ok())
+                .is_some_and(|age| age > older_than);
+            if !old_enough {
+                return false;
+            }
+        }
+        if filter
+            .flavor
+            .as_ref()
+            .is_some_and(|flavor| &policy::flavor(kernel) != flavor)
+        {
+            return false;
+        }
+        if filter.missing_files && !kernel.files_missing() {
+            return false;
+        }
+        if filter.orphans && !self.orphans().contains(&kernel) {
+            return false;
+        }
+        true
+    }
+
+    /// The kernels that pass `filter` with their `size`, in `order`
+    pub fn list(
+        &self,
+        filter: &ListFilter,
+        order: ListOrder,
+    ) -> Result<Vec<(&InstalledKernel, u64)>, JanitorError> {
+        let now = SystemTime::now();
+        let mut listed = self
+            .kernels
+            .iter()
+            .filter(|k| self.matches(k, filter, now))
+            .map(|k| Ok((k, self.size(k)?)))
+            .collect::<Result<Vec<_>, JanitorError>>()?;
+        // Stable sorts, so ties stay oldest first
+        match order {
+            ListOrder::Version => (),
+            ListOrder::Size => listed.sort_by(|(_, a), (_, b)| b.cmp(a)),
+            ListOrder::Date => listed.sort_by_key(|(k, _)| {
+                let installed = self.installed_at(k).ok();
+                (installed.is_none(), installed)
+            }),
+        }
+        Ok(listed)
+    }
+
+    /// What `prune-sources` would free, the build artifacts in `prunable_sources`
+    pub fn prunable_size(&self) -> Result<u64, JanitorError> {
+        self.prunable_sources()
+            .into_iter()
+            .try_fold(0, |total, kernel| {
+                Ok(total + self.space(kernel)?.build_artifacts)
+            })
+    }
+
+    /// Bytes used by every installed kernel
+    pub fn total_size(&self) -> Result<u64, JanitorError> {
+        self.kernels
+            .iter()
+            .try_fold(0, |total, kernel| Ok(total + self.size(kernel)?))
+    }
+
+    /// Keep `version` around no matter how many newer kernels there are
+    pub fn pin(&mut self, version: KernelVersion) {
+        self.pinned.insert(version);
+    }
+
+    pub fn is_pinned(&self, version: &KernelVersion) -> bool {
+        self.pinned.contains(version)
+    }
+
+    /// Pinned or the fallback, either way cleanup leaves it alone
+    pub fn is_kept(&self, version: &KernelVersion) -> bool {
+        self.is_pinned(version) || self.fallback.as_ref() == Some(version)
+    }
+
+    /// The kernels to remove to keep only the newest `num_versions_to_keep`, oldest first
+    /// Pinned kernels and the fallback are kept on top of those
+    pub fn removable(&self, num_versions_to_keep: usize) -> Vec<KernelVersion> {
+        let num_old = self.kernels.len().saturating_sub(num_versions_to_keep);
+        self.kernels
+            .iter()
+            .take(num_old)
+            .map(|k| k.version.clone())
+            .filter(|v| !self.is_kept(v))
+            .collect()
+    }
+
+    /// Uninstall a kernel and drop it from the inventory, returns the bytes freed
+    pub fn uninstall(
+        &mut self,
+        version: &KernelVersion,
+        cmd_config: &RunCmdConfig,
+    ) -> Result<u64, JanitorError> {
+        if self.is_pinned(version) {
+            return Err(JanitorError::new(
+                ErrorKind::Cleanup,
+                format!("Kernel {} is pinned, refusing to uninstall it", version),
+            ));
+        }
+        if self.fallback.as_ref() == Some(version) {
+            return Err(JanitorError::new(
+                ErrorKind::Cleanup,
+                format!(
+                    "Kernel {} is the last one that booted fine, refusing to uninstall it",
+                    version
+                ),
+            ));
+        }
+        let idx = self
+            .kernels
+            .iter()
+            .position(|k| &k.version == version)
+            .ok_or_else(|| {
+                JanitorError::new(
+                    ErrorKind::Cleanup,
+                    format!("Kernel {} is not installed", version),
+                )
+            })?;
+        // A kernel that can't be archived isn't removed. Archived before anything is, the initramfs
+        // goes into the archive too
+        if let Some(archive_dir) = &cmd_config.archive_dir {
+            archive::archive(cmd_config, &self.kernels[idx], archive_dir)?;
+        }
+        if let Some(bootloader) = &cmd_config.bootloader {
+            bootloader.remove_entry(cmd_config, version)?;
+        }
+        let mut bytes = 0;
+        if let Some(initramfs) = &cmd_config.initramfs {
+            bytes += initramfs.remove(cmd_config, version)?;
+        }
+        bytes += self.kernels[idx].uninstall(cmd_config)?;
+        self.kernels.remove(idx);
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        initramfs::InitramfsKind,
+        test_utils::{inventory, memory_search},
+        update::{InteractiveStatus, PretendStatus},
+    };
+    use std::path::Path;
+
+    fn version(release: &str) -> KernelVersion {
+        KernelVersion::try_from(release).unwrap()
+    }
+
+    #[test]
+    fn queries() -> Result<(), JanitorError> {
+        let (inventory, _) = inventory();
+        assert_eq!(inventory.len(), 4);
+        assert_eq!(
+            inventory.newest().unwrap().version,
+            version("5.12.0-gentoo")
+        );
+        assert!(inventory.by_release("5.10.1-gentoo").is_some());
+        assert!(inventory.by_version(&version("5.9.0-gentoo")).is_none());
+        let mut inventory = inventory.with_default_boot(Some(version("5.11.0-gentoo")));
+        inventory.pin(version("5.10.1-gentoo"));
+        let newest = inventory.by_version(&version("5.11.0-gentoo")).unwrap();
+        assert_eq!(inventory.markers(newest)?, vec!["default boot"]);
+        let pinned = inventory.by_version(&version("5.10.1-gentoo")).unwrap();
+        assert_eq!(inventory.markers(pinned)?, vec!["pinned"]);
+        let inventory = inventory
+            .with_saved_default(Some(version("5.10.1-gentoo")))
+            .with_next_boot(Some(version("5.11.0-gentoo")));
+        let newest = inventory.by_version(&version("5.11.0-gentoo")).unwrap();
+        assert_eq!(
+            inventory.markers(newest)?,
+            vec!["default boot", "boots next"]
+        );
+        let pinned = inventory.by_version(&version("5.10.1-gentoo")).unwrap();
+        assert_eq!(inventory.markers(pinned)?, vec!["saved default", "pinned"]);
+        let orphans: Vec<_> = inventory
+            .orphans()
+            .iter()
+            .map(|k| k.version.clone())
+            .collect();
+        assert_eq!(orphans, vec![version("5.4.97-gentoo")]);
+        let pending: Vec<_> = inventory
+            .pending_sources()
+            .iter()
+            .map(|k| k.version.clone())
+            .collect();
+        assert_eq!(pending, vec![version("5.12.0-gentoo")]);
+        assert_eq!(inventory.total_size()?, 7 + 2 * 132 + 100);
+        Ok(())
+    }
+
+    #[test]
+    fn incomplete_sources() -> Result<(), JanitorError> {
+        let (_, memfs) = inventory();
+        let memfs = memfs
+            .with_dir(Path::new("/usr/src/linux-5.13.0-gentoo"))
+            .with_file(Path::new("/usr/src/linux-5.14.0-gentoo/README"), 1);
+        let inventory = memory_search(&memfs).inventory()?;
+        let incomplete: Vec<_> = inventory
+            .incomplete_sources()
+            .iter()
+            .map(|k| k.version.clone())
+            .collect();
+        assert_eq!(
+            incomplete,
+            vec![version("5.13.0-gentoo"), version("5.14.0-gentoo")]
+        );
+        assert_eq!(
+            inventory.newest_buildable().unwrap().version,
+            version("5.12.0-gentoo")
+        );
+        let pending: Vec<_> = inventory
+            .pending_sources()
+            .iter()
+            .map(|k| k.version.clone())
+            .collect();
+        assert_eq!(pending, vec![version("5.12.0-gentoo")]);
+        let newest = inventory.newest().unwrap();
+        assert_eq!(inventory.markers(newest)?, vec!["incomplete sources"]);
+        Ok(())
+    }
+
+    #[test]
+    fn space_usage() -> Result<(), JanitorError> {
+        let (_, memfs) = inventory();
+        let memfs = memfs
+            .with_file(Path::new("/usr/src/linux-5.10.1-gentoo/vmlinux"), 300)
+            .with_file(Path::new("/usr/src/linux-5.10.1-gentoo/kernel/fork.o"), 40)
+            .with_file(Path::new("/usr/src/linux-5.10.1-gentoo/kernel/fork.c"), 4);
+        let inventory = memory_search(&memfs).inventory()?;
+        let kernel = inventory.by_version(&version("5.10.1-gentoo")).unwrap();
+        let space = inventory.space(kernel)?;
+        assert_eq!(
+            space,
+            SpaceUsage {
+                sources: 444,
+                build_artifacts: 340,
+                modules: 20,
+                boot: 12,
+            }
+        );
+        assert_eq!(space.total(), inventory.size(kernel)?);
+        // 5.12.0 is built next, so only 5.10.1 and 5.11.0 can be pruned
+        assert_eq!(inventory.prunable_size()?, 340);
+        Ok(())
+    }
+
+    #[test]
+    fn sizes_with_initramfs() -> Result<(), JanitorError> {
+        let (inventory, memfs) = inventory();
+        let memfs = memfs.with_file(Path::new("/boot/initramfs-5.10.1-gentoo.img"), 50);
+        let initramfs = InitramfsKind::Dracut.create(Path::new("/boot"));
+        let inventory =
+            KernelInventory::new(inventory.kernels, Arc::new(memfs)).with_initramfs(initramfs);
+        let by_size: Vec<_> = inventory
+            .list(&ListFilter::default(), ListOrder::Size)?
+            .into_iter()
+            .map(|(k, size)| (k.version.clone(), size))
+            .collect();
+        assert_eq!(
+            by_size,
+            vec![
+                (version("5.10.1-gentoo"), 182),
+                (version("5.11.0-gentoo"), 132),
+                (version("5.12.0-gentoo"), 100),
+                (version("5.4.97-gentoo"), 7),
+            ]
+        );
+        assert!("name".parse::<ListOrder>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn boot_status() -> Result<(), JanitorError> {
+        let (inventory, memfs) = inventory();
+        let memfs = memfs.with_file(Path::new("/boot/initramfs-5.11.0-gentoo.img"), 50);
+        let initramfs = InitramfsKind::Dracut.create(Path::new("/boot"));
+        let inventory = KernelInventory::new(inventory.kernels, Arc::new(memfs))
+            .with_initramfs(initramfs)
+            .with_boot_entries(Some(vec![
+                version("5.10.1-gentoo"),
+                version("5.11.0-gentoo"),
+            ]));
+        let status = |release| {
+            inventory
+                .boot_status(inventory.by_version(&version(release)).unwrap())
+                .unwrap()
+        };
+        assert_eq!(status("5.4.97-gentoo"), None);
+        assert_eq!(
+            status("5.10.1-gentoo"),
+            Some(BootStatus {
+                initramfs: Some(false),
+                boot_entry: Some(true)
+            })
+        );
+        assert!(status("5.11.0-gentoo").unwrap().bootable());
+        let unbootable = inventory.by_version(&version("5.10.1-gentoo")).unwrap();
+        assert_eq!(inventory.markers(unbootable)?, vec!["unbootable"]);
+        Ok(())
+    }
+
+    #[test]
+    fn list_filters() -> Result<(), JanitorError> {
+        let (inventory, _) = inventory();
+        let listed = |filter: ListFilter| -> Vec<KernelVersion> {
+            inventory
+                .list(&filter, ListOrder::Version)
+                .unwrap()
+                .into_iter()
+                .map(|(k, _)| k.version.clone())
+                .collect()
+        };
+        assert_eq!(
+            listed(ListFilter {
+                orphans: true,
+                ..ListFilter::default()
+            }),
+            vec![version("5.4.97-gentoo")]
+        );
+        assert_eq!(
+            listed(ListFilter {
+                missing_files: true,
+                ..ListFilter::default()
+            }),
+            vec![version("5.4.97-gentoo"), version("5.12.0-gentoo")]
+        );
+        assert!(listed(ListFilter {
+            flavor: Some("zen".to_string()),
+            ..ListFilter::default()
+        })
+        .is_empty());
+        // The memory filesystem doesn't know when files were written
+        assert!(listed(ListFilter {
+            older_than: Some(Duration::from_secs(1)),
+            ..ListFilter::default()
+        })
+        .is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn pinned_kernels_stay() -> Result<(), JanitorError> {
+        let (mut inventory, memfs) = inventory();
+        inventory.pin(version("5.10.1-gentoo"));
+        assert_eq!(inventory.removable(2), vec![version("5.4.97-gentoo")]);
+
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_fs(memfs.clone())
+            .with_removable_roots(vec![PathBuf::from("/")]);
+        assert!(inventory
+            .uninstall(&version("5.10.1-gentoo"), &cfg)
+            .is_err());
+        assert_eq!(inventory.uninstall(&version("5.11.0-gentoo"), &cfg)?, 132);
+        assert!(inventory.by_version(&version("5.11.0-gentoo")).is_none());
+        assert!(!memfs.exists(Path::new("/boot/vmlinuz-5.11.0-gentoo")));
+        Ok(())
+    }
+
+    #[test]
+    fn fallback_stays() -> Result<(), JanitorError> {
+        let (inventory, memfs) = inventory();
+        let record = |booted| RunRecord {
+            timestamp: 0,
+            outcome: "success".to_string(),
+            built: None,
+            build_time: None,
+            removed: Vec::new(),
+            bytes_freed: 0,
+            installed_bytes: None,
+            snapshots: Vec::new(),
+            booted,
+        };
+        // The kernel these tests run on isn't in the inventory, so it comes from the history
+        // 5.9.0 isn't installed anymore and 5.11.0 doesn't say what it booted
+        let history = [
+            record(Some(version("5.10.1-gentoo"))),
+            record(Some(version("5.9.0-gentoo"))),
+            record(None),
+        ];
+        let fallback = inventory.last_booted(&history);
+        assert_eq!(fallback, Some(version("5.10.1-gentoo")));
+        assert!(inventory.awaiting_boot().is_empty());
+        let booted: Vec<_> = history
+            .iter()
+            .filter_map(|record| record.booted.clone())
+            .collect();
+        let mut inventory = inventory.with_fallback(fallback).with_booted(&booted);
+        let kept = inventory.fallback().unwrap();
+        assert_eq!(inventory.markers(kept)?, vec!["fallback"]);
+        // 5.12.0 has only been unpacked, not installed
+        let awaiting = inventory.awaiting_boot();
+        assert_eq!(awaiting.len(), 1);
+        assert_eq!(awaiting[0].version, version("5.11.0-gentoo"));
+        assert_eq!(inventory.markers(awaiting[0])?, vec!["not booted yet"]);
+        assert_eq!(
+            inventory.removable(1),
+            vec![version("5.4.97-gentoo"), version("5.11.0-gentoo")]
+        );
+
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_fs(memfs)
+            .with_removable_roots(vec![PathBuf::from("/")]);
+        assert!(inventory
+            .uninstall(&version("5.10.1-gentoo"), &cfg)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_sources() -> Result<(), JanitorError> {
+        let (_, memfs) = inventory();
+        let copy = Path::new("/home/src/linux-5.11.0-gentoo");
+        let dist = Path::new("/boot/vmlinuz-5.11.0-gentoo-dist");
+        let memfs = memfs
+            .with_file(&copy.join("Makefile"), 100)
+            .with_file(dist, 10);
+        let mut inventory = KernelSearch::new(
+            Path::new("/boot"),
+            &[PathBuf::from("/usr/src"), PathBuf::from("/home/src")],
+            Path::new("/lib/modules"),
+        )
+        .with_filesystem(Arc::new(memfs.clone()))
+        .inventory()?;
+        // Another flavor with the same numbers is another kernel
+        assert_eq!(inventory.len(), 5);
+        let kernel = inventory.by_release("5.11.0-gentoo").unwrap();
+        assert_eq!(
+            kernel.source_path,
+            Some(PathBuf::from("/usr/src/linux-5.11.0-gentoo"))
+        );
+        // The same release somewhere else is the same kernel
+        assert_eq!(
+            kernel.extra_paths,
+            vec![(InstalledItemKind::SourceDir, copy.to_path_buf())]
+        );
+        assert_eq!(inventory.space(kernel)?.sources, 200);
+
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_fs(memfs.clone())
+            .with_removable_roots(vec![PathBuf::from("/")]);
+        assert_eq!(inventory.uninstall(&version("5.11.0-gentoo"), &cfg)?, 232);
+        assert!(!memfs.exists(copy));
+        assert!(memfs.exists(dist));
+        Ok(())
+    }
+
+    #[test]
+    fn device_trees() -> Result<(), JanitorError> {
+        let (_, memfs) = inventory();
+        let dtb = Path::new("/boot/dtbs/5.11.0-gentoo/broadcom/bcm2711-rpi-4-b.dtb");
+        let memfs = memfs.with_file(dtb, 30);
+        let mut inventory = memory_search(&memfs)
+            .with_dtb_search_path(Some(PathBuf::from("/boot/dtbs")))
+            .inventory()?;
+        // Still just the kernels, the dtbs directory isn't one
+        assert_eq!(inventory.len(), 4);
+        let kernel = inventory.by_release("5.11.0-gentoo").unwrap();
+        assert_eq!(
+            kernel.dtb_path,
+            Some(PathBuf::from("/boot/dtbs/5.11.0-gentoo"))
+        );
+        assert!(!kernel.files_missing());
+
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_fs(memfs.clone())
+            .with_removable_roots(vec![PathBuf::from("/")]);
+        assert_eq!(inventory.uninstall(&version("5.11.0-gentoo"), &cfg)?, 162);
+        assert!(!memfs.exists(dtb));
+        Ok(())
+    }
+}
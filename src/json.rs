@@ -0,0 +1,100 @@
+use std::fmt;
+
+/// Just enough JSON to serialize events and reports without pulling in a dependency
+/// Fields are kept in insertion order so output is stable
+#[derive(Default, Clone)]
+pub struct JsonObject {
+    fields: Vec<(String, String)>,
+}
+
+/// Escape a string and wrap it in quotes
+pub fn string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Join values that are already serialized into an array
+pub fn array<I: IntoIterator<Item = String>>(values: I) -> String {
+    format!("[{}]", values.into_iter().collect::<Vec<_>>().join(","))
+}
+
+impl JsonObject {
+    pub fn new() -> JsonObject {
+        JsonObject { fields: Vec::new() }
+    }
+
+    pub fn string(self, key: &str, value: &str) -> JsonObject {
+        self.raw(key, string(value))
+    }
+
+    pub fn number<N: fmt::Display>(self, key: &str, value: N) -> JsonObject {
+        self.raw(key, value.to_string())
+    }
+
+    pub fn boolean(self, key: &str, value: bool) -> JsonObject {
+        self.raw(key, value.to_string())
+    }
+
+    /// Strings that are missing become `null`
+    pub fn optional_string(self, key: &str, value: Option<&str>) -> JsonObject {
+        match value {
+            Some(v) => self.string(key, v),
+            None => self.raw(key, "null".to_string()),
+        }
+    }
+
+    /// Insert a value that's already been serialized, like a nested object or array
+    pub fn raw(mut self, key: &str, value: String) -> JsonObject {
+        self.fields.push((key.to_string(), value));
+        self
+    }
+}
+
+impl fmt::Display for JsonObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let fields = self
+            .fields
+            .iter()
+            .map(|(key, value)| format!("{}:{}", string(key), value))
+            .collect::<Vec<_>>()
+            .join(",");
+        write!(f, "{{{}}}", fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn escape_string() {
+        assert_eq!(string("a \"b\"\n"), "\"a \\\"b\\\"\\n\"");
+        assert_eq!(string("\u{1}"), "\"\\u0001\"");
+    }
+
+    #[test]
+    fn build_object() {
+        let obj = JsonObject::new()
+            .string("stage", "build")
+            .number("bytes", 42)
+            .boolean("pretend", true)
+            .optional_string("version", None)
+            .raw("paths", array(vec![string("/boot")]));
+        assert_eq!(
+            obj.to_string(),
+            r#"{"stage":"build","bytes":42,"pretend":true,"version":null,"paths":["/boot"]}"#
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+
+use crate::error::{Context, ErrorKind, JanitorError};
+
+/// The options set in a `.config` and their values, like `CONFIG_BTRFS_FS` => `y`
+/// Options that are `# CONFIG_X is not set` aren't in it
+pub fn parse(contents: &str) -> BTreeMap<String, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+/// The options a `.config` explicitly has as `# CONFIG_X is not set`
+fn parse_not_set(contents: &str) -> BTreeSet<String> {
+    contents
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("# ")?.strip_suffix(" is not set"))
+        .map(str::to_string)
+        .collect()
+}
+
+/// The options that were built in or modules in `old` but aren't in `new`, with what they were
+/// and whether Kconfig still knows them, e.g. `CONFIG_FOO (was y, no longer exists)`
+/// A symbol that no longer exists was usually renamed, and what it was renamed to is now off
+pub fn dropped(old: &str, new: &str) -> Vec<String> {
+    let new_options = parse(new);
+    let new_not_set = parse_not_set(new);
+    parse(old)
+        .into_iter()
+        .filter(|(_, value)| value == "y" || value == "m")
+        .filter(|(name, _)| {
+            new_options
+                .get(name)
+                .is_none_or(|value| value != "y" && value != "m")
+        })
+        .map(|(name, value)| match new_not_set.contains(&name) {
+            true => format!("{} (was {}, now not set)", name, value),
+            false => format!("{} (was {}, no longer exists)", name, value),
+        })
+        .collect()
+}
+
+/// The option of the kernel_gcc_patch (gentoo-sources with USE=experimental) that already builds
+/// for the CPU of this machine, like `CONFIG_MNATIVE_INTEL`, if one is enabled
+pub fn native_cpu_option(contents: &str) -> Option<String> {
+    parse(contents)
+        .into_iter()
+        .find(|(name, value)| name.starts_with("CONFIG_MNATIVE") && value == "y")
+        .map(|(name, _)| name)
+}
+
+/// `BTRFS_FS` and `CONFIG_BTRFS_FS` are the same option
+fn option_name(name: &str) -> String {
+    match name.starts_with("CONFIG_") {
+        true => name.to_string(),
+        false => format!("CONFIG_{}", name),
+    }
+}
+
+/// The required options that `options` doesn't satisfy, with what they are instead
+/// `CONFIG_X=y` needs exactly that value, `CONFIG_X` alone is fine built in or as a module
+/// and `CONFIG_X=n` needs it to be unset
+pub fn unmet(required: &[String], options: &BTreeMap<String, String>) -> Vec<String> {
+    required
+        .iter()
+        .filter_map(|requirement| {
+            let (name, wanted) = match requirement.split_once('=') {
+                Some((name, wanted)) => (option_name(name.trim()), Some(wanted.trim())),
+                None => (option_name(requirement.trim()), None),
+            };
+            let actual = options.get(&name).map(String::as_str);
+            let met = match (wanted, actual) {
+                (Some("n"), actual) => actual.is_none_or(|value| value == "n"),
+                (Some(wanted), Some(actual)) => wanted == actual,
+                (None, Some(actual)) => actual == "y" || actual == "m",
+                (_, None) => false,
+            };
+            match (met, actual) {
+                (true, _) => None,
+                (false, Some(actual)) => Some(format!("{} (it's {})", requirement, actual)),
+                (false, None) => Some(format!("{} (it's not set)", requirement)),
+            }
+        })
+        .collect()
+}
+
+/// Fail if the `.config` in `src_dir` lost any of the `required` options, which happens when
+/// olddefconfig drops or renames them on a major version bump
+pub fn check_required(src_dir: &Path, required: &[String]) -> Result<(), JanitorError> {
+    if required.is_empty() {
+        return Ok(());
+    }
+    let config_path = src_dir.join(".config");
+    let contents = fs::read_to_string(&config_path)
+        .with_context(|| format!("failed to read {:?}", config_path))?;
+    let unmet = unmet(required, &parse(&contents));
+    if unmet.is_empty() {
+        return Ok(());
+    }
+    Err(JanitorError::new(
+        ErrorKind::Build,
+        format!(
+            "{:?} doesn't have these RequiredConfigOptions: {}. Set them with 'make menuconfig' in {:?} and run again",
+            config_path,
+            unmet.join(", "),
+            src_dir
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn required_options() {
+        let options = parse(
+            "\
+# Automatically generated file; DO NOT EDIT.
+CONFIG_BTRFS_FS=m
+CONFIG_VFAT_FS=y
+# CONFIG_DEBUG_INFO is not set
+CONFIG_LOCALVERSION=\"-custom\"
+",
+        );
+        assert_eq!(options.get("CONFIG_VFAT_FS").map(String::as_str), Some("y"));
+        assert!(!options.contains_key("CONFIG_DEBUG_INFO"));
+
+        let required = [
+            "CONFIG_BTRFS_FS=y",
+            "CONFIG_VFAT_FS=y",
+            "BTRFS_FS",
+            "CONFIG_DEBUG_INFO=n",
+            "CONFIG_LOCALVERSION=\"-custom\"",
+            "CONFIG_ZRAM",
+        ]
+        .iter()
+        .map(|r| r.to_string())
+        .collect::<Vec<_>>();
+        assert_eq!(
+            unmet(&required, &options),
+            vec![
+                "CONFIG_BTRFS_FS=y (it's m)".to_string(),
+                "CONFIG_ZRAM (it's not set)".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn dropped_options() {
+        let old = "\
+CONFIG_EXT4_FS=y
+CONFIG_BTRFS_FS=m
+CONFIG_OLD_NAME=y
+CONFIG_LOCALVERSION=\"-custom\"
+# CONFIG_DEBUG_INFO is not set
+";
+        let new = "\
+CONFIG_EXT4_FS=m
+# CONFIG_BTRFS_FS is not set
+CONFIG_NEW_NAME=y
+CONFIG_LOCALVERSION=\"\"
+CONFIG_DEBUG_INFO=y
+";
+        assert_eq!(
+            dropped(old, new),
+            vec![
+                "CONFIG_BTRFS_FS (was m, now not set)".to_string(),
+                "CONFIG_OLD_NAME (was y, no longer exists)".to_string(),
+            ]
+        );
+        assert!(dropped(new, new).is_empty());
+        assert_eq!(native_cpu_option(new), None);
+        assert_eq!(
+            native_cpu_option("CONFIG_MNATIVE_AMD=y\n").as_deref(),
+            Some("CONFIG_MNATIVE_AMD")
+        );
+    }
+}
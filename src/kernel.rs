@@ -1,24 +1,49 @@
 use std::{
+    cell::RefCell,
     cmp::Ordering,
     collections::HashMap,
     convert::TryFrom,
-    fmt, io,
+    fmt,
+    hash::{Hash, Hasher},
+    io,
     option::Option,
     path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    sync::Arc,
 };
 
-use crate::{update::PretendStatus, utils};
+use crate::{
+    conf::Config,
+    debug,
+    error::{Context, ErrorKind, JanitorError},
+    events::{self, Event},
+    filesystem::{FileSystem, RealFileSystem},
+    naming::{self, NameTemplate, Naming},
+    search_cache::{self, CacheUse, Found},
+    signals, signing, trace,
+    update::RunCmdConfig,
+    utils, warn, JanitorErrorFrom, JanitorResultErr,
+};
 
 /// A kernel version can be found as a config, vmlinuz binary, system map, or source directory.
 /// Format: SomeIgnoredValue-<major>.<minor>.<patch>-gentoo
-///         or SomeIgnoredValue-<major>.<minor>.<patch>-rc<release_candidate_num>-gentoo
+///         or SomeIgnoredValue-<major>.<minor>.<patch>-gentoo-r<release_candidate_num>
 ///         or SomeIgnoredValue-<major>.<minor>.<patch>-gentoo.old
-#[derive(Hash, Eq, Debug, Clone, Copy)]
+///         or SomeIgnoredValue-<major>.<minor>-rc<prerelease> (vanilla-sources, git-sources)
+/// The flavor (gentoo, rt34, zen1, git...) can be anything, kernels of different flavors are
+/// different kernels even when their numbers are the same
+#[derive(Eq, Debug, Clone)]
 pub struct KernelVersion {
     major: u32,
     minor: u32,
     patch: u32,
+    /// The N of a mainline release candidate like 6.8-rc3
+    prerelease: Option<u32>,
+    /// The Gentoo revision, the N of -rN
     release_candidate_num: Option<u32>,
+    /// Everything in the release after the numbers, like `-gentoo-r1` or `-rc3-git`
+    localversion: String,
     is_old: bool,
 }
 
@@ -32,6 +57,8 @@ impl fmt::Display for VersionParseError {
     }
 }
 
+impl std::error::Error for VersionParseError {}
+
 impl From<&str> for VersionParseError {
     fn from(v: &str) -> Self {
         VersionParseError {
@@ -45,12 +72,84 @@ pub struct InstalledItem {
     version: KernelVersion,
     path: PathBuf,
 }
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum InstalledItemKind {
     KernelImage,
     Config,
     SystemMap,
     SourceDir,
     ModuleDir,
+    DtbDir,
+}
+
+const ITEM_KINDS: &[InstalledItemKind] = &[
+    InstalledItemKind::KernelImage,
+    InstalledItemKind::Config,
+    InstalledItemKind::SystemMap,
+    InstalledItemKind::SourceDir,
+    InstalledItemKind::ModuleDir,
+    InstalledItemKind::DtbDir,
+];
+
+/// The files `OptionalFiles` can list, a kernel can boot without them
+const OPTIONAL_KINDS: &[InstalledItemKind] = &[
+    InstalledItemKind::Config,
+    InstalledItemKind::SystemMap,
+    InstalledItemKind::SourceDir,
+];
+
+impl InstalledItemKind {
+    /// What it's called in the config and the search cache
+    pub fn name(&self) -> &'static str {
+        match self {
+            InstalledItemKind::KernelImage => "image",
+            InstalledItemKind::Config => "config",
+            InstalledItemKind::SystemMap => "system-map",
+            InstalledItemKind::SourceDir => "sources",
+            InstalledItemKind::ModuleDir => "modules",
+            InstalledItemKind::DtbDir => "dtbs",
+        }
+    }
+}
+
+impl FromStr for InstalledItemKind {
+    type Err = JanitorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ITEM_KINDS
+            .iter()
+            .copied()
+            .find(|kind| kind.name() == s.to_ascii_lowercase())
+            .ok_or_else(|| {
+                let names: Vec<_> = ITEM_KINDS.iter().map(InstalledItemKind::name).collect();
+                JanitorErrorFrom!("Unknown file {}, expected one of {}", s, names.join(", "))
+            })
+    }
+}
+
+/// The files `OptionalFiles` says a kernel isn't missing anything without, like the sources of
+/// a kernel whose binaries are kept. None when it isn't set
+pub fn optional_files(config: &Config) -> Result<Vec<InstalledItemKind>, JanitorError> {
+    if !config.contains("OptionalFiles") {
+        return Ok(Vec::new());
+    }
+    let mut kinds = Vec::new();
+    for name in config.get_list("OptionalFiles")? {
+        let kind: InstalledItemKind = name.parse()?;
+        if !OPTIONAL_KINDS.contains(&kind) {
+            let names: Vec<_> = OPTIONAL_KINDS.iter().map(InstalledItemKind::name).collect();
+            return Err(JanitorError::new(
+                ErrorKind::Config,
+                format!(
+                    "OptionalFiles can't have {}, a kernel doesn't boot without it. Only {} can be",
+                    name,
+                    names.join(", ")
+                ),
+            ));
+        }
+        kinds.push(kind);
+    }
+    Ok(kinds)
 }
 
 #[derive(Eq)]
@@ -58,93 +157,153 @@ pub struct InstalledKernel {
     pub version: KernelVersion,
     pub module_path: Option<PathBuf>,
     pub source_path: Option<PathBuf>,
+    /// Which of the source search paths the sources were found in
+    pub source_root: Option<PathBuf>,
     pub vmlinuz_path: Option<PathBuf>,
     pub config_path: Option<PathBuf>,
     pub system_map_path: Option<PathBuf>,
+    /// The device trees `make dtbs_install` put in a directory named after the release, on ARM
+    /// Not every kernel has them, so they're never missing
+    pub dtb_path: Option<PathBuf>,
+    /// Everything else found with the same release, like a second image on the ESP or the same
+    /// sources in two source search paths. Removed along with the paths above
+    pub extra_paths: Vec<(InstalledItemKind, PathBuf)>,
+    /// The files it isn't missing when they aren't there, the ones the install path doesn't get
+    pub optional: Vec<InstalledItemKind>,
 }
 
 pub struct KernelSearch {
     module_search_path: PathBuf,
-    source_search_path: PathBuf,
+    /// Searched in order, the first one with a kernel's sources has them
+    source_search_paths: Vec<PathBuf>,
     // Expect to find vmlinuz, config, and system map in this search path
     install_search_path: PathBuf,
+    /// The names the files in the install search path have
+    naming: Naming,
+    /// Where each kernel's device trees are, None when they aren't installed
+    dtb_search_path: Option<PathBuf>,
+    fs: Arc<dyn FileSystem>,
+    /// Where what was found is cached and how, None to always search
+    cache: Option<(PathBuf, CacheUse)>,
+    /// What the last search found that the cache doesn't have yet, with its stamp, see `save_cache`
+    unsaved: RefCell<Option<(Vec<String>, Found)>>,
+    /// The files kernels aren't missing when they aren't there, besides the ones `naming` doesn't install
+    optional: Vec<InstalledItemKind>,
 }
 
 impl KernelVersion {
     pub fn is_old(&self) -> bool {
         self.is_old
     }
+
+    /// Returns major, minor, patch versions
+    pub fn version_triple(&self) -> (u32, u32, u32) {
+        (self.major, self.minor, self.patch)
+    }
+
+    /// What `uname -r` says when it's running, like `5.11.8-gentoo-r1`. Without the `.old`
+    pub fn release(&self) -> String {
+        format!(
+            "{}.{}.{}{}",
+            self.major, self.minor, self.patch, self.localversion
+        )
+    }
+
+    /// The part of the release after the numbers, like `-gentoo-r1`. Empty for vanilla releases
+    pub fn localversion(&self) -> &str {
+        &self.localversion
+    }
+}
+
+/// VERSION, PATCHLEVEL and SUBLEVEL from the top-level Makefile of a kernel source tree
+/// None when one of them is missing, like in a Makefile that's only partly written
+pub fn makefile_version(makefile: &str) -> Option<(u32, u32, u32)> {
+    let value = |name: &str| {
+        makefile.lines().find_map(|line| {
+            let (key, value) = line.split_once('=')?;
+            match key.trim() == name {
+                true => value.trim().parse::<u32>().ok(),
+                false => None,
+            }
+        })
+    };
+    Some((value("VERSION")?, value("PATCHLEVEL")?, value("SUBLEVEL")?))
 }
 
 impl TryFrom<&str> for KernelVersion {
     type Error = VersionParseError;
 
     fn try_from(raw_value: &str) -> Result<Self, Self::Error> {
-        let first_char = raw_value.chars().nth(0);
-        let first_char_is_num = match first_char {
-            Some(c) => c.is_numeric(),
-            None => return Err(VersionParseError::from(raw_value)),
-        };
+        let parse_error = || VersionParseError::from(raw_value);
+        let is_old = raw_value.ends_with(".old");
 
-        //  Skip the first item if the string doesn't start with a number
-        let (version_triple, rc_str) = if first_char_is_num {
-            // Example modules dir:
-            //  5.7.11-gentoo-rc10
-            // -> ['5.7.11', 'gentoo' 'rc10']
-            //        0        1         2
-            let split_by_dash: Vec<&str> = raw_value.split('-').collect();
-            match split_by_dash.len() {
-                0..=1 => return Err(VersionParseError::from(raw_value)),
-                2 => (split_by_dash[0], ""),
-                3 => (split_by_dash[0], split_by_dash[2]),
-                _ => return Err(VersionParseError::from(raw_value)),
-            }
-        } else {
-            // Example linux src dir:
-            // linux-5.7.11-gentoo-rc10.old
-            // -> ['linux', '5.7.11', 'gentoo', 'rc10.old']
-            //        0        1          2           3
-            let split_by_dash: Vec<&str> = raw_value.split('-').collect();
-            match split_by_dash.len() {
-                0..=2 => return Err(VersionParseError::from(raw_value)),
-                3 => (split_by_dash[1], ""),
-                4 => (split_by_dash[1], split_by_dash[3]),
-                _ => return Err(VersionParseError::from(raw_value)),
-            }
-        };
+        // Example linux src dir:
+        // linux-5.7.11-gentoo-r10.old
+        // -> ['linux', '5.7.11', 'gentoo', 'r10']
+        // Module dirs and what `Display` writes have no prefix, like 5.7.11-gentoo or 5.7.11-r10
+        let mut parts = raw_value.trim_end_matches(".old").split('-').peekable();
+        let has_prefix = !parts
+            .peek()
+            .and_then(|part| part.chars().next())
+            .is_some_and(|c| c.is_ascii_digit());
+        if has_prefix {
+            parts.next();
+        }
 
-        // Collect the first 3 items or return in error
-        // ['major', 'minor', 'patch']
-        let version_triple: Result<Vec<_>, _> = version_triple
+        // 5.7.11, or 6.8 and Portage's 6.8_rc3 for the release candidates of vanilla-sources
+        let version = parts.next().ok_or_else(parse_error)?;
+        let (version, mut prerelease) = match version.split_once("_rc") {
+            Some((version, rc)) => (version, Some(rc.parse().map_err(|_| parse_error())?)),
+            None => (version, None),
+        };
+        // Written the way the kernel writes it, so 6.8_rc3 is the same release as 6.8.0-rc3
+        let mut localversion: String = prerelease.map(|n| format!("-rc{}", n)).unwrap_or_default();
+        let numbers = version
             .split('.')
-            .into_iter()
             .take(3)
             .map(|x| x.parse::<u32>())
-            .collect();
-        if version_triple.is_err() {
-            return Err(VersionParseError::from(raw_value));
-        }
-        let version_triple = version_triple.unwrap();
-
-        let is_old = raw_value.ends_with(".old");
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| parse_error())?;
+        let (major, minor, patch) = match numbers[..] {
+            // Mainline release candidates leave the patch out of the source dir's name
+            [major, minor] => (major, minor, 0),
+            [major, minor, patch] => (major, minor, patch),
+            _ => return Err(parse_error()),
+        };
 
-        // release candidate
-        let release_candidate_num = rc_str
-            .trim_start_matches("r")
-            .trim_end_matches(".old")
-            .parse::<u32>()
-            .ok();
+        let mut release_candidate_num = None;
+        // Everything else, like gentoo, dist, rt34, zen1 or git, is the flavor
+        for part in parts {
+            if let Some(n) = numbered_suffix(part, "rc") {
+                prerelease = Some(n);
+            } else if let Some(n) = numbered_suffix(part, "r") {
+                release_candidate_num = Some(n);
+            }
+            localversion.push('-');
+            localversion.push_str(part);
+        }
 
         Ok(KernelVersion {
-            major: version_triple[0],
-            minor: version_triple[1],
-            patch: version_triple[2],
-            release_candidate_num: release_candidate_num,
-            is_old: is_old,
+            major,
+            minor,
+            patch,
+            prerelease,
+            release_candidate_num,
+            localversion,
+            is_old,
         })
     }
 }
 
+/// The N of a part like `rc3` or `r1`, None if it's something else like `rt34`
+fn numbered_suffix(part: &str, prefix: &str) -> Option<u32> {
+    let number = part.strip_prefix(prefix)?;
+    match !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()) {
+        true => number.parse().ok(),
+        false => None,
+    }
+}
+
 impl TryFrom<String> for KernelVersion {
     type Error = VersionParseError;
 
@@ -153,22 +312,54 @@ impl TryFrom<String> for KernelVersion {
     }
 }
 
-/// We're just comparing versions for the sake of ordering them
+/// The version in the file name, like /boot/vmlinuz-5.11.8-gentoo or /usr/src/linux-5.11.8-gentoo
+impl TryFrom<&Path> for KernelVersion {
+    type Error = VersionParseError;
+
+    fn try_from(path: &Path) -> Result<Self, Self::Error> {
+        let parse_error = || VersionParseError {
+            path: path.to_path_buf(),
+        };
+        let filename = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(parse_error)?;
+        KernelVersion::try_from(filename).map_err(|_| parse_error())
+    }
+}
+
+/// For versions from the command line, `"5.11.8-gentoo".parse()` and what `Display` writes
+/// parse to the same version
+impl FromStr for KernelVersion {
+    type Err = VersionParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        KernelVersion::try_from(s)
+    }
+}
+
+/// Ordered by their numbers, kernels with the same numbers by their localversion
 impl Ord for KernelVersion {
     fn cmp(&self, other: &Self) -> Ordering {
+        // Release candidates come before the release
+        let prerelease = |v: &KernelVersion| v.prerelease.map_or((1, 0), |n| (0, n));
         (
             self.major,
             self.minor,
             self.patch,
+            prerelease(self),
             self.release_candidate_num,
             !self.is_old,
+            &self.localversion,
         )
             .cmp(&(
                 other.major,
                 other.minor,
                 other.patch,
+                prerelease(other),
                 other.release_candidate_num,
                 !other.is_old,
+                &other.localversion,
             ))
     }
 }
@@ -182,17 +373,11 @@ impl PartialOrd for KernelVersion {
 impl KernelVersion {
     // An `old` version will map to a non `old` source dir and module dir
     pub fn eq_ignore_is_old(&self, other: &Self) -> bool {
+        // The prerelease and revision are part of the localversion
         self.major == other.major
             && self.minor == other.minor
             && self.patch == other.patch
-            // Ensure both do or don't have a release candidate
-            &&
-            (self.release_candidate_num.is_some() == other.release_candidate_num.is_some()
-                // At this point,they both have or don't have a release candidate number.
-                // Compare values, default to zero. If neither have it they'll be equal.
-                // If they both have it, they'll unwrap valid values.
-                && self.release_candidate_num.unwrap_or(0) == other.release_candidate_num.unwrap_or(0)
-            )
+            && self.localversion == other.localversion
     }
 }
 
@@ -201,24 +386,50 @@ impl PartialEq for KernelVersion {
         self.eq_ignore_is_old(other) && self.is_old == other.is_old()
     }
 }
+
+/// Hash the same fields that `PartialEq` compares
+impl Hash for KernelVersion {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (
+            self.major,
+            self.minor,
+            self.patch,
+            &self.localversion,
+            self.is_old,
+        )
+            .hash(state);
+    }
+}
+
+/// The release with `.old` for old versions, parses back to the same version
 impl fmt::Display for KernelVersion {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut postfix = String::new();
-        if let Some(n) = self.release_candidate_num {
-            postfix.push_str("-r");
-            postfix.push_str(&n.to_string());
-        }
-        if self.is_old {
-            postfix.push_str(".old");
-        }
-        write!(f, "{}.{}.{}{}", self.major, self.minor, self.patch, postfix)
+        write!(
+            f,
+            "{}{}",
+            self.release(),
+            if self.is_old { ".old" } else { "" }
+        )
     }
 }
 
 impl InstalledItem {
     pub fn new(kind: InstalledItemKind, path: PathBuf) -> Result<InstalledItem, VersionParseError> {
-        let filename = utils::paths::filename_from_path(&path).unwrap_or_default();
-        let maybe_version = KernelVersion::try_from(filename);
+        let version = KernelVersion::try_from(path.as_path())?;
+        Ok(InstalledItem {
+            kind,
+            version,
+            path,
+        })
+    }
+
+    /// An item whose version is in `release` rather than its file name
+    pub fn with_release(
+        kind: InstalledItemKind,
+        path: PathBuf,
+        release: &str,
+    ) -> Result<InstalledItem, VersionParseError> {
+        let maybe_version = KernelVersion::try_from(release);
         maybe_version.map(|version| InstalledItem {
             kind,
             version,
@@ -233,62 +444,179 @@ impl InstalledKernel {
             version,
             module_path: None,
             source_path: None,
+            source_root: None,
             vmlinuz_path: None,
             config_path: None,
             system_map_path: None,
+            dtb_path: None,
+            extra_paths: Vec::new(),
+            optional: Vec::new(),
         }
     }
 
-    /// True if any of the paths are empty (not found)
-    /// False if all paths are Some
+    /// True if any of the paths that aren't optional are empty (not found)
+    /// False if all of them are Some
     pub fn files_missing(&self) -> bool {
-        self.module_path.is_none()
-            || self.vmlinuz_path.is_none()
-            || self.source_path.is_none()
-            || self.config_path.is_none()
-            || self.system_map_path.is_none()
+        !self.missing_files().is_empty()
     }
 
-    pub fn uninstall(self, pretend: &PretendStatus) -> io::Result<()> {
+    /// What's missing out of the image, config, System.map, sources and modules
+    pub fn missing_files(&self) -> Vec<&'static str> {
+        [
+            (
+                InstalledItemKind::KernelImage,
+                "kernel image",
+                &self.vmlinuz_path,
+            ),
+            (InstalledItemKind::Config, "config", &self.config_path),
+            (
+                InstalledItemKind::SystemMap,
+                "System.map",
+                &self.system_map_path,
+            ),
+            (InstalledItemKind::SourceDir, "sources", &self.source_path),
+            (InstalledItemKind::ModuleDir, "modules", &self.module_path),
+        ]
+        .iter()
+        .filter(|(kind, _, path)| path.is_none() && !self.optional.contains(kind))
+        .map(|(_, name, _)| *name)
+        .collect()
+    }
+
+    /// Returns the number of bytes freed, or that would be freed when pretending
+    pub fn uninstall(&self, cmd_config: &RunCmdConfig) -> Result<u64, JanitorError> {
         // Don't delete source and module dirs for old versions since they rely on non-old versions
         if self.files_missing() {
-            let err = std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!(
-                    "Error: Trying to uninstall kernel without all of its files. Kernel: {:?}",
-                    self
-                ),
+            return JanitorResultErr!(
+                "Error: Trying to uninstall kernel without all of its files. Kernel: {:?}",
+                self
             );
-            return Err(err);
-        }
-        let module_path = self.module_path.unwrap();
-        let source_path = self.source_path.unwrap();
-        let config_path = self.config_path.unwrap();
-        let kernel_image_path = self.vmlinuz_path.unwrap();
-        let system_map_path = self.system_map_path.unwrap();
-        if !self.version.is_old() {
-            if pretend == &PretendStatus::Pretend {
-                println!("Pretending to delete {:?}", module_path);
-                println!("Pretending to delete {:?}", source_path);
-            } else {
-                std::fs::remove_dir_all(module_path)?;
-                std::fs::remove_dir_all(source_path)?;
+        }
+        let module_path = self.module_path.as_ref().unwrap();
+        let kernel_image_path = self.vmlinuz_path.as_ref().unwrap();
+        let is_old = self.version.is_old();
+        if !is_old {
+            check_module_tree(cmd_config.fs.as_ref(), module_path, &self.version)?;
+            for (_, path) in self
+                .extra_paths
+                .iter()
+                .filter(|(kind, _)| *kind == InstalledItemKind::ModuleDir)
+            {
+                check_module_tree(cmd_config.fs.as_ref(), path, &self.version)?;
             }
         }
+        // Stopping halfway would leave a kernel that can't be found or removed later
+        signals::uninterruptible(|| {
+            let mut bytes_freed = 0;
+            if !is_old {
+                bytes_freed += remove_path(module_path, cmd_config)?;
+                // Optional, kept binaries don't need them
+                if let Some(source_path) = &self.source_path {
+                    bytes_freed += remove_path(source_path, cmd_config)?;
+                }
+            }
 
-        if pretend == &PretendStatus::Pretend {
-            println!("Pretending to delete {:?}", config_path);
-            println!("Pretending to delete {:?}", kernel_image_path);
-            println!("Pretending to delete {:?}", system_map_path);
-        } else {
-            std::fs::remove_file(config_path)?;
-            std::fs::remove_file(kernel_image_path)?;
-            std::fs::remove_file(system_map_path)?;
-        }
+            bytes_freed += remove_path(kernel_image_path, cmd_config)?;
+            // Only optional ones can be missing here
+            for path in [&self.config_path, &self.system_map_path]
+                .iter()
+                .copied()
+                .flatten()
+            {
+                bytes_freed += remove_path(path, cmd_config)?;
+            }
+            // The device trees of the release were replaced along with it
+            if let (Some(dtb_path), false) = (&self.dtb_path, is_old) {
+                bytes_freed += remove_path(dtb_path, cmd_config)?;
+            }
+            let signature = signing::signature_path(kernel_image_path);
+            if cmd_config.fs.disk_usage(&signature).is_ok() {
+                bytes_freed += remove_path(&signature, cmd_config)?;
+            }
+            for (_, path) in &self.extra_paths {
+                bytes_freed += remove_path(path, cmd_config)?;
+            }
 
-        Ok(())
+            Ok(bytes_freed)
+        })
     }
 }
+
+/// What `make modules_install` puts in every module tree
+const MODULE_TREE_MARKERS: &[&str] = &["modules.builtin", "modules.order", "modules.dep", "kernel"];
+
+/// Fails unless `module_path` is the module tree of `version`, so a KernelModulesPath pointing
+/// somewhere else doesn't get a directory deleted that only happens to have the right name
+/// An empty one, or one with only the `build` and `source` links left, is fine to delete too
+fn check_module_tree(
+    fs: &dyn FileSystem,
+    module_path: &Path,
+    version: &KernelVersion,
+) -> Result<(), JanitorError> {
+    // Module trees are named after the release, an .old image still uses its original one
+    let named_after = utils::paths::filename_from_path(module_path);
+    if named_after.as_deref() != Some(version.release().as_str()) {
+        return Err(JanitorError::new(
+            ErrorKind::Cleanup,
+            format!(
+                "{} isn't named after {}, not deleting it. Check KernelModulesPath",
+                module_path.display(),
+                version
+            ),
+        ));
+    }
+    let entries = fs
+        .read_dir(module_path)
+        .with_context(|| format!("failed to read {}", module_path.display()))?;
+    let is_module_tree = entries.iter().any(|entry| {
+        utils::paths::filename_from_path(entry)
+            .is_some_and(|name| MODULE_TREE_MARKERS.contains(&name.as_str()))
+    });
+    if !is_module_tree && entries.iter().any(|entry| !fs.is_symlink(entry)) {
+        return Err(JanitorError::new(
+            ErrorKind::Cleanup,
+            format!(
+                "{} doesn't look like a module tree, it has none of {}. Check KernelModulesPath, \
+                 or delete it by hand if it really is {}'s",
+                module_path.display(),
+                MODULE_TREE_MARKERS.join(", "),
+                version
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Deletes a file or an entire directory and reports it, returns the bytes freed
+/// When pretending the file is only removed from the in-memory filesystem
+pub fn remove_path(path: &Path, cmd_config: &RunCmdConfig) -> Result<u64, JanitorError> {
+    let bytes = cmd_config
+        .fs
+        .disk_usage(path)
+        .context(format!("failed to measure {}", path.display()))?;
+    let failed_to_delete = || format!("failed to delete {}", path.display());
+    // Pretending, `fs` is in memory and the removal is only made there
+    if cmd_config.escalation.is_some() && !cmd_config.pretending() {
+        utils::paths::check_removable(path, &cmd_config.removable_roots)
+            .with_context(failed_to_delete)?;
+        let mut rm = Command::new("rm");
+        rm.arg("-rf").arg("--").arg(path);
+        cmd_config.run_without_prompt(
+            &mut cmd_config.privileged(rm),
+            &format!("delete {:?}", path),
+        )?;
+    } else {
+        cmd_config
+            .fs
+            .remove(path, &cmd_config.removable_roots)
+            .with_context(failed_to_delete)?;
+    }
+    events::emit(Event::FileDeleted {
+        path,
+        pretend: cmd_config.pretending(),
+    });
+    Ok(bytes)
+}
 impl fmt::Display for InstalledKernel {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -305,7 +633,11 @@ impl fmt::Display for InstalledKernel {
             self.system_map_path,
             self.source_path,
             self.module_path
-        )
+        )?;
+        for (_, path) in &self.extra_paths {
+            write!(f, "\n  Also found:      {:?}", path)?;
+        }
+        Ok(())
     }
 }
 
@@ -324,7 +656,7 @@ impl Ord for InstalledKernel {
 
 impl PartialOrd for InstalledKernel {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.version.cmp(&other.version))
+        Some(self.cmp(other))
     }
 }
 
@@ -335,91 +667,329 @@ impl PartialEq for InstalledKernel {
 }
 
 impl KernelSearch {
+    /// Sources are looked for in all of `source_search_paths`, like /usr/src and a directory of
+    /// out-of-tree checkouts
     pub fn new(
         install_search_path: &Path,
-        source_search_path: &Path,
+        source_search_paths: &[PathBuf],
         module_search_path: &Path,
     ) -> KernelSearch {
         KernelSearch {
             // Use default paths
             install_search_path: install_search_path.to_path_buf(),
-            source_search_path: source_search_path.to_path_buf(),
+            source_search_paths: source_search_paths.to_vec(),
             module_search_path: module_search_path.to_path_buf(),
+            naming: Naming::default(),
+            dtb_search_path: None,
+            fs: Arc::new(RealFileSystem),
+            cache: None,
+            unsaved: RefCell::new(None),
+            optional: Vec::new(),
         }
     }
 
-    fn find_all_installed_items(&self) -> io::Result<Vec<InstalledItem>> {
-        // Search for vmlinuz
-        let kernel_images: Vec<_> =
-            utils::paths::all_paths_with_prefix("vmlinuz-", &self.install_search_path)?
+    /// Also find the device tree directories `make dtbs_install` made in `dtb_search_path`
+    pub fn with_dtb_search_path(mut self, dtb_search_path: Option<PathBuf>) -> KernelSearch {
+        self.dtb_search_path = dtb_search_path;
+        self
+    }
+
+    /// Find the image, config and System.map by these names instead of installkernel's
+    pub fn with_naming(mut self, naming: Naming) -> KernelSearch {
+        self.naming = naming;
+        self
+    }
+
+    /// Don't count `optional` as missing when a kernel doesn't have them, see `optional_files`
+    pub fn with_optional_files(mut self, optional: Vec<InstalledItemKind>) -> KernelSearch {
+        self.optional = optional;
+        self
+    }
+
+    /// Cache what's found in `path`, see `CacheUse`. The search only reads it, `save_cache` writes it
+    pub fn with_cache(mut self, path: PathBuf, cache_use: CacheUse) -> KernelSearch {
+        self.cache = Some((path, cache_use));
+        self
+    }
+
+    /// The filesystem that's searched
+    pub fn filesystem(&self) -> Arc<dyn FileSystem> {
+        self.fs.clone()
+    }
+
+    /// Search this filesystem instead of the real one
+    pub fn with_filesystem(mut self, fs: Arc<dyn FileSystem>) -> KernelSearch {
+        self.fs = fs;
+        self
+    }
+
+    /// Drop dangling symlinks and links to something that was already found
+    /// so a symlinked layout doesn't show up as extra kernels
+    /// Links are kept as links, the file they point to is never deleted through them
+    fn dedup_symlinks(&self, mut paths: Vec<PathBuf>) -> Vec<PathBuf> {
+        // Directories list in no particular order, sorting keeps which of two names with the same
+        // version is the kernel's own the same from run to run
+        paths.sort();
+        // Prefer the real file over any link to it
+        paths.sort_by_key(|path| self.fs.is_symlink(path));
+        let mut targets: HashMap<PathBuf, PathBuf> = HashMap::new();
+        let mut deduped = Vec::new();
+        for path in paths {
+            let target = match self.fs.canonicalize(&path) {
+                Ok(t) => t,
+                Err(_) => {
+                    warn!("{:?} is a dangling symlink. Ignoring file.", path);
+                    continue;
+                }
+            };
+            if let Some(existing) = targets.get(&target) {
+                debug!("{:?} points to {:?}. Ignoring link.", path, existing);
+                continue;
+            }
+            targets.insert(target, path.clone());
+            deduped.push(path);
+        }
+        deduped
+    }
+
+    /// The release each pkgbase in the `pkgbase` files of the module directories is installed as
+    /// Modules left over from earlier builds have the same pkgbase, the newest is the installed one
+    fn find_pkgbases(&self, module_root: &Path) -> Result<HashMap<String, String>, JanitorError> {
+        let mut pkgbases: HashMap<String, (KernelVersion, String)> = HashMap::new();
+        if !self.uses_pkgbase() {
+            return Ok(HashMap::new());
+        }
+        let module_dirs = self
+            .fs
+            .read_dir(module_root)
+            .with_context(|| format!("failed to read {}", module_root.display()))?;
+        for module_dir in module_dirs {
+            let release = utils::paths::filename_from_path(&module_dir).unwrap_or_default();
+            let (version, pkgbase) = match (
+                KernelVersion::try_from(release.as_str()),
+                self.fs
+                    .read_to_string(&module_dir.join(naming::PKGBASE_FILE)),
+            ) {
+                (Ok(version), Ok(pkgbase)) => (version, pkgbase.trim().to_string()),
+                _ => continue,
+            };
+            match pkgbases.get(&pkgbase) {
+                Some((newest, _)) if newest >= &version => (),
+                _ => {
+                    pkgbases.insert(pkgbase, (version, release));
+                }
+            }
+        }
+        Ok(pkgbases
+            .into_iter()
+            .map(|(pkgbase, (_, release))| (pkgbase, release))
+            .collect())
+    }
+
+    fn uses_pkgbase(&self) -> bool {
+        let naming = &self.naming;
+        [&naming.image, &naming.config, &naming.system_map]
+            .iter()
+            .any(|template| template.uses_pkgbase())
+    }
+
+    /// Resolve the roots themselves, e.g. when /boot is a link to /efi
+    fn resolve(&self, root: &Path) -> Result<PathBuf, JanitorError> {
+        self.fs
+            .canonicalize(root)
+            .with_context(|| format!("failed to resolve search path {}", root.display()))
+    }
+
+    /// The search from the cache when none of the directories it looks in changed since it was
+    /// written, searching again when they did
+    fn cached_search(&self, path: &Path, cache_use: CacheUse) -> Result<Found, JanitorError> {
+        // Which release a pkgbase is installed as is read from inside the module directories
+        if self.uses_pkgbase() {
+            return self.search();
+        }
+        let mut dirs = vec![self.resolve(&self.install_search_path)?];
+        for root in &self.source_search_paths {
+            dirs.push(self.resolve(root)?);
+        }
+        dirs.push(self.resolve(&self.module_search_path)?);
+        if let Some(root) = &self.dtb_search_path {
+            dirs.extend(self.resolve(root).ok());
+        }
+        let naming = &self.naming;
+        let key = format!("{} {} {}", naming.image, naming.config, naming.system_map);
+        let stamp = search_cache::stamp(self.fs.as_ref(), &key, &dirs);
+        if let (Some(stamp), CacheUse::Reuse | CacheUse::ReadOnly) = (&stamp, cache_use) {
+            if let Some(found) = search_cache::read(self.fs.as_ref(), path, stamp) {
+                debug!(
+                    "Nothing changed since the search cached in {}",
+                    path.display()
+                );
+                return Ok(found);
+            }
+        }
+        let found = self.search()?;
+        if let (Some(stamp), CacheUse::Reuse | CacheUse::Refresh) = (stamp, cache_use) {
+            self.unsaved.replace(Some((stamp, found.clone())));
+        }
+        Ok(found)
+    }
+
+    /// Write what the search found to the cache when it wasn't up to date
+    /// Through `cmd_config` like any other change, so a pretend run leaves it alone
+    pub fn save_cache(&self, cmd_config: &RunCmdConfig) {
+        if let (Some((path, _)), Some((stamp, found))) = (&self.cache, self.unsaved.take()) {
+            search_cache::write(cmd_config, path, &stamp, &found);
+        }
+    }
+
+    fn find_all_installed_items(&self) -> Result<Vec<InstalledItem>, JanitorError> {
+        let found = match &self.cache {
+            Some((path, cache_use)) => self.cached_search(path, *cache_use)?,
+            None => self.search()?,
+        };
+        let all_items: Vec<InstalledItem> = found
+            .into_iter()
+            .map(|(item_kind, pathbuf, release)| {
+                InstalledItem::with_release(item_kind, pathbuf, &release)
+            })
+            .filter_map(|installed_item| match installed_item {
+                // Report any errors and remove those invalid versions
+                Ok(v) => Some(v),
+                Err(e) => {
+                    warn!("{}. Ignoring file.", e);
+                    None
+                }
+            })
+            .collect();
+
+        debug!("Found {} installed items", all_items.len());
+        for item in &all_items {
+            trace!("Found {:?} for version {}", item.path, item.version);
+        }
+        Ok(all_items)
+    }
+
+    /// Every item in the search paths and the release it's for
+    fn search(&self) -> Result<Found, JanitorError> {
+        let resolve = |root: &Path| self.resolve(root);
+        let install_root = resolve(&self.install_search_path)?;
+        let source_roots = self
+            .source_search_paths
+            .iter()
+            .map(|root| resolve(root))
+            .collect::<Result<Vec<_>, _>>()?;
+        let module_root = resolve(&self.module_search_path)?;
+        let find = |prefix: &str, root: &Path| -> Result<Vec<PathBuf>, JanitorError> {
+            let paths = self
+                .fs
+                .read_dir_with_prefix(prefix, root)
+                .with_context(|| format!("failed to read {}", root.display()))?
                 .into_iter()
-                .map(|path| (InstalledItemKind::KernelImage, path))
+                .filter(|path| !signing::is_signature(path))
                 .collect();
+            Ok(self.dedup_symlinks(paths))
+        };
 
-        // Search for config
-        let configs: Vec<_> =
-            utils::paths::all_paths_with_prefix("config-", &self.install_search_path)?
+        let pkgbases = self.find_pkgbases(&module_root)?;
+        // The release is wherever the template put it, files that don't fit it aren't kernels
+        let named = |template: &NameTemplate| -> Result<Vec<(PathBuf, String)>, JanitorError> {
+            Ok(find(template.prefix(), &install_root)?
                 .into_iter()
-                .map(|path| (InstalledItemKind::Config, path))
-                .collect();
+                .filter_map(|path| {
+                    let name = utils::paths::filename_from_path(&path)?;
+                    // Which release a replaced `vmlinuz-linux.old` was is lost
+                    let by_pkgbase = || match name.ends_with(".old") {
+                        true => None,
+                        false => pkgbases.get(&template.pkgbase_of(&name)?).cloned(),
+                    };
+                    match template.release_of(&name).or_else(by_pkgbase) {
+                        Some(release) => Some((path, release)),
+                        None => {
+                            trace!("{:?} isn't named like {}", path, template);
+                            None
+                        }
+                    }
+                })
+                .collect())
+        };
+        let by_name = |paths: Vec<PathBuf>| -> Vec<(PathBuf, String)> {
+            paths
+                .into_iter()
+                .map(|path| {
+                    let name = utils::paths::filename_from_path(&path).unwrap_or_default();
+                    (path, name)
+                })
+                .collect()
+        };
+
+        // Search for vmlinuz
+        let kernel_images: Vec<_> = named(&self.naming.image)?
+            .into_iter()
+            .map(|(path, release)| (InstalledItemKind::KernelImage, path, release))
+            .collect();
+
+        // Search for config
+        let configs: Vec<_> = named(&self.naming.config)?
+            .into_iter()
+            .map(|(path, release)| (InstalledItemKind::Config, path, release))
+            .collect();
 
         // Search for system map
-        let system_maps: Vec<_> =
-            utils::paths::all_paths_with_prefix("System.map-", &self.install_search_path)?
-                .into_iter()
-                .map(|path| (InstalledItemKind::SystemMap, path))
-                .collect();
+        let system_maps: Vec<_> = named(&self.naming.system_map)?
+            .into_iter()
+            .map(|(path, release)| (InstalledItemKind::SystemMap, path, release))
+            .collect();
 
-        // Search for source dir
-        let source_dirs: Vec<_> =
-            utils::paths::all_paths_with_prefix("linux-", &self.source_search_path)?
-                .into_iter()
-                .map(|path| (InstalledItemKind::SourceDir, path))
-                .collect();
+        // Search for source dir, the roots in order so the sources in an earlier one are the kernel's
+        let mut source_dirs = Vec::new();
+        for root in source_roots.iter() {
+            source_dirs.extend(
+                by_name(find("linux-", root)?)
+                    .into_iter()
+                    .map(|(path, name)| (InstalledItemKind::SourceDir, path, name)),
+            );
+        }
 
         // Search for module path
-        let module_dirs: Vec<_> = utils::paths::all_paths(&self.module_search_path)?
+        let module_dirs: Vec<_> = by_name(find("", &module_root)?)
             .into_iter()
-            .map(|path| (InstalledItemKind::ModuleDir, path))
+            .map(|(path, name)| (InstalledItemKind::ModuleDir, path, name))
             .collect();
 
-        let all_items: Vec<InstalledItem> = vec![
+        // Search for device trees, there are none until the first kernel installs them
+        let dtb_dirs: Vec<_> = match &self.dtb_search_path {
+            Some(root) if self.fs.read_dir(root).is_ok() => by_name(find("", &resolve(root)?)?),
+            _ => Vec::new(),
+        }
+        .into_iter()
+        .map(|(path, name)| (InstalledItemKind::DtbDir, path, name))
+        .collect();
+
+        Ok(vec![
             kernel_images,
             configs,
             system_maps,
             source_dirs,
             module_dirs,
+            dtb_dirs,
         ]
         .into_iter()
         .flatten()
-        .map(|(item_kind, pathbuf)| {
-            // Grab the trimmed filename so it can be used to make a KernelVersion
-            InstalledItem::new(item_kind, pathbuf)
-        })
-        .filter_map(|installed_item| match installed_item {
-            // Report any errors and remove those invalid versions
-            Ok(v) => Some(v),
-            Err(e) => {
-                eprintln!("{}. Ignoring file.", e);
-                None
-            }
-        })
-        .collect();
-
-        Ok(all_items)
+        .collect())
     }
 
     /// Kernel installs marked `.old` rely on the non `.old` equivalent source directory
     /// and module path. This function searches for all old installs and then copies the
     /// source and module paths from their non-old equivalents.
+    /// Unless the sources are `optional`, the non-old one has to have them
     fn find_src_and_mod_for_old_install(
         version_map: &mut HashMap<KernelVersion, InstalledKernel>,
+        optional: &[InstalledItemKind],
     ) -> io::Result<()> {
         let old_versions: Vec<KernelVersion> = version_map
             .keys()
-            .into_iter()
             .filter(|version| version.is_old())
-            .map(|version| version.clone())
+            .cloned()
             .collect();
         // return an error if there isn't one in the map
         for old_version in old_versions {
@@ -427,45 +997,42 @@ impl KernelSearch {
             let mut non_old_version = old_version.clone();
             non_old_version.is_old = false;
             // Find the non-old equivalent module dir in the version map
-            let (module_path, src_path) = match version_map.get(&non_old_version) {
+            let (module_path, src_path, src_root) = match version_map.get(&non_old_version) {
                 Some(non_old_install) => {
                     if non_old_install.module_path.is_none() {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            format!(
-                                "{:?} did not have a module path and {:?} relies on it",
-                                non_old_version, old_version
-                            ),
-                        ));
-                    } else if non_old_install.source_path.is_none() {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            format!(
-                                "{:?} did not have a source path and {:?} relies on it",
-                                non_old_version, old_version
-                            ),
-                        ));
+                        return Err(std::io::Error::other(format!(
+                            "{:?} did not have a module path and {:?} relies on it",
+                            non_old_version, old_version
+                        )));
+                    } else if non_old_install.source_path.is_none()
+                        && !optional.contains(&InstalledItemKind::SourceDir)
+                    {
+                        return Err(std::io::Error::other(format!(
+                            "{:?} did not have a source path and {:?} relies on it",
+                            non_old_version, old_version
+                        )));
                     }
                     (
                         non_old_install.module_path.clone(),
                         non_old_install.source_path.clone(),
+                        non_old_install.source_root.clone(),
                     )
                 }
                 None => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("Could not find a non.old equivalent for {:?}", old_version),
-                    ));
+                    return Err(std::io::Error::other(format!(
+                        "Could not find a non.old equivalent for {:?}",
+                        old_version
+                    )));
                 }
             };
             match version_map.get_mut(&old_version) {
                 Some(old_install) => {
                     old_install.module_path = module_path;
                     old_install.source_path = src_path;
+                    old_install.source_root = src_root;
                 }
                 None => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
+                    return Err(std::io::Error::other(
                         "Could not find old version despite it just being here",
                     ));
                 }
@@ -475,195 +1042,103 @@ impl KernelSearch {
     }
 
     /// Fold the vector of installed item info into InstalledKernels
-    fn fold_items_to_kernels(items: Vec<InstalledItem>) -> io::Result<Vec<InstalledKernel>> {
+    fn fold_items_to_kernels(
+        items: Vec<InstalledItem>,
+        optional: &[InstalledItemKind],
+    ) -> io::Result<Vec<InstalledKernel>> {
         let mut version_map: HashMap<KernelVersion, InstalledKernel> = HashMap::new();
         // - Check if that KernelVersion is already present as an InstalledKernel
         //   - If it is, add the path to the InstalledKernel
         //   - otherwise, create a new InstalledKernel with the pair
         for item in items {
-            match item.kind {
-                InstalledItemKind::KernelImage => {
-                    let old_path = version_map
-                        .entry(item.version)
-                        .or_insert(InstalledKernel::new(item.version))
-                        .vmlinuz_path
-                        .replace(item.path);
-                    if old_path.is_some() {
-                        eprintln!(
-                            "Overwriting previously present kernel image  {:?} for version {:?}",
-                            old_path, item.version
-                        );
-                    }
-                }
-                InstalledItemKind::Config => {
-                    let old_path = version_map
-                        .entry(item.version)
-                        .or_insert(InstalledKernel::new(item.version))
-                        .config_path
-                        .replace(item.path);
-                    if old_path.is_some() {
-                        eprintln!(
-                            "Overwriting previously present config {:?} for version {:?}",
-                            old_path, item.version
-                        );
-                    }
-                }
-                InstalledItemKind::SystemMap => {
-                    let old_path = version_map
-                        .entry(item.version)
-                        .or_insert(InstalledKernel::new(item.version))
-                        .system_map_path
-                        .replace(item.path);
-                    if old_path.is_some() {
-                        eprintln!(
-                            "Overwriting previously present system map {:?} for version {:?}",
-                            old_path, item.version
-                        );
-                    }
-                }
-
-                InstalledItemKind::SourceDir => {
-                    let old_path = version_map
-                        .entry(item.version)
-                        .or_insert(InstalledKernel::new(item.version))
-                        .source_path
-                        .replace(item.path);
-                    if old_path.is_some() {
-                        eprintln!(
-                            "Overwriting previously present source path {:?} for version {:?}",
-                            old_path, item.version
-                        );
-                    }
+            let kernel =
+                version_map
+                    .entry(item.version.clone())
+                    .or_insert_with(|| InstalledKernel {
+                        optional: optional.to_vec(),
+                        ..InstalledKernel::new(item.version.clone())
+                    });
+            let slot = match item.kind {
+                InstalledItemKind::KernelImage => &mut kernel.vmlinuz_path,
+                InstalledItemKind::Config => &mut kernel.config_path,
+                InstalledItemKind::SystemMap => &mut kernel.system_map_path,
+                InstalledItemKind::SourceDir => &mut kernel.source_path,
+                InstalledItemKind::ModuleDir => &mut kernel.module_path,
+                InstalledItemKind::DtbDir => &mut kernel.dtb_path,
+            };
+            match slot {
+                // The items are in search order, the first one found is the kernel's own
+                Some(existing) => {
+                    debug!(
+                        "{:?} has the same release as {:?}, keeping both for {}",
+                        item.path, existing, item.version
+                    );
+                    kernel.extra_paths.push((item.kind, item.path));
                 }
-                InstalledItemKind::ModuleDir => {
-                    let old_path = version_map
-                        .entry(item.version)
-                        .or_insert(InstalledKernel::new(item.version))
-                        .module_path
-                        .replace(item.path);
-                    if old_path.is_some() {
-                        eprintln!(
-                            "Overwriting previously present module path {:?} for version {:?}",
-                            old_path, item.version
-                        );
+                None => {
+                    if item.kind == InstalledItemKind::SourceDir {
+                        kernel.source_root = item.path.parent().map(Path::to_path_buf);
                     }
+                    *slot = Some(item.path);
                 }
             }
         }
-        KernelSearch::find_src_and_mod_for_old_install(&mut version_map)?;
+        KernelSearch::find_src_and_mod_for_old_install(&mut version_map, optional)?;
 
-        Ok(version_map
-            .into_iter()
-            .map(|(_, installed_kernel)| installed_kernel)
-            .collect())
+        Ok(version_map.into_values().collect())
     }
 
     /// Actually run the search and return all of the found InstalledKernels
     /// Oldest kernels are first, newest are last
-    pub fn execute(&self) -> io::Result<Vec<InstalledKernel>> {
-        let all_installed_items = self.find_all_installed_items()?;
-
-        let mut installed_kernels = KernelSearch::fold_items_to_kernels(all_installed_items)?;
+    pub fn execute(&self) -> Result<Vec<InstalledKernel>, JanitorError> {
+        let search_error = |e: JanitorError| e.with_kind(ErrorKind::Search);
+        let all_installed_items = self.find_all_installed_items().map_err(search_error)?;
+
+        let mut optional = self.naming.not_installed();
+        optional.extend(&self.optional);
+        let mut installed_kernels =
+            KernelSearch::fold_items_to_kernels(all_installed_items, &optional)
+                .map_err(|e| search_error(e.into()))?;
         installed_kernels.sort();
         Ok(installed_kernels)
     }
 }
+
+/// Builds a version without a file name to parse it from, for the tests of every module
 #[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::utils::tests::*;
-    impl KernelVersion {
-        pub fn new(
-            major: u32,
-            minor: u32,
-            patch: u32,
-            release_candidate_num: Option<u32>,
-            is_old: bool,
-        ) -> KernelVersion {
-            KernelVersion {
-                major,
-                minor,
-                patch,
-                release_candidate_num,
-                is_old,
-            }
-        }
-        /// Returns major, minor, patch versions
-        pub fn version_triple(&self) -> (u32, u32, u32) {
-            (self.major, self.minor, self.patch)
-        }
-        pub fn release_candidate_num(&self) -> Option<u32> {
-            self.release_candidate_num
+impl KernelVersion {
+    pub fn new(
+        major: u32,
+        minor: u32,
+        patch: u32,
+        release_candidate_num: Option<u32>,
+        is_old: bool,
+    ) -> KernelVersion {
+        KernelVersion {
+            major,
+            minor,
+            patch,
+            prerelease: None,
+            release_candidate_num,
+            localversion: release_candidate_num
+                .map(|n| format!("-r{}", n))
+                .unwrap_or_default(),
+            is_old,
         }
     }
-
-    impl InstalledKernel {
-        pub fn with_module_path(mut self, dir: PathBuf) -> InstalledKernel {
-            self.module_path = Some(dir);
-            self
-        }
-        pub fn with_vmlinuz_path(mut self, dir: PathBuf) -> InstalledKernel {
-            self.vmlinuz_path = Some(dir);
-            self
-        }
-        pub fn with_source_path(mut self, dir: PathBuf) -> InstalledKernel {
-            self.source_path = Some(dir);
-            self
-        }
-        pub fn with_config_path(mut self, dir: PathBuf) -> InstalledKernel {
-            self.config_path = Some(dir);
-            self
-        }
-        pub fn with_system_map_path(mut self, dir: PathBuf) -> InstalledKernel {
-            self.system_map_path = Some(dir);
-            self
-        }
-
-        pub fn create_test_version(version_triple: &str, is_old: bool) -> InstalledKernel {
-            let is_old_str = match is_old {
-                true => ".old",
-                false => "",
-            };
-            let version_str = format!("linux-{}-gentoo{}", version_triple, is_old_str);
-            let version = KernelVersion::try_from(version_str).unwrap();
-
-            let kernel_image_path = get_test_install_pathbuf()
-                .join(format!("vmlinuz-{}-gentoo{}", version_triple, is_old_str));
-            std::fs::File::create(&kernel_image_path).unwrap();
-            let system_map_path = get_test_install_pathbuf().join(format!(
-                "System.map-{}-gentoo{}",
-                version_triple, is_old_str
-            ));
-            std::fs::File::create(&system_map_path).unwrap();
-            let config_path = get_test_install_pathbuf()
-                .join(format!("config-{}-gentoo{}", version_triple, is_old_str));
-            std::fs::File::create(&config_path).unwrap();
-
-            let module_path = get_test_module_pathbuf().join(format!("{}-gentoo", version_triple));
-            if !module_path.exists() {
-                std::fs::DirBuilder::new()
-                    .recursive(true)
-                    .create(&module_path)
-                    .unwrap();
-            }
-            let src_path = get_test_src_pathbuf().join(format!("linux-{}-gentoo", version_triple));
-            if !src_path.exists() {
-                std::fs::DirBuilder::new()
-                    .recursive(true)
-                    .create(&src_path)
-                    .unwrap();
-            }
-
-            InstalledKernel {
-                version: version,
-                module_path: Some(module_path),
-                source_path: Some(src_path),
-                vmlinuz_path: Some(kernel_image_path),
-                config_path: Some(config_path),
-                system_map_path: Some(system_map_path),
-            }
-        }
+    pub fn release_candidate_num(&self) -> Option<u32> {
+        self.release_candidate_num
     }
+}
+
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison, clippy::get_first)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_utils::TestDir,
+        update::{InteractiveStatus, PretendStatus},
+    };
     #[test]
     fn create_kernel_version() {
         let ver = KernelVersion::try_from("linux-5.7.11-gentoo");
@@ -710,6 +1185,115 @@ mod tests {
         assert!(ver.release_candidate_num().is_some());
         assert_eq!(ver.release_candidate_num().unwrap(), 1234);
     }
+    #[test]
+    fn other_source_packages() {
+        let version = |s: &str| KernelVersion::try_from(s).unwrap();
+        // vanilla-sources, a release and a release candidate
+        assert_eq!(version("linux-5.11.8"), version("5.11.8"));
+        assert_eq!(version("linux-6.8-rc3"), version("6.8.0-rc3"));
+        assert_eq!(version("6.8_rc3"), version("linux-6.8-rc3"));
+        // git-sources
+        assert_eq!(
+            version("linux-6.8-rc3-git"),
+            version("vmlinuz-6.8.0-rc3-git")
+        );
+        // rt-sources and zen-sources, the rt and zen numbers are part of the flavor
+        assert_eq!(version("linux-5.10.21-rt34"), version("5.10.21-rt34"));
+        assert_eq!(version("linux-5.11.8-zen1").release_candidate_num(), None);
+        assert_eq!(
+            version("linux-5.11.8-gentoo-dist-r1").release_candidate_num(),
+            Some(1)
+        );
+
+        assert!(version("linux-6.8-rc3") < version("linux-6.8-rc4"));
+        assert!(version("linux-6.8-rc4-git") < version("linux-6.8.0"));
+        assert!(version("linux-6.8.0") < version("linux-6.8.1-rc1"));
+        assert_eq!(
+            version("linux-6.8-rc3-git.old").to_string(),
+            "6.8.0-rc3-git.old"
+        );
+        for s in &[
+            "6.8.0-rc3",
+            "6.8.0-rc3-r1",
+            "5.11.8-r2.old",
+            "5.11.8-gentoo-r1-dist",
+        ] {
+            assert_eq!(version(s).to_string(), *s);
+        }
+        assert!(KernelVersion::try_from("linux-6").is_err());
+        assert!(KernelVersion::try_from("linux-6.8_rcx").is_err());
+    }
+
+    #[test]
+    fn std_traits() {
+        use std::collections::{hash_map::DefaultHasher, HashSet};
+        let hash = |v: &KernelVersion| {
+            let mut hasher = DefaultHasher::new();
+            v.hash(&mut hasher);
+            hasher.finish()
+        };
+        let version: KernelVersion = "5.11.8-gentoo-r1".parse().unwrap();
+        assert_eq!(
+            version.to_string().parse::<KernelVersion>().unwrap(),
+            version
+        );
+        // Display gives back exactly the release that was parsed
+        for release in &[
+            "5.11.8-gentoo-r1",
+            "5.11.8-gentoo-dist.old",
+            "6.8.0-rc3-git",
+            "6.8.0",
+        ] {
+            let parsed: KernelVersion = release.parse().unwrap();
+            assert_eq!(parsed.to_string(), *release);
+        }
+        assert_eq!(
+            KernelVersion::try_from(Path::new("/boot/vmlinuz-5.11.8-gentoo-r1")).unwrap(),
+            version
+        );
+        assert_eq!(
+            KernelVersion::try_from(Path::new("/usr/src/linux-5.11.8-gentoo-r1.old")).unwrap(),
+            "5.11.8-gentoo-r1.old".parse().unwrap()
+        );
+        let err = KernelVersion::try_from(Path::new("/boot/grub")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Could not parse \"/boot/grub\" as a KernelVersion"
+        );
+        assert!("".parse::<KernelVersion>().is_err());
+
+        // The same release wherever it's found is the same version and hashes the same
+        let same = [
+            "linux-6.8-rc3-git",
+            "6.8.0-rc3-git",
+            "vmlinuz-6.8.0-rc3-git",
+        ];
+        let versions: Vec<KernelVersion> = same.iter().map(|s| s.parse().unwrap()).collect();
+        for pair in versions.windows(2) {
+            assert_eq!(pair[0].cmp(&pair[1]), Ordering::Equal);
+            assert_eq!(pair[0], pair[1]);
+            assert_eq!(hash(&pair[0]), hash(&pair[1]));
+        }
+        // Flavors of the same numbers are different kernels
+        let distinct: HashSet<KernelVersion> = [
+            "6.8.0",
+            "6.8.0.old",
+            "6.8.0-r1",
+            "6.8.0-rc3",
+            "6.8.0-gentoo",
+            "6.8.0-gentoo-dist",
+            "6.8.0-zen1",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect();
+        assert_eq!(distinct.len(), 7);
+        assert_ne!(
+            "5.11.8-gentoo".parse::<KernelVersion>().unwrap(),
+            "5.11.8-zen1".parse().unwrap()
+        );
+    }
+
     #[test]
     fn kernel_not_equal() {
         let error_msg = "Could not construct test KernelVersion!";
@@ -834,36 +1418,236 @@ mod tests {
     }
     #[test]
     fn find_all_installed_items() {
-        cleanup_test_dir();
-        init_test_dir();
+        let test_dir = TestDir::new();
 
-        let _ = InstalledKernel::create_test_version("5.4.97", false);
-        let install_path = get_test_install_pathbuf();
-        let module_path = get_test_module_pathbuf();
-        let src_path = get_test_src_pathbuf();
+        let dummy_install = InstalledKernel::create_test_version(&test_dir, "5.4.97", false);
+        let install_path = test_dir.install_path();
+        let module_path = test_dir.module_path();
+        let src_paths = vec![test_dir.src_path()];
+        // Its signature isn't a second kernel image
+        let image = dummy_install.vmlinuz_path.unwrap();
+        std::fs::write(signing::signature_path(&image), b"").unwrap();
 
-        let installed_kernels = KernelSearch::new(&install_path, &src_path, &module_path).execute();
+        let installed_kernels =
+            KernelSearch::new(&install_path, &src_paths, &module_path).execute();
 
         assert!(installed_kernels.is_ok());
         let installed_kernels = installed_kernels.unwrap();
         assert_eq!(installed_kernels.len(), 1);
         let ker = installed_kernels.get(0).unwrap();
+        assert_eq!(ker.vmlinuz_path, Some(image));
         println!("Kernel:{}", ker);
         assert_eq!(ker.files_missing(), false);
     }
 
+    #[test]
+    fn multiple_source_paths() {
+        let test_dir = TestDir::new();
+        let _ = InstalledKernel::create_test_version(&test_dir, "5.4.97", false);
+        let checkouts = test_dir.path().join("checkouts");
+        for version in &["5.4.97", "5.11.0"] {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .create(checkouts.join(format!("linux-{}-gentoo", version)))
+                .unwrap();
+        }
+
+        let installed_kernels = KernelSearch::new(
+            &test_dir.install_path(),
+            &[test_dir.src_path(), checkouts.clone()],
+            &test_dir.module_path(),
+        )
+        .execute()
+        .unwrap();
+        let roots: Vec<_> = installed_kernels
+            .iter()
+            .map(|k| (k.version.to_string(), k.source_root.clone()))
+            .collect();
+        // The first path has the sources of a version that's in both, the other copy is kept too
+        assert_eq!(
+            roots,
+            vec![
+                (
+                    "5.4.97-gentoo".to_string(),
+                    Some(std::fs::canonicalize(test_dir.src_path()).unwrap())
+                ),
+                (
+                    "5.11.0-gentoo".to_string(),
+                    Some(std::fs::canonicalize(&checkouts).unwrap())
+                ),
+            ]
+        );
+        assert_eq!(
+            installed_kernels[0].extra_paths,
+            vec![(
+                InstalledItemKind::SourceDir,
+                std::fs::canonicalize(&checkouts)
+                    .unwrap()
+                    .join("linux-5.4.97-gentoo")
+            )]
+        );
+        assert!(installed_kernels[1].extra_paths.is_empty());
+    }
+
+    #[test]
+    fn custom_naming() {
+        let test_dir = TestDir::new();
+        let dummy_install = InstalledKernel::create_test_version(&test_dir, "5.4.97", false);
+        let install_path = test_dir.install_path();
+        let image = install_path.join("kernel-gentoo-5.4.97");
+        std::fs::rename(dummy_install.vmlinuz_path.unwrap(), &image).unwrap();
+
+        let naming = Naming {
+            image: "kernel-{flavor}-{version}".parse().unwrap(),
+            ..Naming::default()
+        };
+        let installed_kernels = KernelSearch::new(
+            &install_path,
+            &[test_dir.src_path()],
+            &test_dir.module_path(),
+        )
+        .with_naming(naming)
+        .execute()
+        .unwrap();
+        assert_eq!(installed_kernels.len(), 1);
+        assert_eq!(installed_kernels[0].vmlinuz_path, Some(image));
+        assert!(!installed_kernels[0].files_missing());
+    }
+
+    #[test]
+    fn config_not_installed() -> Result<(), JanitorError> {
+        let test_dir = TestDir::new();
+        let dummy_install = InstalledKernel::create_test_version(&test_dir, "5.4.97", false);
+        std::fs::remove_file(dummy_install.config_path.unwrap())?;
+        let install_path = test_dir.install_path();
+        let search = |naming: Naming| {
+            KernelSearch::new(
+                &install_path,
+                &[test_dir.src_path()],
+                &test_dir.module_path(),
+            )
+            .with_naming(naming)
+            .execute()
+        };
+        assert!(search(Naming::default())?[0].files_missing());
+
+        let naming = Naming {
+            install_config: false,
+            ..Naming::default()
+        };
+        let installed_kernels = search(naming.clone())?;
+        assert_eq!(installed_kernels[0].missing_files(), Vec::<&str>::new());
+        // Only the one that isn't installed is optional
+        std::fs::remove_file(dummy_install.system_map_path.unwrap())?;
+        assert_eq!(
+            search(naming.clone())?[0].missing_files(),
+            vec!["System.map"]
+        );
+
+        // Removed without the config it never had
+        let naming = Naming {
+            install_system_map: false,
+            ..naming
+        };
+        let kernel = &search(naming)?[0];
+        let cmd_config = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_removable_roots(vec![test_dir.path().to_path_buf()]);
+        kernel.uninstall(&cmd_config)?;
+        assert!(!dummy_install.vmlinuz_path.unwrap().exists());
+        assert!(!dummy_install.source_path.unwrap().exists());
+        Ok(())
+    }
+
+    #[test]
+    fn sources_optional() -> Result<(), JanitorError> {
+        let test_dir = TestDir::new();
+        let path = test_dir.path().join("kernel-janitor.conf");
+        let optional = |value: &str| {
+            std::fs::write(
+                &path,
+                format!(
+                    "[settings]\nVersionsToKeep = 3\nOptionalFiles = {}\n",
+                    value
+                ),
+            )?;
+            optional_files(&Config::new(&path)?)
+        };
+        let err = optional("sources, image").unwrap_err().to_string();
+        assert!(err.contains("can't have image"), "{}", err);
+        assert!(optional("kernel").is_err());
+
+        InstalledKernel::create_test_version(&test_dir, "5.4.97", true);
+        let dummy_install = InstalledKernel::create_test_version(&test_dir, "5.4.97", false);
+        std::fs::remove_dir(dummy_install.source_path.unwrap())?;
+        let search = |optional: Vec<InstalledItemKind>| {
+            KernelSearch::new(
+                &test_dir.install_path(),
+                &[test_dir.src_path()],
+                &test_dir.module_path(),
+            )
+            .with_optional_files(optional)
+            .execute()
+        };
+        // The .old kernel relies on the sources
+        assert!(search(Vec::new()).is_err());
+
+        let installed_kernels = search(optional("Sources")?)?;
+        assert_eq!(installed_kernels.len(), 2);
+        assert!(installed_kernels
+            .iter()
+            .all(|kernel| !kernel.files_missing()));
+        let cmd_config = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_removable_roots(vec![test_dir.path().to_path_buf()]);
+        installed_kernels[1].uninstall(&cmd_config)?;
+        assert!(!dummy_install.module_path.unwrap().exists());
+        Ok(())
+    }
+
+    #[test]
+    fn pkgbase_naming() {
+        use crate::filesystem::MemoryFileSystem;
+        let image = PathBuf::from("/boot/vmlinuz-linux-custom");
+        // The pkgbase file is only on the filesystem the search is given
+        let fs = MemoryFileSystem::new()
+            .with_file(&image, 10)
+            .with_file(Path::new("/boot/config-5.4.97-gentoo"), 1)
+            .with_file(Path::new("/boot/System.map-5.4.97-gentoo"), 1)
+            .with_file(Path::new("/usr/src/linux-5.4.97-gentoo/Makefile"), 100)
+            .with_contents(
+                &Path::new("/lib/modules/5.4.97-gentoo").join(naming::PKGBASE_FILE),
+                "linux-custom\n",
+            );
+
+        let naming = Naming {
+            image: "vmlinuz-{pkgbase}".parse().unwrap(),
+            ..Naming::default()
+        };
+        let installed_kernels = KernelSearch::new(
+            Path::new("/boot"),
+            &[PathBuf::from("/usr/src")],
+            Path::new("/lib/modules"),
+        )
+        .with_naming(naming)
+        .with_filesystem(Arc::new(fs))
+        .execute()
+        .unwrap();
+        assert_eq!(installed_kernels.len(), 1);
+        assert_eq!(installed_kernels[0].vmlinuz_path, Some(image));
+        assert!(!installed_kernels[0].files_missing());
+    }
+
     #[test]
     fn old_kernels_use_new_module_and_src() {
-        cleanup_test_dir();
-        init_test_dir();
+        let test_dir = TestDir::new();
 
-        let dummy_install = InstalledKernel::create_test_version("5.4.97", false);
-        let dummy_install_old = InstalledKernel::create_test_version("5.4.97", true);
-        let install_path = get_test_install_pathbuf();
-        let module_path = get_test_module_pathbuf();
-        let src_path = get_test_src_pathbuf();
+        let dummy_install = InstalledKernel::create_test_version(&test_dir, "5.4.97", false);
+        let dummy_install_old = InstalledKernel::create_test_version(&test_dir, "5.4.97", true);
+        let install_path = test_dir.install_path();
+        let module_path = test_dir.module_path();
+        let src_paths = vec![test_dir.src_path()];
 
-        let installed_kernels = KernelSearch::new(&install_path, &src_path, &module_path).execute();
+        let installed_kernels =
+            KernelSearch::new(&install_path, &src_paths, &module_path).execute();
         assert!(
             installed_kernels.is_ok(),
             "{}",
@@ -893,24 +1677,54 @@ mod tests {
         assert_eq!(installed_kernels[1].source_path, dummy_install.source_path);
     }
 
+    #[test]
+    fn symlinks_are_not_extra_kernels() {
+        let test_dir = TestDir::new();
+
+        let dummy_install = InstalledKernel::create_test_version(&test_dir, "5.4.97", false);
+        let install_path = test_dir.install_path();
+        let module_path = test_dir.module_path();
+        let src_paths = vec![test_dir.src_path()];
+
+        // A second name for the same image and a link to nothing
+        let image = dummy_install.vmlinuz_path.clone().unwrap();
+        std::os::unix::fs::symlink(&image, install_path.join("vmlinuz-5.4.97-gentoo.old")).unwrap();
+        std::os::unix::fs::symlink(
+            install_path.join("missing"),
+            install_path.join("vmlinuz-5.10.1-gentoo"),
+        )
+        .unwrap();
+        // The search root itself is reached through a link. Everything is created here in the
+        // test's own temp dir, nothing is checked in
+        let linked_root = test_dir.path().join("boot-link");
+        std::os::unix::fs::symlink(std::fs::canonicalize(&install_path).unwrap(), &linked_root)
+            .unwrap();
+
+        let installed_kernels = KernelSearch::new(&linked_root, &src_paths, &module_path).execute();
+        let installed_kernels = installed_kernels.unwrap();
+        assert_eq!(installed_kernels.len(), 1, "{:?}", installed_kernels);
+        assert_eq!(installed_kernels[0].vmlinuz_path, Some(image));
+        assert_eq!(installed_kernels[0].module_path, dummy_install.module_path);
+    }
+
     #[test]
     fn newly_downloaded_sources() {
-        cleanup_test_dir();
-        init_test_dir();
+        let test_dir = TestDir::new();
 
-        let _ = InstalledKernel::create_test_version("5.4.97", false);
-        let install_path = get_test_install_pathbuf();
-        let module_path = get_test_module_pathbuf();
-        let src_path = get_test_src_pathbuf();
+        let _ = InstalledKernel::create_test_version(&test_dir, "5.4.97", false);
+        let install_path = test_dir.install_path();
+        let module_path = test_dir.module_path();
+        let src_paths = vec![test_dir.src_path()];
 
         // Only the sources are installed for this version
-        let new_installed_sources = get_test_src_pathbuf().join("linux-5.11.0-gentoo");
+        let new_installed_sources = test_dir.src_path().join("linux-5.11.0-gentoo");
         std::fs::DirBuilder::new()
             .recursive(true)
             .create(&new_installed_sources)
             .unwrap();
 
-        let installed_kernels = KernelSearch::new(&install_path, &src_path, &module_path).execute();
+        let installed_kernels =
+            KernelSearch::new(&install_path, &src_paths, &module_path).execute();
         assert!(installed_kernels.is_ok());
         let installed_kernels = installed_kernels.unwrap();
         for k in &installed_kernels {
@@ -919,4 +1733,39 @@ mod tests {
 
         assert_eq!(installed_kernels.len(), 2, "Expected to find two kernels!");
     }
+
+    #[test]
+    fn module_tree_ownership() {
+        use crate::filesystem::MemoryFileSystem;
+        let version = KernelVersion::try_from("5.10.1-gentoo").unwrap();
+        let fs = MemoryFileSystem::new()
+            .with_file(Path::new("/lib/modules/5.10.1-gentoo/modules.builtin"), 1)
+            .with_dir(Path::new("/lib/modules/5.10.1-gentoo-dist"))
+            .with_dir(Path::new("/lib/modules/5.11.8-gentoo"))
+            // KernelModulesPath = /usr/lib instead of /usr/lib/modules
+            .with_file(Path::new("/usr/lib/5.10.1-gentoo/libsomething.so"), 1);
+        let check = |path: &str| check_module_tree(&fs, Path::new(path), &version);
+
+        assert!(check("/lib/modules/5.10.1-gentoo").is_ok());
+        // Another flavor's
+        assert!(check("/lib/modules/5.10.1-gentoo-dist").is_err());
+        // An empty tree named after the release is fine, also for the .old image
+        let dist = KernelVersion::try_from("5.10.1-gentoo-dist.old").unwrap();
+        assert!(
+            check_module_tree(&fs, Path::new("/lib/modules/5.10.1-gentoo-dist"), &dist).is_ok()
+        );
+        let err = check("/lib/modules/5.11.8-gentoo").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Cleanup);
+        assert!(
+            err.to_string().contains("isn't named after 5.10.1"),
+            "{}",
+            err
+        );
+        let err = check("/usr/lib/5.10.1-gentoo").unwrap_err();
+        assert!(
+            err.to_string().contains("doesn't look like a module tree"),
+            "{}",
+            err
+        );
+    }
 }
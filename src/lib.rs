@@ -0,0 +1,104 @@
+//! Finds installed kernels and their leftovers, builds and installs new ones and cleans up old ones
+//!
+//! The `kernel-janitor` binary is a thin command line wrapper around this crate, other tools
+//! (Portage hooks, GUIs) can use the same discovery and cleanup logic
+//!
+//! ```no_run
+//! use kernel_janitor::{conf::Config, kernel::KernelSearch};
+//! use std::path::Path;
+//!
+//! let config = Config::new(Path::new("/etc/kernel-janitor.conf"))?;
+//! let inventory = KernelSearch::new(
+//!     &config.get_path("InstallPath")?,
+//!     &config.get_paths("KernelSourcePath")?,
+//!     &config.get_path("KernelModulesPath")?,
+//! )
+//! .inventory()?;
+//! for kernel in inventory.orphans() {
+//!     println!("{} can't be booted anymore, its files use {} bytes", kernel.version, inventory.size(kernel)?);
+//! }
+//! # Ok::<(), kernel_janitor::error::JanitorError>(())
+//! ```
+
+/// Archiving kernels to zstd tarballs before they're removed, and `kernel-janitor restore`
+pub mod archive;
+/// The `Bootloader` trait and its implementations
+pub mod bootloader;
+/// The `sha256sums` manifest of the installed boot files, and `kernel-janitor verify-checksums`
+pub mod checksums;
+/// `KernelCmdline`, the command line the kernels should boot with
+pub mod cmdline;
+/// Reading `kernel-janitor.conf`
+pub mod conf;
+/// `kernel-janitor doctor`, checks of the config and the tools and paths it points at
+pub mod doctor;
+/// `JanitorError` and the `ErrorKind` that decides the exit code
+pub mod error;
+/// Machine readable progress events for `--output json`
+pub mod events;
+/// The filesystem operations done on installed kernels, real or in memory for pretend runs
+pub mod filesystem;
+/// The state file that every run that changed something is recorded in
+pub mod history;
+/// The Portage postsync hook that reports new kernel sources
+pub mod hook;
+/// The `InitramfsGenerator` trait and its implementations
+pub mod initramfs;
+/// `KernelInventory`, what's installed and what can be done about it
+pub mod inventory;
+/// The JSON builder behind every document in `schema`
+pub mod json;
+/// Reading a kernel `.config` and checking it has the options the system needs
+pub mod kconfig;
+/// Installed kernel discovery, version parsing and uninstalling
+pub mod kernel;
+/// Leveled logging through the `error!`, `warn!`, `info!`, `debug!` and `trace!` macros
+pub mod log;
+/// Out-of-tree modules like ZFS or the NVIDIA driver that the new kernel is unusable without
+pub mod modules;
+/// Mounting a `noauto` /boot or remounting a read-only one for the duration of a run
+pub mod mount;
+/// Templates for the names of the installed image, config and System.map
+pub mod naming;
+/// Applying the patches in `PatchesDir` to the new source tree
+pub mod patches;
+/// The `[pipeline]` section, the order the stages after the build run in and commands between them
+pub mod pipeline;
+/// `CleanupPolicy`, which kernels get removed
+pub mod policy;
+/// Portage's `make.conf`, for building the kernel with the same MAKEOPTS and niceness
+pub mod portage;
+mod progress;
+mod pty;
+/// Rebooting into the new kernel once it's installed, per `RebootPolicy`
+pub mod reboot;
+/// The Markdown or HTML report of a run, written to `ReportPath`
+pub mod report;
+/// Running external commands with timeouts and a sanitized environment
+pub mod runner;
+/// The versioned JSON documents `--output json` writes, a stable contract for other tools
+pub mod schema;
+/// The state file that lets `--list` and `doctor` skip searching directories that didn't change
+pub mod search_cache;
+/// SIGINT/SIGTERM handling so a run stops between steps instead of half way through one
+pub mod signals;
+/// Detached GPG signatures of installed kernel images
+pub mod signing;
+/// btrfs, snapper or ZFS snapshots taken before installing and cleaning up
+pub mod snapshot;
+/// `kernel-janitor suggest`, what would free space without changing anything
+pub mod suggest;
+/// What a run did, printed at the end
+pub mod summary;
+/// The libc functions the crate calls
+mod sys;
+/// Reporting to the system log
+pub mod syslog;
+#[cfg(test)]
+mod test_utils;
+/// The stages of building, installing and cleaning up kernels
+pub mod update;
+/// Prompts, command helpers and path helpers
+pub mod utils;
+/// `kernel-janitor verify`, checks that every installed kernel is whole and bootable
+pub mod verify;
@@ -0,0 +1,212 @@
+use crate::events;
+use std::{
+    fmt,
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+/// Severity of a log message, lower is more severe
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+// Keeps every warning, even ones below the max level, for the end-of-run summary
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+// Set when stdout is used for machine readable output
+static STDOUT_RESERVED: AtomicBool = AtomicBool::new(false);
+// Serializes writes so stdout and stderr lines don't get mixed up mid-line
+static OUTPUT_LOCK: Mutex<()> = Mutex::new(());
+// Everything that would have been printed while `hold_output` is in effect
+static HELD: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+
+/// Use like `println!` but only prints if errors are enabled
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {{
+        $crate::log::log($crate::log::Level::Error, format_args!($($arg)*))
+    }}
+}
+/// Use like `println!` but only prints if warnings are enabled
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        $crate::log::log($crate::log::Level::Warn, format_args!($($arg)*))
+    }}
+}
+/// Use like `println!` but only prints if info messages are enabled
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {{
+        $crate::log::log($crate::log::Level::Info, format_args!($($arg)*))
+    }}
+}
+/// Use like `println!` but only prints if debug messages are enabled
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        $crate::log::log($crate::log::Level::Debug, format_args!($($arg)*))
+    }}
+}
+/// Use like `println!` but only prints if trace messages are enabled
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => {{
+        $crate::log::log($crate::log::Level::Trace, format_args!($($arg)*))
+    }}
+}
+
+impl Level {
+    fn from_usize(level: usize) -> Level {
+        match level {
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+
+    fn prefix(&self) -> &'static str {
+        match self {
+            Level::Error => "error: ",
+            Level::Warn => "warning: ",
+            Level::Info => "",
+            Level::Debug => "debug: ",
+            Level::Trace => "trace: ",
+        }
+    }
+}
+
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as usize, Ordering::Relaxed);
+}
+
+pub fn max_level() -> Level {
+    Level::from_usize(MAX_LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= max_level()
+}
+
+/// Every warning logged so far, oldest first
+pub fn warnings() -> Vec<String> {
+    WARNINGS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Send every log message to stderr so stdout only has what `write_stdout` writes
+pub fn reserve_stdout(reserved: bool) {
+    STDOUT_RESERVED.store(reserved, Ordering::Relaxed);
+}
+
+/// Keep everything that would be printed instead of printing it, until `release_output` or
+/// `discard_output`. For `--cron`, which only has something to say when a run fails
+pub fn hold_output() {
+    *HELD.lock().unwrap_or_else(|e| e.into_inner()) = Some(Vec::new());
+}
+
+pub fn output_held() -> bool {
+    HELD.lock().unwrap_or_else(|e| e.into_inner()).is_some()
+}
+
+/// Print what was held back, in order, and print as usual from now on
+pub fn release_output() {
+    let _guard = OUTPUT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(held) = HELD.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        let mut stdout = std::io::stdout();
+        let _ = stdout.write_all(&held).and_then(|_| stdout.flush());
+    }
+}
+
+/// Forget what was held back and print as usual from now on
+pub fn discard_output() {
+    HELD.lock().unwrap_or_else(|e| e.into_inner()).take();
+}
+
+/// Keep `bytes` if output is held, false if they should be printed
+fn hold(bytes: &[u8]) -> bool {
+    match HELD.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        Some(held) => {
+            held.extend_from_slice(bytes);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Errors and warnings go to stderr, everything else goes to stdout
+pub fn log(level: Level, args: fmt::Arguments) {
+    if level == Level::Warn {
+        let message = args.to_string();
+        events::emit(events::Event::Warning { message: &message });
+        WARNINGS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(message);
+    }
+    if !enabled(level) {
+        return;
+    }
+    // A poisoned lock only means another thread panicked while printing
+    let _guard = OUTPUT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    if hold(format!("{}{}\n", level.prefix(), args).as_bytes()) {
+        return;
+    }
+    match level {
+        _ if STDOUT_RESERVED.load(Ordering::Relaxed) => {
+            eprintln!("{}{}", level.prefix(), args)
+        }
+        Level::Error | Level::Warn => {
+            let _ = std::io::stdout().flush();
+            eprintln!("{}{}", level.prefix(), args);
+        }
+        _ => println!("{}{}", level.prefix(), args),
+    }
+}
+
+/// Write a line to stdout regardless of the log level
+/// Pass command output through untouched, escape sequences and all
+pub fn write_raw(bytes: &[u8]) {
+    if !enabled(Level::Info) {
+        return;
+    }
+    let _guard = OUTPUT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    if hold(bytes) {
+        return;
+    }
+    let _ = match STDOUT_RESERVED.load(Ordering::Relaxed) {
+        true => std::io::stderr().write_all(bytes),
+        false => {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(bytes).and_then(|_| stdout.flush())
+        }
+    };
+}
+
+pub fn write_stdout(line: &str) {
+    let _guard = OUTPUT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    if hold(format!("{}\n", line).as_bytes()) {
+        return;
+    }
+    println!("{}", line);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn level_ordering() {
+        assert!(Level::Error < Level::Warn);
+        assert!(Level::Debug < Level::Trace);
+        assert_eq!(Level::from_usize(Level::Debug as usize), Level::Debug);
+    }
+}
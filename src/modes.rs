@@ -0,0 +1,302 @@
+use kernel_janitor::{
+    archive, checksums,
+    cmdline::{self, KernelCmdline},
+    doctor,
+    error::{ErrorKind, JanitorError},
+    events, history, hook, info,
+    inventory::{KernelInventory, ListFilter, ListOrder, SpaceUsage},
+    kernel, log, schema, signing, suggest, update, utils, verify,
+};
+use std::path::{Path, PathBuf};
+use update::Stage;
+
+use crate::{cli::ParseResults, setup::Setup};
+
+/// Subcommands that don't need the config, and rejecting the ones that don't exist
+/// Returns whether the subcommand was handled
+pub fn without_config(parsed_results: &ParseResults) -> Result<bool, JanitorError> {
+    match parsed_results.subcommand() {
+        Some("doctor") => {
+            let checks = doctor::run();
+            match events::json_enabled() {
+                true => log::write_stdout(&schema::doctor(&checks).to_string()),
+                false => println!("{}", doctor::render(&checks)),
+            }
+            doctor::result(&checks).map(|_| true)
+        }
+        // Need the config
+        Some("history")
+        | Some("stats")
+        | Some("verify")
+        | Some("verify-checksums")
+        | Some("prune-sources")
+        | Some("install-hook")
+        | Some("restore")
+        | Some("suggest")
+        | Some("space") => Ok(false),
+        Some(other) => Err(JanitorError::new(
+            ErrorKind::Config,
+            format!("Unknown subcommand {}, try --help", other),
+        )),
+        None => Ok(false),
+    }
+}
+
+/// `history` and `stats`, which only read the history
+/// Returns whether the subcommand was handled
+pub fn history(parsed_results: &ParseResults, state_dir: &Path) -> Result<bool, JanitorError> {
+    match parsed_results.subcommand() {
+        Some("history") => {
+            println!("{}", history::render(&history::read(state_dir)?));
+            Ok(true)
+        }
+        Some("stats") => {
+            println!("{}", history::render_stats(&history::read(state_dir)?));
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// The modes that only look at the installed kernels, they don't need root
+/// Returns whether one of them was handled
+pub fn looking(parsed_results: &ParseResults, setup: &Setup) -> Result<bool, JanitorError> {
+    if parsed_results.flag_enabled("list") {
+        return list_kernels(parsed_results, &setup.inventory).map(|_| true);
+    }
+    if parsed_results.flag_enabled("update_check") {
+        update_check(&setup.inventory);
+        return Ok(true);
+    }
+    match parsed_results.subcommand() {
+        Some("space") => space_report(&setup.inventory).map(|_| true),
+        Some("suggest") => suggest(setup).map(|_| true),
+        Some("verify") => verify(setup).map(|_| true),
+        _ => Ok(false),
+    }
+}
+
+/// The subcommands that look after the installed kernels without building one
+/// Returns whether one of them was handled
+pub fn maintenance(parsed_results: &ParseResults, setup: &Setup) -> Result<bool, JanitorError> {
+    match parsed_results.subcommand() {
+        Some("verify-checksums") => {
+            let checks = checksums::verify(&setup.cmd_config, &setup.state_dir)?;
+            match events::json_enabled() {
+                true => log::write_stdout(&schema::doctor(&checks).to_string()),
+                false => println!("{}", doctor::render(&checks)),
+            }
+            doctor::result(&checks).map(|_| true)
+        }
+        Some("install-hook") => {
+            hook::install(
+                &setup.cmd_config,
+                Path::new(hook::POSTSYNC_DIR),
+                setup.config.path(),
+            )?;
+            Ok(true)
+        }
+        Some("restore") => restore(parsed_results, setup).map(|_| true),
+        Some("prune-sources") => update::run_stage(Stage::PruneSources, None, || {
+            update::prune_sources(&setup.cmd_config, &setup.inventory)
+        })
+        .map(|_| true),
+        _ => Ok(false),
+    }
+}
+
+/// `--list` and the options that filter and sort it
+fn list_kernels(
+    parsed_results: &ParseResults,
+    inventory: &KernelInventory,
+) -> Result<(), JanitorError> {
+    let order = match parsed_results.option_value("sort") {
+        Some(order) => order.parse::<ListOrder>()?,
+        None => ListOrder::Version,
+    };
+    let filter = ListFilter {
+        older_than: parsed_results
+            .option_value("older_than")
+            .map(utils::parse_duration)
+            .transpose()?,
+        flavor: parsed_results.option_value("flavor").map(str::to_string),
+        missing_files: parsed_results.flag_enabled("missing_files"),
+        orphans: parsed_results.flag_enabled("orphans"),
+    };
+    if events::json_enabled() {
+        log::write_stdout(&schema::inventory(inventory, &filter, order)?.to_string());
+        return Ok(());
+    }
+    match order {
+        ListOrder::Version => println!("Listing installed kernels (oldest to newest)...\n"),
+        ListOrder::Size => println!("Listing installed kernels (largest first)...\n"),
+        ListOrder::Date => println!("Listing installed kernels (first installed first)...\n"),
+    }
+    let listed = inventory.list(&filter, order)?;
+    for (k, size) in &listed {
+        let markers: String = inventory
+            .markers(k)?
+            .iter()
+            .map(|m| format!(" [{}]", m))
+            .collect();
+        // The markers go on the first line, next to the version
+        let listing = k.to_string().replacen('\n', &format!("{}\n", markers), 1);
+        println!(
+            "{}\n  Size:            {}",
+            listing,
+            utils::format_bytes(*size)
+        );
+        if inventory.awaiting_boot().contains(k) {
+            if let Ok(installed) = inventory.installed_at(k) {
+                let age = installed.elapsed().unwrap_or_default();
+                println!("  Not booted yet:  installed {}", utils::format_age(age));
+            }
+        }
+        if let Some(status) = inventory.boot_status(k)? {
+            let found = |found| match found {
+                true => "found",
+                false => "missing",
+            };
+            if let Some(initramfs) = status.initramfs {
+                println!("  Initramfs:       {}", found(initramfs));
+            }
+            if let Some(boot_entry) = status.boot_entry {
+                println!("  Boot entry:      {}", found(boot_entry));
+            }
+        }
+        println!();
+    }
+    println!(
+        "Total: {} in {} kernels",
+        utils::format_bytes(listed.iter().map(|(_, size)| size).sum()),
+        listed.len()
+    );
+    Ok(())
+}
+
+/// `--update-check`, whether there are sources newer than the installed kernels
+fn update_check(inventory: &KernelInventory) {
+    let pending = inventory.pending_sources();
+    match pending.last() {
+        Some(newest) => println!(
+            "New kernel sources: {}. Run kernel-janitor to build and install them",
+            newest
+                .source_path
+                .as_deref()
+                .and_then(utils::paths::filename_from_path)
+                .unwrap_or_else(|| newest.version.to_string())
+        ),
+        None => info!("No kernel sources newer than the installed kernels"),
+    }
+}
+
+/// `space`, a du-style breakdown of every kernel
+fn space_report(inventory: &KernelInventory) -> Result<(), JanitorError> {
+    if events::json_enabled() {
+        log::write_stdout(&schema::space(inventory)?.to_string());
+        return Ok(());
+    }
+    println!("Listing space used by installed kernels (oldest to newest)...\n");
+    let row = |version: &str, space: &SpaceUsage| {
+        println!(
+            "{:<14}{:<12}{:<12}{:<12}{:<12}{}",
+            version,
+            utils::format_bytes(space.sources),
+            utils::format_bytes(space.build_artifacts),
+            utils::format_bytes(space.modules),
+            utils::format_bytes(space.boot),
+            utils::format_bytes(space.total())
+        )
+    };
+    let header = format!(
+        "{:<14}{:<12}{:<12}{:<12}{:<12}{}",
+        "Version", "Sources", "(built)", "Modules", "Boot", "Total"
+    );
+    println!("{}", header);
+    let mut total = SpaceUsage::default();
+    for k in inventory.kernels() {
+        let space = inventory.space(k)?;
+        row(&k.version.to_string(), &space);
+        total.sources += space.sources;
+        total.build_artifacts += space.build_artifacts;
+        total.modules += space.modules;
+        total.boot += space.boot;
+    }
+    row("Total", &total);
+    println!(
+        "\nprune-sources would free {} of build artifacts, the Total column is what removing a kernel frees",
+        utils::format_bytes(inventory.prunable_size()?)
+    );
+    Ok(())
+}
+
+/// `suggest`, what cleaning up would remove and why
+fn suggest(setup: &Setup) -> Result<(), JanitorError> {
+    let suggestions = suggest::suggest(&setup.inventory, setup.cleanup_policy.as_ref())?;
+    match events::json_enabled() {
+        true => log::write_stdout(&schema::suggestions(&suggestions).to_string()),
+        false => println!("{}", suggest::render(&suggestions)),
+    }
+    Ok(())
+}
+
+/// `verify`, whether every installed kernel can actually boot
+fn verify(setup: &Setup) -> Result<(), JanitorError> {
+    let cmd_config = &setup.cmd_config;
+    let mut checks = verify::run(
+        cmd_config.fs.as_ref(),
+        &setup.inventory,
+        cmd_config.bootloader.as_deref(),
+        &cmd_config.naming.image,
+    );
+    let mut signed: Vec<PathBuf> = setup
+        .inventory
+        .kernels()
+        .iter()
+        .filter_map(|kernel| kernel.vmlinuz_path.clone())
+        .collect();
+    signed.push(checksums::manifest_path(&setup.state_dir));
+    checks.extend(signing::verify(
+        cmd_config,
+        &signed,
+        setup.signing_key.is_some(),
+    ));
+    if let (Some(bootloader), Some(kernel_cmdline)) = (
+        cmd_config.bootloader.as_deref(),
+        KernelCmdline::from_config(&setup.config)?,
+    ) {
+        checks.extend(cmdline::check(
+            cmd_config.fs.as_ref(),
+            bootloader,
+            &kernel_cmdline,
+        ));
+    }
+    match events::json_enabled() {
+        true => log::write_stdout(&schema::doctor(&checks).to_string()),
+        false => println!("{}", doctor::render(&checks)),
+    }
+    doctor::result(&checks)
+}
+
+/// `restore`, putting an archived kernel back
+fn restore(parsed_results: &ParseResults, setup: &Setup) -> Result<(), JanitorError> {
+    let cmd_config = &setup.cmd_config;
+    let version = match parsed_results.subcommand_args() {
+        [version] => version.parse::<kernel::KernelVersion>()?,
+        _ => {
+            return Err(JanitorError::new(
+                ErrorKind::Config,
+                "restore needs the version to restore, like 'restore 6.1.0-gentoo'",
+            ))
+        }
+    };
+    let archive_dir = cmd_config.archive_dir.clone().ok_or_else(|| {
+        JanitorError::new(ErrorKind::Config, "restore needs ArchiveDir in the config")
+    })?;
+    archive::restore(cmd_config, &setup.inventory, &version, &archive_dir)?;
+    // Back in place, but the bootloader doesn't know about it yet
+    if let Some(bootloader) = &cmd_config.bootloader {
+        update::update_bootloader(cmd_config, bootloader.as_ref(), &version)?;
+    }
+    Ok(())
+}
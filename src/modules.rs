@@ -0,0 +1,426 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    debug,
+    error::{Context, ErrorKind, JanitorError},
+    info,
+    update::RunCmdConfig,
+    utils, warn,
+};
+
+/// Portage's database of installed packages, one directory per category
+pub const PACKAGE_DB: &str = "/var/db/pkg";
+const MOUNTS: &str = "/proc/mounts";
+/// The modules the running kernel has loaded, one per line starting with its name
+const PROC_MODULES: &str = "/proc/modules";
+/// Where the kernel loads firmware from, sys-kernel/linux-firmware installs it there
+pub const FIRMWARE_DIR: &str = "/lib/firmware";
+/// With CONFIG_FW_LOADER_COMPRESS the kernel also loads `<name>.xz` and `<name>.zst`
+const FIRMWARE_SUFFIXES: [&str; 3] = ["", ".xz", ".zst"];
+
+/// Modules get installed compressed when the kernel is configured to
+const MODULE_SUFFIXES: [&str; 4] = [".ko", ".ko.xz", ".ko.zst", ".ko.gz"];
+
+/// Whether a package like `sys-fs/zfs-kmod` is installed, in any version
+pub fn package_installed(package_db: &Path, package: &str) -> bool {
+    let (category, name) = match package.split_once('/') {
+        Some(split) => split,
+        None => return false,
+    };
+    // Entries are named like zfs-kmod-2.0.4-r1, the version starts at the first dash and digit
+    let prefix = format!("{}-", name);
+    utils::paths::all_paths_with_prefix(&prefix, &package_db.join(category))
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|path| utils::paths::filename_from_path(path))
+        .any(|entry| {
+            entry[prefix.len()..]
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_digit())
+        })
+}
+
+/// Where module `name` (like `zfs`) was installed under `module_dir`, compressed or not
+pub fn find_module(module_dir: &Path, name: &str) -> io::Result<Option<PathBuf>> {
+    for entry in fs::read_dir(module_dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if let Some(found) = find_module(&entry.path(), name)? {
+                return Ok(Some(found));
+            }
+        } else if file_type.is_file() {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if MODULE_SUFFIXES
+                .iter()
+                .any(|suffix| file_name.strip_suffix(suffix) == Some(name))
+            {
+                return Ok(Some(entry.path()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Every module under `dir` by name, with `-` as `_` like the kernel calls them
+fn module_index(dir: &Path, index: &mut BTreeMap<String, PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            module_index(&entry.path(), index)?;
+        } else if file_type.is_file() {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            if let Some(name) = MODULE_SUFFIXES
+                .iter()
+                .find_map(|suffix| file_name.strip_suffix(suffix))
+            {
+                index.insert(name.replace('-', "_"), entry.path());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The names of the modules in `/proc/modules` format
+fn loaded_modules(proc_modules: &str) -> Vec<String> {
+    proc_modules
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(str::to_string)
+        .collect()
+}
+
+fn firmware_present(firmware_dir: &Path, firmware: &str) -> bool {
+    FIRMWARE_SUFFIXES.iter().any(|suffix| {
+        firmware_dir
+            .join(format!("{}{}", firmware, suffix))
+            .exists()
+    })
+}
+
+/// The firmware files each of the `loaded` modules in `module_dir` references that aren't in
+/// `firmware_dir`, by module. Modules that weren't built for the new kernel are left out
+fn missing_firmware_in(
+    config: &RunCmdConfig,
+    module_dir: &Path,
+    loaded: &[String],
+    firmware_dir: &Path,
+) -> Result<BTreeMap<String, Vec<String>>, JanitorError> {
+    let mut index = BTreeMap::new();
+    module_index(module_dir, &mut index)
+        .with_context(|| format!("failed to read the modules in {}", module_dir.display()))?;
+    let mut missing = BTreeMap::new();
+    for (name, path) in loaded
+        .iter()
+        .filter_map(|name| Some((name, index.get(name)?)))
+    {
+        // modinfo reads compressed modules too
+        let mut modinfo = Command::new("modinfo");
+        modinfo.arg("-F").arg("firmware").arg(path);
+        let firmware = config
+            .runner
+            .output(&mut modinfo)
+            .with_context(|| format!("failed to read the firmware {} needs", name))?;
+        let absent: Vec<String> = firmware
+            .lines()
+            .map(str::trim)
+            .filter(|firmware| !firmware.is_empty() && !firmware_present(firmware_dir, firmware))
+            .map(str::to_string)
+            .collect();
+        if !absent.is_empty() {
+            missing.insert(name.clone(), absent);
+        }
+    }
+    Ok(missing)
+}
+
+/// Warn about the modules in use now whose build for the new kernel in `module_dir` references
+/// firmware that isn't installed. Modules of hardware that isn't there reference plenty of
+/// firmware nobody needs, so only the loaded ones are checked. Returns the modules missing some
+pub fn check_firmware(
+    config: &RunCmdConfig,
+    module_dir: &Path,
+) -> Result<Vec<String>, JanitorError> {
+    let loaded = loaded_modules(&fs::read_to_string(PROC_MODULES).unwrap_or_default());
+    if loaded.is_empty() {
+        debug!("No modules are loaded, not checking their firmware");
+        return Ok(Vec::new());
+    }
+    let missing = missing_firmware_in(config, module_dir, &loaded, Path::new(FIRMWARE_DIR))?;
+    for (module, firmware) in &missing {
+        warn!(
+            "{} of the new kernel needs firmware that isn't in {}: {}. Try 'emerge sys-kernel/linux-firmware'",
+            module,
+            FIRMWARE_DIR,
+            firmware.join(", ")
+        );
+    }
+    Ok(missing.into_keys().collect())
+}
+
+/// Resolve the dependencies of each of the `critical` modules (like `nvme` or `btrfs`) in the module
+/// tree of `release` with `modprobe --dry-run`, which loads nothing. Returns the ones that failed
+pub fn dry_run_load(config: &RunCmdConfig, release: &str, critical: &[String]) -> Vec<String> {
+    let mut failed = Vec::new();
+    for module in critical {
+        let mut modprobe = Command::new("modprobe");
+        modprobe
+            .arg("--dry-run")
+            .arg("--set-version")
+            .arg(release)
+            .arg(module);
+        match config.runner.output(&mut modprobe) {
+            Ok(_) => debug!("{} would load on {}", module, release),
+            Err(e) => {
+                warn!(
+                    "{} is in CriticalModules but wouldn't load on {}: {}",
+                    module, release, e
+                );
+                failed.push(module.clone());
+            }
+        }
+    }
+    failed
+}
+
+/// The filesystem type of `/` in `/proc/mounts` format, the last mount wins
+fn root_fstype(mounts: &str) -> Option<&str> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            Some((fields.next()?, fields.next()?))
+        })
+        .filter(|(mount_point, _)| *mount_point == "/")
+        .map(|(_, fstype)| fstype)
+        .next_back()
+}
+
+/// A module that's built by a package instead of the kernel, checked when the package is installed
+struct PackagedModule {
+    package: &'static str,
+    module: &'static str,
+    /// What breaks without it
+    impact: &'static str,
+}
+
+const PACKAGED_MODULES: [PackagedModule; 2] = [
+    PackagedModule {
+        package: "sys-fs/zfs-kmod",
+        module: "zfs",
+        impact: "ZFS pools won't import",
+    },
+    PackagedModule {
+        package: "x11-drivers/nvidia-drivers",
+        module: "nvidia",
+        impact: "the display won't come up",
+    },
+];
+
+fn check_packaged_in(
+    module_dir: &Path,
+    package_db: &Path,
+    mounts: &str,
+) -> Result<Vec<&'static str>, JanitorError> {
+    let mut missing = Vec::new();
+    for packaged in PACKAGED_MODULES
+        .iter()
+        .filter(|p| package_installed(package_db, p.package))
+    {
+        let found = find_module(module_dir, packaged.module).map_err(|e| {
+            JanitorError::new(
+                ErrorKind::Build,
+                format!(
+                    "Could not look for {}.ko in {}: {}",
+                    packaged.module,
+                    module_dir.display(),
+                    e
+                ),
+            )
+        })?;
+        if let Some(module) = found {
+            info!("Found {} at {}", packaged.module, module.display());
+            continue;
+        }
+        // Without zfs.ko the kernel couldn't mount its root
+        if packaged.module == "zfs" && root_fstype(mounts) == Some("zfs") {
+            return Err(JanitorError::new(
+                ErrorKind::Build,
+                format!(
+                    "zfs.ko wasn't built into {} but / is on ZFS, the new kernel wouldn't boot. \
+                     Stopping before cleanup so the old kernels stay, try \'emerge -1 {}\'",
+                    module_dir.display(),
+                    packaged.package
+                ),
+            ));
+        }
+        warn!(
+            "{} is installed but {}.ko wasn't built into {}, {} with the new kernel. Try \'emerge -1 {}\'",
+            packaged.package,
+            packaged.module,
+            module_dir.display(),
+            packaged.impact,
+            packaged.package
+        );
+        missing.push(packaged.module);
+    }
+    Ok(missing)
+}
+
+/// The modules of `required` (like `vboxdrv`) that aren't in `module_dir`
+pub fn check_required(module_dir: &Path, required: &[String]) -> Result<Vec<String>, JanitorError> {
+    let mut missing = Vec::new();
+    for name in required {
+        let found = find_module(module_dir, name).map_err(|e| {
+            JanitorError::new(
+                ErrorKind::Build,
+                format!(
+                    "Could not look for {}.ko in {}: {}",
+                    name,
+                    module_dir.display(),
+                    e
+                ),
+            )
+        })?;
+        match found {
+            Some(module) => info!("Found {} at {}", name, module.display()),
+            None => {
+                warn!(
+                    "{}.ko is in RequiredModules but wasn't built into {}",
+                    name,
+                    module_dir.display()
+                );
+                missing.push(name.clone());
+            }
+        }
+    }
+    Ok(missing)
+}
+
+/// Make sure the new kernel's modules in `module_dir` include the ones installed packages build,
+/// like ZFS and the NVIDIA driver. Returns the missing ones, a missing zfs.ko with / on ZFS is an error
+pub fn check_packaged(module_dir: &Path) -> Result<Vec<&'static str>, JanitorError> {
+    let mounts = fs::read_to_string(MOUNTS).unwrap_or_default();
+    check_packaged_in(module_dir, Path::new(PACKAGE_DB), &mounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runner::RecordingRunner,
+        test_utils::TestDir,
+        update::{InteractiveStatus, PretendStatus},
+    };
+
+    #[test]
+    fn required_modules() -> Result<(), JanitorError> {
+        let test_dir = TestDir::new();
+        let module_dir = test_dir.module_path().join("5.11.8-gentoo");
+        fs::create_dir_all(module_dir.join("misc")).unwrap();
+        fs::write(module_dir.join("misc/vboxdrv.ko.xz"), b"").unwrap();
+        let required = vec!["vboxdrv".to_string(), "wl".to_string()];
+        assert_eq!(check_required(&module_dir, &required)?, vec!["wl"]);
+        Ok(())
+    }
+
+    #[test]
+    fn packaged_modules() -> Result<(), JanitorError> {
+        let test_dir = TestDir::new();
+        let package_db = test_dir.path().join("pkg");
+        let module_dir = test_dir.module_path().join("5.11.8-gentoo");
+        fs::create_dir_all(module_dir.join("extra")).unwrap();
+        let root_on_zfs = "rpool/ROOT/gentoo / zfs rw,noatime 0 0\n";
+        let root_on_ext4 = "/dev/sda2 / ext4 rw 0 0\n";
+
+        // Without the packages nothing is checked
+        assert!(check_packaged_in(&module_dir, &package_db, root_on_zfs)?.is_empty());
+
+        fs::create_dir_all(package_db.join("sys-fs/zfs-kmod-2.0.4-r1")).unwrap();
+        fs::create_dir_all(package_db.join("sys-fs/zfs-kmod-utils-1.0")).unwrap();
+        assert!(package_installed(&package_db, "sys-fs/zfs-kmod"));
+        assert!(!package_installed(&package_db, "sys-fs/zfs"));
+        let err = check_packaged_in(&module_dir, &package_db, root_on_zfs).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Build);
+        assert_eq!(
+            check_packaged_in(&module_dir, &package_db, root_on_ext4)?,
+            vec!["zfs"]
+        );
+
+        fs::write(module_dir.join("extra/zfs.ko.zst"), b"").unwrap();
+        assert!(check_packaged_in(&module_dir, &package_db, root_on_zfs)?.is_empty());
+
+        fs::create_dir_all(package_db.join("x11-drivers/nvidia-drivers-460.67")).unwrap();
+        assert_eq!(
+            check_packaged_in(&module_dir, &package_db, root_on_zfs)?,
+            vec!["nvidia"]
+        );
+        fs::create_dir_all(module_dir.join("video")).unwrap();
+        fs::write(module_dir.join("video/nvidia.ko"), b"").unwrap();
+        assert!(check_packaged_in(&module_dir, &package_db, root_on_zfs)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn missing_firmware() -> Result<(), JanitorError> {
+        let test_dir = TestDir::new();
+        let module_dir = test_dir.module_path().join("5.11.8-gentoo");
+        fs::create_dir_all(module_dir.join("net")).unwrap();
+        fs::write(module_dir.join("net/iwlwifi.ko.xz"), b"").unwrap();
+        fs::write(module_dir.join("net/r8169.ko"), b"").unwrap();
+        let firmware_dir = test_dir.path().join("firmware");
+        fs::create_dir_all(&firmware_dir).unwrap();
+        fs::write(firmware_dir.join("iwlwifi-cc-a0-59.ucode.zst"), b"").unwrap();
+
+        let loaded = loaded_modules(
+            "iwlwifi 450560 1 iwlmvm, Live 0x0000000000000000\n\
+             snd_hda_intel 57344 3 - Live 0x0000000000000000\n",
+        );
+        assert_eq!(loaded, vec!["iwlwifi", "snd_hda_intel"]);
+
+        let runner = RecordingRunner::new().with_output(
+            "modinfo",
+            "iwlwifi-cc-a0-59.ucode\niwlwifi-Qu-b0-hr-b0-59.ucode\n",
+        );
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone());
+        let missing = missing_firmware_in(&cfg, &module_dir, &loaded, &firmware_dir)?;
+        assert_eq!(
+            missing.get("iwlwifi"),
+            Some(&vec!["iwlwifi-Qu-b0-hr-b0-59.ucode".to_string()])
+        );
+        assert_eq!(missing.len(), 1);
+        // Only the loaded module that was built for the new kernel
+        assert_eq!(
+            runner.command_lines(),
+            vec![format!(
+                "modinfo -F firmware {}",
+                module_dir.join("net/iwlwifi.ko.xz").display()
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn critical_modules() {
+        let runner = RecordingRunner::new()
+            .with_failure("modprobe --dry-run --set-version 5.11.8-gentoo btrfs");
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone());
+        let critical = vec!["nvme".to_string(), "btrfs".to_string()];
+        assert_eq!(
+            dry_run_load(&cfg, "5.11.8-gentoo", &critical),
+            vec!["btrfs"]
+        );
+        assert_eq!(runner.command_lines().len(), 2);
+    }
+}
@@ -0,0 +1,302 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+};
+
+use crate::{
+    error::{ErrorKind, JanitorError},
+    info,
+    runner::CommandRunner,
+    update::RunCmdConfig,
+    warn,
+};
+
+const FSTAB: &str = "/etc/fstab";
+const MOUNTS: &str = "/proc/mounts";
+
+/// The second field of every line in `/etc/fstab` or `/proc/mounts` format
+pub fn mount_points(table: &str) -> Vec<PathBuf> {
+    table
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// The fstab mount point `path` lives on, None when it's on the root filesystem
+pub fn fstab_mount_point(path: &Path, fstab: &str) -> Option<PathBuf> {
+    mount_points(fstab)
+        .into_iter()
+        .filter(|point| point != Path::new("/") && path.starts_with(point))
+        .max_by_key(|point| point.components().count())
+}
+
+/// A line of `/proc/mounts`
+struct MountEntry<'a> {
+    point: &'a Path,
+    fstype: &'a str,
+    options: &'a str,
+}
+
+impl MountEntry<'_> {
+    fn read_only(&self) -> bool {
+        self.options.split(',').any(|option| option == "ro")
+    }
+}
+
+/// The mount `path` lives on, in `/proc/mounts` format
+fn mount_of<'a>(path: &Path, mounts: &'a str) -> Option<MountEntry<'a>> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            Some(MountEntry {
+                point: Path::new(fields.next()?),
+                fstype: fields.next()?,
+                options: fields.next()?,
+            })
+        })
+        .filter(|entry| path.starts_with(entry.point))
+        // The deepest mount wins, and of mounts on the same point the last one
+        .max_by_key(|entry| entry.point.components().count())
+}
+
+/// The filesystem type of `path` if it's a FAT variant, like an ESP mounted at /boot or /efi
+pub fn fat_filesystem(path: &Path) -> Option<String> {
+    let mounts = fs::read_to_string(MOUNTS).unwrap_or_default();
+    mount_of(path, &mounts)
+        .map(|entry| entry.fstype)
+        .filter(|fstype| matches!(*fstype, "vfat" | "msdos" | "fat" | "exfat"))
+        .map(str::to_string)
+}
+
+/// The mount point `path` lives on if it's in fstab but isn't mounted, like a `noauto` /boot
+fn unmounted(path: &Path, fstab: &str, mounts: &str) -> Option<PathBuf> {
+    fstab_mount_point(path, fstab).filter(|point| !mount_points(mounts).contains(point))
+}
+
+/// Warn that what `install_path` lives on is in fstab but not mounted, for runs that only look
+/// and leave mounting it to the ones that install or remove kernels
+pub fn warn_if_unmounted(install_path: &Path) {
+    let fstab = fs::read_to_string(FSTAB).unwrap_or_default();
+    let mounts = fs::read_to_string(MOUNTS).unwrap_or_default();
+    if let Some(mount_point) = unmounted(install_path, &fstab, &mounts) {
+        warn!(
+            "{} is in /etc/fstab but not mounted, the kernels on it aren't seen",
+            mount_point.display()
+        );
+    }
+}
+
+/// A mount or remount done for this run, undone again when dropped
+pub struct BootMount {
+    mount_point: PathBuf,
+    undo: Command,
+    /// What undoing it does, like `Unmounted`
+    undone: &'static str,
+    /// The runner of the run it was done for, which undoes it the same way
+    runner: Arc<dyn CommandRunner>,
+}
+
+impl BootMount {
+    /// Mount what `install_path` lives on if fstab has it but it isn't mounted
+    /// Without `auto_mount` that's an error, installing into the empty mount point would hide the kernel
+    /// When pretending it's only reported, the search won't see the kernels on it
+    pub fn ensure(
+        cmd_config: &RunCmdConfig,
+        install_path: &Path,
+        auto_mount: bool,
+    ) -> Result<Option<BootMount>, JanitorError> {
+        if cmd_config.pretending() {
+            warn_if_unmounted(install_path);
+            return Ok(None);
+        }
+        let fstab = fs::read_to_string(FSTAB).unwrap_or_default();
+        let mounts = fs::read_to_string(MOUNTS).unwrap_or_default();
+        let mount_point = match unmounted(install_path, &fstab, &mounts) {
+            Some(point) => point,
+            None => return Ok(None),
+        };
+        if !auto_mount {
+            return Err(JanitorError::new(
+                ErrorKind::Config,
+                format!(
+                    "{} is in /etc/fstab but not mounted, mount it or set MountBoot = true to have it mounted for the run",
+                    mount_point.display()
+                ),
+            ));
+        }
+        let mut mount = Command::new("mount");
+        mount.arg(&mount_point);
+        cmd_config.run_without_prompt(
+            &mut cmd_config.privileged(mount),
+            &format!("mount {}", mount_point.display()),
+        )?;
+        let mut umount = Command::new("umount");
+        umount.arg(&mount_point);
+        Ok(Some(BootMount::new(
+            cmd_config,
+            mount_point,
+            umount,
+            "Unmounted",
+        )))
+    }
+
+    /// Remount what `install_path` lives on read-write if it's mounted read-only, so installing
+    /// doesn't stop halfway with EROFS. With `restore` it's remounted read-only when dropped
+    /// Without `auto_remount` a read-only mount is an error before anything is changed, pretending
+    /// it only gets a warning
+    pub fn ensure_writable(
+        cmd_config: &RunCmdConfig,
+        install_path: &Path,
+        auto_remount: bool,
+        restore: bool,
+    ) -> Result<Option<BootMount>, JanitorError> {
+        let mounts = fs::read_to_string(MOUNTS).unwrap_or_default();
+        let mount_point = match mount_of(install_path, &mounts) {
+            Some(entry) if entry.read_only() => entry.point.to_path_buf(),
+            _ => return Ok(None),
+        };
+        if cmd_config.pretending() {
+            match auto_remount {
+                true => warn!(
+                    "Would remount {} read-write for this run",
+                    mount_point.display()
+                ),
+                false => warn!(
+                    "{} is mounted read-only, a real run would stop here unless it's remounted read-write or RemountBoot = true",
+                    mount_point.display()
+                ),
+            }
+            return Ok(None);
+        }
+        if !auto_remount {
+            return Err(JanitorError::new(
+                ErrorKind::Config,
+                format!(
+                    "{} is mounted read-only, remount it read-write or set RemountBoot = true",
+                    mount_point.display()
+                ),
+            ));
+        }
+        let remount = |options: &str| {
+            let mut mount = Command::new("mount");
+            mount.arg("-o").arg(options).arg(&mount_point);
+            cmd_config.privileged(mount)
+        };
+        cmd_config.run_without_prompt(
+            &mut remount("remount,rw"),
+            &format!("remount {} read-write", mount_point.display()),
+        )?;
+        if !restore {
+            return Ok(None);
+        }
+        let mut remount_ro = Command::new("mount");
+        remount_ro.arg("-o").arg("remount,ro").arg(&mount_point);
+        Ok(Some(BootMount::new(
+            cmd_config,
+            mount_point,
+            remount_ro,
+            "Remounted read-only",
+        )))
+    }
+
+    /// `undo` is run as root through the runner of `cmd_config` when dropped
+    fn new(
+        cmd_config: &RunCmdConfig,
+        mount_point: PathBuf,
+        undo: Command,
+        undone: &'static str,
+    ) -> BootMount {
+        let mut undo = cmd_config.privileged(undo);
+        cmd_config.env.apply(&mut undo);
+        BootMount {
+            mount_point,
+            undo,
+            undone,
+            runner: cmd_config.runner.clone(),
+        }
+    }
+}
+
+impl Drop for BootMount {
+    fn drop(&mut self) {
+        let cmd_desc = format!("{} {}", self.undone, self.mount_point.display());
+        match self.runner.run(&mut self.undo, &cmd_desc) {
+            Ok(()) => info!("{}", cmd_desc),
+            Err(e) => warn!("Could not restore {}: {}", self.mount_point.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn noauto_boot() {
+        let fstab = "\
+# <fs>      <mountpoint> <type> <opts>         <dump/pass>
+/dev/sda1   /boot        vfat   noauto,noatime 1 2
+/dev/sda2   /            ext4   noatime        0 1
+";
+        let mounts = "/dev/sda2 / ext4 rw,noatime 0 0\n";
+        assert_eq!(
+            unmounted(Path::new("/boot/efi"), fstab, mounts),
+            Some(PathBuf::from("/boot"))
+        );
+        assert_eq!(unmounted(Path::new("/usr/src"), fstab, mounts), None);
+        let mounts = "/dev/sda2 / ext4 rw,noatime 0 0\n/dev/sda1 /boot vfat rw 0 0\n";
+        assert_eq!(unmounted(Path::new("/boot"), fstab, mounts), None);
+        assert_eq!(
+            mount_of(Path::new("/boot/EFI"), mounts).unwrap().fstype,
+            "vfat"
+        );
+        assert_eq!(
+            mount_of(Path::new("/usr/src"), mounts).unwrap().fstype,
+            "ext4"
+        );
+    }
+
+    #[test]
+    fn read_only_boot() {
+        let mounts = "/dev/sda2 / ext4 rw,noatime 0 0
+/dev/sda1 /boot vfat ro,relatime,fmask=0022 0 0
+";
+        let boot = mount_of(Path::new("/boot"), mounts).unwrap();
+        assert_eq!(boot.point, Path::new("/boot"));
+        assert!(boot.read_only());
+        assert!(!mount_of(Path::new("/usr/src"), mounts).unwrap().read_only());
+        // Remounted read-write later on
+        let mounts = format!(
+            "{}/dev/sda1 /boot vfat rw,relatime 0 0
+",
+            mounts
+        );
+        assert!(!mount_of(Path::new("/boot"), &mounts).unwrap().read_only());
+    }
+
+    #[test]
+    fn undone_through_the_runner() {
+        use crate::{
+            runner::RecordingRunner,
+            update::{InteractiveStatus, PretendStatus},
+        };
+        let runner = RecordingRunner::new();
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone())
+            .with_escalation(Some(vec!["doas".to_string()]));
+        let mut umount = Command::new("umount");
+        umount.arg("/boot");
+        drop(BootMount::new(
+            &cfg,
+            PathBuf::from("/boot"),
+            umount,
+            "Unmounted",
+        ));
+        assert_eq!(runner.command_lines(), vec!["doas env umount /boot"]);
+    }
+}
@@ -0,0 +1,392 @@
+use std::{fmt, str::FromStr};
+
+use crate::{
+    error::{ErrorKind, JanitorError},
+    kernel::InstalledItemKind,
+    policy,
+};
+
+/// The file in a module directory with the pkgbase of the kernel it belongs to
+pub const PKGBASE_FILE: &str = "pkgbase";
+
+/// What a placeholder in a name template stands for, given the release `5.11.8-gentoo-r1`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    /// `5.11.8-gentoo-r1`, what `make kernelrelease` prints
+    Release,
+    /// `5.11.8`
+    Version,
+    /// `-gentoo-r1`, everything after the version
+    Suffix,
+    /// `gentoo`, without the revision
+    Flavor,
+    /// `linux-custom`, Arch's name for a kernel that keeps the same files across versions
+    Pkgbase,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        match name {
+            "release" => Some(Field::Release),
+            "version" => Some(Field::Version),
+            "suffix" => Some(Field::Suffix),
+            "flavor" => Some(Field::Flavor),
+            "pkgbase" => Some(Field::Pkgbase),
+            _ => None,
+        }
+    }
+
+    fn render(&self, release: &str, pkgbase: &str) -> String {
+        let (version, suffix) = match release.find('-') {
+            Some(i) => release.split_at(i),
+            None => (release, ""),
+        };
+        match self {
+            Field::Release => release.to_string(),
+            Field::Version => version.to_string(),
+            Field::Suffix => suffix.to_string(),
+            Field::Flavor => policy::release_flavor(release),
+            Field::Pkgbase => pkgbase.to_string(),
+        }
+    }
+
+    /// Whether `value` is something this field could have rendered to
+    fn accepts(&self, value: &str) -> bool {
+        let starts_with_digit = value.starts_with(|c: char| c.is_ascii_digit());
+        match self {
+            Field::Release => starts_with_digit,
+            Field::Version => {
+                starts_with_digit
+                    && value
+                        .chars()
+                        .all(|c| c.is_ascii_digit() || c == '.' || c == '_' || c == 'r' || c == 'c')
+            }
+            Field::Suffix => value.is_empty() || value.starts_with('-'),
+            Field::Flavor | Field::Pkgbase => !value.is_empty(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// The name an installed file has, like `vmlinuz-{release}` or `kernel-{flavor}-{version}`
+/// Used to name the files when they're copied into place and to find them again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    template: String,
+    parts: Vec<Part>,
+    /// What `{pkgbase}` is rendered as
+    pkgbase: Option<String>,
+}
+
+impl FromStr for NameTemplate {
+    type Err = JanitorError;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let invalid = |why: &str| {
+            JanitorError::new(
+                ErrorKind::Config,
+                format!("Invalid name template '{}': {}", template, why),
+            )
+        };
+        if template.contains('/') {
+            return Err(invalid("it has to be a file name"));
+        }
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(open) = rest.find('{') {
+            let close = rest[open..]
+                .find('}')
+                .ok_or_else(|| invalid("a '{' isn't closed"))?
+                + open;
+            let name = &rest[open + 1..close];
+            let field = Field::from_name(name).ok_or_else(|| {
+                invalid(&format!(
+                    "{{{}}} isn't one of {{release}}, {{version}}, {{suffix}}, {{flavor}} or {{pkgbase}}",
+                    name
+                ))
+            })?;
+            if open > 0 {
+                parts.push(Part::Literal(rest[..open].to_string()));
+            }
+            // Two fields in a row couldn't be told apart, except for the version and what follows it
+            if open == 0
+                && matches!(parts.last(), Some(Part::Field(previous)) if *previous != Field::Version)
+            {
+                return Err(invalid("fields have to be separated"));
+            }
+            parts.push(Part::Field(field));
+            rest = &rest[close + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+        if !parts.iter().any(|part| {
+            matches!(
+                part,
+                Part::Field(Field::Release)
+                    | Part::Field(Field::Version)
+                    | Part::Field(Field::Pkgbase)
+            )
+        }) {
+            return Err(invalid("it needs {release}, {version} or {pkgbase}"));
+        }
+        Ok(NameTemplate {
+            template: template.to_string(),
+            parts,
+            pkgbase: None,
+        })
+    }
+}
+
+impl fmt::Display for NameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.template)
+    }
+}
+
+impl NameTemplate {
+    fn new(template: &str) -> NameTemplate {
+        template.parse().expect("default templates are valid")
+    }
+
+    /// The name of the file for `release`
+    pub fn render(&self, release: &str) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal.clone(),
+                Part::Field(field) => {
+                    field.render(release, self.pkgbase.as_deref().unwrap_or_default())
+                }
+            })
+            .collect()
+    }
+
+    /// What every name made from this template starts with, to narrow down the search
+    pub fn prefix(&self) -> &str {
+        match self.parts.first() {
+            Some(Part::Literal(literal)) => literal,
+            _ => "",
+        }
+    }
+
+    /// Whether the names are made with `{pkgbase}`
+    pub fn uses_pkgbase(&self) -> bool {
+        self.parts.contains(&Part::Field(Field::Pkgbase))
+    }
+
+    /// What the fields of the template are in `name`, without its `.old`
+    fn fields(&self, name: &str) -> Option<Vec<(Field, String)>> {
+        let mut fields = Vec::new();
+        match match_parts(&self.parts, name.trim_end_matches(".old"), &mut fields) {
+            true => Some(fields),
+            false => None,
+        }
+    }
+
+    /// The pkgbase in a file called `name`, like `linux` for `vmlinuz-linux`
+    pub fn pkgbase_of(&self, name: &str) -> Option<String> {
+        self.fields(name)?
+            .into_iter()
+            .find(|(field, _)| *field == Field::Pkgbase)
+            .map(|(_, value)| value)
+    }
+
+    /// The release a file called `name` was installed for, keeping the `.old` of a replaced one
+    /// None when the name doesn't fit the template, or only has the pkgbase of the kernel
+    pub fn release_of(&self, name: &str) -> Option<String> {
+        let old = match name.ends_with(".old") {
+            true => ".old",
+            false => "",
+        };
+        let fields = self.fields(name)?;
+        let value = |wanted: Field| {
+            fields
+                .iter()
+                .find(|(field, _)| *field == wanted)
+                .map(|(_, value)| value.as_str())
+        };
+        let release = match (value(Field::Release), value(Field::Version)) {
+            (Some(release), _) => release.to_string(),
+            (None, Some(version)) => match (value(Field::Suffix), value(Field::Flavor)) {
+                (Some(suffix), _) => format!("{}{}", version, suffix),
+                (None, Some(flavor)) if flavor != "vanilla" => format!("{}-{}", version, flavor),
+                _ => version.to_string(),
+            },
+            (None, None) => return None,
+        };
+        Some(format!("{}{}", release, old))
+    }
+}
+
+/// Match `name` against `parts`, collecting what each field matched
+/// Fields take as little as they can, so `{version}{suffix}` splits at the first `-`
+fn match_parts(parts: &[Part], name: &str, fields: &mut Vec<(Field, String)>) -> bool {
+    match parts.split_first() {
+        None => name.is_empty(),
+        Some((Part::Literal(literal), rest)) => match name.strip_prefix(literal.as_str()) {
+            Some(name) => match_parts(rest, name, fields),
+            None => false,
+        },
+        Some((Part::Field(field), rest)) => {
+            for end in (0..=name.len()).filter(|end| name.is_char_boundary(*end)) {
+                let value = &name[..end];
+                if !field.accepts(value) {
+                    continue;
+                }
+                fields.push((*field, value.to_string()));
+                if match_parts(rest, &name[end..], fields) {
+                    return true;
+                }
+                fields.pop();
+            }
+            false
+        }
+    }
+}
+
+/// The names of the image, config and System.map in the install path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Naming {
+    pub image: NameTemplate,
+    pub config: NameTemplate,
+    pub system_map: NameTemplate,
+    /// Written to `pkgbase` in the module directory of new kernels, like Arch's packages do
+    pub pkgbase: Option<String>,
+    /// Whether new kernels get their config and System.map next to the image, a small ESP
+    /// might only have room for the image. Kernels without them aren't missing anything
+    pub install_config: bool,
+    pub install_system_map: bool,
+}
+
+/// What installkernel names them
+impl Default for Naming {
+    fn default() -> Naming {
+        Naming {
+            image: NameTemplate::new("vmlinuz-{release}"),
+            config: NameTemplate::new("config-{release}"),
+            system_map: NameTemplate::new("System.map-{release}"),
+            pkgbase: None,
+            install_config: true,
+            install_system_map: true,
+        }
+    }
+}
+
+impl Naming {
+    /// `make install` can only be used with the names installkernel gives the files
+    pub fn is_default(&self) -> bool {
+        self == &Naming::default()
+    }
+
+    /// The files in the install path that new kernels don't get
+    pub fn not_installed(&self) -> Vec<InstalledItemKind> {
+        let mut kinds = Vec::new();
+        if !self.install_config {
+            kinds.push(InstalledItemKind::Config);
+        }
+        if !self.install_system_map {
+            kinds.push(InstalledItemKind::SystemMap);
+        }
+        kinds
+    }
+
+    /// Render `{pkgbase}` as `pkgbase`
+    pub fn with_pkgbase(mut self, pkgbase: Option<String>) -> Naming {
+        for template in [&mut self.image, &mut self.config, &mut self.system_map] {
+            template.pkgbase = pkgbase.clone();
+        }
+        self.pkgbase = pkgbase;
+        self
+    }
+
+    /// Templates with `{pkgbase}` can't name a new kernel's files without one
+    pub fn check(&self) -> Result<(), JanitorError> {
+        match [&self.image, &self.config, &self.system_map]
+            .iter()
+            .find(|template| template.uses_pkgbase())
+        {
+            Some(template) if self.pkgbase.is_none() => Err(JanitorError::new(
+                ErrorKind::Config,
+                format!("{} uses {{pkgbase}} but Pkgbase isn't set", template),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_templates() -> Result<(), JanitorError> {
+        let default = Naming::default();
+        assert_eq!(
+            default.image.render("5.11.8-gentoo"),
+            "vmlinuz-5.11.8-gentoo"
+        );
+        assert_eq!(default.image.prefix(), "vmlinuz-");
+        assert_eq!(
+            default
+                .image
+                .release_of("vmlinuz-5.11.8-gentoo.old")
+                .as_deref(),
+            Some("5.11.8-gentoo.old")
+        );
+        assert_eq!(default.image.release_of("vmlinuz"), None);
+
+        let split: NameTemplate = "vmlinuz-{version}{suffix}".parse()?;
+        assert_eq!(split.render("5.11.8-gentoo-r1"), "vmlinuz-5.11.8-gentoo-r1");
+        assert_eq!(
+            split.release_of("vmlinuz-5.11.8-gentoo-r1").as_deref(),
+            Some("5.11.8-gentoo-r1")
+        );
+
+        let genkernel: NameTemplate = "kernel-{flavor}-{version}".parse()?;
+        assert_eq!(genkernel.render("5.11.8-gentoo-r1"), "kernel-gentoo-5.11.8");
+        assert_eq!(
+            genkernel.release_of("kernel-gentoo-5.11.8").as_deref(),
+            Some("5.11.8-gentoo")
+        );
+        assert_eq!(genkernel.release_of("kernel-gentoo-latest"), None);
+
+        let efi: NameTemplate = "{release}.efi".parse()?;
+        assert_eq!(efi.prefix(), "");
+        assert_eq!(
+            efi.release_of("6.8.0-rc3-git.efi").as_deref(),
+            Some("6.8.0-rc3-git")
+        );
+
+        let arch = Naming {
+            image: "vmlinuz-{pkgbase}".parse()?,
+            ..Naming::default()
+        };
+        assert_eq!(arch.check().unwrap_err().kind(), ErrorKind::Config);
+        let arch = arch.with_pkgbase(Some("linux-custom".to_string()));
+        arch.check()?;
+        assert_eq!(arch.image.render("6.1.2-arch1"), "vmlinuz-linux-custom");
+        assert_eq!(arch.image.release_of("vmlinuz-linux-custom"), None);
+        assert_eq!(
+            arch.image.pkgbase_of("vmlinuz-linux-custom.old").as_deref(),
+            Some("linux-custom")
+        );
+
+        for invalid in &[
+            "vmlinuz",
+            "vmlinuz-{kernel}",
+            "vmlinuz-{release",
+            "{flavor}{version}",
+            "boot/vmlinuz-{release}",
+        ] {
+            let err = invalid.parse::<NameTemplate>().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Config, "{}", invalid);
+        }
+        Ok(())
+    }
+}
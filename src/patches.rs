@@ -0,0 +1,340 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    debug,
+    error::{Context, ErrorKind, JanitorError},
+    info, policy,
+    update::RunCmdConfig,
+    utils,
+};
+
+/// The patches applied to a source tree, one file name per line, so running again doesn't apply
+/// them a second time
+const APPLIED: &str = ".kernel-janitor-patches";
+/// Where `eapply_user` looks for user patches, in `<category>/<package>` directories
+pub const PORTAGE_PATCHES: &str = "/etc/portage/patches";
+
+/// The files in `dir` with one of `extensions`, sorted by name
+fn files_with_extension(dir: &Path, extensions: &[&str]) -> std::io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|ext| extensions.iter().any(|wanted| ext == *wanted))
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// The `*.patch` files in `dir`, in the order they're applied
+pub fn patch_files(dir: &Path) -> Result<Vec<PathBuf>, JanitorError> {
+    files_with_extension(dir, &["patch"])
+        .with_context(|| format!("failed to read PatchesDir {:?}", dir))
+}
+
+/// The package a source tree like `linux-5.11.8-gentoo-r1` was installed by, as the directory
+/// names `eapply_user` tries, most specific first: `gentoo-sources-5.11.8-r1`, `gentoo-sources-5.11.8`
+/// and `gentoo-sources`
+fn portage_package_dirs(src_dir_name: &str) -> Vec<String> {
+    let mut parts = src_dir_name.trim_start_matches("linux-").split('-');
+    let mut version = match parts.next() {
+        Some(version) if version.starts_with(|c: char| c.is_ascii_digit()) => version.to_string(),
+        _ => return Vec::new(),
+    };
+    let mut revision = None;
+    for part in parts {
+        let number = |prefix: &str| {
+            part.strip_prefix(prefix)
+                .filter(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+                .is_some()
+        };
+        if number("rc") {
+            version = format!("{}_{}", version, part);
+        } else if number("r") {
+            revision = Some(part);
+        }
+    }
+    let flavor = policy::release_flavor(src_dir_name);
+    // The sources of distribution kernels are installed by gentoo-kernel or vanilla-kernel
+    let package = match flavor.strip_suffix("-dist") {
+        Some(base) => format!("{}-kernel", base),
+        None => format!("{}-sources", flavor),
+    };
+    let mut dirs = Vec::new();
+    if let Some(revision) = revision {
+        dirs.push(format!("{}-{}-{}", package, version, revision));
+    }
+    dirs.push(format!("{}-{}", package, version));
+    dirs.push(package);
+    dirs
+}
+
+/// The user patches for the package `src_dir` was installed by, found in `patches_root` like
+/// `eapply_user` does: `*.patch` and `*.diff` from every matching directory, a patch in a more
+/// specific directory replacing one with the same name, sorted by name
+pub fn portage_patch_files(patches_root: &Path, src_dir: &Path) -> Vec<PathBuf> {
+    let name = utils::paths::filename_from_path(src_dir).unwrap_or_default();
+    let mut patches: Vec<PathBuf> = Vec::new();
+    for dir in portage_package_dirs(&name) {
+        let dir = patches_root.join("sys-kernel").join(dir);
+        for patch in files_with_extension(&dir, &["patch", "diff"]).unwrap_or_default() {
+            if !patches.iter().any(|p| p.file_name() == patch.file_name()) {
+                patches.push(patch);
+            }
+        }
+    }
+    patches.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    patches
+}
+
+fn applied(src_dir: &Path) -> Vec<String> {
+    fs::read_to_string(src_dir.join(APPLIED))
+        .unwrap_or_default()
+        .lines()
+        .map(str::to_string)
+        .collect()
+}
+
+fn name_of(patch: &Path) -> String {
+    utils::paths::filename_from_path(patch).unwrap_or_default()
+}
+
+/// `patch -p1` in `src_dir`, with `extra` arguments like `--dry-run` or `-R`
+fn run_patch(
+    config: &RunCmdConfig,
+    src_dir: &Path,
+    patch: &Path,
+    extra: &[&str],
+) -> Result<(), JanitorError> {
+    let mut cmd = Command::new("patch");
+    cmd.arg("-p1")
+        .arg("--forward")
+        .arg("--batch")
+        .args(extra)
+        .arg("-i")
+        .arg(patch)
+        .current_dir(src_dir);
+    let (mut cmd, as_user) = config.unprivileged(cmd);
+    utils::exec_and_print_command(
+        &mut cmd,
+        format!(
+            "\'{}\' with {} in {:?}{}",
+            ["patch", "-p1"]
+                .iter()
+                .chain(extra)
+                .copied()
+                .collect::<Vec<_>>()
+                .join(" "),
+            name_of(patch),
+            src_dir,
+            as_user
+        ),
+        config,
+    )
+}
+
+/// Apply every one of `patches` to `src_dir` that wasn't applied already, in order
+/// Each one is tried with `--dry-run` first so a patch that doesn't apply changes nothing, and the
+/// ones applied before it are reverted, leaving the tree as it was
+/// A patch that can be reversed instead was already applied, e.g. by `eapply_user` when emerging
+pub fn apply(
+    config: &RunCmdConfig,
+    src_dir: &Path,
+    patches: &[PathBuf],
+) -> Result<(), JanitorError> {
+    let already_applied = applied(src_dir);
+    let mut done: Vec<PathBuf> = Vec::new();
+    let mut found_applied = Vec::new();
+    for patch in patches {
+        let name = name_of(patch);
+        if already_applied.contains(&name) {
+            debug!("{} is already applied to {:?}", name, src_dir);
+            continue;
+        }
+        let result = match run_patch(config, src_dir, patch, &["--dry-run"]) {
+            Ok(()) => run_patch(config, src_dir, patch, &[]),
+            Err(e) => match run_patch(config, src_dir, patch, &["-R", "--dry-run"]) {
+                Ok(()) => {
+                    info!("{} is already applied to {:?}", name, src_dir);
+                    found_applied.push(name);
+                    continue;
+                }
+                Err(_) => Err(e),
+            },
+        };
+        if let Err(e) = result {
+            return Err(JanitorError::new(
+                ErrorKind::Build,
+                format!(
+                    "Could not apply {} to {:?}: {}. {}",
+                    name,
+                    src_dir,
+                    e,
+                    revert(config, src_dir, &done)
+                ),
+            ));
+        }
+        done.push(patch.clone());
+    }
+    if done.is_empty() && found_applied.is_empty() {
+        info!("No new patches for {:?}", src_dir);
+        return Ok(());
+    }
+    if !config.pretending() {
+        let record = already_applied
+            .into_iter()
+            .chain(found_applied)
+            .chain(done.iter().map(|patch| name_of(patch)))
+            .map(|name| format!("{}\n", name))
+            .collect::<String>();
+        fs::write(src_dir.join(APPLIED), record)
+            .with_context(|| format!("failed to record the applied patches in {:?}", src_dir))?;
+    }
+    info!("Applied {} patches to {:?}", done.len(), src_dir);
+    Ok(())
+}
+
+/// Reverse the `done` patches, last one first. Returns what state that left the tree in
+fn revert(config: &RunCmdConfig, src_dir: &Path, done: &[PathBuf]) -> String {
+    if done.is_empty() {
+        return "The tree wasn't changed".to_string();
+    }
+    for (reverted, patch) in done.iter().rev().enumerate() {
+        if let Err(e) = run_patch(config, src_dir, patch, &["-R"]) {
+            let left = done[..done.len() - reverted]
+                .iter()
+                .map(|patch| name_of(patch))
+                .collect::<Vec<_>>();
+            return format!(
+                "Reverting {} failed too ({}), revert these by hand with 'patch -p1 -R': {}",
+                name_of(patch),
+                e,
+                left.join(", ")
+            );
+        }
+    }
+    format!(
+        "Reverted the {} patches applied before it, the tree is as it was",
+        done.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runner::RecordingRunner,
+        test_utils::TestDir,
+        update::{InteractiveStatus, PretendStatus},
+    };
+
+    #[test]
+    fn failed_patch_is_rolled_back() -> Result<(), JanitorError> {
+        let test_dir = TestDir::new();
+        let patches_dir = test_dir.path().join("patches");
+        fs::create_dir_all(&patches_dir).unwrap();
+        for name in &[
+            "02-second.patch",
+            "01-first.patch",
+            "03-third.patch",
+            "README",
+        ] {
+            fs::write(patches_dir.join(name), b"").unwrap();
+        }
+        let src_dir = test_dir.src_path();
+        let line = |patch: &str, extra: &str| {
+            format!(
+                "patch -p1 --forward --batch {}-i {}",
+                extra,
+                patches_dir.join(patch).display()
+            )
+        };
+
+        let runner = RecordingRunner::new()
+            .with_failure(&line("03-third.patch", "--dry-run "))
+            .with_failure(&line("03-third.patch", "-R --dry-run "));
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone());
+        let patches = patch_files(&patches_dir)?;
+        assert_eq!(patches.len(), 3);
+        let err = apply(&cfg, &src_dir, &patches).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Build);
+        let message = err.to_string();
+        assert!(
+            message.contains("Could not apply 03-third.patch"),
+            "{}",
+            message
+        );
+        assert!(message.contains("Reverted the 2 patches"), "{}", message);
+        assert_eq!(
+            runner.command_lines(),
+            vec![
+                line("01-first.patch", "--dry-run "),
+                line("01-first.patch", ""),
+                line("02-second.patch", "--dry-run "),
+                line("02-second.patch", ""),
+                line("03-third.patch", "--dry-run "),
+                line("03-third.patch", "-R --dry-run "),
+                line("02-second.patch", "-R "),
+                line("01-first.patch", "-R "),
+            ]
+        );
+        assert!(applied(&src_dir).is_empty());
+
+        // The third one was applied by someone else, so it's skipped, and they're all applied once
+        let runner = RecordingRunner::new().with_failure(&line("03-third.patch", "--dry-run "));
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone());
+        apply(&cfg, &src_dir, &patches)?;
+        assert_eq!(
+            applied(&src_dir),
+            vec!["03-third.patch", "01-first.patch", "02-second.patch"]
+        );
+        apply(&cfg, &src_dir, &patches)?;
+        assert_eq!(runner.command_lines().len(), 6);
+        Ok(())
+    }
+
+    #[test]
+    fn portage_user_patches() {
+        assert_eq!(
+            portage_package_dirs("linux-5.11.8-gentoo-r1"),
+            vec![
+                "gentoo-sources-5.11.8-r1",
+                "gentoo-sources-5.11.8",
+                "gentoo-sources"
+            ]
+        );
+        assert_eq!(
+            portage_package_dirs("linux-5.12-rc3"),
+            vec!["vanilla-sources-5.12_rc3", "vanilla-sources"]
+        );
+        assert_eq!(
+            portage_package_dirs("linux-5.11.8-gentoo-dist")[1],
+            "gentoo-kernel"
+        );
+        assert!(portage_package_dirs("linux").is_empty());
+
+        let test_dir = TestDir::new();
+        let root = test_dir.path().join("patches");
+        let specific = root.join("sys-kernel/gentoo-sources-5.11.8");
+        let any_version = root.join("sys-kernel/gentoo-sources");
+        fs::create_dir_all(&specific).unwrap();
+        fs::create_dir_all(&any_version).unwrap();
+        fs::write(specific.join("fix.patch"), b"").unwrap();
+        fs::write(any_version.join("fix.patch"), b"").unwrap();
+        fs::write(any_version.join("0-first.diff"), b"").unwrap();
+        fs::write(any_version.join("notes.txt"), b"").unwrap();
+        assert_eq!(
+            portage_patch_files(&root, Path::new("/usr/src/linux-5.11.8-gentoo")),
+            vec![any_version.join("0-first.diff"), specific.join("fix.patch")]
+        );
+    }
+}
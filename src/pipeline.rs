@@ -0,0 +1,272 @@
+use std::{path::Path, process::Command};
+
+use crate::{
+    conf::Config,
+    error::{ErrorKind, JanitorError},
+    kernel::KernelVersion,
+    update::{self, RunCmdConfig, Stage},
+};
+
+/// The section of the config the pipeline is read from
+pub const SECTION: &str = "pipeline";
+/// The value that runs a stage the way the janitor does it
+const BUILTIN: &str = "builtin";
+
+/// The stages that can be reordered or replaced, in the order they run without a `[pipeline]`
+/// copy-config, patch and build always run first, everything else needs the new kernel
+pub const STAGES: &[Stage] = &[
+    Stage::ModuleRebuild,
+    Stage::ModuleCheck,
+    Stage::Initramfs,
+    Stage::Bootloader,
+    Stage::Cleanup,
+];
+
+/// One line of the `[pipeline]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// A stage the way the janitor does it
+    Builtin(Stage),
+    /// A command of the user's, named by its line. Reported as the stage it replaces,
+    /// `Stage::Command` when it doesn't replace one
+    Command {
+        name: String,
+        stage: Stage,
+        command: Vec<String>,
+    },
+}
+
+/// What runs after the new kernel was built and in which order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pipeline {
+    steps: Vec<Step>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Pipeline {
+        Pipeline {
+            steps: STAGES.iter().copied().map(Step::Builtin).collect(),
+        }
+    }
+}
+
+fn stage_named(name: &str) -> Option<Stage> {
+    STAGES
+        .iter()
+        .copied()
+        .find(|stage| stage.to_string() == name)
+}
+
+impl Pipeline {
+    /// The `[pipeline]` section, the default stages when there isn't one
+    pub fn from_config(config: &Config) -> Result<Pipeline, JanitorError> {
+        let entries = config.section(SECTION);
+        if entries.is_empty() {
+            return Ok(Pipeline::default());
+        }
+        let invalid = |message: String| {
+            Err(JanitorError::new(
+                ErrorKind::Config,
+                format!("[{}] in {}: {}", SECTION, config.path().display(), message),
+            ))
+        };
+        let mut steps = Vec::new();
+        for entry in entries {
+            let name = entry.name.as_str();
+            if ["copy-config", "patch", "build"].contains(&name) {
+                return invalid(format!(
+                    "{} always runs before the pipeline, everything in it needs the new kernel",
+                    name
+                ));
+            }
+            if steps.iter().any(|step: &Step| step.name() == name) {
+                return invalid(format!("{} is in it twice", name));
+            }
+            let step = match (stage_named(name), entry.value == BUILTIN) {
+                (Some(stage), true) => Step::Builtin(stage),
+                (None, true) => {
+                    let stages: Vec<_> = STAGES.iter().map(Stage::to_string).collect();
+                    return invalid(format!(
+                        "{} isn't a stage, only {} are. Give it a command to run instead",
+                        name,
+                        stages.join(", ")
+                    ));
+                }
+                (_, false) if entry.value.trim().is_empty() => {
+                    return invalid(format!("{} has no command", name));
+                }
+                (stage, false) => Step::Command {
+                    name: name.to_string(),
+                    stage: stage.unwrap_or(Stage::Command),
+                    command: entry.value.split_whitespace().map(str::to_string).collect(),
+                },
+            };
+            steps.push(step);
+        }
+        Ok(Pipeline { steps })
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// Whether `stage` runs the way the janitor does it, not left out or replaced by a command
+    /// Stages that can't be in the pipeline always do
+    pub fn runs_builtin(&self, stage: Stage) -> bool {
+        !STAGES.contains(&stage) || self.steps.contains(&Step::Builtin(stage))
+    }
+
+    /// The stages that don't run at all, neither the janitor's way nor replaced
+    pub fn left_out(&self) -> Vec<Stage> {
+        STAGES
+            .iter()
+            .copied()
+            .filter(|stage| {
+                !self.steps.iter().any(|step| match step {
+                    Step::Builtin(s) | Step::Command { stage: s, .. } => s == stage,
+                })
+            })
+            .collect()
+    }
+
+    /// The programs the commands run, with the stage they replace
+    pub fn programs(&self) -> Vec<(Stage, String)> {
+        self.steps
+            .iter()
+            .filter_map(|step| match step {
+                Step::Command { stage, command, .. } => Some((*stage, command[0].clone())),
+                Step::Builtin(_) => None,
+            })
+            .collect()
+    }
+}
+
+impl Step {
+    pub fn name(&self) -> String {
+        match self {
+            Step::Builtin(stage) => stage.to_string(),
+            Step::Command { name, .. } => name.clone(),
+        }
+    }
+}
+
+/// Run the command of a step for the kernel built in `src_dir`, as root like the stages are
+/// `{version}`, `{release}`, `{source}`, `{install}` and `{image}` are filled in
+pub fn run_command(
+    config: &RunCmdConfig,
+    command: &[String],
+    version: &KernelVersion,
+    src_dir: &Path,
+    install_path: &Path,
+) -> Result<(), JanitorError> {
+    let uses_release = command
+        .iter()
+        .any(|word| word.contains("{release}") || word.contains("{image}"));
+    // Asks make, so only when it's needed
+    let release = match uses_release {
+        true => update::kernel_release(config, src_dir),
+        false => String::new(),
+    };
+    let image = install_path.join(config.naming.image.render(&release));
+    let filled: Vec<String> = command
+        .iter()
+        .map(|word| {
+            word.replace("{version}", &version.to_string())
+                .replace("{release}", &release)
+                .replace("{source}", &src_dir.to_string_lossy())
+                .replace("{install}", &install_path.to_string_lossy())
+                .replace("{image}", &image.to_string_lossy())
+        })
+        .collect();
+    let mut cmd = Command::new(&filled[0]);
+    cmd.args(&filled[1..]);
+    let cmd_desc = format!("'{}'", filled.join(" "));
+    config.confirm(&cmd_desc)?;
+    config.run_without_prompt(&mut config.privileged(cmd), &cmd_desc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runner::RecordingRunner,
+        test_utils::TestDir,
+        update::{InteractiveStatus, PretendStatus},
+    };
+    use std::{convert::TryFrom, fs};
+
+    fn pipeline(section: &str) -> Result<Pipeline, JanitorError> {
+        let test_dir = TestDir::new();
+        let path = test_dir.path().join("kernel-janitor.conf");
+        fs::write(
+            &path,
+            format!("[settings]\nVersionsToKeep = 3\n[pipeline]\n{}", section),
+        )?;
+        Pipeline::from_config(&Config::new(&path)?)
+    }
+
+    #[test]
+    fn reorder_and_replace_stages() -> Result<(), JanitorError> {
+        assert_eq!(pipeline("")?, Pipeline::default());
+
+        let reordered = pipeline(
+            "module-rebuild = builtin\n\
+             sign = sbsign --output {image} {image}\n\
+             initramfs = ugrd --kver {release}\n\
+             bootloader = builtin\n",
+        )?;
+        assert_eq!(
+            reordered.steps().iter().map(Step::name).collect::<Vec<_>>(),
+            vec!["module-rebuild", "sign", "initramfs", "bootloader"]
+        );
+        assert!(reordered.runs_builtin(Stage::Build));
+        assert!(!reordered.runs_builtin(Stage::Initramfs));
+        assert_eq!(
+            reordered.left_out(),
+            vec![Stage::ModuleCheck, Stage::Cleanup]
+        );
+        assert_eq!(
+            reordered.programs(),
+            vec![
+                (Stage::Command, "sbsign".to_string()),
+                (Stage::Initramfs, "ugrd".to_string())
+            ]
+        );
+
+        for (section, message) in [
+            ("build = builtin\n", "build always runs before the pipeline"),
+            ("sign = builtin\n", "sign isn't a stage"),
+            (
+                "cleanup = builtin\ncleanup = builtin\n",
+                "cleanup is in it twice",
+            ),
+        ] {
+            let err = pipeline(section).unwrap_err().to_string();
+            assert!(err.contains(message), "{}", err);
+        }
+
+        // The command is filled in for the new kernel
+        let runner = RecordingRunner::new().with_output("make", "6.1.0-gentoo\n");
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone());
+        let command = match &reordered.steps()[1] {
+            Step::Command { command, .. } => command.clone(),
+            step => panic!("{:?} isn't a command", step),
+        };
+        run_command(
+            &cfg,
+            &command,
+            &KernelVersion::try_from("6.1.0-gentoo")?,
+            Path::new("/usr/src/linux-6.1.0-gentoo"),
+            Path::new("/boot"),
+        )?;
+        assert_eq!(
+            runner.command_lines(),
+            vec![
+                "make -s kernelrelease",
+                "sbsign --output /boot/vmlinuz-6.1.0-gentoo /boot/vmlinuz-6.1.0-gentoo"
+            ]
+        );
+        Ok(())
+    }
+}
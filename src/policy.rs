@@ -0,0 +1,319 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    debug,
+    error::JanitorError,
+    inventory::KernelInventory,
+    kernel::{InstalledKernel, KernelVersion},
+    utils, JanitorErrorFrom,
+};
+
+/// Decides which installed kernels get removed during cleanup
+/// Pinned kernels and the fallback are never removed, whatever the policy picks
+pub trait CleanupPolicy {
+    /// Short description for log messages, like `keep the newest 3`
+    fn describe(&self) -> String;
+
+    /// The kernels to remove, oldest first
+    fn select(&self, inventory: &KernelInventory) -> Result<Vec<KernelVersion>, JanitorError>;
+}
+
+/// Which `CleanupPolicy` to use, set with `CleanupPolicy` in the config
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CleanupPolicyKind {
+    Count,
+    Age,
+    DiskBudget,
+    Flavor,
+}
+
+impl FromStr for CleanupPolicyKind {
+    type Err = JanitorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "count" => Ok(CleanupPolicyKind::Count),
+            "age" => Ok(CleanupPolicyKind::Age),
+            "disk-budget" => Ok(CleanupPolicyKind::DiskBudget),
+            "flavor" => Ok(CleanupPolicyKind::Flavor),
+            _ => Err(JanitorErrorFrom!(
+                "Unknown cleanup policy {}, expected one of count, age, disk-budget, flavor",
+                s
+            )),
+        }
+    }
+}
+
+/// Keeps a fixed number of the newest kernels
+pub struct KeepNewest {
+    pub count: usize,
+}
+
+impl CleanupPolicy for KeepNewest {
+    fn describe(&self) -> String {
+        format!("keep the newest {}", self.count)
+    }
+
+    fn select(&self, inventory: &KernelInventory) -> Result<Vec<KernelVersion>, JanitorError> {
+        Ok(inventory.removable(self.count))
+    }
+}
+
+/// Removes kernels installed longer ago than `max_age`, the newest one always stays
+/// Kernels without a readable install time are kept
+pub struct MaxAge {
+    pub max_age: Duration,
+}
+
+impl CleanupPolicy for MaxAge {
+    fn describe(&self) -> String {
+        format!(
+            "remove kernels older than {}",
+            utils::format_duration(self.max_age)
+        )
+    }
+
+    fn select(&self, inventory: &KernelInventory) -> Result<Vec<KernelVersion>, JanitorError> {
+        let now = SystemTime::now();
+        let kernels = inventory.kernels();
+        Ok(kernels
+            .iter()
+            .take(kernels.len().saturating_sub(1))
+            .filter(|k| match inventory.installed_at(k) {
+                Ok(installed) => now
+                    .duration_since(installed)
+                    .is_ok_and(|age| age > self.max_age),
+                Err(e) => {
+                    debug!("Keeping {}, its age is unknown: {}", k.version, e);
+                    false
+                }
+            })
+            .map(|k| k.version.clone())
+            .filter(|v| !inventory.is_kept(v))
+            .collect())
+    }
+}
+
+/// Removes the oldest kernels until all of them fit in `max_bytes`, the newest one always stays
+pub struct DiskBudget {
+    pub max_bytes: u64,
+}
+
+impl CleanupPolicy for DiskBudget {
+    fn describe(&self) -> String {
+        format!(
+            "keep kernels within {}",
+            utils::format_bytes(self.max_bytes)
+        )
+    }
+
+    fn select(&self, inventory: &KernelInventory) -> Result<Vec<KernelVersion>, JanitorError> {
+        let mut total = inventory.total_size()?;
+        let kernels = inventory.kernels();
+        let mut selected = Vec::new();
+        for kernel in kernels.iter().take(kernels.len().saturating_sub(1)) {
+            if total <= self.max_bytes {
+                break;
+            }
+            if inventory.is_kept(&kernel.version) {
+                continue;
+            }
+            total = total.saturating_sub(inventory.size(kernel)?);
+            selected.push(kernel.version.clone());
+        }
+        Ok(selected)
+    }
+}
+
+/// Like `KeepNewest` but counts each flavor (`gentoo`, `gentoo-dist`, ...) on its own
+pub struct KeepNewestPerFlavor {
+    pub count: usize,
+}
+
+/// The part of the release after the version, like `gentoo` for `5.10.1-gentoo-r1`
+pub fn flavor(kernel: &InstalledKernel) -> String {
+    release_flavor(&kernel.version.release())
+}
+
+/// Like `flavor` but for a release string, like the running kernel's `uname -r`
+/// vanilla-sources have no suffix at all, they're called `vanilla`
+pub fn release_flavor(release: &str) -> String {
+    let is_numbered = |part: &str, prefix: &str| {
+        part.strip_prefix(prefix)
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+    };
+    let flavor = release
+        .trim_start_matches("vmlinuz-")
+        .trim_start_matches("linux-")
+        .trim_end_matches(".old")
+        .split('-')
+        .skip(1)
+        // Gentoo revisions like `r1` and release candidates like `rc3` are the same flavor
+        .filter(|part| !is_numbered(part, "r") && !is_numbered(part, "rc"))
+        // and so are the patch set versions of rt34 or zen1
+        .map(|part| part.trim_end_matches(|c: char| c.is_ascii_digit()))
+        .collect::<Vec<_>>()
+        .join("-");
+    match flavor.is_empty() {
+        true => "vanilla".to_string(),
+        false => flavor,
+    }
+}
+
+impl CleanupPolicy for KeepNewestPerFlavor {
+    fn describe(&self) -> String {
+        format!("keep the newest {} of each flavor", self.count)
+    }
+
+    fn select(&self, inventory: &KernelInventory) -> Result<Vec<KernelVersion>, JanitorError> {
+        let mut kept: HashMap<String, usize> = HashMap::new();
+        let mut selected: Vec<KernelVersion> = inventory
+            .kernels()
+            .iter()
+            .rev()
+            .filter(|k| {
+                let kept = kept.entry(flavor(k)).or_insert(0);
+                *kept += 1;
+                *kept > self.count
+            })
+            .map(|k| k.version.clone())
+            .filter(|v| !inventory.is_kept(v))
+            .collect();
+        selected.reverse();
+        Ok(selected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filesystem::MemoryFileSystem, kernel::KernelSearch, test_utils::*};
+    use std::{
+        convert::TryFrom,
+        fs,
+        path::{Path, PathBuf},
+        sync::Arc,
+    };
+
+    fn versions(releases: &[&str]) -> Vec<KernelVersion> {
+        releases
+            .iter()
+            .map(|r| KernelVersion::try_from(*r).unwrap())
+            .collect()
+    }
+
+    /// Every kernel is `size` bytes
+    fn inventory(releases: &[&str], size: u64) -> KernelInventory {
+        let mut memfs = MemoryFileSystem::new().with_dir(Path::new("/usr/src"));
+        for release in releases {
+            memfs = memfs
+                .with_file(&PathBuf::from(format!("/boot/vmlinuz-{}", release)), size)
+                .with_dir(&PathBuf::from(format!("/lib/modules/{}", release)));
+        }
+        KernelSearch::new(
+            Path::new("/boot"),
+            &[PathBuf::from("/usr/src")],
+            Path::new("/lib/modules"),
+        )
+        .with_filesystem(Arc::new(memfs))
+        .inventory()
+        .unwrap()
+    }
+
+    #[test]
+    fn count_and_budget() -> Result<(), JanitorError> {
+        let mut inventory = inventory(&["5.4.97-gentoo", "5.10.1-gentoo", "5.11.8-gentoo"], 10);
+        assert_eq!(
+            KeepNewest { count: 1 }.select(&inventory)?,
+            versions(&["5.4.97-gentoo", "5.10.1-gentoo"])
+        );
+        assert_eq!(
+            DiskBudget { max_bytes: 20 }.select(&inventory)?,
+            versions(&["5.4.97-gentoo"])
+        );
+        // Never removes the newest, even if it alone is over budget
+        inventory.pin(KernelVersion::try_from("5.4.97-gentoo").unwrap());
+        assert_eq!(
+            DiskBudget { max_bytes: 0 }.select(&inventory)?,
+            versions(&["5.10.1-gentoo"])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn per_flavor() -> Result<(), JanitorError> {
+        let inventory = inventory(
+            &[
+                "5.4.97-gentoo",
+                "5.10.1-gentoo-dist",
+                "5.10.2-gentoo-r1",
+                "5.11.8-gentoo-dist",
+            ],
+            1,
+        );
+        let flavors: Vec<_> = inventory.kernels().iter().map(flavor).collect();
+        assert_eq!(
+            flavors,
+            vec!["gentoo", "gentoo-dist", "gentoo", "gentoo-dist"]
+        );
+        assert_eq!(release_flavor("linux-6.8-rc3"), "vanilla");
+        assert_eq!(release_flavor("linux-6.8-rc3-git"), "git");
+        assert_eq!(release_flavor("5.10.21-rt34"), "rt");
+        assert_eq!(release_flavor("vmlinuz-5.11.8-zen1.old"), "zen");
+        assert_eq!(
+            KeepNewestPerFlavor { count: 1 }.select(&inventory)?,
+            versions(&["5.4.97-gentoo", "5.10.1-gentoo-dist"])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn per_flavor_same_version() -> Result<(), JanitorError> {
+        let inventory = inventory(
+            &[
+                "5.10.1-gentoo",
+                "5.10.1-gentoo-dist",
+                "5.11.8-gentoo",
+                "5.11.8-gentoo-dist",
+            ],
+            1,
+        );
+        assert_eq!(
+            KeepNewestPerFlavor { count: 1 }.select(&inventory)?,
+            versions(&["5.10.1-gentoo", "5.10.1-gentoo-dist"])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn max_age() -> Result<(), JanitorError> {
+        let test_dir = TestDir::new();
+        let day = Duration::from_secs(24 * 60 * 60);
+        for (release, age_days) in &[
+            ("5.4.97-gentoo", 40),
+            ("5.10.1-gentoo", 10),
+            ("5.11.8-gentoo", 50),
+        ] {
+            let image = test_dir.install_path().join(format!("vmlinuz-{}", release));
+            let file = fs::File::create(&image).unwrap();
+            file.set_modified(SystemTime::now() - day * *age_days)
+                .unwrap();
+            fs::create_dir(test_dir.module_path().join(release)).unwrap();
+        }
+        let inventory = KernelSearch::new(
+            &test_dir.install_path(),
+            &[test_dir.src_path()],
+            &test_dir.module_path(),
+        )
+        .inventory()?;
+        assert_eq!(
+            MaxAge { max_age: day * 30 }.select(&inventory)?,
+            versions(&["5.4.97-gentoo"])
+        );
+        Ok(())
+    }
+}
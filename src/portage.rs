@@ -0,0 +1,264 @@
+use std::{collections::HashMap, iter::Peekable, path::Path, str::Chars};
+
+use crate::{
+    conf::Config,
+    debug,
+    error::{Context, ErrorKind, JanitorError},
+    filesystem::FileSystem,
+};
+
+/// Portage's settings, a file or a directory of files that are read in name order
+pub const MAKE_CONF: &str = "/etc/portage/make.conf";
+/// Rebuilds the out-of-tree modules when `ModuleRebuildCommand` isn't set
+pub const MODULE_REBUILD: &[&str] = &["emerge", "@module-rebuild"];
+
+/// What the kernel build takes over from make.conf
+/// emerge reads make.conf itself, EMERGE_DEFAULT_OPTS and PORTAGE_NICENESS already apply to it
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MakeConf {
+    /// MAKEOPTS split into words, like `-j8 -l8`, used instead of `-j$(nproc)`
+    pub makeopts: Vec<String>,
+    /// PORTAGE_NICENESS, the build runs under `nice -n` with it
+    pub niceness: Option<i32>,
+}
+
+impl MakeConf {
+    /// Read `path` on `fs`, or every file in it when it's a directory
+    pub fn read(fs: &dyn FileSystem, path: &Path) -> Result<MakeConf, JanitorError> {
+        let files = match fs.read_dir(path) {
+            Ok(entries) => {
+                // Only what can't be listed is a file
+                let mut files: Vec<_> = entries
+                    .into_iter()
+                    .filter(|entry| fs.read_dir(entry).is_err())
+                    .collect();
+                files.sort();
+                files
+            }
+            Err(_) => vec![path.to_path_buf()],
+        };
+        let mut contents = String::new();
+        for file in files {
+            contents.push_str(
+                &fs.read_to_string(&file)
+                    .with_context(|| format!("failed to read {:?}", file))?,
+            );
+            contents.push('\n');
+        }
+        MakeConf::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<MakeConf, JanitorError> {
+        let variables = shell_variables(contents);
+        let makeopts = variables
+            .get("MAKEOPTS")
+            .map(|makeopts| makeopts.split_whitespace().map(str::to_string).collect())
+            .unwrap_or_default();
+        let niceness = match variables.get("PORTAGE_NICENESS") {
+            Some(niceness) => Some(niceness.trim().parse().map_err(|_| {
+                JanitorError::new(
+                    ErrorKind::Config,
+                    format!(
+                        "PORTAGE_NICENESS in {} should be a number, not {}",
+                        MAKE_CONF, niceness
+                    ),
+                )
+            })?),
+            None => None,
+        };
+        Ok(MakeConf { makeopts, niceness })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.makeopts.is_empty() && self.niceness.is_none()
+    }
+}
+
+/// The variables a file sourced by bash like make.conf or /etc/default/grub assigns, with the
+/// `${VAR}` and `$VAR` of earlier ones expanded. Anything that isn't an assignment is skipped
+pub(crate) fn shell_variables(contents: &str) -> HashMap<String, String> {
+    let mut variables = HashMap::new();
+    let mut chars = contents.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c == '#' {
+            skip_line(&mut chars);
+            continue;
+        }
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c.is_whitespace() {
+                break;
+            }
+            name.push(c);
+            chars.next();
+        }
+        if name == "export" {
+            continue;
+        }
+        let is_name =
+            !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !is_name || chars.peek() != Some(&'=') {
+            skip_line(&mut chars);
+            continue;
+        }
+        chars.next();
+        let value = value(&mut chars, &variables);
+        variables.insert(name, value);
+    }
+    variables
+}
+
+fn skip_line(chars: &mut Peekable<Chars>) {
+    for c in chars.by_ref() {
+        if c == '\n' {
+            break;
+        }
+    }
+}
+
+/// A value up to the first whitespace outside of quotes
+fn value(chars: &mut Peekable<Chars>, variables: &HashMap<String, String>) -> String {
+    let mut value = String::new();
+    let mut quote = None;
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, c) if c.is_whitespace() => break,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => value.push(c),
+            (_, '\\') => match chars.next() {
+                // A line continuation
+                Some('\n') => (),
+                Some(escaped) => value.push(escaped),
+                None => (),
+            },
+            (_, '$') => value.push_str(&expand(chars, variables)),
+            (_, c) => value.push(c),
+        }
+    }
+    value
+}
+
+/// The value of the variable after a `$`, empty when it isn't set
+fn expand(chars: &mut Peekable<Chars>, variables: &HashMap<String, String>) -> String {
+    let mut name = String::new();
+    match chars.peek() {
+        Some('{') => {
+            chars.next();
+            for c in chars.by_ref() {
+                if c == '}' {
+                    break;
+                }
+                name.push(c);
+            }
+        }
+        _ => {
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+            if name.is_empty() {
+                return "$".to_string();
+            }
+        }
+    }
+    variables.get(&name).cloned().unwrap_or_default()
+}
+
+/// Read make.conf on `fs`, a missing one is the same as an empty one
+pub fn read_make_conf(fs: &dyn FileSystem) -> Result<MakeConf, JanitorError> {
+    let path = Path::new(MAKE_CONF);
+    match fs.disk_usage(path).is_ok() {
+        true => MakeConf::read(fs, path),
+        false => {
+            debug!("There's no {}, building with the defaults", MAKE_CONF);
+            Ok(MakeConf::default())
+        }
+    }
+}
+
+/// `ModuleRebuildCommand` split into the program and its arguments, `emerge @module-rebuild`
+/// when it isn't set
+pub fn module_rebuild_command(config: &Config) -> Result<Vec<String>, JanitorError> {
+    if !config.contains("ModuleRebuildCommand") {
+        return Ok(MODULE_REBUILD.iter().map(|word| word.to_string()).collect());
+    }
+    // Only whitespace separates the words, arguments like --exclude can have commas in them
+    let command: Vec<String> = config
+        .get_string("ModuleRebuildCommand")?
+        .split_whitespace()
+        .map(str::to_string)
+        .collect();
+    match command.is_empty() {
+        true => Err(JanitorError::new(
+            ErrorKind::Config,
+            "ModuleRebuildCommand is empty, set RebuildPortageModules = false to not rebuild modules"
+                .to_string(),
+        )),
+        false => Ok(command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFileSystem;
+
+    #[test]
+    fn parse_make_conf() -> Result<(), JanitorError> {
+        let make_conf = MakeConf::parse(
+            r#"
+# These settings were set by the catalyst build script
+COMMON_FLAGS="-O2 -pipe"
+CFLAGS="${COMMON_FLAGS}"
+USE="X
+    -systemd"
+MAKEOPTS='-j8'
+MAKEOPTS="$MAKEOPTS -l8" # keep the desktop responsive
+export PORTAGE_NICENESS=15
+source /etc/portage/make.conf.local
+EMERGE_DEFAULT_OPTS="--jobs=2 \
+    --load-average=8"
+"#,
+        )?;
+        assert_eq!(
+            make_conf,
+            MakeConf {
+                makeopts: vec!["-j8".to_string(), "-l8".to_string()],
+                niceness: Some(15),
+            }
+        );
+        assert!(MakeConf::parse("")?.is_empty());
+        let err = MakeConf::parse("PORTAGE_NICENESS=low").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Config);
+        Ok(())
+    }
+
+    #[test]
+    fn read_make_conf_dir() -> Result<(), JanitorError> {
+        assert!(read_make_conf(&MemoryFileSystem::new())?.is_empty());
+        // Read in name order, so the later file's MAKEOPTS wins
+        let fs = MemoryFileSystem::new()
+            .with_contents(&Path::new(MAKE_CONF).join("10-local"), "MAKEOPTS=\"-j4\"\n")
+            .with_contents(
+                &Path::new(MAKE_CONF).join("00-base"),
+                "MAKEOPTS=\"-j2\"\nPORTAGE_NICENESS=5\n",
+            )
+            .with_dir(&Path::new(MAKE_CONF).join("unused.d"));
+        assert_eq!(
+            read_make_conf(&fs)?,
+            MakeConf {
+                makeopts: vec!["-j4".to_string()],
+                niceness: Some(5),
+            }
+        );
+        Ok(())
+    }
+}
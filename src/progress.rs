@@ -0,0 +1,153 @@
+use std::{
+    fs, io,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{info, utils};
+
+/// Report every time the estimate moves by this many percent
+const REPORT_STEP_PERCENT: usize = 5;
+/// Without an estimate, report every this many objects
+const REPORT_STEP_OBJECTS: usize = 1000;
+
+struct State {
+    compiled: usize,
+    last_report: usize,
+}
+
+/// Estimates how far along a kernel build is by counting the `CC` lines kbuild prints
+/// The expected total comes from how many objects a previous build produced
+pub struct BuildProgress {
+    expected: Option<usize>,
+    started: Instant,
+    state: Mutex<State>,
+}
+
+/// kbuild prints `  CC      kernel/fork.o` or `  CC [M]  fs/foo.o` for each compiled object
+fn is_compile_line(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with("CC ") && line.ends_with(".o")
+}
+
+/// Number of object files under a previously built source directory
+pub fn count_objects(dir: &Path) -> io::Result<usize> {
+    let mut count = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            count += count_objects(&entry.path())?;
+        } else if file_type.is_file() && entry.file_name().to_string_lossy().ends_with(".o") {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+impl BuildProgress {
+    pub fn new(expected: Option<usize>) -> BuildProgress {
+        BuildProgress {
+            expected: expected.filter(|&e| e > 0),
+            started: Instant::now(),
+            state: Mutex::new(State {
+                compiled: 0,
+                last_report: 0,
+            }),
+        }
+    }
+
+    /// Feed one line of `make` output, occasionally logs the progress
+    pub fn observe(&self, line: &str) {
+        if !is_compile_line(line) {
+            return;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.compiled += 1;
+        if let Some(report) = self.report(state.compiled, state.last_report, self.started.elapsed())
+        {
+            state.last_report = state.compiled;
+            info!("{}", report);
+        }
+    }
+
+    /// A progress message if enough was compiled since the last one
+    fn report(&self, compiled: usize, last_report: usize, elapsed: Duration) -> Option<String> {
+        let expected = match self.expected {
+            Some(e) => e,
+            None if compiled / REPORT_STEP_OBJECTS > last_report / REPORT_STEP_OBJECTS => {
+                return Some(format!(
+                    "Build progress: {} objects compiled in {}",
+                    compiled,
+                    utils::format_duration(elapsed)
+                ))
+            }
+            None => return None,
+        };
+        // The estimate can be off, never claim to be done before make is
+        let percent = |n: usize| (n * 100 / expected).min(99);
+        if percent(compiled) / REPORT_STEP_PERCENT <= percent(last_report) / REPORT_STEP_PERCENT {
+            return None;
+        }
+        let remaining = expected.saturating_sub(compiled) as u32;
+        let eta = elapsed / compiled as u32 * remaining;
+        Some(format!(
+            "Build progress: {}% ({}/~{} objects), about {} left",
+            percent(compiled),
+            compiled,
+            expected,
+            utils::format_duration(eta)
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn compile_lines() {
+        assert!(is_compile_line("  CC      kernel/fork.o"));
+        assert!(is_compile_line("  CC [M]  fs/btrfs/inode.o"));
+        assert!(!is_compile_line("  LD      vmlinux.o"));
+        assert!(!is_compile_line("  CC      scripts/mod/empty.s"));
+    }
+
+    #[test]
+    fn report_with_estimate() {
+        let progress = BuildProgress::new(Some(200));
+        assert_eq!(progress.report(9, 0, Duration::from_secs(9)), None);
+        assert_eq!(
+            progress.report(10, 0, Duration::from_secs(10)),
+            Some("Build progress: 5% (10/~200 objects), about 3m 10s left".to_string())
+        );
+        assert_eq!(progress.report(19, 10, Duration::from_secs(19)), None);
+        // Overshooting the estimate caps the percentage
+        let report = progress.report(300, 100, Duration::from_secs(300)).unwrap();
+        assert!(report.starts_with("Build progress: 99%"), "{}", report);
+    }
+
+    #[test]
+    fn report_without_estimate() {
+        let progress = BuildProgress::new(None);
+        assert_eq!(progress.report(999, 0, Duration::from_secs(1)), None);
+        assert_eq!(
+            progress.report(1000, 0, Duration::from_secs(61)),
+            Some("Build progress: 1000 objects compiled in 1m 1s".to_string())
+        );
+    }
+
+    #[test]
+    fn count_built_objects() {
+        let test_dir = TestDir::new();
+        let dir = test_dir.src_path().join("built/kernel");
+        fs::create_dir_all(&dir).unwrap();
+        for name in &["fork.o", "exit.o", "fork.c", "built-in.a"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let count = count_objects(&test_dir.src_path().join("built"));
+        assert_eq!(count.unwrap(), 2);
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    ffi::CStr,
+    fs::{File, OpenOptions},
+    io,
+    os::{
+        raw::{c_int, c_ulong, c_ushort},
+        unix::io::{AsRawFd, FromRawFd},
+    },
+};
+
+use crate::sys::{grantpt, ioctl, posix_openpt, ptsname, unlockpt};
+
+const O_RDWR: c_int = 0o2;
+const O_NOCTTY: c_int = 0o400;
+const TIOCGWINSZ: c_ulong = 0x5413;
+const TIOCSWINSZ: c_ulong = 0x5414;
+
+#[repr(C)]
+#[derive(Default)]
+struct WinSize {
+    rows: c_ushort,
+    cols: c_ushort,
+    x_pixels: c_ushort,
+    y_pixels: c_ushort,
+}
+
+/// Both ends of a pseudo-terminal
+/// Commands write to `secondary` and think they're talking to a terminal,
+/// the janitor reads what they wrote from `primary`
+pub struct Pty {
+    pub primary: File,
+    pub secondary: File,
+}
+
+fn check(ret: c_int) -> io::Result<c_int> {
+    match ret {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(ret),
+    }
+}
+
+/// Open a new pty sized like the janitor's own terminal
+pub fn open() -> io::Result<Pty> {
+    let primary = unsafe {
+        let fd = check(posix_openpt(O_RDWR | O_NOCTTY))?;
+        File::from_raw_fd(fd)
+    };
+    let secondary_name = unsafe {
+        check(grantpt(primary.as_raw_fd()))?;
+        check(unlockpt(primary.as_raw_fd()))?;
+        let name = ptsname(primary.as_raw_fd());
+        if name.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        CStr::from_ptr(name).to_string_lossy().to_string()
+    };
+    let secondary = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&secondary_name)?;
+
+    // Progress bars need to know how wide the terminal is, a failure just means no resize
+    let mut size = WinSize::default();
+    unsafe {
+        if ioctl(
+            io::stdout().as_raw_fd(),
+            TIOCGWINSZ,
+            &mut size as *mut WinSize,
+        ) == 0
+        {
+            ioctl(primary.as_raw_fd(), TIOCSWINSZ, &size as *const WinSize);
+        }
+    }
+    Ok(Pty { primary, secondary })
+}
+
+/// Remove terminal escape sequences so captured output reads cleanly in errors
+pub fn strip_escapes(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\u{1b}' => {
+                // CSI sequences end with a byte in the range '@'..='~'
+                if chars.peek() == Some(&'[') {
+                    chars.next();
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
+                } else {
+                    chars.next();
+                }
+            }
+            '\r' => (),
+            c => stripped.push(c),
+        }
+    }
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn strip_color_codes() {
+        assert_eq!(
+            strip_escapes("\u{1b}[01;31merror:\u{1b}[m bad\r"),
+            "error: bad"
+        );
+    }
+
+    #[test]
+    fn open_pty() {
+        let mut pty = open().unwrap();
+        pty.secondary.write_all(b"hello\n").unwrap();
+        let mut buf = [0u8; 16];
+        let n = io::Read::read(&mut pty.primary, &mut buf).unwrap();
+        // The terminal translates the newline to \r\n
+        assert_eq!(&buf[..n], b"hello\r\n");
+    }
+}
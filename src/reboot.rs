@@ -0,0 +1,185 @@
+use std::{
+    io::{self, BufRead, IsTerminal},
+    process::Command,
+    str::FromStr,
+};
+
+use crate::{
+    error::JanitorError, info, kernel::KernelVersion, update::RunCmdConfig, utils, JanitorErrorFrom,
+};
+
+/// What to do once a new kernel is installed and nothing it needs is missing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RebootPolicy {
+    /// Only say that a reboot is needed
+    Never,
+    /// Ask whether to reboot right away, when there's someone to ask
+    Prompt,
+    /// Have `shutdown -r` reboot at this time of day, like `03:00`
+    Schedule(String),
+}
+
+impl FromStr for RebootPolicy {
+    type Err = JanitorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            JanitorErrorFrom!(
+                "Unknown reboot policy {}, expected one of never, prompt, schedule:HH:MM",
+                s
+            )
+        };
+        match s.to_ascii_lowercase().as_str() {
+            "never" => Ok(RebootPolicy::Never),
+            "prompt" => Ok(RebootPolicy::Prompt),
+            policy => {
+                let at = policy.strip_prefix("schedule:").ok_or_else(invalid)?;
+                let (hour, minute) = at.split_once(':').ok_or_else(invalid)?;
+                match (hour.parse::<u8>(), minute.parse::<u8>()) {
+                    (Ok(hour), Ok(minute)) if hour < 24 && minute < 60 && minute_digits(at) => {
+                        Ok(RebootPolicy::Schedule(format!("{:02}:{:02}", hour, minute)))
+                    }
+                    _ => Err(invalid()),
+                }
+            }
+        }
+    }
+}
+
+/// `3:5` is ambiguous, `03:05` and `3:05` aren't
+fn minute_digits(at: &str) -> bool {
+    at.split_once(':')
+        .is_some_and(|(_, minute)| minute.len() == 2)
+}
+
+/// `shutdown -r` at `when`, which is `now` or a time of day
+fn shutdown(
+    config: &RunCmdConfig,
+    when: &str,
+    version: &KernelVersion,
+) -> Result<(), JanitorError> {
+    let mut shutdown = Command::new("shutdown");
+    shutdown
+        .arg("-r")
+        .arg(when)
+        .arg(format!("kernel-janitor: rebooting into {}", version));
+    utils::exec_and_print_command(
+        &mut config.privileged(shutdown),
+        format!("\'shutdown -r {}\'", when),
+        config,
+    )
+}
+
+/// Whether the answer read from `reader` is yes
+fn confirmed<R: BufRead>(mut reader: R) -> Result<bool, JanitorError> {
+    let mut answer = String::new();
+    reader.read_line(&mut answer)?;
+    Ok(answer.trim().to_ascii_lowercase().starts_with('y'))
+}
+
+/// A reboot that was agreed to but waits until the run has been recorded in the history and
+/// the report, which would be lost otherwise
+pub struct PendingReboot {
+    config: RunCmdConfig,
+    version: KernelVersion,
+}
+
+impl PendingReboot {
+    /// `shutdown -r now`, the last thing a run does
+    pub fn run(self) -> Result<(), JanitorError> {
+        shutdown(&self.config, "now", &self.version)
+    }
+}
+
+/// Reboot into `version` the way `policy` says to, after a run that installed it
+/// Rebooting right away is left to the caller, as the returned `PendingReboot`
+pub fn after_update(
+    config: RunCmdConfig,
+    policy: &RebootPolicy,
+    version: &KernelVersion,
+) -> Result<Option<PendingReboot>, JanitorError> {
+    match policy {
+        RebootPolicy::Never => info!("Reboot to start using {}", version),
+        RebootPolicy::Prompt => {
+            if config.pretending() || !io::stdin().is_terminal() {
+                info!("Reboot to start using {}", version);
+                return Ok(None);
+            }
+            println!("Reboot into {} now? (y/n)", version);
+            match confirmed(io::stdin().lock())? {
+                true => {
+                    return Ok(Some(PendingReboot {
+                        config,
+                        version: version.clone(),
+                    }))
+                }
+                false => info!("Reboot to start using {}", version),
+            }
+        }
+        RebootPolicy::Schedule(at) => {
+            shutdown(&config, at, version)?;
+            info!(
+                "Rebooting into {} at {}, cancel it with \'shutdown -c\'",
+                version, at
+            );
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        runner::RecordingRunner,
+        update::{InteractiveStatus, PretendStatus},
+    };
+    use std::convert::TryFrom;
+
+    #[test]
+    fn reboot_policies() -> Result<(), JanitorError> {
+        assert_eq!("never".parse::<RebootPolicy>()?, RebootPolicy::Never);
+        assert_eq!("Prompt".parse::<RebootPolicy>()?, RebootPolicy::Prompt);
+        assert_eq!(
+            "schedule:3:00".parse::<RebootPolicy>()?,
+            RebootPolicy::Schedule("03:00".to_string())
+        );
+        for invalid in &[
+            "always",
+            "schedule",
+            "schedule:24:00",
+            "schedule:03:5",
+            "schedule:3",
+        ] {
+            assert!(invalid.parse::<RebootPolicy>().is_err(), "{}", invalid);
+        }
+        assert!(confirmed("yes\n".as_bytes())?);
+        assert!(!confirmed("\n".as_bytes())?);
+
+        let runner = RecordingRunner::new();
+        let cfg = || {
+            RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+                .with_runner(runner.clone())
+        };
+        let version = KernelVersion::try_from("5.11.8-gentoo").unwrap();
+        assert!(after_update(cfg(), &RebootPolicy::Never, &version)?.is_none());
+        assert!(runner.command_lines().is_empty());
+        assert!(after_update(cfg(), &"schedule:03:00".parse()?, &version)?.is_none());
+        assert_eq!(
+            runner.command_lines(),
+            vec!["shutdown -r 03:00 kernel-janitor: rebooting into 5.11.8-gentoo"]
+        );
+        // Only run once the caller is done with everything else
+        let pending = PendingReboot {
+            config: cfg(),
+            version: version.clone(),
+        };
+        assert_eq!(runner.command_lines().len(), 1);
+        pending.run()?;
+        assert_eq!(
+            runner.command_lines()[1],
+            "shutdown -r now kernel-janitor: rebooting into 5.11.8-gentoo"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,319 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{Context, JanitorError},
+    info,
+    kernel::KernelVersion,
+    summary::{self, RunReport},
+    utils, warn, JanitorErrorFrom,
+};
+
+/// What the report at `ReportPath` is written as
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ReportFormat {
+    Markdown,
+    Html,
+}
+
+/// Where the report goes, how it's written and whether runs that changed nothing skip it
+static REPORT: OnceLock<(PathBuf, ReportFormat, bool)> = OnceLock::new();
+
+impl FromStr for ReportFormat {
+    type Err = JanitorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "markdown" | "md" => Ok(ReportFormat::Markdown),
+            "html" => Ok(ReportFormat::Html),
+            _ => Err(JanitorErrorFrom!(
+                "Unknown report format {}, expected one of markdown, html",
+                s
+            )),
+        }
+    }
+}
+
+impl ReportFormat {
+    /// Used when `ReportFormat` isn't set, HTML for `.html` and `.htm` files
+    pub fn from_path(path: &Path) -> ReportFormat {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("html") | Some("htm") => ReportFormat::Html,
+            _ => ReportFormat::Markdown,
+        }
+    }
+}
+
+/// A report is written to `path` at the end of the run from now on
+/// With `only_on_change` the last one is kept until a run fails or changes something
+pub fn init(path: &Path, format: ReportFormat, only_on_change: bool) {
+    let _ = REPORT.set((path.to_path_buf(), format, only_on_change));
+}
+
+/// Whether a run that found nothing to do still has something to tell
+fn noteworthy(report: &RunReport) -> bool {
+    let changed = report.built.is_some() || !report.removed.is_empty() || !report.pruned.is_empty();
+    report.error.is_some() || !report.degraded.is_empty() || (changed && !report.pretend)
+}
+
+fn outcome(report: &RunReport) -> String {
+    let outcome = match &report.error {
+        Some(error) => format!("failed: {}", error.lines().next().unwrap_or_default()),
+        None if !report.degraded.is_empty() => {
+            format!("degraded, {}", report.degraded.join(", "))
+        }
+        None => "success".to_string(),
+    };
+    match report.pretend {
+        true => format!("{} (pretend, nothing was changed)", outcome),
+        false => outcome,
+    }
+}
+
+fn versions(kernels: &[(KernelVersion, u64)]) -> Vec<(String, String)> {
+    kernels
+        .iter()
+        .map(|(version, bytes)| (version.to_string(), utils::format_bytes(*bytes)))
+        .collect()
+}
+
+pub fn render_markdown(report: &RunReport, timestamp: u64) -> String {
+    let mut lines = vec![
+        format!(
+            "# kernel-janitor run, {} UTC",
+            utils::format_timestamp(timestamp)
+        ),
+        String::new(),
+        format!("**Outcome:** {}", outcome(report)),
+        String::new(),
+    ];
+    if let Some(error) = &report.error {
+        lines.push("```".to_string());
+        lines.extend(error.lines().map(str::to_string));
+        lines.push("```".to_string());
+        lines.push(String::new());
+    }
+    lines.push("## Stages".to_string());
+    lines.push(String::new());
+    lines.push("| Stage | Result | Time |".to_string());
+    lines.push("| --- | --- | --- |".to_string());
+    for (stage, result, time) in &report.stages {
+        lines.push(format!("| {} | {} | {} |", stage, result, time));
+    }
+    lines.push(String::new());
+    lines.push("## Kernels".to_string());
+    lines.push(String::new());
+    if let Some(built) = &report.built {
+        lines.push(format!("- Built {}", built));
+    }
+    for (version, bytes) in versions(&report.removed) {
+        lines.push(format!("- Removed {}, {} freed", version, bytes));
+    }
+    for (version, bytes) in versions(&report.pruned) {
+        lines.push(format!(
+            "- Pruned the sources of {}, {} freed",
+            version, bytes
+        ));
+    }
+    lines.push(format!(
+        "- {} freed in total",
+        utils::format_bytes(report.bytes_freed)
+    ));
+    lines.push(String::new());
+    lines.push(format!("## Warnings ({})", report.warnings.len()));
+    lines.push(String::new());
+    for warning in &report.warnings {
+        lines.push(format!("- {}", warning));
+    }
+    lines.join("\n").trim_end().to_string() + "\n"
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn render_html(report: &RunReport, timestamp: u64) -> String {
+    let title = format!(
+        "kernel-janitor run, {} UTC",
+        utils::format_timestamp(timestamp)
+    );
+    let mut lines = vec![
+        "<!DOCTYPE html>".to_string(),
+        "<html>".to_string(),
+        format!(
+            "<head><meta charset=\"utf-8\"><title>{}</title></head>",
+            title
+        ),
+        "<body>".to_string(),
+        format!("<h1>{}</h1>", title),
+        format!(
+            "<p><strong>Outcome:</strong> {}</p>",
+            html_escape(&outcome(report))
+        ),
+    ];
+    if let Some(error) = &report.error {
+        lines.push(format!("<pre>{}</pre>", html_escape(error)));
+    }
+    lines.push("<h2>Stages</h2>".to_string());
+    lines.push("<table>".to_string());
+    lines.push("<tr><th>Stage</th><th>Result</th><th>Time</th></tr>".to_string());
+    for (stage, result, time) in &report.stages {
+        lines.push(format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+            stage, result, time
+        ));
+    }
+    lines.push("</table>".to_string());
+    lines.push("<h2>Kernels</h2>".to_string());
+    lines.push("<ul>".to_string());
+    if let Some(built) = &report.built {
+        lines.push(format!("<li>Built {}</li>", built));
+    }
+    for (version, bytes) in versions(&report.removed) {
+        lines.push(format!("<li>Removed {}, {} freed</li>", version, bytes));
+    }
+    for (version, bytes) in versions(&report.pruned) {
+        lines.push(format!(
+            "<li>Pruned the sources of {}, {} freed</li>",
+            version, bytes
+        ));
+    }
+    lines.push(format!(
+        "<li>{} freed in total</li>",
+        utils::format_bytes(report.bytes_freed)
+    ));
+    lines.push("</ul>".to_string());
+    lines.push(format!("<h2>Warnings ({})</h2>", report.warnings.len()));
+    if !report.warnings.is_empty() {
+        lines.push("<ul>".to_string());
+        for warning in &report.warnings {
+            lines.push(format!("<li>{}</li>", html_escape(warning)));
+        }
+        lines.push("</ul>".to_string());
+    }
+    lines.push("</body>".to_string());
+    lines.push("</html>".to_string());
+    lines.join("\n") + "\n"
+}
+
+fn write_to(path: &Path, contents: &str) -> Result<(), JanitorError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    fs::write(path, contents).with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Write the report of the run that just finished, if one is configured and anything was attempted
+/// Called once at the very end, after `init`
+pub fn write(error: Option<&JanitorError>) {
+    let ((path, format, only_on_change), report) = match (REPORT.get(), summary::run_report(error))
+    {
+        (Some(target), Some(report)) => (target, report),
+        _ => return,
+    };
+    if *only_on_change && !noteworthy(&report) {
+        return;
+    }
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let contents = match format {
+        ReportFormat::Markdown => render_markdown(&report, timestamp),
+        ReportFormat::Html => render_html(&report, timestamp),
+    };
+    match write_to(path, &contents) {
+        Ok(()) => info!("Wrote the report of this run to {}", path.display()),
+        Err(e) => warn!("Could not write the report of this run: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> RunReport {
+        RunReport {
+            pretend: false,
+            error: None,
+            degraded: Vec::new(),
+            stages: vec![(
+                "build".to_string(),
+                "done".to_string(),
+                "20m 0s".to_string(),
+            )],
+            built: Some(KernelVersion::new(5, 11, 8, None, false)),
+            removed: vec![(KernelVersion::new(5, 4, 97, None, false), 2048)],
+            pruned: Vec::new(),
+            bytes_freed: 2048,
+            warnings: vec!["<careful> | twice".to_string()],
+        }
+    }
+
+    #[test]
+    fn markdown_and_html() {
+        let markdown = render_markdown(&report(), 86400);
+        assert!(
+            markdown.starts_with(
+                "# kernel-janitor run, 1970-01-02 00:00 UTC\n\n**Outcome:** success\n"
+            ),
+            "{}",
+            markdown
+        );
+        assert!(
+            markdown.contains("| build | done | 20m 0s |\n"),
+            "{}",
+            markdown
+        );
+        assert!(
+            markdown.contains("- Removed 5.4.97, 2.0 KiB freed\n"),
+            "{}",
+            markdown
+        );
+        assert!(markdown.ends_with("- <careful> | twice\n"), "{}", markdown);
+
+        let html = render_html(&report(), 86400);
+        assert!(
+            html.contains("<li>&lt;careful&gt; | twice</li>"),
+            "{}",
+            html
+        );
+        assert!(html.contains("<tr><td>build</td><td>done</td><td>20m 0s</td></tr>"));
+    }
+
+    #[test]
+    fn only_on_change() {
+        let mut report = report();
+        assert!(noteworthy(&report));
+        report.pretend = true;
+        assert!(!noteworthy(&report));
+        report.built = None;
+        report.removed.clear();
+        report.pretend = false;
+        assert!(!noteworthy(&report));
+        report.error = Some("build failed".to_string());
+        assert!(noteworthy(&report));
+    }
+
+    #[test]
+    fn format_from_path() {
+        assert_eq!(
+            ReportFormat::from_path(Path::new("/srv/www/kernel.html")),
+            ReportFormat::Html
+        );
+        assert_eq!(
+            ReportFormat::from_path(Path::new("/var/lib/kernel-janitor/report.md")),
+            ReportFormat::Markdown
+        );
+        assert_eq!("HTML".parse::<ReportFormat>().unwrap(), ReportFormat::Html);
+    }
+}
@@ -0,0 +1,683 @@
+use kernel_janitor::{
+    bootloader, checksums, debug,
+    doctor::{self, RunKind},
+    error::{ErrorKind, JanitorError},
+    info,
+    inventory::KernelInventory,
+    kernel::{self, KernelVersion},
+    mount, patches,
+    pipeline::{self, Step},
+    reboot, signing,
+    snapshot::{self, SnapshotKind},
+    summary, update, utils, warn, JanitorErrorFrom,
+};
+use std::path::{Path, PathBuf};
+use update::{BuildUser, RunCmdConfig, Stage};
+
+use crate::{cli::ParseResults, setup::Setup};
+
+/// Refuse flags that contradict each other and runs that can't write to the install path
+pub fn check_flags(parsed_results: &ParseResults, setup: &Setup) -> Result<(), JanitorError> {
+    let build_only = parsed_results.flag_enabled("build_only");
+    let install_only = parsed_results.flag_enabled("install_only");
+    if build_only || install_only {
+        let conflicting = [
+            ("--install-only", build_only && install_only),
+            ("--rebuild", parsed_results.flag_enabled("rebuild")),
+            (
+                "--delete",
+                parsed_results.flag_enabled("delete_interactive"),
+            ),
+            ("--skip-build", parsed_results.flag_enabled("skip_build")),
+        ];
+        if let Some((flag, _)) = conflicting.iter().find(|(_, given)| *given) {
+            return Err(JanitorError::new(
+                ErrorKind::Config,
+                format!(
+                    "{} can't be combined with {}",
+                    match build_only {
+                        true => "--build-only",
+                        false => "--install-only",
+                    },
+                    flag
+                ),
+            ));
+        }
+    }
+
+    let cmd_config = &setup.cmd_config;
+    if !cmd_config.pretending()
+        && !build_only
+        && !setup.user_is_root
+        && cmd_config.escalation.is_none()
+    {
+        return Err(JanitorError::new(ErrorKind::NotRoot, "User is not root and \'pretend\' isn\'t specified. Try running with \'-p\' or \'--pretend\' or set EscalationCommand in the config. Exiting..."));
+    }
+    Ok(())
+}
+
+/// The build drops root when the config names a user to compile as
+pub fn with_build_user(mut setup: Setup) -> Result<Setup, JanitorError> {
+    if setup.config.contains("BuildUser") && setup.user_is_root {
+        let name = setup.config.get_string("BuildUser")?;
+        let (uid, gid) = utils::lookup_user(&name)?;
+        info!("Compiling as user {}", name);
+        setup.cmd_config = setup
+            .cmd_config
+            .with_build_user(Some(BuildUser { name, uid, gid }));
+    }
+    Ok(setup)
+}
+
+/// Delete, clean up, or build and install the newest kernel
+pub fn run(
+    parsed_results: &ParseResults,
+    mut setup: Setup,
+) -> Result<Option<reboot::PendingReboot>, JanitorError> {
+    let build_only = parsed_results.flag_enabled("build_only");
+    let install_only = parsed_results.flag_enabled("install_only");
+    let skip_build = parsed_results.flag_enabled("skip_build");
+    let skipped = preflight(parsed_results, &setup)?;
+
+    // Everything from here on may write to the install path, except a build on its own
+    let config = &setup.config;
+    let remount_boot = config.contains("RemountBoot") && config.get_bool("RemountBoot")?;
+    let restore_read_only = match config.contains("RestoreReadOnlyBoot") {
+        true => config.get_bool("RestoreReadOnlyBoot")?,
+        false => true,
+    };
+    let _boot_remount = match build_only {
+        true => None,
+        false => Some(mount::BootMount::ensure_writable(
+            &setup.cmd_config,
+            &setup.install_path,
+            remount_boot,
+            restore_read_only,
+        )?),
+    };
+
+    let snapshot_kind = match config.contains("Snapshot") {
+        true => Some(config.get_string("Snapshot")?.parse::<SnapshotKind>()?),
+        false => None,
+    };
+
+    if parsed_results.flag_enabled("delete_interactive") {
+        take_snapshot(
+            &setup.cmd_config,
+            &setup.install_path,
+            snapshot_kind,
+            "delete",
+        )?;
+        update::run_stage(Stage::Delete, None, || {
+            update::delete_interactive(&setup.cmd_config, &mut setup.inventory)
+        })?;
+        // Forgets the checksums of what was deleted
+        forget_checksums(&setup)?;
+        return Ok(None);
+    }
+
+    if parsed_results.flag_enabled("clean_only") {
+        take_snapshot(
+            &setup.cmd_config,
+            &setup.install_path,
+            snapshot_kind,
+            "cleanup",
+        )?;
+        update::run_stage(Stage::Cleanup, None, || {
+            update::cleanup_old_installs(
+                &setup.cmd_config,
+                setup.cleanup_policy.as_ref(),
+                &mut setup.inventory,
+            )
+        })?;
+        forget_checksums(&setup)?;
+        record_installed_size(&setup.inventory);
+        return Ok(None);
+    }
+
+    for kernel in setup.inventory.incomplete_sources() {
+        warn!(
+            "Skipping {:?}, it's empty or has no Makefile. Was its emerge or unpack interrupted?",
+            kernel
+                .source_path
+                .as_deref()
+                .unwrap_or_else(|| Path::new(""))
+        );
+    }
+
+    let rebuild = match parsed_results.flag_enabled("rebuild") {
+        true => Some(rebuild_target(
+            &setup.inventory,
+            parsed_results.option_value("rebuild"),
+        )?),
+        false => None,
+    };
+
+    if parsed_results.flag_enabled("incremental") && rebuild.is_none() {
+        warn!("--incremental only changes --rebuild, new kernels are built from the tree as it is");
+    }
+    if let Some((version, src_dir)) = &rebuild {
+        info!(
+            "Rebuilding {} with the config already in {:?}",
+            version, src_dir
+        );
+        summary::record_skipped(Stage::CopyConfig);
+    } else if install_only {
+        info!("Installing the kernel that was built with --build-only");
+        summary::record_skipped(Stage::CopyConfig);
+    } else if skip_build {
+        info!("Not building, --skip-build was given");
+        summary::record_skipped(Stage::CopyConfig);
+    } else {
+        copy_newest_config(parsed_results, &setup)?;
+    }
+
+    let (newest_version, newest_source_dir) = newest_source(&setup, rebuild.as_ref())?;
+    // The previous kernel's build tree tells roughly how many objects this build compiles
+    // A rebuild compiles as many as it did last time
+    let previous_source_dir = match &rebuild {
+        Some(_) => Some(newest_source_dir.clone()),
+        None => setup
+            .inventory
+            .kernels()
+            .iter()
+            .rev()
+            .skip(1)
+            .find_map(|k| k.source_path.clone()),
+    };
+    // The tree was patched when it was built
+    patch(
+        &setup,
+        &newest_version,
+        &newest_source_dir,
+        install_only || skip_build,
+    )?;
+    if build_only {
+        update::run_stage(Stage::Build, Some(&newest_version), || {
+            update::check_source_version(
+                setup.cmd_config.fs.as_ref(),
+                &newest_source_dir,
+                &newest_version,
+            )?;
+            update::compile_kernel(
+                &setup.cmd_config,
+                &newest_source_dir,
+                previous_source_dir.as_deref(),
+            )
+        })?;
+        summary::record_built(newest_version.clone());
+        info!(
+            "Built {}, install it with --install-only when it's convenient",
+            newest_version
+        );
+        return Ok(None);
+    }
+    take_snapshot(
+        &setup.cmd_config,
+        &setup.install_path,
+        snapshot_kind,
+        "install",
+    )?;
+    if skip_build {
+        summary::record_skipped(Stage::Build);
+    } else {
+        update::run_stage(Stage::Build, Some(&newest_version), || {
+            update::check_source_version(
+                setup.cmd_config.fs.as_ref(),
+                &newest_source_dir,
+                &newest_version,
+            )?;
+            match &rebuild {
+                Some(_) => update::rebuild_kernel(
+                    &setup.cmd_config,
+                    &newest_source_dir,
+                    previous_source_dir.as_deref(),
+                    &setup.install_path,
+                    &setup.module_path,
+                    parsed_results.flag_enabled("incremental"),
+                ),
+                None if install_only => update::install_built_kernel(
+                    &setup.cmd_config,
+                    &newest_source_dir,
+                    &setup.install_path,
+                ),
+                None => update::build_kernel(
+                    &setup.cmd_config,
+                    &newest_source_dir,
+                    previous_source_dir.as_deref(),
+                    &setup.install_path,
+                ),
+            }?;
+            update::record_pkgbase(&setup.cmd_config, &newest_source_dir, &setup.module_path)
+        })?;
+        summary::record_built(newest_version.clone());
+    }
+
+    // A rebuild doesn't add a kernel, so there's nothing new to make room for
+    let cleanup = rebuild.is_none() && !skipped.contains(&Stage::Cleanup);
+    let missing_modules = run_pipeline(
+        &mut setup,
+        &skipped,
+        cleanup,
+        snapshot_kind,
+        &newest_version,
+        &newest_source_dir,
+    )?;
+    let all_initramfs = parsed_results.flag_enabled("all_initramfs");
+    if all_initramfs {
+        match &setup.cmd_config.initramfs {
+            Some(initramfs) => update::run_stage(Stage::RetainedInitramfs, None, || {
+                update::generate_retained_initramfs(
+                    &setup.cmd_config,
+                    initramfs.as_ref(),
+                    &setup.inventory,
+                    &newest_version,
+                )
+            })?,
+            None => warn!("--all-initramfs does nothing without an InitramfsGenerator"),
+        }
+    }
+    record_checksums(&setup, &newest_version, all_initramfs)?;
+    record_installed_size(&setup.inventory);
+
+    if !setup.cmd_config.pretending() {
+        match missing_modules.is_empty() {
+            true => {
+                return reboot::after_update(
+                    setup.cmd_config,
+                    &setup.reboot_policy,
+                    &newest_version,
+                )
+            }
+            false => warn!(
+                "Don't reboot into {} yet, it can't load {}. Rebuild the packages that provide them first",
+                newest_version,
+                missing_modules
+                    .iter()
+                    .map(|m| format!("{}.ko", m))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    Ok(None)
+}
+
+/// Everything from here on runs tools, better to hear about every missing one now than about
+/// the first one after the build. Pretending runs none of them
+/// Returns the stages the --skip flags leave out
+fn preflight(parsed_results: &ParseResults, setup: &Setup) -> Result<Vec<Stage>, JanitorError> {
+    let skip_build = parsed_results.flag_enabled("skip_build");
+    let run_kind = match (
+        parsed_results.flag_enabled("build_only"),
+        parsed_results.flag_enabled("install_only"),
+    ) {
+        (true, _) => RunKind::BuildOnly,
+        (_, true) => RunKind::InstallOnly,
+        _ if parsed_results.flag_enabled("delete_interactive") => RunKind::Delete,
+        _ if parsed_results.flag_enabled("clean_only") => RunKind::Clean,
+        _ => RunKind::Update,
+    };
+    // Stages the --skip flags leave out, for re-running after one of the later ones failed
+    let mut skipped = Vec::new();
+    if skip_build {
+        skipped.extend([Stage::CopyConfig, Stage::Patch, Stage::Build]);
+    }
+    if parsed_results.flag_enabled("skip_initramfs") {
+        skipped.push(Stage::Initramfs);
+    }
+    if parsed_results.flag_enabled("skip_bootloader") {
+        skipped.push(Stage::Bootloader);
+    }
+    if parsed_results.flag_enabled("skip_cleanup") {
+        skipped.push(Stage::Cleanup);
+    }
+    if let Err(e) = doctor::preflight(&setup.config, run_kind, &skipped) {
+        match setup.cmd_config.pretending() {
+            true => warn!("{}", e),
+            false => return Err(e),
+        }
+    }
+    Ok(skipped)
+}
+
+/// Recorded in the history so there's something to roll back to
+fn take_snapshot(
+    cmd_config: &RunCmdConfig,
+    install_path: &Path,
+    kind: Option<SnapshotKind>,
+    label: &str,
+) -> Result<(), JanitorError> {
+    if let Some(kind) = kind {
+        summary::record_snapshots(snapshot::take(cmd_config, kind, install_path, label)?);
+    }
+    Ok(())
+}
+
+/// Rewrite the checksums and their signature after kernels were removed
+fn forget_checksums(setup: &Setup) -> Result<(), JanitorError> {
+    if let Err(e) = checksums::record(&setup.cmd_config, &setup.state_dir, &[]) {
+        warn!("Could not update the checksums: {}", e);
+    }
+    if let Some(key) = &setup.signing_key {
+        signing::sign(
+            &setup.cmd_config,
+            key,
+            &[checksums::manifest_path(&setup.state_dir)],
+        )?;
+    }
+    Ok(())
+}
+
+fn record_installed_size(inventory: &KernelInventory) {
+    match inventory.total_size() {
+        Ok(bytes) => summary::record_installed_size(bytes),
+        Err(e) => debug!("Could not measure the installed kernels: {}", e),
+    }
+}
+
+/// The installed kernel `--rebuild` builds again and its source directory, the newest one with an
+/// image and sources when no release is given
+fn rebuild_target(
+    inventory: &KernelInventory,
+    release: Option<&str>,
+) -> Result<(KernelVersion, PathBuf), JanitorError> {
+    let kernel = match release {
+        Some(release) => inventory.by_release(release).ok_or_else(|| {
+            JanitorError::new(
+                ErrorKind::Search,
+                format!(
+                    "{} isn't installed, --rebuild only rebuilds installed kernels",
+                    release
+                ),
+            )
+        })?,
+        None => inventory
+            .kernels()
+            .iter()
+            .rfind(|k| !k.version.is_old() && k.vmlinuz_path.is_some() && k.source_path.is_some())
+            .ok_or_else(|| {
+                JanitorError::new(
+                    ErrorKind::Search,
+                    "No installed kernel has both an image and sources to rebuild",
+                )
+            })?,
+    };
+    match (&kernel.vmlinuz_path, &kernel.source_path) {
+        (Some(_), Some(src_dir)) => Ok((kernel.version.clone(), src_dir.clone())),
+        (None, _) => Err(JanitorError::new(
+            ErrorKind::Search,
+            format!("Kernel {} has no image to replace", kernel.version),
+        )),
+        (_, None) => Err(JanitorError::new(
+            ErrorKind::Search,
+            format!("Kernel {} doesn't have a source directory", kernel.version),
+        )),
+    }
+}
+
+/// Copy the newest installed config into the newest sources, unless it was edited by hand
+fn copy_newest_config(parsed_results: &ParseResults, setup: &Setup) -> Result<(), JanitorError> {
+    let inventory = &setup.inventory;
+    // Grab the newest config
+    // The last element is the newest kernel so search in reverse
+    let newest_config = inventory
+        .kernels()
+        .iter()
+        .rfind(|k| k.config_path.is_some())
+        .ok_or(JanitorError::new(
+            ErrorKind::Search,
+            format!(
+                "Could not find any kernels with an installed configuration file in {:?}",
+                setup.install_path
+            ),
+        ))?
+        .config_path
+        .clone()
+        .ok_or(JanitorErrorFrom!(
+            "config_path shouldn't have been empty, wtf"
+        ))?;
+
+    let newest_source_dir = inventory
+        .kernels()
+        .iter()
+        .rfind(|k| inventory.source_complete(k))
+        .ok_or(JanitorError::new(
+            ErrorKind::Search,
+            format!(
+                "Could not find any kernels with a source dir in {:?}",
+                setup.install_path
+            ),
+        ))?
+        .source_path
+        .clone()
+        .ok_or(JanitorErrorFrom!(
+            "source_path shouldn't have been empty, wtf"
+        ))?;
+
+    if parsed_results.flag_enabled("manual_edit") {
+        info!("Expecting a kernel config to be present in the newest kernel source directory");
+        summary::record_skipped(Stage::CopyConfig);
+    } else {
+        info!("Auto-copying config enabled");
+        update::run_stage(Stage::CopyConfig, None, || {
+            update::copy_config(&setup.cmd_config, &newest_config, &newest_source_dir)
+        })?;
+    }
+    Ok(())
+}
+
+/// The kernel this run builds and installs, and its source directory
+fn newest_source(
+    setup: &Setup,
+    rebuild: Option<&(KernelVersion, PathBuf)>,
+) -> Result<(KernelVersion, PathBuf), JanitorError> {
+    // Nested matches can't be the right thing to do
+    match rebuild {
+        Some((version, src_dir)) => Ok((version.clone(), src_dir.clone())),
+        None => match setup.inventory.newest_buildable() {
+            Some(newest_kernel) => match &newest_kernel.source_path {
+                Some(s) => Ok((newest_kernel.version.clone(), s.clone())),
+                None => Err(JanitorError::new(
+                    ErrorKind::Search,
+                    format!(
+                        "Kernel {} doesn't have a source directory in {:?}",
+                        newest_kernel.version, &setup.install_path
+                    ),
+                )),
+            },
+            None => Err(JanitorError::new(
+                ErrorKind::Search,
+                format!(
+                    "No installed kernels were found in {:?}",
+                    &setup.install_path
+                ),
+            )),
+        },
+    }
+}
+
+/// Apply the patches from PatchesDir and Portage's user patches, when either is configured
+fn patch(
+    setup: &Setup,
+    newest_version: &KernelVersion,
+    newest_source_dir: &Path,
+    already_built: bool,
+) -> Result<(), JanitorError> {
+    let config = &setup.config;
+    let patches_dir = match config.contains("PatchesDir") {
+        true => Some(config.get_path("PatchesDir")?),
+        false => None,
+    };
+    let portage_patches = config.contains("PortagePatches") && config.get_bool("PortagePatches")?;
+    if (patches_dir.is_none() && !portage_patches) || already_built {
+        return Ok(());
+    }
+    update::run_stage(Stage::Patch, Some(newest_version), || {
+        // The package's own user patches come first, like when emerging it
+        let mut patch_files = Vec::new();
+        if portage_patches {
+            patch_files.extend(patches::portage_patch_files(
+                Path::new(patches::PORTAGE_PATCHES),
+                newest_source_dir,
+            ));
+        }
+        if let Some(dir) = &patches_dir {
+            patch_files.extend(patches::patch_files(dir)?);
+        }
+        patches::apply(&setup.cmd_config, newest_source_dir, &patch_files)
+    })
+}
+
+/// The configured steps after the build, in order
+/// Returns the modules the new kernel can't load
+fn run_pipeline(
+    setup: &mut Setup,
+    skipped: &[Stage],
+    cleanup: bool,
+    snapshot_kind: Option<SnapshotKind>,
+    newest_version: &KernelVersion,
+    newest_source_dir: &Path,
+) -> Result<Vec<String>, JanitorError> {
+    let Setup {
+        cmd_config,
+        inventory,
+        pipeline,
+        cleanup_policy,
+        install_path,
+        module_path,
+        state_dir,
+        fallback,
+        grub_reboot_fallback,
+        rebuild_portage_modules,
+        ..
+    } = setup;
+    let mut missing_modules = Vec::new();
+    for step in pipeline.steps() {
+        match step {
+            Step::Builtin(Stage::ModuleRebuild) if *rebuild_portage_modules => {
+                update::run_stage(Stage::ModuleRebuild, Some(newest_version), || {
+                    update::rebuild_portage_modules(cmd_config)
+                })?
+            }
+            Step::Builtin(Stage::ModuleCheck) => {
+                missing_modules =
+                    update::run_stage(Stage::ModuleCheck, Some(newest_version), || {
+                        update::check_modules(cmd_config, newest_source_dir, module_path)
+                    })?
+            }
+            Step::Builtin(Stage::Initramfs) => match &cmd_config.initramfs {
+                Some(initramfs) if !skipped.contains(&Stage::Initramfs) => {
+                    update::run_stage(Stage::Initramfs, Some(newest_version), || {
+                        update::generate_initramfs(
+                            cmd_config,
+                            initramfs.as_ref(),
+                            newest_source_dir,
+                        )
+                    })?
+                }
+                _ => summary::record_skipped(Stage::Initramfs),
+            },
+            Step::Builtin(Stage::Bootloader) => match &cmd_config.bootloader {
+                Some(bootloader) if !skipped.contains(&Stage::Bootloader) => {
+                    update::run_stage(Stage::Bootloader, Some(newest_version), || {
+                        update::update_bootloader(cmd_config, bootloader.as_ref(), newest_version)?;
+                        // The new kernel boots once, the next reboot after that is the fallback's
+                        match fallback {
+                            Some(fallback)
+                                if *grub_reboot_fallback && fallback != newest_version =>
+                            {
+                                bootloader::try_once(
+                                    cmd_config,
+                                    bootloader.as_ref(),
+                                    newest_version,
+                                    fallback,
+                                    state_dir,
+                                )
+                            }
+                            _ => Ok(()),
+                        }
+                    })?
+                }
+                _ => summary::record_skipped(Stage::Bootloader),
+            },
+            Step::Builtin(Stage::Cleanup) if cleanup => {
+                take_snapshot(cmd_config, install_path, snapshot_kind, "cleanup")?;
+                update::run_stage(Stage::Cleanup, None, || {
+                    update::cleanup_old_installs(cmd_config, cleanup_policy.as_ref(), inventory)
+                })?;
+            }
+            Step::Builtin(stage) => summary::record_skipped(*stage),
+            // A command that replaces a stage is skipped with it
+            Step::Command { stage, .. } if skipped.contains(stage) => {
+                summary::record_skipped(*stage)
+            }
+            Step::Command {
+                name,
+                stage,
+                command,
+            } => {
+                info!("Running the {} step of the pipeline", name);
+                update::run_stage(*stage, Some(newest_version), || {
+                    pipeline::run_command(
+                        cmd_config,
+                        command,
+                        newest_version,
+                        newest_source_dir,
+                        install_path,
+                    )
+                })?
+            }
+        }
+    }
+    for stage in pipeline.left_out() {
+        summary::record_skipped(stage);
+    }
+    Ok(missing_modules)
+}
+
+/// Record the checksums of the new kernel's files and sign its image and the manifest
+fn record_checksums(
+    setup: &Setup,
+    newest_version: &KernelVersion,
+    all_initramfs: bool,
+) -> Result<(), JanitorError> {
+    let cmd_config = &setup.cmd_config;
+    // The new kernel's files weren't there when the inventory was taken
+    let mut installed_files = Vec::new();
+    let mut to_sign = vec![checksums::manifest_path(&setup.state_dir)];
+    let installed =
+        kernel::KernelSearch::new(&setup.install_path, &setup.src_paths, &setup.module_path)
+            .with_filesystem(cmd_config.fs.clone())
+            .with_naming(cmd_config.naming.clone())
+            .with_dtb_search_path(cmd_config.dtb_install_path.clone())
+            .with_optional_files(setup.optional_files.clone())
+            .inventory();
+    for kernel in installed.iter().flat_map(|found| found.kernels()) {
+        if kernel.version == *newest_version {
+            to_sign.extend(kernel.vmlinuz_path.clone());
+            installed_files.extend(checksums::boot_files(
+                cmd_config.fs.as_ref(),
+                kernel,
+                cmd_config.initramfs.as_deref(),
+            ));
+        } else if let (Some(initramfs), true) = (&cmd_config.initramfs, all_initramfs) {
+            installed_files.extend(
+                initramfs
+                    .artifact_paths(cmd_config.fs.as_ref(), &kernel.version)
+                    .unwrap_or_default(),
+            );
+        }
+    }
+    if let Err(e) = checksums::record(cmd_config, &setup.state_dir, &installed_files) {
+        warn!(
+            "Could not record the checksums of {}: {}",
+            newest_version, e
+        );
+    }
+    if let Some(key) = &setup.signing_key {
+        signing::sign(cmd_config, key, &to_sign)?;
+    }
+    Ok(())
+}
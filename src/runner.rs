@@ -0,0 +1,675 @@
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    io::{BufRead, BufReader, Read},
+    os::unix::process::CommandExt,
+    process::{Child, Command, ExitStatus, Stdio},
+    thread::{self, ScopedJoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    debug,
+    error::{ErrorKind, JanitorError},
+    info, log, pty, signals, utils, warn,
+};
+
+/// How many trailing lines of stderr (or pty output) are kept for the error message
+const STDERR_TAIL_LINES: usize = 20;
+/// How often a running command is checked for timeouts and interrupts
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a killed process group gets to exit after SIGTERM before SIGKILL
+const TERM_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Passed through when no allowlist is configured
+const DEFAULT_PASS_ENV: [&str; 3] = ["HOME", "TERM", "USER"];
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Controls what environment child commands see
+/// Stray variables like CFLAGS or MAKEFLAGS in the user's shell can silently change
+/// a kernel build, so `sanitized` only inherits an allowlist. By default everything is inherited
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandEnv {
+    sanitize: bool,
+    /// None keeps the janitor's PATH, or uses `DEFAULT_PATH` when sanitizing
+    path: Option<String>,
+    pass_through: Vec<String>,
+}
+
+impl Default for CommandEnv {
+    fn default() -> CommandEnv {
+        CommandEnv {
+            sanitize: false,
+            path: None,
+            pass_through: DEFAULT_PASS_ENV.iter().map(|v| v.to_string()).collect(),
+        }
+    }
+}
+
+impl CommandEnv {
+    /// Only pass through the allowlist, with a fixed PATH and locale
+    pub fn sanitized() -> CommandEnv {
+        CommandEnv {
+            sanitize: true,
+            ..CommandEnv::default()
+        }
+    }
+
+    pub fn with_path(mut self, path: &str) -> CommandEnv {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    pub fn with_pass_through(mut self, names: Vec<String>) -> CommandEnv {
+        self.pass_through = names;
+        self
+    }
+
+    /// Replace the inherited environment, variables set on `cmd` itself are kept
+    pub fn apply(&self, cmd: &mut Command) {
+        self.apply_from(cmd, &|name| std::env::var_os(name))
+    }
+
+    /// `apply` with the janitor's environment read through `var`
+    fn apply_from(&self, cmd: &mut Command, var: &dyn Fn(&str) -> Option<OsString>) {
+        if !self.sanitize {
+            if let Some(path) = &self.path {
+                if !cmd.get_envs().any(|(key, _)| key == "PATH") {
+                    cmd.env("PATH", path);
+                }
+            }
+            return;
+        }
+        let explicit: Vec<(OsString, Option<OsString>)> = cmd
+            .get_envs()
+            .map(|(k, v)| (k.to_os_string(), v.map(|v| v.to_os_string())))
+            .collect();
+        cmd.env_clear();
+        for name in &self.pass_through {
+            if let Some(value) = var(name) {
+                cmd.env(name, value);
+            }
+        }
+        cmd.env("PATH", self.path.as_deref().unwrap_or(DEFAULT_PATH))
+            .env("LC_ALL", "C");
+        for (key, value) in explicit {
+            match value {
+                Some(v) => cmd.env(key, v),
+                None => cmd.env_remove(key),
+            };
+        }
+    }
+}
+
+/// Runs external commands on behalf of `utils::exec_and_print_command`
+/// Pretend and interactive handling happen before a runner is ever called
+pub trait CommandRunner {
+    /// Run the command to completion, printing its output as it runs
+    fn run(&self, cmd: &mut Command, cmd_desc: &str) -> Result<(), JanitorError>;
+
+    /// Like `run`, but every line of output is also handed to `observe`
+    fn run_observed(
+        &self,
+        cmd: &mut Command,
+        cmd_desc: &str,
+        _observe: &(dyn Fn(&str) + Sync),
+    ) -> Result<(), JanitorError> {
+        self.run(cmd, cmd_desc)
+    }
+
+    /// Run the command and capture its standard output
+    fn output(&self, cmd: &mut Command) -> Result<String, JanitorError>;
+}
+
+/// Actually spawns processes
+/// Each command gets its own process group so it can be killed along with everything
+/// it spawned, like the compiler jobs started by `make -j`
+#[derive(Default)]
+pub struct SystemRunner {
+    timeout: Option<Duration>,
+    use_pty: bool,
+}
+
+impl SystemRunner {
+    pub fn new() -> SystemRunner {
+        SystemRunner {
+            timeout: None,
+            use_pty: false,
+        }
+    }
+
+    /// Run commands on a pseudo-terminal so colors and progress output survive
+    /// stdout and stderr are merged when this is on
+    pub fn with_pty(mut self, use_pty: bool) -> SystemRunner {
+        self.use_pty = use_pty;
+        self
+    }
+
+    fn run_in_pty(
+        &self,
+        cmd: &mut Command,
+        cmd_desc: &str,
+        observe: &(dyn Fn(&str) + Sync),
+    ) -> Result<(), JanitorError> {
+        let pty = pty::open().map_err(|e| {
+            JanitorError::from(format!("Could not open a pty for {}: {}", cmd_desc, e))
+        })?;
+        let mut primary = pty.primary;
+        let mut child = cmd
+            .stdout(pty.secondary.try_clone()?)
+            .stderr(pty.secondary)
+            .process_group(0)
+            .spawn()?;
+        // `cmd` still holds the secondary end, the reader only sees EOF once every copy is closed
+        cmd.stdout(Stdio::null()).stderr(Stdio::null());
+
+        // Output goes out as raw bytes, only the tail is split into lines
+        thread::scope(|scope| {
+            let reader_thread = scope.spawn(move || {
+                let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+                let mut partial = Vec::new();
+                let mut buf = [0u8; 4096];
+                // Linux returns EIO instead of EOF once the last writer closes the pty
+                while let Ok(n @ 1..) = primary.read(&mut buf) {
+                    log::write_raw(&buf[..n]);
+                    partial.extend_from_slice(&buf[..n]);
+                    while let Some(end) = partial.iter().position(|&b| b == b'\n') {
+                        let line: Vec<u8> = partial.drain(..=end).collect();
+                        if tail.len() == STDERR_TAIL_LINES {
+                            tail.pop_front();
+                        }
+                        let line = pty::strip_escapes(String::from_utf8_lossy(&line).trim_end());
+                        observe(&line);
+                        tail.push_back(line);
+                    }
+                }
+                tail
+            });
+            let status = self.wait_or_kill(&mut child, cmd_desc);
+            let tail = join_reader(reader_thread, cmd_desc);
+            let status = status?;
+            check_status(status, cmd_desc, tail?.into_iter().collect())
+        })
+    }
+
+    /// Commands running longer than `timeout` are killed
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> SystemRunner {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Wait for the child while watching for a timeout or an interrupt
+    fn wait_or_kill(&self, child: &mut Child, cmd_desc: &str) -> Result<ExitStatus, JanitorError> {
+        let start = Instant::now();
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+            let reason = if signals::should_abort() {
+                Some("was aborted".to_string())
+            } else {
+                match self.timeout {
+                    Some(t) if start.elapsed() > t => {
+                        Some(format!("timed out after {}", utils::format_duration(t)))
+                    }
+                    _ => None,
+                }
+            };
+            if let Some(reason) = reason {
+                kill_process_group(child)?;
+                let kind = match signals::should_abort() {
+                    true => ErrorKind::Interrupted,
+                    false => ErrorKind::Other,
+                };
+                return Err(JanitorError::new(
+                    kind,
+                    format!(
+                        "{} {}, killed process group {}",
+                        cmd_desc,
+                        reason,
+                        child.id()
+                    ),
+                ));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// How often a command that can fail for reasons of the moment, like `emerge` not reaching a
+/// mirror, is tried before the run gives up. The wait doubles after every failed attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Including the first one, 1 means failures aren't retried
+    pub attempts: usize,
+    /// Before the second attempt
+    pub delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 1,
+            delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait after `attempt` failed, counting from 1
+    pub fn delay_after(&self, attempt: usize) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(16) as u32;
+        self.delay.saturating_mul(1 << doublings)
+    }
+
+    /// Sleep for `delay_after(attempt)`, false when interrupted before it was over
+    pub fn wait(&self, attempt: usize) -> bool {
+        let until = Instant::now() + self.delay_after(attempt);
+        while !signals::interrupted() {
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return true;
+            }
+            thread::sleep(left.min(POLL_INTERVAL));
+        }
+        false
+    }
+}
+
+/// A panicking reader thread fails the command instead of taking down the janitor
+fn join_reader<T>(handle: ScopedJoinHandle<T>, cmd_desc: &str) -> Result<T, JanitorError> {
+    handle.join().map_err(|panic| {
+        let reason = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown reason".to_string());
+        JanitorError::from(format!(
+            "The thread reading output from {} panicked: {}",
+            cmd_desc, reason
+        ))
+    })
+}
+
+/// SIGTERM the child's process group, escalate to SIGKILL if it lingers, then reap
+fn kill_process_group(child: &mut Child) -> Result<(), JanitorError> {
+    for (signum, name) in &[(signals::SIGTERM, "TERM"), (signals::SIGKILL, "KILL")] {
+        // The group may already be gone, that's fine
+        let _ = signals::kill_group(child.id(), *signum);
+        let deadline = Instant::now() + TERM_GRACE_PERIOD;
+        while Instant::now() < deadline {
+            if child.try_wait()?.is_some() {
+                return Ok(());
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        warn!("Process group {} survived SIG{}", child.id(), name);
+    }
+    child.wait()?;
+    Ok(())
+}
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, cmd: &mut Command, cmd_desc: &str) -> Result<(), JanitorError> {
+        self.run_observed(cmd, cmd_desc, &|_| ())
+    }
+
+    fn run_observed(
+        &self,
+        cmd: &mut Command,
+        cmd_desc: &str,
+        observe: &(dyn Fn(&str) + Sync),
+    ) -> Result<(), JanitorError> {
+        debug!("{:?}", cmd);
+        if self.use_pty {
+            return self.run_in_pty(cmd, cmd_desc, observe);
+        }
+        let mut child = cmd
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .process_group(0)
+            .spawn()?;
+        let stdout = child.stdout.take().ok_or_else(|| {
+            JanitorError::from(format!(
+                "Could not capture standard output for {}",
+                cmd_desc
+            ))
+        })?;
+        let stderr = child.stderr.take().ok_or_else(|| {
+            JanitorError::from(format!("Could not capture standard error for {}", cmd_desc))
+        })?;
+
+        thread::scope(|scope| {
+            let out_thread = scope.spawn(move || {
+                let stdout_reader = BufReader::new(stdout);
+                stdout_reader.lines().map_while(Result::ok).for_each(|l| {
+                    info!("{}", l);
+                    observe(&l);
+                });
+            });
+            // Keep the end of stderr around in case the command fails
+            let err_thread = scope.spawn(move || {
+                let stderr_reader = BufReader::new(stderr);
+                let mut tail = VecDeque::with_capacity(STDERR_TAIL_LINES);
+                for line in stderr_reader.lines().map_while(Result::ok) {
+                    info!("stderr: {}", line);
+                    observe(&line);
+                    if tail.len() == STDERR_TAIL_LINES {
+                        tail.pop_front();
+                    }
+                    tail.push_back(line);
+                }
+                tail
+            });
+            let status = self.wait_or_kill(&mut child, cmd_desc);
+            let out = join_reader(out_thread, cmd_desc);
+            let stderr_tail = join_reader(err_thread, cmd_desc);
+            let status = status?;
+            out?;
+            check_status(status, cmd_desc, stderr_tail?.into_iter().collect())
+        })
+    }
+
+    fn output(&self, cmd: &mut Command) -> Result<String, JanitorError> {
+        debug!("{:?}", cmd);
+        let output = cmd.output()?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr_lines = stderr.lines().map(str::to_string).collect::<Vec<_>>();
+        let first_tail_line = stderr_lines.len().saturating_sub(STDERR_TAIL_LINES);
+        check_status(
+            output.status,
+            &format!("{:?}", cmd),
+            stderr_lines[first_tail_line..].to_vec(),
+        )?;
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+}
+
+/// Turns a non-zero exit into an error that ends with the last lines of stderr
+fn check_status(
+    status: ExitStatus,
+    cmd_desc: &str,
+    stderr_tail: Vec<String>,
+) -> Result<(), JanitorError> {
+    if status.success() {
+        return Ok(());
+    }
+    let mut message = format!("{} failed with {}", cmd_desc, status);
+    if !stderr_tail.is_empty() {
+        message.push_str(&format!(
+            ". Last {} lines of stderr:\n{}",
+            stderr_tail.len(),
+            stderr_tail.join("\n")
+        ));
+    }
+    Err(JanitorError::from(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn failed_command_includes_stderr() {
+        let res = SystemRunner::new().run(
+            Command::new("sh")
+                .arg("-c")
+                .arg("echo first >&2; echo second >&2; exit 3"),
+            "sh",
+        );
+        assert!(res.is_err());
+        let message = res.unwrap_err().to_string();
+        assert!(message.contains("exit status: 3"), "{}", message);
+        assert!(message.ends_with("stderr:\nfirst\nsecond"), "{}", message);
+    }
+
+    #[test]
+    fn pty_output_is_a_terminal() {
+        let runner = SystemRunner::new().with_pty(true);
+        assert!(runner
+            .run(Command::new("test").arg("-t").arg("1"), "test")
+            .is_ok());
+        let res = runner.run(
+            Command::new("sh").arg("-c").arg("echo oops >&2; exit 2"),
+            "sh",
+        );
+        let message = res.unwrap_err().to_string();
+        assert!(message.ends_with("stderr:\noops"), "{}", message);
+    }
+
+    #[test]
+    fn observer_sees_every_line() {
+        let seen = std::sync::Mutex::new(Vec::new());
+        let observe = |line: &str| seen.lock().unwrap().push(line.to_string());
+        for runner in [SystemRunner::new(), SystemRunner::new().with_pty(true)] {
+            let res = runner.run_observed(
+                Command::new("sh").arg("-c").arg("echo out; echo err >&2"),
+                "sh",
+                &observe,
+            );
+            assert!(res.is_ok());
+            let mut lines = seen.lock().unwrap().drain(..).collect::<Vec<_>>();
+            lines.sort();
+            assert_eq!(lines, vec!["err", "out"]);
+        }
+    }
+
+    #[test]
+    fn reader_panic_is_an_error() {
+        for runner in [SystemRunner::new(), SystemRunner::new().with_pty(true)] {
+            let res = runner.run_observed(Command::new("echo").arg("hi"), "echo", &|_| {
+                panic!("observer broke")
+            });
+            let message = res.unwrap_err().to_string();
+            assert!(
+                message.contains("reading output from echo panicked: observer broke"),
+                "{}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn output_checks_status() {
+        let runner = SystemRunner::new();
+        assert!(runner.output(Command::new("false").arg("x")).is_err());
+        let out = runner.output(Command::new("echo").arg("hi"));
+        assert_eq!(out.unwrap(), "hi\n");
+    }
+
+    #[test]
+    fn sanitized_env() {
+        let janitor_env = |name: &str| match name {
+            "JANITOR_TEST_PASSED" => Some(OsString::from("yes")),
+            "JANITOR_TEST_DROPPED" => Some(OsString::from("no")),
+            _ => None,
+        };
+        let env = CommandEnv::sanitized()
+            .with_path("/bin")
+            .with_pass_through(vec!["JANITOR_TEST_PASSED".to_string()]);
+        let mut cmd = Command::new("env");
+        cmd.env("INSTALL_PATH", "/boot");
+        env.apply_from(&mut cmd, &janitor_env);
+
+        let out = SystemRunner::new().output(&mut cmd).unwrap();
+        let mut vars = out.lines().collect::<Vec<_>>();
+        vars.sort();
+        assert_eq!(
+            vars,
+            vec![
+                "INSTALL_PATH=/boot",
+                "JANITOR_TEST_PASSED=yes",
+                "LC_ALL=C",
+                "PATH=/bin"
+            ]
+        );
+    }
+
+    #[test]
+    fn inherited_env() {
+        let mut cmd = Command::new("env");
+        CommandEnv::default().apply(&mut cmd);
+        assert_eq!(cmd.get_envs().count(), 0);
+
+        CommandEnv::default().with_path("/bin").apply(&mut cmd);
+        let envs = cmd.get_envs().collect::<Vec<_>>();
+        assert_eq!(
+            envs,
+            vec![(
+                std::ffi::OsStr::new("PATH"),
+                Some(std::ffi::OsStr::new("/bin"))
+            )]
+        );
+    }
+
+    #[test]
+    fn timeout_kills_process_group() {
+        let runner = SystemRunner::new().with_timeout(Some(Duration::from_millis(300)));
+        let start = Instant::now();
+        // The background sleep is a grandchild that also has to die
+        let res = runner.run(
+            Command::new("sh")
+                .arg("-c")
+                .arg("sleep 30 & sleep 30; wait"),
+            "sleepy",
+        );
+        assert!(res.is_err());
+        let message = res.unwrap_err().to_string();
+        assert!(message.contains("sleepy timed out after 0s"), "{}", message);
+        // Reader threads only finish once every process holding the pipes is gone
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+}
+
+#[cfg(test)]
+pub use recording::RecordingRunner;
+
+#[cfg(test)]
+mod recording {
+    use std::{
+        collections::HashMap,
+        path::PathBuf,
+        process::Command,
+        sync::{Arc, Mutex},
+    };
+
+    use super::CommandRunner;
+    use crate::{error::JanitorError, JanitorErrorFrom};
+
+    /// What a `RecordingRunner` saw instead of running
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct RecordedCommand {
+        pub program: String,
+        pub args: Vec<String>,
+        pub current_dir: Option<PathBuf>,
+        pub envs: Vec<(String, String)>,
+    }
+
+    /// Records commands instead of running them, clones share the same record
+    #[derive(Default, Clone)]
+    pub struct RecordingRunner {
+        commands: Arc<Mutex<Vec<RecordedCommand>>>,
+        // Canned stdout keyed by program name for `output`
+        outputs: Arc<Mutex<HashMap<String, String>>>,
+        // Command lines that fail instead of succeeding
+        failures: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl RecordedCommand {
+        fn from_command(cmd: &Command) -> RecordedCommand {
+            RecordedCommand {
+                program: cmd.get_program().to_string_lossy().to_string(),
+                args: cmd
+                    .get_args()
+                    .map(|a| a.to_string_lossy().to_string())
+                    .collect(),
+                current_dir: cmd.get_current_dir().map(|d| d.to_path_buf()),
+                envs: cmd
+                    .get_envs()
+                    .map(|(k, v)| {
+                        (
+                            k.to_string_lossy().to_string(),
+                            v.map(|v| v.to_string_lossy().to_string())
+                                .unwrap_or_default(),
+                        )
+                    })
+                    .collect(),
+            }
+        }
+
+        /// Program and arguments joined with spaces
+        pub fn command_line(&self) -> String {
+            std::iter::once(self.program.clone())
+                .chain(self.args.iter().cloned())
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+    }
+
+    impl RecordingRunner {
+        pub fn new() -> RecordingRunner {
+            RecordingRunner::default()
+        }
+
+        pub fn with_output(self, program: &str, stdout: &str) -> RecordingRunner {
+            self.outputs
+                .lock()
+                .unwrap()
+                .insert(program.to_string(), stdout.to_string());
+            self
+        }
+
+        /// `run` and `output` still record the command but then fail it
+        pub fn with_failure(self, command_line: &str) -> RecordingRunner {
+            self.failures.lock().unwrap().push(command_line.to_string());
+            self
+        }
+
+        pub fn commands(&self) -> Vec<RecordedCommand> {
+            self.commands.lock().unwrap().clone()
+        }
+
+        pub fn command_lines(&self) -> Vec<String> {
+            self.commands()
+                .iter()
+                .map(RecordedCommand::command_line)
+                .collect()
+        }
+    }
+
+    impl CommandRunner for RecordingRunner {
+        fn run(&self, cmd: &mut Command, cmd_desc: &str) -> Result<(), JanitorError> {
+            let recorded = RecordedCommand::from_command(cmd);
+            let fails = self
+                .failures
+                .lock()
+                .unwrap()
+                .contains(&recorded.command_line());
+            self.commands.lock().unwrap().push(recorded);
+            match fails {
+                true => Err(JanitorErrorFrom!("{} exited with exit status: 1", cmd_desc)),
+                false => Ok(()),
+            }
+        }
+
+        fn output(&self, cmd: &mut Command) -> Result<String, JanitorError> {
+            let recorded = RecordedCommand::from_command(cmd);
+            let command_line = recorded.command_line();
+            let fails = self.failures.lock().unwrap().contains(&command_line);
+            let stdout = self
+                .outputs
+                .lock()
+                .unwrap()
+                .get(&recorded.program)
+                .cloned()
+                .unwrap_or_default();
+            self.commands.lock().unwrap().push(recorded);
+            match fails {
+                true => Err(JanitorErrorFrom!(
+                    "{} exited with exit status: 1",
+                    command_line
+                )),
+                false => Ok(stdout),
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use std::path::PathBuf;
+
+use crate::{
+    doctor::Check,
+    error::JanitorError,
+    inventory::{KernelInventory, ListFilter, ListOrder},
+    json::{self, JsonObject},
+    kernel::InstalledKernel,
+    suggest::Suggestion,
+};
+
+/// Version of every JSON document and event the janitor writes
+/// Bumped when a field is removed, renamed or changes meaning. New fields don't bump it,
+/// so consumers should ignore fields they don't know
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Every JSON document starts with its schema version
+pub fn document() -> JsonObject {
+    JsonObject::new().number("schema_version", SCHEMA_VERSION)
+}
+
+fn optional_path(path: &Option<PathBuf>) -> Option<String> {
+    path.as_ref().map(|p| p.to_string_lossy().to_string())
+}
+
+fn optional_bool(value: Option<bool>) -> String {
+    value.map_or_else(|| "null".to_string(), |v| v.to_string())
+}
+
+/// One installed kernel, part of `inventory`
+pub fn kernel(
+    inventory: &KernelInventory,
+    kernel: &InstalledKernel,
+    size_bytes: u64,
+) -> Result<JsonObject, JanitorError> {
+    // Kernels without an image can't be booted either way
+    let boot = match inventory.boot_status(kernel)? {
+        Some(status) => JsonObject::new()
+            .boolean("bootable", status.bootable())
+            .raw("initramfs", optional_bool(status.initramfs))
+            .raw("boot_entry", optional_bool(status.boot_entry)),
+        None => JsonObject::new()
+            .boolean("bootable", false)
+            .raw("initramfs", optional_bool(None))
+            .raw("boot_entry", optional_bool(None)),
+    };
+    let paths = JsonObject::new()
+        .optional_string(
+            "kernel_image",
+            optional_path(&kernel.vmlinuz_path).as_deref(),
+        )
+        .optional_string("config", optional_path(&kernel.config_path).as_deref())
+        .optional_string(
+            "system_map",
+            optional_path(&kernel.system_map_path).as_deref(),
+        )
+        .optional_string("source", optional_path(&kernel.source_path).as_deref())
+        .optional_string("source_root", optional_path(&kernel.source_root).as_deref())
+        .optional_string("modules", optional_path(&kernel.module_path).as_deref())
+        .raw(
+            "extra",
+            json::array(
+                kernel
+                    .extra_paths
+                    .iter()
+                    .map(|(_, path)| json::string(&path.to_string_lossy())),
+            ),
+        );
+    Ok(JsonObject::new()
+        .string("version", &kernel.version.to_string())
+        .boolean("old", kernel.version.is_old())
+        .boolean("pinned", inventory.is_pinned(&kernel.version))
+        .boolean(
+            "fallback",
+            inventory
+                .fallback()
+                .is_some_and(|k| k.version == kernel.version),
+        )
+        .boolean(
+            "running",
+            inventory
+                .running()
+                .is_some_and(|k| k.version == kernel.version),
+        )
+        .boolean(
+            "default_boot",
+            inventory
+                .default_boot()
+                .is_some_and(|k| k.version == kernel.version),
+        )
+        .boolean("saved_default", inventory.is_saved_default(&kernel.version))
+        .boolean(
+            "next_boot",
+            inventory
+                .next_boot()
+                .is_some_and(|k| k.version == kernel.version),
+        )
+        .boolean("awaiting_boot", inventory.awaiting_boot().contains(&kernel))
+        .raw("boot", boot.to_string())
+        .number("size_bytes", size_bytes)
+        .raw("paths", paths.to_string()))
+}
+
+/// What `--list --output json` prints, the kernels that pass `filter` in `order`
+pub fn inventory(
+    inventory: &KernelInventory,
+    filter: &ListFilter,
+    order: ListOrder,
+) -> Result<JsonObject, JanitorError> {
+    let listed = inventory.list(filter, order)?;
+    let total: u64 = listed.iter().map(|(_, size)| size).sum();
+    let kernels = listed
+        .into_iter()
+        .map(|(k, size)| Ok(kernel(inventory, k, size)?.to_string()))
+        .collect::<Result<Vec<_>, JanitorError>>()?;
+    Ok(document()
+        .raw("kernels", json::array(kernels))
+        .number("total_size_bytes", total))
+}
+
+/// What `space --output json` prints, where the space of every kernel goes
+pub fn space(inventory: &KernelInventory) -> Result<JsonObject, JanitorError> {
+    let kernels = inventory
+        .kernels()
+        .iter()
+        .map(|k| {
+            let space = inventory.space(k)?;
+            Ok(JsonObject::new()
+                .string("version", &k.version.to_string())
+                .number("sources_bytes", space.sources)
+                .number("build_artifacts_bytes", space.build_artifacts)
+                .number("modules_bytes", space.modules)
+                .number("boot_bytes", space.boot)
+                .number("total_bytes", space.total())
+                .to_string())
+        })
+        .collect::<Result<Vec<_>, JanitorError>>()?;
+    Ok(document()
+        .raw("kernels", json::array(kernels))
+        .number("prunable_bytes", inventory.prunable_size()?))
+}
+
+/// What `suggest --output json` prints, the most space first
+pub fn suggestions(suggestions: &[Suggestion]) -> JsonObject {
+    let items = suggestions.iter().map(|suggestion| {
+        JsonObject::new()
+            .string("action", suggestion.action.as_str())
+            .string("version", &suggestion.version.to_string())
+            .number("bytes", suggestion.bytes)
+            .string("reason", &suggestion.reason)
+            .to_string()
+    });
+    let total: u64 = suggestions.iter().map(|suggestion| suggestion.bytes).sum();
+    document()
+        .raw("suggestions", json::array(items))
+        .number("total_bytes", total)
+}
+
+/// What `doctor --output json` and `verify --output json` print
+pub fn doctor(checks: &[Check]) -> JsonObject {
+    let checks = checks.iter().map(|check| {
+        JsonObject::new()
+            .string("name", &check.name)
+            .string("status", check.status.as_str())
+            .string("detail", &check.detail)
+            .optional_string("hint", check.hint.as_deref())
+            .to_string()
+    });
+    document().raw("checks", json::array(checks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{filesystem::MemoryFileSystem, kernel::KernelSearch};
+    use std::{path::Path, sync::Arc};
+
+    #[test]
+    fn inventory_document() -> Result<(), JanitorError> {
+        let memfs = MemoryFileSystem::new()
+            .with_file(Path::new("/boot/vmlinuz-5.10.1-gentoo"), 10)
+            .with_file(Path::new("/lib/modules/5.10.1-gentoo/kernel/kernel.ko"), 5)
+            .with_dir(Path::new("/usr/src"));
+        let inventory = KernelSearch::new(
+            Path::new("/boot"),
+            &[PathBuf::from("/usr/src")],
+            Path::new("/lib/modules"),
+        )
+        .with_filesystem(Arc::new(memfs))
+        .inventory()?
+        .with_running_release(Some("5.10.1-gentoo".to_string()));
+        assert_eq!(
+            super::inventory(&inventory, &ListFilter::default(), ListOrder::Version)?.to_string(),
+            r#"{"schema_version":1,"kernels":[{"version":"5.10.1-gentoo","old":false,"pinned":false,"fallback":false,"running":true,"default_boot":false,"saved_default":false,"next_boot":false,"awaiting_boot":false,"boot":{"bootable":true,"initramfs":null,"boot_entry":null},"size_bytes":15,"paths":{"kernel_image":"/boot/vmlinuz-5.10.1-gentoo","config":null,"system_map":null,"source":null,"source_root":null,"modules":"/lib/modules/5.10.1-gentoo","extra":[]}}],"total_size_bytes":15}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn full_releases() -> Result<(), JanitorError> {
+        let memfs = MemoryFileSystem::new()
+            .with_file(Path::new("/boot/vmlinuz-5.10.1-gentoo-r1"), 10)
+            .with_file(Path::new("/boot/vmlinuz-5.10.1-gentoo-dist"), 10)
+            .with_file(Path::new("/boot/vmlinuz-5.10.1-gentoo-dist.old"), 10)
+            .with_dir(Path::new("/lib/modules/5.10.1-gentoo-dist"))
+            .with_dir(Path::new("/usr/src/linux-5.10.1-gentoo-dist"));
+        let inventory = KernelSearch::new(
+            Path::new("/boot"),
+            &[PathBuf::from("/usr/src")],
+            Path::new("/lib/modules"),
+        )
+        .with_filesystem(Arc::new(memfs))
+        .inventory()?;
+        let document =
+            super::inventory(&inventory, &ListFilter::default(), ListOrder::Version)?.to_string();
+        // Each flavor under its own release, not only its version numbers
+        for release in &[
+            "5.10.1-gentoo-r1",
+            "5.10.1-gentoo-dist",
+            "5.10.1-gentoo-dist.old",
+        ] {
+            assert!(
+                document.contains(&format!(r#""version":"{}""#, release)),
+                "{}",
+                document
+            );
+        }
+        Ok(())
+    }
+}
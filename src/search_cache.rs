@@ -0,0 +1,155 @@
+use std::{
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::{debug, filesystem::FileSystem, kernel::InstalledItemKind, update::RunCmdConfig};
+
+/// Kept in the state dir
+pub const CACHE_FILE: &str = "search-cache";
+const HEADER: &str = "# kernel-janitor search cache, deleting it only makes the next search slower";
+/// The last line, a cache without it was cut short while being written
+const END: &str = "end";
+
+/// Every item a search found, with the release it's for
+pub type Found = Vec<(InstalledItemKind, PathBuf, String)>;
+
+/// How a `KernelSearch` uses the cache of what it found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheUse {
+    /// Trust the cache while none of the searched directories changed, search and update it otherwise
+    Reuse,
+    /// Always search, and update the cache for the next time
+    Refresh,
+    /// Trust an up to date cache but never write it, for pretend runs and checks
+    ReadOnly,
+}
+
+pub fn cache_path(state_dir: &Path) -> PathBuf {
+    state_dir.join(CACHE_FILE)
+}
+
+/// The lines a cache of searching `dirs` for `key` starts with
+/// Adding, removing or renaming anything in a directory changes its modification time, so a
+/// cache with other lines is stale. None if a time can't be read, then there's nothing to compare
+pub fn stamp(fs: &dyn FileSystem, key: &str, dirs: &[PathBuf]) -> Option<Vec<String>> {
+    let mut lines = vec![HEADER.to_string(), format!("key\t{}", key)];
+    for dir in dirs {
+        let modified = match fs.modified(dir).map(|time| time.duration_since(UNIX_EPOCH)) {
+            Ok(Ok(modified)) => modified,
+            _ => {
+                debug!(
+                    "Can't tell when {} changed, not caching the search",
+                    dir.display()
+                );
+                return None;
+            }
+        };
+        lines.push(format!(
+            "dir\t{}.{:09}\t{}",
+            modified.as_secs(),
+            modified.subsec_nanos(),
+            dir.to_str()?
+        ));
+    }
+    Some(lines)
+}
+
+/// What the search found the last time, None if `path` on `fs` wasn't written with this `stamp`
+pub fn read(fs: &dyn FileSystem, path: &Path, stamp: &[String]) -> Option<Found> {
+    let contents = fs.read_to_string(path).ok()?;
+    let mut lines = contents.lines();
+    if !stamp
+        .iter()
+        .all(|expected| lines.next() == Some(expected.as_str()))
+    {
+        debug!("{} is stale", path.display());
+        return None;
+    }
+    if lines.next_back() != Some(END) {
+        debug!("{} is incomplete", path.display());
+        return None;
+    }
+    lines
+        .map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let kind = fields.next()?.parse::<InstalledItemKind>().ok()?;
+            let release = fields.next()?.to_string();
+            Some((kind, PathBuf::from(fields.next()?), release))
+        })
+        .collect()
+}
+
+/// Remember what the search found for the next one with the same `stamp`, with `cmd_config` so
+/// pretending leaves it alone. The cache only saves time, not being able to write it isn't an error
+pub fn write(cmd_config: &RunCmdConfig, path: &Path, stamp: &[String], found: &Found) {
+    let mut lines = stamp.to_vec();
+    for (kind, item_path, release) in found {
+        // Names that can't be written on a line of their own are rare enough to search again
+        match item_path.to_str() {
+            Some(item) if !item.contains('\n') && !release.contains(['\t', '\n']) => {
+                lines.push(format!("{}\t{}\t{}", kind.name(), release, item))
+            }
+            _ => {
+                debug!(
+                    "Not caching the search, {:?} has an awkward name",
+                    item_path
+                );
+                return;
+            }
+        }
+    }
+    lines.push(END.to_string());
+    lines.push(String::new());
+    if let Err(e) = cmd_config.write_file(path, &lines.join("\n"), 0o644) {
+        debug!("Could not cache the search in {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filesystem::{MemoryFileSystem, RealFileSystem},
+        test_utils::TestDir,
+        update::{InteractiveStatus, PretendStatus},
+    };
+    use std::fs;
+
+    #[test]
+    fn stale_after_a_change() {
+        let test_dir = TestDir::new();
+        let root = test_dir.path().join("boot");
+        fs::create_dir_all(&root).unwrap();
+        let path = cache_path(&test_dir.path().join("state"));
+        let found = vec![(
+            InstalledItemKind::KernelImage,
+            root.join("vmlinuz-6.1.0-gentoo"),
+            "6.1.0-gentoo".to_string(),
+        )];
+        let fs = RealFileSystem;
+        let dirs = vec![root.clone()];
+        let first = stamp(&fs, "vmlinuz-{release}", &dirs).unwrap();
+        // Pretending leaves the disk alone
+        let pretend = RunCmdConfig::new(PretendStatus::Pretend, InteractiveStatus::Off);
+        write(&pretend, &path, &first, &found);
+        assert!(!path.exists());
+        let cmd_config = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off);
+        write(&cmd_config, &path, &first, &found);
+        assert_eq!(read(&fs, &path, &first), Some(found));
+        let other_key = stamp(&fs, "bzImage-{release}", &dirs).unwrap();
+        assert_eq!(read(&fs, &path, &other_key), None);
+        // Cut short before the end
+        let contents = fs::read_to_string(&path).unwrap();
+        let memfs =
+            MemoryFileSystem::new().with_contents(&path, contents.trim_end_matches("end\n"));
+        assert_eq!(read(&memfs, &path, &first), None);
+
+        // The time has to move on for the change to show
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        fs::write(root.join("vmlinuz-6.2.0-gentoo"), b"").unwrap();
+        let changed = stamp(&fs, "vmlinuz-{release}", &dirs).unwrap();
+        assert_ne!(first, changed);
+        assert_eq!(read(&fs, &path, &changed), None);
+    }
+}
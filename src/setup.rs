@@ -0,0 +1,420 @@
+use kernel_janitor::{
+    bootloader::{self, BootloaderKind},
+    conf::Config,
+    debug,
+    error::{ErrorKind, JanitorError},
+    events, history, info,
+    initramfs::InitramfsKind,
+    inventory::KernelInventory,
+    kernel::{self, InstalledItemKind, KernelVersion},
+    mount,
+    naming::Naming,
+    pipeline::Pipeline,
+    policy::{
+        CleanupPolicy, CleanupPolicyKind, DiskBudget, KeepNewest, KeepNewestPerFlavor, MaxAge,
+    },
+    portage,
+    reboot::RebootPolicy,
+    report::{self, ReportFormat},
+    runner::{CommandEnv, RetryPolicy, SystemRunner},
+    search_cache::{self, CacheUse},
+    summary,
+    syslog::{self, SystemLogBackend},
+    update::{InteractiveStatus, PretendStatus, RunCmdConfig},
+    utils, warn,
+};
+use std::{io::IsTerminal, path::PathBuf, time::Duration};
+
+use crate::{args, cli::ParseResults};
+
+/// Everything a run reads from the config and finds on the system before it does anything
+pub struct Setup {
+    pub config: Config,
+    pub state_dir: PathBuf,
+    pub cmd_config: RunCmdConfig,
+    pub inventory: KernelInventory,
+    pub install_path: PathBuf,
+    pub module_path: PathBuf,
+    pub src_paths: Vec<PathBuf>,
+    pub optional_files: Vec<InstalledItemKind>,
+    pub cleanup_policy: Box<dyn CleanupPolicy>,
+    pub pipeline: Pipeline,
+    pub rebuild_portage_modules: bool,
+    pub reboot_policy: RebootPolicy,
+    pub signing_key: Option<String>,
+    /// The last kernel that booted fine, kept by cleanup and the default while a new one is tried
+    pub fallback: Option<KernelVersion>,
+    pub grub_reboot_fallback: bool,
+    pub user_is_root: bool,
+    /// Unmounted again when the run ends, whichever way it ends
+    _boot_mount: Option<mount::BootMount>,
+}
+
+/// The command env, which is inherited unless SanitizeEnvironment is set
+fn command_env(config: &Config) -> Result<CommandEnv, JanitorError> {
+    let mut command_env = match config.contains("SanitizeEnvironment") {
+        true if config.get_bool("SanitizeEnvironment")? => CommandEnv::sanitized(),
+        _ => CommandEnv::default(),
+    };
+    if config.contains("CommandPath") {
+        command_env = command_env.with_path(&config.get_string("CommandPath")?);
+    }
+    if config.contains("PassEnvironment") {
+        command_env = command_env.with_pass_through(config.get_list("PassEnvironment")?);
+    }
+    Ok(command_env)
+}
+
+/// How commands are run, before anything that depends on the install path is known
+fn run_cmd_config(
+    config: &Config,
+    pretend: PretendStatus,
+    interactive: InteractiveStatus,
+) -> Result<RunCmdConfig, JanitorError> {
+    // Zero or missing means commands may run forever
+    let timeout = match config.contains("CommandTimeoutMinutes") {
+        true => Some(config.get_usize("CommandTimeoutMinutes")?)
+            .filter(|&mins| mins > 0)
+            .map(|mins| Duration::from_secs(mins as u64 * 60)),
+        false => None,
+    };
+    // A pty only helps when someone is watching the output
+    let use_pty = match config.contains("UsePty") {
+        true => config.get_bool("UsePty")?,
+        false => true,
+    } && std::io::stdout().is_terminal()
+        && !events::json_enabled();
+    let mut retry = RetryPolicy::default();
+    if config.contains("RetryAttempts") {
+        // Zero would never run the command at all
+        retry.attempts = config.get_usize("RetryAttempts")?.max(1);
+    }
+    if config.contains("RetryDelaySeconds") {
+        retry.delay = Duration::from_secs(config.get_usize("RetryDelaySeconds")? as u64);
+    }
+    Ok(RunCmdConfig::new(pretend, interactive)
+        .with_runner(SystemRunner::new().with_timeout(timeout).with_pty(use_pty))
+        .with_env(command_env(config)?)
+        .with_retry(retry))
+}
+
+fn cleanup_policy(config: &Config) -> Result<Box<dyn CleanupPolicy>, JanitorError> {
+    /*
+     * TODO move old files to trash instead of deleting them
+     * I may need to implement a `mv` that copies content and deletes the old ones.
+     * Either that or just use the `mv` command
+     */
+    //let _ = config.get_path("TrashPath")?; TrashPath isn't used yet
+    let num_versions_to_keep = config.get_usize("VersionsToKeep")?;
+    Ok(match config.contains("CleanupPolicy") {
+        true => match config
+            .get_string("CleanupPolicy")?
+            .parse::<CleanupPolicyKind>()?
+        {
+            CleanupPolicyKind::Count => Box::new(KeepNewest {
+                count: num_versions_to_keep,
+            }),
+            CleanupPolicyKind::Age => Box::new(MaxAge {
+                max_age: Duration::from_secs(config.get_usize("MaxKernelAgeDays")? as u64 * 86400),
+            }),
+            CleanupPolicyKind::DiskBudget => Box::new(DiskBudget {
+                max_bytes: config.get_usize("KernelDiskBudgetMiB")? as u64 * 1024 * 1024,
+            }),
+            CleanupPolicyKind::Flavor => Box::new(KeepNewestPerFlavor {
+                count: num_versions_to_keep,
+            }),
+        },
+        false => Box::new(KeepNewest {
+            count: num_versions_to_keep,
+        }),
+    })
+}
+
+/// The names the kernel files are installed as
+fn naming(config: &Config) -> Result<Naming, JanitorError> {
+    let mut naming = Naming::default();
+    if config.contains("KernelImageName") {
+        naming.image = config.get_string("KernelImageName")?.parse()?;
+    }
+    if config.contains("KernelConfigName") {
+        naming.config = config.get_string("KernelConfigName")?.parse()?;
+    }
+    if config.contains("SystemMapName") {
+        naming.system_map = config.get_string("SystemMapName")?.parse()?;
+    }
+    if config.contains("InstallConfig") {
+        naming.install_config = config.get_bool("InstallConfig")?;
+    }
+    if config.contains("InstallSystemMap") {
+        naming.install_system_map = config.get_bool("InstallSystemMap")?;
+    }
+    let pkgbase = match config.contains("Pkgbase") {
+        true => Some(config.get_string("Pkgbase")?),
+        false => None,
+    };
+    let naming = naming.with_pkgbase(pkgbase);
+    naming.check()?;
+    Ok(naming)
+}
+
+/// What the build and the checks after it are told by the config
+fn with_build_settings(
+    config: &Config,
+    mut cmd_config: RunCmdConfig,
+    rebuild_portage_modules: bool,
+) -> Result<RunCmdConfig, JanitorError> {
+    if config.contains("RequiredModules") {
+        cmd_config = cmd_config.with_required_modules(config.get_list("RequiredModules")?);
+    }
+    if config.contains("CriticalModules") {
+        cmd_config = cmd_config.with_critical_modules(config.get_list("CriticalModules")?);
+    }
+    if config.contains("MarchNative") {
+        cmd_config = cmd_config.with_march_native(config.get_bool("MarchNative")?);
+    }
+    if rebuild_portage_modules {
+        cmd_config = cmd_config.with_module_rebuild(portage::module_rebuild_command(config)?);
+    }
+    if config.contains("UsePortageMakeConf") && config.get_bool("UsePortageMakeConf")? {
+        let make_conf = portage::read_make_conf(cmd_config.fs.as_ref())?;
+        cmd_config = cmd_config.with_make_conf(make_conf);
+    }
+    if config.contains("MakeMaxLoad") {
+        let load = config.get_string("MakeMaxLoad")?;
+        match load.parse::<f64>() {
+            Ok(load) if load > 0.0 => cmd_config = cmd_config.with_max_load(Some(load)),
+            _ => {
+                return Err(JanitorError::new(
+                    ErrorKind::Config,
+                    format!(
+                        "MakeMaxLoad should be a load average like 6.5, not {}",
+                        load
+                    ),
+                ))
+            }
+        }
+    }
+    if config.contains("RequiredConfigOptions") {
+        cmd_config =
+            cmd_config.with_required_config_options(config.get_list("RequiredConfigOptions")?);
+    }
+    Ok(cmd_config)
+}
+
+/// Mark what the bootloader boots by default and next, and which kernels have an entry
+/// Only used for annotations, not being able to read it isn't worth stopping for
+fn annotate_boot_entries(
+    cmd_config: &RunCmdConfig,
+    mut inventory: KernelInventory,
+) -> KernelInventory {
+    let bootloader = match &cmd_config.bootloader {
+        Some(bootloader) => bootloader,
+        None => return inventory,
+    };
+    match bootloader.default_entry(cmd_config.fs.as_ref()) {
+        Ok(default_boot) => inventory = inventory.with_default_boot(default_boot),
+        Err(e) => debug!("Could not tell the default boot entry: {}", e),
+    }
+    match bootloader.saved_default(cmd_config.fs.as_ref()) {
+        Ok(saved_default) => inventory = inventory.with_saved_default(saved_default),
+        Err(e) => debug!("Could not read the saved default boot entry: {}", e),
+    }
+    match bootloader.next_boot(cmd_config.fs.as_ref()) {
+        Ok(next_boot) => inventory = inventory.with_next_boot(next_boot),
+        Err(e) => debug!("Could not tell what boots next: {}", e),
+    }
+    match bootloader.entries(cmd_config.fs.as_ref()) {
+        Ok(entries) => inventory = inventory.with_boot_entries(entries),
+        Err(e) => debug!("Could not read the boot entries: {}", e),
+    }
+    inventory
+}
+
+impl Setup {
+    /// Read the config, mount the install path when the run writes to it and take the inventory
+    pub fn new(
+        parsed_results: &ParseResults,
+        config: Config,
+        state_dir: PathBuf,
+        pretend: PretendStatus,
+        interactive: InteractiveStatus,
+    ) -> Result<Setup, JanitorError> {
+        history::init(&state_dir);
+        if config.contains("ReportPath") {
+            let path = config.get_path("ReportPath")?;
+            let format = match config.contains("ReportFormat") {
+                true => config.get_string("ReportFormat")?.parse::<ReportFormat>()?,
+                false => ReportFormat::from_path(&path),
+            };
+            let only_on_change =
+                config.contains("ReportOnlyOnChange") && config.get_bool("ReportOnlyOnChange")?;
+            report::init(&path, format, only_on_change);
+        }
+        let signing_key = match config.contains("SigningKey") {
+            true => Some(config.get_string("SigningKey")?),
+            false => None,
+        };
+
+        let mut cmd_config = run_cmd_config(&config, pretend, interactive)?;
+
+        let reboot_policy = match config.contains("RebootPolicy") {
+            true => config.get_string("RebootPolicy")?.parse::<RebootPolicy>()?,
+            false => RebootPolicy::Never,
+        };
+
+        let system_log = match config.contains("SystemLog") {
+            true => config
+                .get_string("SystemLog")?
+                .parse::<SystemLogBackend>()?,
+            false => SystemLogBackend::None,
+        };
+        if let Err(e) = syslog::init(system_log) {
+            warn!("{}. Continuing without system logging.", e);
+        }
+
+        let cleanup_policy = cleanup_policy(&config)?;
+        let rebuild_portage_modules = config.get_bool("RebuildPortageModules")?;
+        let pipeline = Pipeline::from_config(&config)?;
+
+        // A build on its own only writes to the source tree, which the build user owns anyway
+        let build_only = parsed_results.flag_enabled("build_only");
+        let user_is_root = utils::user_is_root()?;
+        // Only the commands that need root get wrapped, the build itself stays unprivileged
+        if !cmd_config.pretending()
+            && !build_only
+            && !user_is_root
+            && config.contains("EscalationCommand")
+        {
+            let escalation = config.get_list("EscalationCommand")?;
+            info!(
+                "Not running as root, using {} for privileged commands",
+                escalation.join(" ")
+            );
+            cmd_config = cmd_config.with_escalation(Some(escalation));
+        }
+
+        let mount_boot = config.contains("MountBoot") && config.get_bool("MountBoot")?;
+        let boot_mount = match args::writes_install_path(parsed_results) {
+            true => {
+                mount::BootMount::ensure(&cmd_config, &config.get_path("InstallPath")?, mount_boot)?
+            }
+            false => {
+                mount::warn_if_unmounted(&config.get_path("InstallPath")?);
+                None
+            }
+        };
+        // Resolved once so everything below uses the same path when /boot is a link to the ESP
+        let install_path = utils::paths::resolve_dir(&config.get_path("InstallPath")?);
+        let module_path = utils::paths::resolve_dir(&config.get_path("KernelModulesPath")?);
+        let src_paths: Vec<_> = config
+            .get_paths("KernelSourcePath")?
+            .iter()
+            .map(|path| utils::paths::resolve_dir(path))
+            .collect();
+        let bootloader = BootloaderKind::from_config(&config)?;
+        cmd_config = cmd_config.with_bootloader(bootloader.create(&install_path));
+        let initramfs = InitramfsKind::from_config(&config)?;
+        cmd_config = cmd_config.with_initramfs(initramfs.create(&install_path));
+        cmd_config = cmd_config.with_naming(naming(&config)?);
+        if config.contains("DtbInstallPath") {
+            cmd_config = cmd_config.with_dtb_install_path(Some(config.get_path("DtbInstallPath")?));
+        }
+        if config.contains("ArchiveDir") {
+            cmd_config = cmd_config.with_archive_dir(Some(config.get_path("ArchiveDir")?));
+        }
+        let optional_files = kernel::optional_files(&config)?;
+        let mut search = kernel::KernelSearch::new(&install_path, &src_paths, &module_path)
+            .with_filesystem(cmd_config.fs.clone())
+            .with_naming(cmd_config.naming.clone())
+            .with_dtb_search_path(cmd_config.dtb_install_path.clone())
+            .with_optional_files(optional_files.clone());
+        // Only looking doesn't need a fresh search, anything that changes the system does
+        let only_looking =
+            parsed_results.flag_enabled("list") || parsed_results.flag_enabled("update_check");
+        let cache_use = match (only_looking, cmd_config.pretending()) {
+            (true, false) => Some(CacheUse::Reuse),
+            (true, true) => Some(CacheUse::ReadOnly),
+            (false, false) => Some(CacheUse::Refresh),
+            (false, true) => None,
+        };
+        if let Some(cache_use) = cache_use {
+            search = search.with_cache(search_cache::cache_path(&state_dir), cache_use);
+        }
+        let inventory = search
+            .inventory()?
+            .with_initramfs(cmd_config.initramfs.clone());
+        search.save_cache(&cmd_config);
+        let mut inventory = annotate_boot_entries(&cmd_config, inventory);
+        let runs = history::read(&state_dir).unwrap_or_else(|e| {
+            warn!(
+                "Could not read the history, only the running kernel can be the fallback: {}",
+                e
+            );
+            Vec::new()
+        });
+        let fallback = inventory.last_booted(&runs);
+        if let Some(version) = &fallback {
+            debug!("Keeping {}, it's the last kernel that booted fine", version);
+        }
+        inventory = inventory.with_fallback(fallback.clone());
+        let grub_reboot_fallback =
+            config.contains("GrubRebootFallback") && config.get_bool("GrubRebootFallback")?;
+        // The history only has the runs that changed something, the boot log has every kernel seen running
+        if let (Some(running), false) = (inventory.running(), cmd_config.pretending()) {
+            if let Err(e) = history::record_booted(&cmd_config, &state_dir, &running.version) {
+                debug!("Could not record that {} booted: {}", running.version, e);
+            }
+            if let (Some(bootloader), true) = (&cmd_config.bootloader, grub_reboot_fallback) {
+                if let Err(e) = bootloader::confirm_boot(
+                    &cmd_config,
+                    bootloader.as_ref(),
+                    &running.version,
+                    &state_dir,
+                ) {
+                    warn!("Could not make {} the default: {}", running.version, e);
+                }
+            }
+        }
+        let mut booted: Vec<_> = runs
+            .iter()
+            .filter_map(|record| record.booted.clone())
+            .collect();
+        match history::read_booted(&state_dir) {
+            Ok(logged) => booted.extend(logged),
+            Err(e) => debug!("Could not read the boot log: {}", e),
+        }
+        inventory = inventory.with_booted(&booted);
+        for kernel in inventory.awaiting_boot() {
+            let age = inventory
+                .installed_at(kernel)
+                .ok()
+                .and_then(|installed| installed.elapsed().ok());
+            summary::record_awaiting_boot(kernel.version.clone(), age);
+        }
+        cmd_config = with_build_settings(&config, cmd_config, rebuild_portage_modules)?;
+        let mut removable_roots = vec![install_path.clone(), module_path.clone()];
+        removable_roots.extend(src_paths.iter().cloned());
+        removable_roots.extend(cmd_config.dtb_install_path.clone());
+        cmd_config = cmd_config.with_removable_roots(removable_roots);
+
+        Ok(Setup {
+            config,
+            state_dir,
+            cmd_config,
+            inventory,
+            install_path,
+            module_path,
+            src_paths,
+            optional_files,
+            cleanup_policy,
+            pipeline,
+            rebuild_portage_modules,
+            reboot_policy,
+            signing_key,
+            fallback,
+            grub_reboot_fallback,
+            user_is_root,
+            _boot_mount: boot_mount,
+        })
+    }
+}
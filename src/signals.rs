@@ -0,0 +1,65 @@
+use std::{
+    convert::TryFrom,
+    io,
+    os::raw::c_int,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use crate::sys::{_exit, kill, signal};
+
+const SIGINT: c_int = 2;
+pub const SIGKILL: c_int = 9;
+pub const SIGTERM: c_int = 15;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static UNINTERRUPTIBLE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// The first signal only sets a flag so running commands can be stopped cleanly,
+/// a second one exits immediately
+extern "C" fn on_signal(signum: c_int) {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        unsafe { _exit(128 + signum) }
+    }
+}
+
+/// Catch SIGINT and SIGTERM instead of dying with children still running
+pub fn install_handlers() {
+    unsafe {
+        signal(SIGINT, on_signal);
+        signal(SIGTERM, on_signal);
+    }
+}
+
+/// Send `signum` to every process in the group led by `pgid`
+pub fn kill_group(pgid: u32, signum: c_int) -> io::Result<()> {
+    let pgid = c_int::try_from(pgid).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    match unsafe { kill(-pgid, signum) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// True once the user asked the janitor to stop
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Whether running commands should be killed right now
+/// False while inside `uninterruptible` even if a signal arrived
+pub fn should_abort() -> bool {
+    interrupted() && UNINTERRUPTIBLE_DEPTH.load(Ordering::SeqCst) == 0
+}
+
+/// Run work that must not be left half done, like removing all of a kernel's files
+/// A first signal is remembered and acted on afterwards, a second one still exits
+pub fn uninterruptible<F: FnOnce() -> T, T>(f: F) -> T {
+    struct Guard;
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            UNINTERRUPTIBLE_DEPTH.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+    UNINTERRUPTIBLE_DEPTH.fetch_add(1, Ordering::SeqCst);
+    let _guard = Guard;
+    f()
+}
@@ -0,0 +1,122 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{doctor::Check, error::JanitorError, update::RunCmdConfig, utils};
+
+/// Where GRUB's `check_signatures` looks for them too
+const SIGNATURE_SUFFIX: &str = ".sig";
+
+/// The detached signature of `file`, next to it like `vmlinuz-5.11.8-gentoo.sig`
+pub fn signature_path(file: &Path) -> PathBuf {
+    let mut name = OsString::from(file.as_os_str());
+    name.push(SIGNATURE_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Signatures share the name of what they sign, so the kernel search has to skip them
+pub fn is_signature(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(SIGNATURE_SUFFIX)
+}
+
+/// Detach-sign each of `files` with the GPG key `key`
+/// gpg runs through the escalation command, so the key has to be in root's keyring
+pub fn sign(config: &RunCmdConfig, key: &str, files: &[PathBuf]) -> Result<(), JanitorError> {
+    for file in files {
+        let signature = signature_path(file);
+        let mut gpg = Command::new("gpg");
+        gpg.arg("--batch")
+            .arg("--yes")
+            .arg("--local-user")
+            .arg(key)
+            .arg("--detach-sign")
+            .arg("--output")
+            .arg(&signature)
+            .arg(file);
+        utils::exec_and_print_command(
+            &mut config.privileged(gpg),
+            format!("\'gpg --detach-sign\' {:?} with key {}", file, key),
+            config,
+        )?;
+    }
+    Ok(())
+}
+
+/// Check the signature of each of `files`. Unsigned files are only reported when `required`
+pub fn verify(config: &RunCmdConfig, files: &[PathBuf], required: bool) -> Vec<Check> {
+    let mut checks = Vec::new();
+    for file in files {
+        let name = format!("{} signature", file.display());
+        let signature = signature_path(file);
+        if !signature.exists() {
+            if required {
+                checks.push(Check::warn(
+                    &name,
+                    "not signed".to_string(),
+                    "it was installed before SigningKey was set, rebuild it with --rebuild to sign it",
+                ));
+            }
+            continue;
+        }
+        let mut gpg = Command::new("gpg");
+        gpg.arg("--batch").arg("--verify").arg(&signature).arg(file);
+        checks.push(match config.runner.output(&mut config.privileged(gpg)) {
+            Ok(_) => Check::pass(&name, "good signature".to_string()),
+            Err(e) => Check::fail(
+                &name,
+                format!("gpg --verify failed: {}", e),
+                "the file changed after it was signed, or the key isn't in root's keyring",
+            ),
+        });
+    }
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        doctor::CheckStatus,
+        runner::RecordingRunner,
+        test_utils::TestDir,
+        update::{InteractiveStatus, PretendStatus},
+    };
+    use std::fs;
+
+    #[test]
+    fn sign_and_verify() -> Result<(), JanitorError> {
+        let test_dir = TestDir::new();
+        let signed = test_dir.install_path().join("vmlinuz-5.11.8-gentoo");
+        let unsigned = test_dir.install_path().join("vmlinuz-5.10.27-gentoo");
+        assert!(is_signature(&signature_path(&signed)));
+        assert!(!is_signature(&signed));
+
+        let runner = RecordingRunner::new();
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone());
+        sign(&cfg, "0xDEADBEEF", std::slice::from_ref(&signed))?;
+        assert_eq!(
+            runner.command_lines(),
+            vec![format!(
+                "gpg --batch --yes --local-user 0xDEADBEEF --detach-sign --output {}.sig {}",
+                signed.display(),
+                signed.display()
+            )]
+        );
+
+        // The recording runner didn't write it
+        fs::write(signature_path(&signed), b"").unwrap();
+        let files = vec![signed.clone(), unsigned.clone()];
+        let statuses = |required| {
+            verify(&cfg, &files, required)
+                .iter()
+                .map(|check| check.status)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(statuses(false), vec![CheckStatus::Pass]);
+        assert_eq!(statuses(true), vec![CheckStatus::Pass, CheckStatus::Warn]);
+        Ok(())
+    }
+}
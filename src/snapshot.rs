@@ -0,0 +1,195 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::{ErrorKind, JanitorError},
+    info,
+    update::RunCmdConfig,
+    JanitorErrorFrom,
+};
+
+const MOUNTS: &str = "/proc/mounts";
+
+/// What takes the snapshots configured with `Snapshot`
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SnapshotKind {
+    /// `snapper create` with the root config
+    Snapper,
+    /// A read-only `btrfs subvolume snapshot` into `.snapshots` of every mount that's btrfs
+    Btrfs,
+    /// `zfs snapshot` of the dataset of every mount that's ZFS
+    Zfs,
+}
+
+impl FromStr for SnapshotKind {
+    type Err = JanitorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "snapper" => Ok(SnapshotKind::Snapper),
+            "btrfs" => Ok(SnapshotKind::Btrfs),
+            "zfs" => Ok(SnapshotKind::Zfs),
+            _ => Err(JanitorErrorFrom!(
+                "Unknown snapshot tool {}, expected one of snapper, btrfs, zfs",
+                s
+            )),
+        }
+    }
+}
+
+/// The device and filesystem type `/` and `install_path` are mounted from, in `/proc/mounts` format
+/// The install path is left out when it's on the root filesystem
+fn snapshot_mounts(install_path: &Path, mounts: &str) -> Vec<(PathBuf, String, String)> {
+    let entries: Vec<(PathBuf, String, String)> = mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let device = fields.next()?;
+            let point = fields.next()?;
+            let fstype = fields.next()?;
+            Some((PathBuf::from(point), device.to_string(), fstype.to_string()))
+        })
+        .collect();
+    // The last mount on a point is the one that's visible
+    let mount_of = |path: &Path| {
+        entries
+            .iter()
+            .filter(|(point, _, _)| path.starts_with(point))
+            .max_by_key(|(point, _, _)| point.components().count())
+            .cloned()
+    };
+    let mut found: Vec<_> = mount_of(Path::new("/")).into_iter().collect();
+    if let Some(boot) = mount_of(install_path) {
+        if !found.iter().any(|(point, _, _)| *point == boot.0) {
+            found.push(boot);
+        }
+    }
+    found
+}
+
+/// Snapshots with this name are easy to tell apart from the ones taken by other tools
+fn snapshot_name(timestamp: u64, label: &str) -> String {
+    format!("kernel-janitor-{}-{}", timestamp, label)
+}
+
+/// Snapshot the root filesystem and the install path's, if it's separate, before `label`
+/// (like `install` or `cleanup`). Returns what was created, `snapper:42`, a btrfs snapshot's path
+/// or a ZFS snapshot's name. When pretending nothing is created
+pub fn take(
+    cmd_config: &RunCmdConfig,
+    kind: SnapshotKind,
+    install_path: &Path,
+    label: &str,
+) -> Result<Vec<String>, JanitorError> {
+    if cmd_config.pretending() {
+        info!("Would take a {:?} snapshot before {}", kind, label);
+        return Ok(Vec::new());
+    }
+    let failed = |e: JanitorError| {
+        JanitorError::new(
+            ErrorKind::Other,
+            format!(
+                "Could not take a snapshot before {}, stopping so there's one to roll back to: {}",
+                label, e
+            ),
+        )
+    };
+    if kind == SnapshotKind::Snapper {
+        let mut snapper = Command::new("snapper");
+        snapper
+            .arg("create")
+            .arg("--print-number")
+            .arg("--cleanup-algorithm")
+            .arg("number")
+            .arg("--description")
+            .arg(format!("kernel-janitor before {}", label));
+        let number = cmd_config
+            .runner
+            .output(&mut cmd_config.privileged(snapper))
+            .map_err(failed)?;
+        let id = format!("snapper:{}", number.trim());
+        info!("Took snapshot {} before {}", id, label);
+        return Ok(vec![id]);
+    }
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let name = snapshot_name(timestamp, label);
+    let mounts = fs::read_to_string(MOUNTS).unwrap_or_default();
+    let fstype = match kind {
+        SnapshotKind::Btrfs => "btrfs",
+        _ => "zfs",
+    };
+    let mut ids = Vec::new();
+    for (point, device, _) in snapshot_mounts(install_path, &mounts)
+        .into_iter()
+        .filter(|(_, _, t)| t == fstype)
+    {
+        let (cmd, id) = match kind {
+            SnapshotKind::Btrfs => {
+                let target = point.join(".snapshots").join(&name);
+                let mut btrfs = Command::new("btrfs");
+                btrfs
+                    .arg("subvolume")
+                    .arg("snapshot")
+                    .arg("-r")
+                    .arg(&point)
+                    .arg(&target);
+                (btrfs, target.display().to_string())
+            }
+            _ => {
+                let snapshot = format!("{}@{}", device, name);
+                let mut zfs = Command::new("zfs");
+                zfs.arg("snapshot").arg(&snapshot);
+                (zfs, snapshot)
+            }
+        };
+        cmd_config
+            .run_without_prompt(
+                &mut cmd_config.privileged(cmd),
+                &format!("snapshot {} as {}", point.display(), id),
+            )
+            .map_err(failed)?;
+        ids.push(id);
+    }
+    if ids.is_empty() {
+        return Err(failed(JanitorErrorFrom!(
+            "neither / nor {} is on {}",
+            install_path.display(),
+            fstype
+        )));
+    }
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mounts_to_snapshot() {
+        let mounts = "\
+rpool/ROOT/gentoo / zfs rw,noatime 0 0
+proc /proc proc rw 0 0
+bpool/BOOT/gentoo /boot zfs rw,noatime 0 0
+";
+        let found = snapshot_mounts(Path::new("/boot"), mounts);
+        let devices: Vec<_> = found.iter().map(|(_, d, _)| d.as_str()).collect();
+        assert_eq!(devices, vec!["rpool/ROOT/gentoo", "bpool/BOOT/gentoo"]);
+        // /boot on the root filesystem is only snapshotted once
+        let found = snapshot_mounts(Path::new("/boot"), "/dev/sda2 / btrfs rw 0 0\n");
+        assert_eq!(found.len(), 1);
+        assert_eq!(
+            snapshot_name(1_616_249_100, "cleanup"),
+            "kernel-janitor-1616249100-cleanup"
+        );
+        assert_eq!("ZFS".parse::<SnapshotKind>().unwrap(), SnapshotKind::Zfs);
+    }
+}
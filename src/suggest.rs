@@ -0,0 +1,191 @@
+use std::{cmp::Reverse, fmt};
+
+use crate::{
+    error::JanitorError, inventory::KernelInventory, kernel::KernelVersion, policy::CleanupPolicy,
+    utils,
+};
+
+/// What a suggestion is to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Remove the whole kernel
+    Delete,
+    /// `make clean` in its source tree
+    PruneSources,
+    /// Build it again with compressed modules
+    CompressModules,
+}
+
+impl Action {
+    /// Its name in JSON
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Delete => "delete",
+            Action::PruneSources => "prune-sources",
+            Action::CompressModules => "compress-modules",
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Action::Delete => "delete",
+            Action::PruneSources => "prune sources of",
+            Action::CompressModules => "compress modules of",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Something that would free space, suggesting it doesn't change anything
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suggestion {
+    pub action: Action,
+    pub version: KernelVersion,
+    /// About how much it would free
+    pub bytes: u64,
+    /// Why it's suggested and how to do it
+    pub reason: String,
+}
+
+/// zstd shrinks modules to about a third of their size
+fn compressed_savings(bytes: u64) -> u64 {
+    bytes / 3 * 2
+}
+
+/// What would free space on this system, the most first
+/// The kernels the cleanup policy would remove and the leftovers of removed ones are deleted,
+/// the sources of the others pruned and their modules compressed. Pinned kernels and the
+/// fallback are never suggested for deletion
+pub fn suggest(
+    inventory: &KernelInventory,
+    policy: &dyn CleanupPolicy,
+) -> Result<Vec<Suggestion>, JanitorError> {
+    let mut suggestions = Vec::new();
+    let mut to_delete = policy.select(inventory)?;
+    to_delete.retain(|version| !inventory.is_kept(version));
+    for version in &to_delete {
+        if let Some(kernel) = inventory.by_version(version) {
+            suggestions.push(Suggestion {
+                action: Action::Delete,
+                version: version.clone(),
+                bytes: inventory.size(kernel)?,
+                reason: format!(
+                    "the cleanup policy is to {}, --clean-only removes it",
+                    policy.describe()
+                ),
+            });
+        }
+    }
+    for kernel in inventory.orphans() {
+        if to_delete.contains(&kernel.version) || inventory.is_kept(&kernel.version) {
+            continue;
+        }
+        to_delete.push(kernel.version.clone());
+        suggestions.push(Suggestion {
+            action: Action::Delete,
+            version: kernel.version.clone(),
+            bytes: inventory.size(kernel)?,
+            reason: "its image is gone and can't boot, --delete removes the leftovers".to_string(),
+        });
+    }
+    for kernel in inventory.prunable_sources() {
+        if to_delete.contains(&kernel.version) {
+            continue;
+        }
+        let bytes = inventory.space(kernel)?.build_artifacts;
+        if bytes > 0 {
+            suggestions.push(Suggestion {
+                action: Action::PruneSources,
+                version: kernel.version.clone(),
+                bytes,
+                reason: "it's not built next, prune-sources keeps its .config".to_string(),
+            });
+        }
+    }
+    for kernel in inventory.kernels() {
+        if to_delete.contains(&kernel.version) {
+            continue;
+        }
+        let bytes = inventory.uncompressed_modules(kernel)?;
+        if bytes > 0 {
+            suggestions.push(Suggestion {
+                action: Action::CompressModules,
+                version: kernel.version.clone(),
+                bytes: compressed_savings(bytes),
+                reason: format!(
+                    "{} of them aren't compressed, build it with CONFIG_MODULE_COMPRESS_ZSTD=y",
+                    utils::format_bytes(bytes)
+                ),
+            });
+        }
+    }
+    // Stable, so the ones that free the same stay in the order above
+    suggestions.sort_by_key(|suggestion| Reverse(suggestion.bytes));
+    Ok(suggestions)
+}
+
+/// What `kernel-janitor suggest` prints
+pub fn render(suggestions: &[Suggestion]) -> String {
+    if suggestions.is_empty() {
+        return "Nothing to suggest, there's no space to free without removing a kernel that's kept"
+            .to_string();
+    }
+    let mut lines = vec!["Suggestions, nothing was changed:".to_string()];
+    for suggestion in suggestions {
+        lines.push(format!(
+            "  {:<30}frees ~{:<12}{}",
+            format!("{} {}", suggestion.action, suggestion.version),
+            utils::format_bytes(suggestion.bytes),
+            suggestion.reason
+        ));
+    }
+    let total: u64 = suggestions.iter().map(|suggestion| suggestion.bytes).sum();
+    lines.push(format!(
+        "Doing all of them frees about {}",
+        utils::format_bytes(total)
+    ));
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        policy::KeepNewest,
+        test_utils::{inventory, memory_search},
+    };
+    use std::path::Path;
+
+    #[test]
+    fn suggestions() -> Result<(), JanitorError> {
+        let (_, memfs) = inventory();
+        let memfs = memfs
+            .with_file(Path::new("/usr/src/linux-5.11.0-gentoo/kernel/fork.o"), 300)
+            .with_file(
+                Path::new("/lib/modules/5.11.0-gentoo/kernel/fs/btrfs.ko.zst"),
+                200,
+            );
+        let inventory = memory_search(&memfs).inventory()?;
+
+        let suggestions = suggest(&inventory, &KeepNewest { count: 2 })?;
+        let summary: Vec<_> = suggestions
+            .iter()
+            .map(|s| (s.action, s.version.to_string(), s.bytes))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Action::PruneSources, "5.11.0-gentoo".to_string(), 300),
+                (Action::Delete, "5.10.1-gentoo".to_string(), 132),
+                (Action::CompressModules, "5.11.0-gentoo".to_string(), 12),
+                (Action::Delete, "5.4.97-gentoo".to_string(), 7),
+            ]
+        );
+        let rendered = render(&suggestions);
+        assert!(rendered.contains("delete 5.10.1-gentoo"), "{}", rendered);
+        assert!(rendered.ends_with("frees about 451 B"), "{}", rendered);
+        Ok(())
+    }
+}
@@ -0,0 +1,607 @@
+use std::{
+    convert::TryFrom,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    error::JanitorError,
+    events,
+    history::RunRecord,
+    inventory,
+    json::{self, JsonObject},
+    kernel::KernelVersion,
+    log, signals,
+    update::Stage,
+    utils,
+};
+
+/// What happened to a stage by the end of the run
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum StageOutcome {
+    Done,
+    Failed,
+    /// Disabled by the config file or command line
+    Skipped,
+}
+
+struct StageRecord {
+    stage: Stage,
+    outcome: StageOutcome,
+    elapsed: Option<Duration>,
+}
+
+struct RemovedKernel {
+    version: KernelVersion,
+    bytes: u64,
+}
+
+/// A kernel whose source tree was cleaned, but is still installed
+struct PrunedSources {
+    version: KernelVersion,
+    bytes: u64,
+}
+
+/// Collects the results of a run so they can be printed at the very end
+pub struct Summary {
+    stages: Vec<StageRecord>,
+    removed: Vec<RemovedKernel>,
+    pruned: Vec<PrunedSources>,
+    /// The kernel the build stage finished building
+    built: Option<KernelVersion>,
+    /// How many object files the build found from an earlier one, 0 when it started from scratch
+    reused_objects: Option<usize>,
+    /// Size of every installed kernel after cleanup
+    installed_bytes: Option<u64>,
+    /// Why the run finished but left something that doesn't work, like a missing module
+    degraded: Vec<String>,
+    /// Taken before installing and cleaning up, to roll back to
+    snapshots: Vec<String>,
+    /// Kernels installed earlier that never booted, with how long ago they were installed
+    awaiting_boot: Vec<(KernelVersion, Option<Duration>)>,
+    pretend: bool,
+}
+
+// Stages that are always listed, even when the run stopped before reaching them
+const PIPELINE: [Stage; 7] = [
+    Stage::CopyConfig,
+    Stage::Build,
+    Stage::ModuleRebuild,
+    Stage::ModuleCheck,
+    Stage::Initramfs,
+    Stage::Bootloader,
+    Stage::Cleanup,
+];
+
+/// Past this the recap stops being readable
+const MAX_RECAPPED_WARNINGS: usize = 20;
+
+static SUMMARY: Mutex<Summary> = Mutex::new(Summary::new());
+
+impl StageOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            StageOutcome::Done => "done",
+            StageOutcome::Failed => "failed",
+            StageOutcome::Skipped => "skipped",
+        }
+    }
+}
+
+impl Summary {
+    const fn new() -> Summary {
+        Summary {
+            stages: Vec::new(),
+            removed: Vec::new(),
+            pruned: Vec::new(),
+            built: None,
+            reused_objects: None,
+            installed_bytes: None,
+            degraded: Vec::new(),
+            snapshots: Vec::new(),
+            awaiting_boot: Vec::new(),
+            pretend: false,
+        }
+    }
+
+    fn outcome_of(&self, stage: Stage) -> Option<&StageRecord> {
+        self.stages.iter().rev().find(|r| r.stage == stage)
+    }
+
+    /// Nothing to summarize when only listing or printing help
+    fn is_empty(&self) -> bool {
+        self.stages.is_empty() && self.removed.is_empty() && self.pruned.is_empty()
+    }
+
+    /// Whether the run built, removed or cleaned anything for real
+    fn changed_anything(&self) -> bool {
+        !self.pretend
+            && (self.built.is_some() || !self.removed.is_empty() || !self.pruned.is_empty())
+    }
+
+    /// Removed kernels and cleaned source trees together
+    fn bytes_freed(&self) -> u64 {
+        let removed: u64 = self.removed.iter().map(|r| r.bytes).sum();
+        let pruned: u64 = self.pruned.iter().map(|p| p.bytes).sum();
+        removed + pruned
+    }
+
+    /// What was finished before an interrupt and how to pick up from there
+    fn resume_hint(&self) -> Vec<String> {
+        let done = self
+            .stages
+            .iter()
+            .filter(|r| r.outcome == StageOutcome::Done)
+            .map(|r| r.stage.to_string())
+            .collect::<Vec<_>>();
+        let mut lines = vec![match done.is_empty() {
+            true => "Interrupted before any stage finished".to_string(),
+            false => format!("Interrupted after finishing: {}", done.join(", ")),
+        }];
+        if self.outcome_of(Stage::Build).is_some() {
+            lines.push(
+                "  make only recompiles what changed, so the build continues where it stopped"
+                    .to_string(),
+            );
+        }
+        if self.outcome_of(Stage::Cleanup).is_some() && !self.removed.is_empty() {
+            lines.push(format!(
+                "  {} kernels were removed completely, the rest are still installed",
+                self.removed.len()
+            ));
+        }
+        lines.push("  Run kernel-janitor again to resume".to_string());
+        lines
+    }
+
+    fn render(&self, warnings: &[String]) -> String {
+        let mut lines = Vec::new();
+        match self.pretend {
+            true => lines.push("Summary (pretend, nothing was changed):".to_string()),
+            false => lines.push("Summary:".to_string()),
+        }
+        lines.push(format!("  {:<16}{:<10}{}", "Stage", "Result", "Time"));
+
+        // Pipeline stages first, in order, then anything else that ran
+        let mut stages: Vec<Stage> = PIPELINE.to_vec();
+        for record in &self.stages {
+            if !stages.contains(&record.stage) {
+                stages.push(record.stage);
+            }
+        }
+        for stage in stages {
+            let (outcome, elapsed) = match self.outcome_of(stage) {
+                Some(r) => (
+                    r.outcome.as_str(),
+                    r.elapsed.map(utils::format_duration).unwrap_or_default(),
+                ),
+                None => ("not run", String::new()),
+            };
+            let line = format!("  {:<16}{:<10}{}", stage.to_string(), outcome, elapsed);
+            lines.push(line.trim_end().to_string());
+        }
+
+        let bytes_freed: u64 = self.removed.iter().map(|r| r.bytes).sum();
+        let versions = self
+            .removed
+            .iter()
+            .map(|r| r.version.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        match self.removed.len() {
+            0 => lines.push("  Kernels removed: none".to_string()),
+            n => lines.push(format!(
+                "  Kernels removed: {} ({}), {} freed",
+                n,
+                versions,
+                utils::format_bytes(bytes_freed)
+            )),
+        }
+        if !self.pruned.is_empty() {
+            lines.push(format!(
+                "  Sources pruned:  {} ({}), {} freed",
+                self.pruned.len(),
+                self.pruned
+                    .iter()
+                    .map(|p| p.version.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                utils::format_bytes(self.pruned.iter().map(|p| p.bytes).sum())
+            ));
+        }
+        match self.reused_objects {
+            Some(0) => lines.push("  Build:           from scratch".to_string()),
+            Some(n) => lines.push(format!(
+                "  Build:           incremental, {} objects reused",
+                n
+            )),
+            None => (),
+        }
+        if !self.degraded.is_empty() {
+            lines.push(format!("  Degraded:        {}", self.degraded.join(", ")));
+        }
+        if !self.snapshots.is_empty() {
+            lines.push(format!("  Snapshots:       {}", self.snapshots.join(", ")));
+        }
+        if !self.awaiting_boot.is_empty() {
+            let kernels: Vec<_> = self
+                .awaiting_boot
+                .iter()
+                .map(|(version, age)| match age {
+                    Some(age) => format!("{} (installed {})", version, utils::format_age(*age)),
+                    None => version.to_string(),
+                })
+                .collect();
+            lines.push(format!("  Not booted yet:  {}", kernels.join(", ")));
+        }
+        lines.push(format!("  Warnings:        {}", warnings.len()));
+        // Warnings scroll away during a long build, repeat them here
+        for warning in warnings.iter().take(MAX_RECAPPED_WARNINGS) {
+            lines.push(format!("    - {}", warning));
+        }
+        if warnings.len() > MAX_RECAPPED_WARNINGS {
+            lines.push(format!(
+                "    ...and {} more",
+                warnings.len() - MAX_RECAPPED_WARNINGS
+            ));
+        }
+        if signals::interrupted() {
+            lines.extend(self.resume_hint());
+        }
+        lines.join("\n")
+    }
+
+    /// Only the stages that were recorded, unlike `render`
+    fn to_json(&self, warnings: &[String]) -> JsonObject {
+        let stages = self.stages.iter().map(|r| {
+            let seconds = r.elapsed.map(|e| e.as_secs_f64().to_string());
+            JsonObject::new()
+                .string("stage", &r.stage.to_string())
+                .string("result", r.outcome.as_str())
+                .raw("seconds", seconds.unwrap_or_else(|| "null".to_string()))
+                .to_string()
+        });
+        let removed = self.removed.iter().map(|r| {
+            JsonObject::new()
+                .string("version", &r.version.to_string())
+                .number("bytes", r.bytes)
+                .to_string()
+        });
+        let pruned = self.pruned.iter().map(|p| {
+            JsonObject::new()
+                .string("version", &p.version.to_string())
+                .number("bytes", p.bytes)
+                .to_string()
+        });
+        JsonObject::new()
+            .boolean("pretend", self.pretend)
+            .raw("stages", json::array(stages))
+            .raw("removed", json::array(removed))
+            .raw("pruned", json::array(pruned))
+            .number("bytes_freed", self.bytes_freed())
+            .raw(
+                "build",
+                match self.reused_objects {
+                    Some(0) => json::string("from-scratch"),
+                    Some(_) => json::string("incremental"),
+                    None => "null".to_string(),
+                },
+            )
+            .raw(
+                "degraded",
+                json::array(self.degraded.iter().map(|d| json::string(d))),
+            )
+            .raw(
+                "snapshots",
+                json::array(self.snapshots.iter().map(|s| json::string(s))),
+            )
+            .raw(
+                "awaiting_boot",
+                json::array(
+                    self.awaiting_boot
+                        .iter()
+                        .map(|(version, _)| json::string(&version.to_string())),
+                ),
+            )
+            .raw(
+                "warnings",
+                json::array(warnings.iter().map(|w| json::string(w))),
+            )
+    }
+}
+
+/// A finished run in plain values, what `report` renders
+pub struct RunReport {
+    pub pretend: bool,
+    /// None when the run succeeded
+    pub error: Option<String>,
+    /// Why the run is `degraded`, if it is
+    pub degraded: Vec<String>,
+    /// Stage, result and time like in the printed summary
+    pub stages: Vec<(String, String, String)>,
+    pub built: Option<KernelVersion>,
+    pub removed: Vec<(KernelVersion, u64)>,
+    pub pruned: Vec<(KernelVersion, u64)>,
+    pub bytes_freed: u64,
+    pub warnings: Vec<String>,
+}
+
+fn with_summary<T, F: FnOnce(&mut Summary) -> T>(f: F) -> T {
+    let mut summary = SUMMARY.lock().unwrap_or_else(|e| e.into_inner());
+    f(&mut summary)
+}
+
+pub fn set_pretend(pretend: bool) {
+    with_summary(|s| s.pretend = pretend);
+}
+
+pub fn record_stage(stage: Stage, outcome: StageOutcome, elapsed: Option<Duration>) {
+    with_summary(|s| {
+        s.stages.push(StageRecord {
+            stage,
+            outcome,
+            elapsed,
+        })
+    });
+}
+
+pub fn record_skipped(stage: Stage) {
+    record_stage(stage, StageOutcome::Skipped, None);
+}
+
+pub fn record_removed(version: KernelVersion, bytes: u64) {
+    with_summary(|s| s.removed.push(RemovedKernel { version, bytes }));
+}
+
+pub fn record_pruned(version: KernelVersion, bytes: u64) {
+    with_summary(|s| s.pruned.push(PrunedSources { version, bytes }));
+}
+
+/// The run goes on, but ends up `degraded` instead of `success`
+pub fn record_degraded(reason: String) {
+    with_summary(|s| s.degraded.push(reason));
+}
+
+pub fn record_snapshots(ids: Vec<String>) {
+    with_summary(|s| s.snapshots.extend(ids));
+}
+
+/// `version` was installed `age` ago and never booted, the update isn't finished until it is
+pub fn record_awaiting_boot(version: KernelVersion, age: Option<Duration>) {
+    with_summary(|s| s.awaiting_boot.push((version, age)));
+}
+
+pub fn record_built(version: KernelVersion) {
+    with_summary(|s| s.built = Some(version));
+}
+
+/// The number of objects left from an earlier build of the same tree, tells an incremental
+/// build from one that starts from scratch
+pub fn record_reused_objects(count: usize) {
+    with_summary(|s| s.reused_objects = Some(count));
+}
+
+pub fn record_installed_size(bytes: u64) {
+    with_summary(|s| s.installed_bytes = Some(bytes));
+}
+
+/// `success`, `degraded` or the kind of error that ended the run
+fn outcome(summary: &Summary, error: Option<&JanitorError>) -> String {
+    match error {
+        Some(e) => e.kind().to_string(),
+        None if !summary.degraded.is_empty() => "degraded".to_string(),
+        None => "success".to_string(),
+    }
+}
+
+/// The run for the history, None if it didn't change anything
+pub fn run_record(error: Option<&JanitorError>) -> Option<RunRecord> {
+    with_summary(|s| {
+        if s.pretend || s.is_empty() {
+            return None;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Some(RunRecord {
+            timestamp,
+            outcome: outcome(s, error),
+            built: s.built.clone(),
+            build_time: s
+                .built
+                .as_ref()
+                .and_then(|_| s.outcome_of(Stage::Build))
+                .and_then(|r| r.elapsed),
+            removed: s.removed.iter().map(|r| r.version.clone()).collect(),
+            bytes_freed: s.bytes_freed(),
+            installed_bytes: s.installed_bytes,
+            snapshots: s.snapshots.clone(),
+            booted: inventory::running_release()
+                .and_then(|release| KernelVersion::try_from(release.as_str()).ok()),
+        })
+    })
+}
+
+/// The run for a report, None if nothing was attempted
+pub fn run_report(error: Option<&JanitorError>) -> Option<RunReport> {
+    let warnings = log::warnings();
+    with_summary(|s| {
+        if s.is_empty() {
+            return None;
+        }
+        let stages = s
+            .stages
+            .iter()
+            .map(|r| {
+                (
+                    r.stage.to_string(),
+                    r.outcome.as_str().to_string(),
+                    r.elapsed.map(utils::format_duration).unwrap_or_default(),
+                )
+            })
+            .collect();
+        Some(RunReport {
+            pretend: s.pretend,
+            error: error.map(|e| e.to_string()),
+            degraded: s.degraded.clone(),
+            stages,
+            built: s.built.clone(),
+            removed: s
+                .removed
+                .iter()
+                .map(|r| (r.version.clone(), r.bytes))
+                .collect(),
+            pruned: s
+                .pruned
+                .iter()
+                .map(|p| (p.version.clone(), p.bytes))
+                .collect(),
+            bytes_freed: s.bytes_freed(),
+            warnings,
+        })
+    })
+}
+
+/// Whether the run built, removed or cleaned anything, pretending doesn't count
+pub fn changed_anything() -> bool {
+    with_summary(|s| s.changed_anything())
+}
+
+/// Print the summary if anything was attempted, even with `--quiet`
+pub fn print() {
+    with_summary(|s| {
+        if !s.is_empty() {
+            println!("\n{}", s.render(&log::warnings()));
+        }
+    });
+}
+
+/// The results as they're written in the `run_finished` event
+pub fn to_json() -> JsonObject {
+    with_summary(|s| s.to_json(&log::warnings()))
+}
+
+/// Tell observers the run is over, if anything was attempted
+pub fn finish(error: Option<&JanitorError>) {
+    // Observers read the summary, so it can't stay locked
+    if !with_summary(|s| s.is_empty()) {
+        events::emit(events::Event::RunFinished { error });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn resume_after_interrupted_cleanup() {
+        let mut summary = Summary::new();
+        summary.stages.push(StageRecord {
+            stage: Stage::Build,
+            outcome: StageOutcome::Done,
+            elapsed: None,
+        });
+        summary.stages.push(StageRecord {
+            stage: Stage::Cleanup,
+            outcome: StageOutcome::Failed,
+            elapsed: None,
+        });
+        summary.removed.push(RemovedKernel {
+            version: KernelVersion::try_from("linux-5.4.97-gentoo").unwrap(),
+            bytes: 0,
+        });
+        let hint = summary.resume_hint().join("\n");
+        assert!(
+            hint.starts_with("Interrupted after finishing: build\n"),
+            "{}",
+            hint
+        );
+        assert!(
+            hint.contains("1 kernels were removed completely"),
+            "{}",
+            hint
+        );
+        assert!(
+            hint.ends_with("Run kernel-janitor again to resume"),
+            "{}",
+            hint
+        );
+    }
+
+    #[test]
+    fn changed_anything() {
+        let mut summary = Summary::new();
+        summary.stages.push(StageRecord {
+            stage: Stage::Cleanup,
+            outcome: StageOutcome::Done,
+            elapsed: None,
+        });
+        assert!(!summary.changed_anything());
+        summary.removed.push(RemovedKernel {
+            version: KernelVersion::try_from("linux-5.4.97-gentoo").unwrap(),
+            bytes: 2048,
+        });
+        assert!(summary.changed_anything());
+        summary.pretend = true;
+        assert!(!summary.changed_anything());
+    }
+
+    #[test]
+    fn summary_json() {
+        let mut summary = Summary::new();
+        summary.stages.push(StageRecord {
+            stage: Stage::Build,
+            outcome: StageOutcome::Done,
+            elapsed: Some(Duration::from_millis(1500)),
+        });
+        summary.removed.push(RemovedKernel {
+            version: KernelVersion::try_from("linux-5.4.97-gentoo").unwrap(),
+            bytes: 2048,
+        });
+        assert_eq!(
+            summary.to_json(&["careful".to_string()]).to_string(),
+            r#"{"pretend":false,"stages":[{"stage":"build","result":"done","seconds":1.5}],"removed":[{"version":"5.4.97-gentoo","bytes":2048}],"pruned":[],"bytes_freed":2048,"build":null,"degraded":[],"snapshots":[],"awaiting_boot":[],"warnings":["careful"]}"#
+        );
+    }
+
+    #[test]
+    fn render_summary() {
+        let mut summary = Summary::new();
+        summary.stages.push(StageRecord {
+            stage: Stage::Build,
+            outcome: StageOutcome::Done,
+            elapsed: Some(Duration::from_secs(61)),
+        });
+        summary.stages.push(StageRecord {
+            stage: Stage::ModuleRebuild,
+            outcome: StageOutcome::Skipped,
+            elapsed: None,
+        });
+        summary.removed.push(RemovedKernel {
+            version: KernelVersion::try_from("linux-5.4.97-gentoo").unwrap(),
+            bytes: 2048,
+        });
+        summary.reused_objects = Some(31_337);
+        summary.awaiting_boot.push((
+            KernelVersion::try_from("linux-5.10.1-gentoo").unwrap(),
+            Some(Duration::from_secs(3 * 86400 + 7200)),
+        ));
+
+        let warnings = vec![
+            "Could not parse linux-foo. Ignoring file.".to_string(),
+            "Overwriting previously present config".to_string(),
+        ];
+        let rendered = summary.render(&warnings);
+        println!("{}", rendered);
+        assert!(rendered.contains("build           done      1m 1s"));
+        assert!(rendered.contains("module-rebuild  skipped"));
+        assert!(rendered.contains("bootloader      not run"));
+        assert!(rendered.contains("Kernels removed: 1 (5.4.97-gentoo), 2.0 KiB freed"));
+        assert!(rendered.contains("Build:           incremental, 31337 objects reused"));
+        assert!(rendered.contains("Not booted yet:  5.10.1-gentoo (installed 3 days ago)"));
+        assert!(rendered
+            .contains("Warnings:        2\n    - Could not parse linux-foo. Ignoring file.\n"));
+    }
+}
@@ -0,0 +1,19 @@
+use std::os::raw::{c_char, c_int, c_ulong};
+
+// std already links against libc so these resolve without a dependency
+extern "C" {
+    // Signals
+    pub fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+    pub fn kill(pid: c_int, signum: c_int) -> c_int;
+    pub fn _exit(status: c_int) -> !;
+
+    // Pseudo-terminals
+    pub fn posix_openpt(flags: c_int) -> c_int;
+    pub fn grantpt(fd: c_int) -> c_int;
+    pub fn unlockpt(fd: c_int) -> c_int;
+    pub fn ptsname(fd: c_int) -> *const c_char;
+    pub fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+
+    // Files
+    pub fn access(path: *const c_char, mode: c_int) -> c_int;
+}
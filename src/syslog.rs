@@ -0,0 +1,205 @@
+use std::{os::unix::net::UnixDatagram, path::Path, str::FromStr, sync::OnceLock};
+
+use crate::{error::JanitorError, kernel::KernelVersion, update::Stage, warn, JanitorErrorFrom};
+
+const IDENTIFIER: &str = "kernel-janitor";
+const SYSLOG_SOCKET: &str = "/dev/log";
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+// Syslog priorities, facility is always LOG_DAEMON
+const LOG_DAEMON: u8 = 3 << 3;
+const LOG_ERR: u8 = 3;
+const LOG_INFO: u8 = 6;
+
+/// Where structured records get sent in addition to stdout
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SystemLogBackend {
+    None,
+    Syslog,
+    Journald,
+}
+
+/// A single structured record describing the outcome of a stage
+pub struct Record<'a> {
+    pub stage: Stage,
+    pub version: Option<&'a KernelVersion>,
+    pub error: Option<&'a JanitorError>,
+}
+
+pub struct SystemLogger {
+    backend: SystemLogBackend,
+    socket: UnixDatagram,
+}
+
+static LOGGER: OnceLock<SystemLogger> = OnceLock::new();
+
+impl FromStr for SystemLogBackend {
+    type Err = JanitorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(SystemLogBackend::None),
+            "syslog" => Ok(SystemLogBackend::Syslog),
+            "journald" => Ok(SystemLogBackend::Journald),
+            _ => Err(JanitorErrorFrom!(
+                "Unknown system log backend {}, expected one of none, syslog, journald",
+                s
+            )),
+        }
+    }
+}
+
+impl Record<'_> {
+    fn priority(&self) -> u8 {
+        match self.error {
+            None => LOG_INFO,
+            Some(_) => LOG_ERR,
+        }
+    }
+
+    fn result_str(&self) -> String {
+        match self.error {
+            None => "success".to_string(),
+            Some(e) => format!("failure: {}", e),
+        }
+    }
+
+    fn version_str(&self) -> String {
+        self.version
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| "none".to_string())
+    }
+
+    /// RFC 3164 style line, the fields are embedded as key=value pairs
+    fn to_syslog(&self) -> String {
+        format!(
+            "<{}>{}[{}]: stage={} version={} result={}",
+            LOG_DAEMON | self.priority(),
+            IDENTIFIER,
+            std::process::id(),
+            self.stage,
+            self.version_str(),
+            one_line(&self.result_str())
+        )
+    }
+
+    /// systemd journal native protocol, one `KEY=value` per line
+    fn to_journald(&self) -> String {
+        [
+            format!(
+                "MESSAGE={} {} for version {}",
+                self.stage,
+                one_line(&self.result_str()),
+                self.version_str()
+            ),
+            format!("PRIORITY={}", self.priority()),
+            format!("SYSLOG_IDENTIFIER={}", IDENTIFIER),
+            format!("JANITOR_STAGE={}", self.stage),
+            format!("JANITOR_KERNEL_VERSION={}", self.version_str()),
+            format!("JANITOR_RESULT={}", one_line(&self.result_str())),
+        ]
+        .iter()
+        .map(|field| format!("{}\n", field))
+        .collect()
+    }
+}
+
+/// The simple journald format can't hold newlines in a value
+fn one_line(s: &str) -> String {
+    s.replace('\n', " ")
+}
+
+impl SystemLogger {
+    pub fn connect(backend: SystemLogBackend) -> Result<Option<SystemLogger>, JanitorError> {
+        let socket_path = match backend {
+            SystemLogBackend::None => return Ok(None),
+            SystemLogBackend::Syslog => Path::new(SYSLOG_SOCKET),
+            SystemLogBackend::Journald => Path::new(JOURNALD_SOCKET),
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket
+            .connect(socket_path)
+            .map_err(|e| JanitorErrorFrom!("Could not connect to {:?}: {}", socket_path, e))?;
+        Ok(Some(SystemLogger { backend, socket }))
+    }
+
+    pub fn send(&self, record: &Record) -> Result<(), JanitorError> {
+        let payload = match self.backend {
+            SystemLogBackend::Syslog => record.to_syslog(),
+            SystemLogBackend::Journald => record.to_journald(),
+            SystemLogBackend::None => return Ok(()),
+        };
+        self.socket.send(payload.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Connects to the configured backend, records are dropped until this is called
+pub fn init(backend: SystemLogBackend) -> Result<(), JanitorError> {
+    if let Some(logger) = SystemLogger::connect(backend)? {
+        let _ = LOGGER.set(logger);
+    }
+    Ok(())
+}
+
+/// Send a record to the system log if one was initialized
+pub fn record(stage: Stage, version: Option<&KernelVersion>, error: Option<&JanitorError>) {
+    if let Some(logger) = LOGGER.get() {
+        let rec = Record {
+            stage,
+            version,
+            error,
+        };
+        // Logging failures shouldn't stop kernel maintenance
+        if let Err(e) = logger.send(&rec) {
+            warn!("Could not write to system log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn parse_backend() {
+        assert_eq!(
+            "journald".parse::<SystemLogBackend>().unwrap(),
+            SystemLogBackend::Journald
+        );
+        assert_eq!(
+            "Syslog".parse::<SystemLogBackend>().unwrap(),
+            SystemLogBackend::Syslog
+        );
+        assert!("stdout".parse::<SystemLogBackend>().is_err());
+    }
+
+    #[test]
+    fn journald_fields() {
+        let version = KernelVersion::try_from("linux-5.11.8-gentoo").unwrap();
+        let error = JanitorError::from("make failed\nbadly");
+        let rec = Record {
+            stage: Stage::Build,
+            version: Some(&version),
+            error: Some(&error),
+        };
+        let payload = rec.to_journald();
+        assert!(payload.contains("JANITOR_STAGE=build\n"));
+        assert!(payload.contains("JANITOR_KERNEL_VERSION=5.11.8-gentoo\n"));
+        assert!(payload.contains("PRIORITY=3\n"));
+        assert_eq!(payload.lines().count(), 6);
+    }
+
+    #[test]
+    fn syslog_line() {
+        let rec = Record {
+            stage: Stage::Cleanup,
+            version: None,
+            error: None,
+        };
+        let line = rec.to_syslog();
+        assert!(line.starts_with("<30>kernel-janitor["));
+        assert!(line.ends_with("stage=cleanup version=none result=success"));
+    }
+}
@@ -0,0 +1,197 @@
+//! Fixtures the tests of every module share
+
+use std::{
+    convert::TryFrom,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    filesystem::MemoryFileSystem,
+    inventory::KernelInventory,
+    kernel::{InstalledKernel, KernelSearch, KernelVersion},
+};
+
+static NEXT_TEST_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A temporary directory only one test uses, removed again when dropped
+/// Laid out like a real system with `boot`, `src` and `modules` inside
+pub struct TestDir {
+    root: PathBuf,
+}
+
+impl TestDir {
+    pub fn new() -> TestDir {
+        let thread = std::thread::current();
+        let test_name = thread.name().unwrap_or("unnamed").replace("::", "_");
+        let root = std::env::temp_dir().join(format!(
+            "kernel-janitor-{}-{}-{}",
+            std::process::id(),
+            NEXT_TEST_DIR.fetch_add(1, Ordering::Relaxed),
+            test_name
+        ));
+        // Left over from a crashed run with the same pid
+        let _ = fs::remove_dir_all(&root);
+        for dir in &["boot", "src", "modules"] {
+            let res = fs::create_dir_all(root.join(dir));
+            assert!(res.is_ok(), "Could not create test dir {:?}", root);
+        }
+        // The temp dir may itself be behind a symlink and KernelSearch reports canonical paths
+        let root = fs::canonicalize(&root).unwrap();
+        TestDir { root }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn install_path(&self) -> PathBuf {
+        self.root.join("boot")
+    }
+
+    pub fn module_path(&self) -> PathBuf {
+        self.root.join("modules")
+    }
+
+    pub fn src_path(&self) -> PathBuf {
+        self.root.join("src")
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.root) {
+            eprintln!("Could not remove test dir {:?}: {}", self.root, e);
+        }
+    }
+}
+
+/// Builders for kernels that aren't found by a search
+impl InstalledKernel {
+    pub fn with_module_path(mut self, dir: PathBuf) -> InstalledKernel {
+        self.module_path = Some(dir);
+        self
+    }
+    pub fn with_vmlinuz_path(mut self, dir: PathBuf) -> InstalledKernel {
+        self.vmlinuz_path = Some(dir);
+        self
+    }
+    pub fn with_source_path(mut self, dir: PathBuf) -> InstalledKernel {
+        self.source_path = Some(dir);
+        self
+    }
+    pub fn with_config_path(mut self, dir: PathBuf) -> InstalledKernel {
+        self.config_path = Some(dir);
+        self
+    }
+    pub fn with_system_map_path(mut self, dir: PathBuf) -> InstalledKernel {
+        self.system_map_path = Some(dir);
+        self
+    }
+
+    pub fn create_test_version(
+        test_dir: &TestDir,
+        version_triple: &str,
+        is_old: bool,
+    ) -> InstalledKernel {
+        let is_old_str = match is_old {
+            true => ".old",
+            false => "",
+        };
+        let version_str = format!("linux-{}-gentoo{}", version_triple, is_old_str);
+        let version = KernelVersion::try_from(version_str).unwrap();
+
+        let kernel_image_path = test_dir
+            .install_path()
+            .join(format!("vmlinuz-{}-gentoo{}", version_triple, is_old_str));
+        std::fs::File::create(&kernel_image_path).unwrap();
+        let system_map_path = test_dir.install_path().join(format!(
+            "System.map-{}-gentoo{}",
+            version_triple, is_old_str
+        ));
+        std::fs::File::create(&system_map_path).unwrap();
+        let config_path = test_dir
+            .install_path()
+            .join(format!("config-{}-gentoo{}", version_triple, is_old_str));
+        std::fs::File::create(&config_path).unwrap();
+
+        let module_path = test_dir
+            .module_path()
+            .join(format!("{}-gentoo", version_triple));
+        if !module_path.exists() {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .create(&module_path)
+                .unwrap();
+        }
+        let src_path = test_dir
+            .src_path()
+            .join(format!("linux-{}-gentoo", version_triple));
+        if !src_path.exists() {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .create(&src_path)
+                .unwrap();
+        }
+
+        InstalledKernel {
+            version,
+            module_path: Some(module_path),
+            source_path: Some(src_path),
+            source_root: Some(test_dir.src_path()),
+            vmlinuz_path: Some(kernel_image_path),
+            config_path: Some(config_path),
+            system_map_path: Some(system_map_path),
+            dtb_path: None,
+            extra_paths: Vec::new(),
+            optional: Vec::new(),
+        }
+    }
+}
+
+/// A search of /boot, /usr/src and /lib/modules in `memfs`
+pub fn memory_search(memfs: &MemoryFileSystem) -> KernelSearch {
+    KernelSearch::new(
+        Path::new("/boot"),
+        &[PathBuf::from("/usr/src")],
+        Path::new("/lib/modules"),
+    )
+    .with_filesystem(Arc::new(memfs.clone()))
+}
+
+/// 5.4.97 only has modules left, 5.10.1 and 5.11.0 are complete and 5.12.0 is only sources
+/// Add files to the MemoryFileSystem and search it again with `memory_search` for more
+pub fn inventory() -> (KernelInventory, MemoryFileSystem) {
+    let mut memfs = MemoryFileSystem::new()
+        .with_file(Path::new("/lib/modules/5.4.97-gentoo/kernel/kernel.ko"), 7)
+        .with_file(Path::new("/usr/src/linux-5.12.0-gentoo/Makefile"), 100);
+    for version in &["5.10.1", "5.11.0"] {
+        memfs = memfs
+            .with_file(
+                &PathBuf::from(format!("/boot/vmlinuz-{}-gentoo", version)),
+                10,
+            )
+            .with_file(
+                &PathBuf::from(format!("/boot/config-{}-gentoo", version)),
+                1,
+            )
+            .with_file(
+                &PathBuf::from(format!("/boot/System.map-{}-gentoo", version)),
+                1,
+            )
+            .with_file(
+                &PathBuf::from(format!("/usr/src/linux-{}-gentoo/Makefile", version)),
+                100,
+            )
+            .with_file(
+                &PathBuf::from(format!("/lib/modules/{}-gentoo/kernel/kernel.ko", version)),
+                20,
+            );
+    }
+    let inventory = memory_search(&memfs).inventory().unwrap();
+    (inventory, memfs)
+}
@@ -1,5 +1,70 @@
-use crate::{error::JanitorError, kernel::InstalledKernel, utils, JanitorErrorFrom};
-use std::{collections::BTreeMap, io::BufRead, path::Path, process::Command};
+use crate::{
+    bootloader::Bootloader,
+    debug, error,
+    error::{Context, ErrorKind, JanitorError},
+    events::{self, Event},
+    filesystem::{FileSystem, MemoryFileSystem, RealFileSystem},
+    info,
+    initramfs::InitramfsGenerator,
+    inventory::KernelInventory,
+    kconfig,
+    kernel::{self, InstalledKernel, KernelVersion},
+    modules, mount,
+    naming::{self, NameTemplate, Naming},
+    policy::CleanupPolicy,
+    portage::{self, MakeConf},
+    progress::{self, BuildProgress},
+    runner::{CommandEnv, CommandRunner, RetryPolicy, SystemRunner},
+    signals,
+    summary::{self, StageOutcome},
+    syslog, utils, warn, JanitorErrorFrom,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    io::{self, BufRead, Write},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Arc,
+    time::Instant,
+};
+
+/// A new directory in the temp dir only this user can get into, for files a privileged command
+/// puts in place. Creating it fails on anything already there, links too, so nobody else can have
+/// prepared it
+fn private_staging_dir() -> Result<PathBuf, JanitorError> {
+    for attempt in 0..100 {
+        let dir =
+            std::env::temp_dir().join(format!("kernel-janitor-{}-{}", std::process::id(), attempt));
+        match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).with_context(|| format!("failed to create {:?}", dir)),
+        }
+    }
+    Err(JanitorError::new(
+        ErrorKind::Io,
+        format!(
+            "Could not create a staging directory in {:?}, everything tried already exists",
+            std::env::temp_dir()
+        ),
+    ))
+}
+
+/// Write a file that must not exist yet, readable by this user only
+fn write_new_file(path: &Path, contents: &str) -> Result<(), JanitorError> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .with_context(|| format!("failed to write {:?}", path))
+}
 
 #[derive(PartialEq, Eq)]
 pub enum PretendStatus {
@@ -14,6 +79,500 @@ pub enum InteractiveStatus {
 pub struct RunCmdConfig {
     pub pretend: PretendStatus,
     pub interactive: InteractiveStatus,
+    pub runner: Arc<dyn CommandRunner>,
+    /// In memory when pretending, so nothing on disk is changed
+    pub fs: Arc<dyn FileSystem>,
+    pub env: CommandEnv,
+    /// Program and arguments (e.g. `sudo` or `doas`) that commands needing root are run through
+    /// None when already running as root
+    pub escalation: Option<Vec<String>>,
+    /// Unprivileged user the compile steps are run as when started as root
+    pub build_user: Option<BuildUser>,
+    /// Directories that files may be deleted from, see `utils::paths::safe_rm`
+    pub removable_roots: Vec<PathBuf>,
+    /// Told about installed and removed kernels, None to leave the boot configuration alone
+    pub bootloader: Option<Arc<dyn Bootloader>>,
+    /// Builds the initramfs of new kernels and removes the ones of old kernels
+    pub initramfs: Option<Arc<dyn InitramfsGenerator>>,
+    /// Out-of-tree modules like `vboxdrv` that every new kernel has to come with
+    pub required_modules: Vec<String>,
+    /// Modules like `nvme` the new kernel has to be able to load, checked with `modprobe --dry-run`
+    pub critical_modules: Vec<String>,
+    /// Options like `CONFIG_BTRFS_FS=y` the `.config` must still have after olddefconfig
+    pub required_config_options: Vec<String>,
+    /// Compile for the CPU of this machine with `-march=native`
+    pub march_native: bool,
+    /// make's `-l`, no new jobs are started while the load average is above it
+    pub max_load: Option<f64>,
+    /// MAKEOPTS and PORTAGE_NICENESS from Portage's make.conf, to build like emerge does
+    pub make_conf: MakeConf,
+    /// The names of the installed files, anything but installkernel's means copying them by hand
+    pub naming: Naming,
+    /// Where `make dtbs_install` puts the device trees of ARM kernels, None to not install them
+    pub dtb_install_path: Option<PathBuf>,
+    /// How commands run with `run_retried` are tried again when they fail
+    pub retry: RetryPolicy,
+    /// Program and arguments that rebuild the out-of-tree modules for the new kernel
+    pub module_rebuild: Vec<String>,
+    /// Where kernels are archived before they're removed, None to only remove them
+    pub archive_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildUser {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl RunCmdConfig {
+    /// Uses a `SystemRunner` so commands are really executed
+    /// Files are only changed in memory when pretending
+    pub fn new(pretend: PretendStatus, interactive: InteractiveStatus) -> RunCmdConfig {
+        let fs: Arc<dyn FileSystem> = match pretend {
+            PretendStatus::Pretend => Arc::new(MemoryFileSystem::over_real()),
+            PretendStatus::RunTheDamnThing => Arc::new(RealFileSystem),
+        };
+        RunCmdConfig {
+            fs,
+            pretend,
+            interactive,
+            runner: Arc::new(SystemRunner::new()),
+            env: CommandEnv::default(),
+            escalation: None,
+            build_user: None,
+            removable_roots: Vec::new(),
+            bootloader: None,
+            initramfs: None,
+            required_modules: Vec::new(),
+            critical_modules: Vec::new(),
+            required_config_options: Vec::new(),
+            march_native: false,
+            max_load: None,
+            make_conf: MakeConf::default(),
+            naming: Naming::default(),
+            dtb_install_path: None,
+            retry: RetryPolicy::default(),
+            module_rebuild: portage::MODULE_REBUILD
+                .iter()
+                .map(|word| word.to_string())
+                .collect(),
+            archive_dir: None,
+        }
+    }
+
+    pub fn with_initramfs(
+        mut self,
+        initramfs: Option<Arc<dyn InitramfsGenerator>>,
+    ) -> RunCmdConfig {
+        self.initramfs = initramfs;
+        self
+    }
+
+    pub fn with_bootloader(mut self, bootloader: Option<Arc<dyn Bootloader>>) -> RunCmdConfig {
+        self.bootloader = bootloader;
+        self
+    }
+
+    pub fn with_required_modules(mut self, modules: Vec<String>) -> RunCmdConfig {
+        self.required_modules = modules;
+        self
+    }
+
+    pub fn with_critical_modules(mut self, modules: Vec<String>) -> RunCmdConfig {
+        self.critical_modules = modules;
+        self
+    }
+
+    pub fn with_required_config_options(mut self, options: Vec<String>) -> RunCmdConfig {
+        self.required_config_options = options;
+        self
+    }
+
+    pub fn with_march_native(mut self, march_native: bool) -> RunCmdConfig {
+        self.march_native = march_native;
+        self
+    }
+
+    pub fn with_max_load(mut self, max_load: Option<f64>) -> RunCmdConfig {
+        self.max_load = max_load;
+        self
+    }
+
+    pub fn with_make_conf(mut self, make_conf: MakeConf) -> RunCmdConfig {
+        self.make_conf = make_conf;
+        self
+    }
+
+    /// Run `cmd` under `nice` when make.conf has a PORTAGE_NICENESS
+    pub fn niced(&self, cmd: Command) -> Command {
+        let niceness = match self.make_conf.niceness {
+            Some(niceness) => niceness,
+            None => return cmd,
+        };
+        let mut wrapped = Command::new("nice");
+        wrapped
+            .arg("-n")
+            .arg(niceness.to_string())
+            .arg(cmd.get_program())
+            .args(cmd.get_args());
+        for (key, value) in cmd.get_envs() {
+            match value {
+                Some(value) => wrapped.env(key, value),
+                None => wrapped.env_remove(key),
+            };
+        }
+        if let Some(dir) = cmd.get_current_dir() {
+            wrapped.current_dir(dir);
+        }
+        wrapped
+    }
+
+    pub fn with_naming(mut self, naming: Naming) -> RunCmdConfig {
+        self.naming = naming;
+        self
+    }
+
+    pub fn with_dtb_install_path(mut self, dtb_install_path: Option<PathBuf>) -> RunCmdConfig {
+        self.dtb_install_path = dtb_install_path;
+        self
+    }
+
+    pub fn with_archive_dir(mut self, archive_dir: Option<PathBuf>) -> RunCmdConfig {
+        self.archive_dir = archive_dir;
+        self
+    }
+
+    pub fn with_removable_roots(mut self, roots: Vec<PathBuf>) -> RunCmdConfig {
+        self.removable_roots = roots;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> RunCmdConfig {
+        self.retry = retry;
+        self
+    }
+
+    /// Rebuild the modules with this instead of `emerge @module-rebuild`, never empty
+    pub fn with_module_rebuild(mut self, command: Vec<String>) -> RunCmdConfig {
+        self.module_rebuild = command;
+        self
+    }
+
+    pub fn with_build_user(mut self, build_user: Option<BuildUser>) -> RunCmdConfig {
+        self.build_user = build_user;
+        self
+    }
+
+    /// Drop to the build user for a command that shouldn't run as root
+    /// Also returns a suffix for the command description
+    pub fn unprivileged(&self, mut cmd: Command) -> (Command, String) {
+        match &self.build_user {
+            Some(user) => {
+                cmd.uid(user.uid).gid(user.gid);
+                (cmd, format!(" as user {}", user.name))
+            }
+            None => (cmd, String::new()),
+        }
+    }
+
+    pub fn with_escalation(mut self, escalation: Option<Vec<String>>) -> RunCmdConfig {
+        self.escalation = escalation.filter(|e| !e.is_empty());
+        self
+    }
+
+    // Commands and file changes go through the methods below. Pretending, commands are only
+    // reported, files only change in `fs`, which is in memory, and nobody is asked anything
+
+    pub fn pretending(&self) -> bool {
+        self.pretend == PretendStatus::Pretend
+    }
+
+    /// Ask before `action` when interactive, never when pretending since nothing is done anyway
+    pub fn confirm(&self, action: &str) -> Result<(), JanitorError> {
+        if self.pretending() || self.interactive == InteractiveStatus::Off {
+            return Ok(());
+        }
+        // Keep asking the user for input until they send something normal
+        loop {
+            println!("{}? (y/n)", action);
+            let mut buf = String::new();
+            std::io::stdin().read_line(&mut buf)?;
+            let buf = buf.to_ascii_lowercase();
+            if buf.starts_with('y') {
+                return Ok(());
+            } else if buf.starts_with('n') {
+                return Err(JanitorError::from("Command declined"));
+            }
+            println!("Could not understand {}", buf);
+        }
+    }
+
+    /// Run a command without the interactive prompt, for steps that were confirmed as a whole
+    /// Only reported when pretending
+    pub fn run_without_prompt(
+        &self,
+        cmd: &mut Command,
+        cmd_desc: &str,
+    ) -> Result<(), JanitorError> {
+        self.run_observed(cmd, cmd_desc, &|_| ())
+    }
+
+    /// `run_without_prompt` that also hands each line of output to `observe`
+    pub fn run_observed(
+        &self,
+        cmd: &mut Command,
+        cmd_desc: &str,
+        observe: &(dyn Fn(&str) + Sync),
+    ) -> Result<(), JanitorError> {
+        events::emit(Event::CommandRun {
+            description: cmd_desc,
+            pretend: self.pretending(),
+        });
+        if self.pretending() {
+            return Ok(());
+        }
+        info!("Running {}", cmd_desc);
+        self.env.apply(cmd);
+        self.runner.run_observed(cmd, cmd_desc, observe)
+    }
+
+    /// `run_without_prompt` for commands that may fail for reasons of the moment, like fetching
+    /// sources. Failed attempts are tried again as `retry` allows, each one is logged as a warning
+    pub fn run_retried(&self, cmd: &mut Command, cmd_desc: &str) -> Result<(), JanitorError> {
+        let mut attempt = 1;
+        loop {
+            let err = match self.run_without_prompt(cmd, cmd_desc) {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            if attempt >= self.retry.attempts || err.kind() == ErrorKind::Interrupted {
+                return Err(err);
+            }
+            warn!(
+                "Attempt {} of {} failed, trying again in {}: {}",
+                attempt,
+                self.retry.attempts,
+                utils::format_duration(self.retry.delay_after(attempt)),
+                err
+            );
+            if !self.retry.wait(attempt) {
+                return Err(err.with_kind(ErrorKind::Interrupted));
+            }
+            attempt += 1;
+        }
+    }
+
+    /// A change made without a command, like writing a file. Reported like a command and only
+    /// reported when pretending
+    pub fn run_in_process<F>(&self, cmd_desc: &str, change: F) -> Result<(), JanitorError>
+    where
+        F: FnOnce() -> Result<(), JanitorError>,
+    {
+        events::emit(Event::CommandRun {
+            description: cmd_desc,
+            pretend: self.pretending(),
+        });
+        if self.pretending() {
+            return Ok(());
+        }
+        info!("Running {}", cmd_desc);
+        change()
+    }
+
+    /// Write `contents` to `path` with `mode`, creating the directories it's in. Places owned by
+    /// root get it staged and put in place with `install` through the escalation command
+    pub fn write_file(&self, path: &Path, contents: &str, mode: u32) -> Result<(), JanitorError> {
+        let cmd_desc = format!("write {:?}", path);
+        if self.escalation.is_some() {
+            let name = utils::paths::filename_from_path(path).unwrap_or_default();
+            // Nothing is staged when pretending, the command is only reported
+            let staging_dir = match self.pretending() {
+                true => None,
+                false => Some(private_staging_dir()?),
+            };
+            let staged = staging_dir
+                .clone()
+                .unwrap_or_else(std::env::temp_dir)
+                .join(name);
+            let mut install = Command::new("install");
+            install
+                .arg("-D")
+                .arg("-m")
+                .arg(format!("{:o}", mode))
+                .arg(&staged)
+                .arg(path);
+            let result = match staging_dir {
+                Some(_) => write_new_file(&staged, contents).and_then(|_| {
+                    self.run_without_prompt(&mut self.privileged(install), &cmd_desc)
+                }),
+                None => self.run_without_prompt(&mut self.privileged(install), &cmd_desc),
+            };
+            if let Some(dir) = staging_dir {
+                let _ = std::fs::remove_dir_all(dir);
+            }
+            return result;
+        }
+        self.run_in_process(&cmd_desc, || {
+            if let Some(dir) = path.parent() {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("failed to create {:?}", dir))?;
+            }
+            std::fs::write(path, contents)
+                .with_context(|| format!("failed to write {:?}", path))?;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                .with_context(|| format!("failed to set the mode of {:?}", path))
+        })
+    }
+
+    /// `mkdir -p`, through the escalation command when there is one
+    pub fn create_dir_all(&self, path: &Path) -> Result<(), JanitorError> {
+        let cmd_desc = format!("create {:?}", path);
+        match self.escalation {
+            Some(_) => {
+                let mut mkdir = Command::new("mkdir");
+                mkdir.arg("-p").arg("--").arg(path);
+                self.run_without_prompt(&mut self.privileged(mkdir), &cmd_desc)
+            }
+            None => self.run_in_process(&cmd_desc, || {
+                std::fs::create_dir_all(path)
+                    .with_context(|| format!("failed to create {:?}", path))
+            }),
+        }
+    }
+
+    /// Wrap a command that needs root in the escalation command
+    /// Variables set on `cmd` are passed through `env` since sudo resets the environment
+    pub fn privileged(&self, cmd: Command) -> Command {
+        let escalation = match &self.escalation {
+            Some(e) => e,
+            None => return cmd,
+        };
+        let mut wrapped = Command::new(&escalation[0]);
+        wrapped.args(&escalation[1..]).arg("env");
+        for (key, value) in cmd.get_envs() {
+            if let Some(value) = value {
+                let mut assignment = key.to_os_string();
+                assignment.push("=");
+                assignment.push(value);
+                wrapped.arg(assignment);
+            }
+        }
+        wrapped.arg(cmd.get_program()).args(cmd.get_args());
+        if let Some(dir) = cmd.get_current_dir() {
+            wrapped.current_dir(dir);
+        }
+        wrapped
+    }
+
+    pub fn with_env(mut self, env: CommandEnv) -> RunCmdConfig {
+        self.env = env;
+        self
+    }
+
+    pub fn with_runner<R: CommandRunner + 'static>(mut self, runner: R) -> RunCmdConfig {
+        self.runner = Arc::new(runner);
+        self
+    }
+}
+
+/// The steps of an upgrade, used to label what was being done when reporting results
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Stage {
+    /// The patches in `PatchesDir`, only when it's set
+    Patch,
+    CopyConfig,
+    Build,
+    ModuleRebuild,
+    ModuleCheck,
+    Initramfs,
+    Bootloader,
+    Cleanup,
+    /// The initramfs of the kernels that are kept, with `--all-initramfs`
+    RetainedInitramfs,
+    Delete,
+    PruneSources,
+    /// A command of the user's from the `[pipeline]` section that doesn't replace a stage
+    Command,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Stage::Patch => "patch",
+            Stage::CopyConfig => "copy-config",
+            Stage::Build => "build",
+            Stage::ModuleRebuild => "module-rebuild",
+            Stage::ModuleCheck => "module-check",
+            Stage::Initramfs => "initramfs",
+            Stage::Bootloader => "bootloader",
+            Stage::Cleanup => "cleanup",
+            Stage::RetainedInitramfs => "retained-initramfs",
+            Stage::Delete => "delete",
+            Stage::PruneSources => "prune-sources",
+            Stage::Command => "command",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Stage {
+    /// Errors from a stage are reported as this kind
+    pub fn error_kind(&self) -> ErrorKind {
+        match self {
+            Stage::Patch
+            | Stage::CopyConfig
+            | Stage::Build
+            | Stage::ModuleRebuild
+            | Stage::ModuleCheck
+            | Stage::Initramfs
+            | Stage::RetainedInitramfs => ErrorKind::Build,
+            Stage::Bootloader => ErrorKind::Bootloader,
+            Stage::Cleanup | Stage::Delete | Stage::PruneSources => ErrorKind::Cleanup,
+            Stage::Command => ErrorKind::Other,
+        }
+    }
+}
+
+/// Runs a single stage and reports its outcome to the system log and summary
+/// Errors coming out of it are tagged with the stage's `ErrorKind`
+pub fn run_stage<F, T>(
+    stage: Stage,
+    version: Option<&KernelVersion>,
+    stage_fn: F,
+) -> Result<T, JanitorError>
+where
+    F: FnOnce() -> Result<T, JanitorError>,
+{
+    if signals::interrupted() {
+        return Err(JanitorError::new(
+            ErrorKind::Interrupted,
+            format!("Interrupted before the {} stage", stage),
+        ));
+    }
+    events::emit(Event::StageStarted { stage, version });
+    let start = Instant::now();
+    let result = stage_fn().map_err(|e| match e.kind() {
+        ErrorKind::Interrupted | ErrorKind::NotRoot => e,
+        _ if signals::interrupted() => e.with_kind(ErrorKind::Interrupted),
+        _ => e.with_kind(stage.error_kind()),
+    });
+    if result.is_err() && signals::interrupted() {
+        error!("The {} stage was aborted", stage);
+    }
+    let outcome = match result {
+        Ok(_) => StageOutcome::Done,
+        Err(_) => StageOutcome::Failed,
+    };
+    summary::record_stage(stage, outcome, Some(start.elapsed()));
+    let error = result.as_ref().err();
+    events::emit(Event::StageFinished {
+        stage,
+        version,
+        error,
+    });
+    syslog::record(stage, version, error);
+    result
 }
 
 /// Expects the newest kernel that has already been built
@@ -25,125 +584,972 @@ pub fn copy_config(
     // Copy most recent kernel config over
     let to = newest_source_dir.join(".config");
     let cmd_desc = format!("copy from {:?} to {:?}", newest_config, to);
-    match &cmd_config.pretend {
-        PretendStatus::Pretend => {
-            println!("Pretending to {}", &cmd_desc);
-        }
-        PretendStatus::RunTheDamnThing => {
-            utils::maybe_prompt_for_confirmation(cmd_config, &cmd_desc)?;
-            println!("Running {}", cmd_desc);
-            let res = std::fs::copy(newest_config, to);
-            if res.is_err() {
-                return Err(res.unwrap_err().into());
-            }
-        }
-    };
+    cmd_config.confirm(&cmd_desc)?;
+    if cmd_config.escalation.is_some() {
+        // The source directory is usually owned by root
+        let mut cp = Command::new("cp");
+        cp.arg("--").arg(newest_config).arg(&to);
+        return cmd_config.run_without_prompt(&mut cmd_config.privileged(cp), &cmd_desc);
+    }
+    cmd_config.run_in_process(&cmd_desc, || {
+        // Copy next to the destination and rename so an interrupted copy can't leave half a config
+        let partial = newest_source_dir.join(".config.janitor-partial");
+        let failed_to_copy = || {
+            format!(
+                "failed to copy {} to {}",
+                newest_config.display(),
+                to.display()
+            )
+        };
+        cmd_config
+            .fs
+            .copy(newest_config, &partial)
+            .with_context(failed_to_copy)?;
+        cmd_config
+            .fs
+            .rename(&partial, &to)
+            .with_context(failed_to_copy)
+    })
+}
 
+/// Fails unless the Makefile in `src_dir` says it's the `version` the directory is named after,
+/// a tree that was unpacked into the wrong directory would be installed as something it isn't
+pub fn check_source_version(
+    fs: &dyn FileSystem,
+    src_dir: &Path,
+    version: &KernelVersion,
+) -> Result<(), JanitorError> {
+    let makefile = src_dir.join("Makefile");
+    let contents = fs
+        .read_to_string(&makefile)
+        .with_context(|| format!("failed to read {:?}", makefile))?;
+    let found = kernel::makefile_version(&contents).ok_or_else(|| {
+        JanitorError::new(
+            ErrorKind::Build,
+            format!(
+                "{:?} doesn't say which version it is, was the unpack interrupted?",
+                makefile
+            ),
+        )
+    })?;
+    if found != version.version_triple() {
+        let (major, minor, patch) = found;
+        return Err(JanitorError::new(
+            ErrorKind::Build,
+            format!(
+                "{:?} holds the sources of {}.{}.{}, not {}. Re-emerge the sources or remove the directory",
+                src_dir, major, minor, patch, version
+            ),
+        ));
+    }
     Ok(())
 }
 
+/// `previous_src_dir` is an older, already built source tree used to estimate build progress
 pub fn build_kernel(
     config: &RunCmdConfig,
     src_dir: &Path,
+    previous_src_dir: Option<&Path>,
     install_path: &Path,
 ) -> Result<(), JanitorError> {
-    utils::exec_and_print_command(
-        Command::new("make")
-            .arg("olddefconfig")
-            .current_dir(src_dir),
-        format!("\'make olddefconfig\' in {:?}", src_dir),
-        &config,
-    )?;
+    if let Some(user) = &config.build_user {
+        warn_if_not_owned_by(src_dir, user);
+    }
+    let reused = reusable_objects(src_dir);
+    compile_and_install(
+        config,
+        src_dir,
+        previous_src_dir,
+        install_path,
+        None,
+        reused,
+    )
+}
 
-    // Number of processors
-    let nproc_stdout = Command::new("nproc").output()?.stdout;
-    // Remove whitespace and newlines
-    let nproc = std::str::from_utf8(&nproc_stdout)?.trim();
+/// Only compile the kernel in `src_dir`, for `--build-only`. Nothing outside the tree is touched,
+/// so this can run unprivileged long before `--install-only` installs it
+pub fn compile_kernel(
+    config: &RunCmdConfig,
+    src_dir: &Path,
+    previous_src_dir: Option<&Path>,
+) -> Result<(), JanitorError> {
+    if let Some(user) = &config.build_user {
+        warn_if_not_owned_by(src_dir, user);
+    }
+    let reused = reusable_objects(src_dir);
+    compile(config, src_dir, previous_src_dir, reused)
+}
+
+/// Install the kernel `--build-only` compiled in `src_dir`, for `--install-only`
+/// Refuses a tree that wasn't built or whose .config changed since, that would install a kernel
+/// that doesn't match its config
+pub fn install_built_kernel(
+    config: &RunCmdConfig,
+    src_dir: &Path,
+    install_path: &Path,
+) -> Result<(), JanitorError> {
+    let mut image_name = Command::new("make");
+    image_name.arg("-s").arg("image_name").current_dir(src_dir);
+    let image = src_dir.join(config.runner.output(&mut image_name)?.trim());
+    let modified = |path: &Path| config.fs.modified(path);
+    let built = modified(&image).map_err(|_| {
+        JanitorError::new(
+            ErrorKind::Build,
+            format!(
+                "{:?} hasn't been built yet, run with --build-only first",
+                src_dir
+            ),
+        )
+    })?;
+    let config_path = src_dir.join(".config");
+    let configured =
+        modified(&config_path).with_context(|| format!("failed to read {:?}", config_path))?;
+    if configured > built {
+        return Err(JanitorError::new(
+            ErrorKind::Build,
+            format!(
+                "{:?} changed after {:?} was built, run with --build-only again",
+                config_path, image
+            ),
+        ));
+    }
+    install(config, src_dir, install_path, None)
+}
+
+/// A renamed Kconfig symbol leaves the new one at its default, which is often off, and olddefconfig
+/// doesn't say anything about it
+fn warn_about_dropped_options(fs: &dyn FileSystem, old_config: &str, config_path: &Path) {
+    let new_config = match fs.read_to_string(config_path) {
+        Ok(new_config) => new_config,
+        Err(e) => return debug!("Could not read {:?}: {}", config_path, e),
+    };
+    let dropped = kconfig::dropped(old_config, &new_config);
+    if !dropped.is_empty() {
+        warn!(
+            "olddefconfig turned off {} options that were on before, check whether they were renamed: {}",
+            dropped.len(),
+            dropped.join(", ")
+        );
+    }
+}
 
-    // make -j $(nproc)
+/// The objects make can reuse from an earlier build in `src_dir`, none in a fresh tree
+fn reusable_objects(src_dir: &Path) -> usize {
+    progress::count_objects(src_dir).unwrap_or_else(|e| {
+        debug!("Could not count objects in {:?}: {}", src_dir, e);
+        0
+    })
+}
+
+/// The build itself, from `make olddefconfig` to `make install`
+/// `reused` is how many objects are left from an earlier build, for the summary, and the modules go
+/// below `module_staging` instead of the module path when it's given
+fn compile_and_install(
+    config: &RunCmdConfig,
+    src_dir: &Path,
+    previous_src_dir: Option<&Path>,
+    install_path: &Path,
+    module_staging: Option<&Path>,
+    reused: usize,
+) -> Result<(), JanitorError> {
+    compile(config, src_dir, previous_src_dir, reused)?;
+    install(config, src_dir, install_path, module_staging)
+}
+
+/// `make olddefconfig` and `make`, as the build user when there is one
+fn compile(
+    config: &RunCmdConfig,
+    src_dir: &Path,
+    previous_src_dir: Option<&Path>,
+    reused: usize,
+) -> Result<(), JanitorError> {
+    match reused {
+        0 => info!("Building {:?} from scratch", src_dir),
+        n => info!(
+            "Building {:?} incrementally, {} objects are left from the last build",
+            src_dir, n
+        ),
+    }
+    summary::record_reused_objects(reused);
+
+    // What the config was before olddefconfig carried it over to this version
+    let config_path = src_dir.join(".config");
+    let old_config = match config.pretending() {
+        true => None,
+        false => config.fs.read_to_string(&config_path).ok(),
+    };
+    let mut olddefconfig = Command::new("make");
+    olddefconfig.arg("olddefconfig").current_dir(src_dir);
+    let (mut olddefconfig, as_user) = config.unprivileged(olddefconfig);
     utils::exec_and_print_command(
-        Command::new("make")
-            .arg("-j")
-            .arg(nproc)
-            .current_dir(src_dir),
-        format!("\'make -j{}\' in {:?}", nproc, src_dir),
-        &config,
+        &mut olddefconfig,
+        format!("\'make olddefconfig\' in {:?}{}", src_dir, as_user),
+        config,
     )?;
+    match config.pretending() {
+        true => {
+            debug!("olddefconfig didn't run when pretending, not checking the required options")
+        }
+        false => {
+            if let Some(old_config) = &old_config {
+                warn_about_dropped_options(config.fs.as_ref(), old_config, &config_path);
+            }
+            kconfig::check_required(src_dir, &config.required_config_options)?
+        }
+    }
+
+    let mut make = Command::new("make");
+    let jobs = match config.make_conf.makeopts.is_empty() {
+        // make -j $(nproc)
+        true => {
+            // Number of processors
+            let nproc_stdout = config.runner.output(&mut Command::new("nproc"))?;
+            // Remove whitespace and newlines
+            let nproc = nproc_stdout.trim();
+            make.arg("-j").arg(nproc);
+            format!("-j{}", nproc)
+        }
+        // The same jobs emerge uses
+        false => {
+            make.args(&config.make_conf.makeopts);
+            config.make_conf.makeopts.join(" ")
+        }
+    };
+    make.current_dir(src_dir);
+    // Still nproc jobs on an idle machine, fewer while something else keeps it busy
+    let load_limit = match config.max_load {
+        Some(load) => {
+            make.arg("-l").arg(load.to_string());
+            format!(" -l{}", load)
+        }
+        None => String::new(),
+    };
+    let mut with_flags = String::new();
+    if config.march_native {
+        // The Kconfig options of the kernel_gcc_patch do the same and more, they win when enabled
+        match kconfig::native_cpu_option(
+            &config.fs.read_to_string(&config_path).unwrap_or_default(),
+        ) {
+            Some(option) => info!(
+                "{} is enabled, building for this CPU without KCFLAGS",
+                option
+            ),
+            None => {
+                make.env("KCFLAGS", MARCH_NATIVE);
+                with_flags = format!(" with KCFLAGS={}", MARCH_NATIVE);
+            }
+        }
+    }
+    let niced = match config.make_conf.niceness {
+        Some(niceness) => format!(" at niceness {}", niceness),
+        None => String::new(),
+    };
+    let (mut make, as_user) = config.unprivileged(config.niced(make));
+    let make_desc = format!(
+        "\'make {}{}\' in {:?}{}{}{}",
+        jobs, load_limit, src_dir, with_flags, niced, as_user
+    );
+    match config.pretending() {
+        true => utils::exec_and_print_command(&mut make, make_desc, config)?,
+        false => {
+            let expected = previous_src_dir.and_then(|dir| match progress::count_objects(dir) {
+                Ok(count) => Some(count),
+                Err(e) => {
+                    debug!("Could not count objects in {:?}: {}", dir, e);
+                    None
+                }
+            });
+            let build_progress = BuildProgress::new(expected);
+            utils::exec_and_observe_command(&mut make, make_desc, config, &|line| {
+                build_progress.observe(line)
+            })?
+        }
+    }
+    Ok(())
+}
 
+/// `make modules_install` and the kernel's files into `install_path`, which need root
+/// With `module_staging` the modules end up in its `lib/modules` instead (INSTALL_MOD_PATH)
+fn install(
+    config: &RunCmdConfig,
+    src_dir: &Path,
+    install_path: &Path,
+    module_staging: Option<&Path>,
+) -> Result<(), JanitorError> {
     // make modules_install
+    let mut modules_install = Command::new("make");
+    modules_install.arg("modules_install").current_dir(src_dir);
+    let with_env = match module_staging {
+        Some(staging) => {
+            modules_install.env("INSTALL_MOD_PATH", staging);
+            format!(" with env INSTALL_MOD_PATH={:?}", staging)
+        }
+        None => String::new(),
+    };
     utils::exec_and_print_command(
-        Command::new("make")
-            .arg("modules_install")
-            .current_dir(src_dir),
-        format!("\'make modules_install\' in {:?}", src_dir),
-        &config,
+        &mut config.privileged(modules_install),
+        format!("\'make modules_install\' in {:?}{}", src_dir, with_env),
+        config,
     )?;
 
+    if let Some(dtb_install_path) = &config.dtb_install_path {
+        install_dtbs(config, src_dir, dtb_install_path)?;
+    }
+
+    if let Some(fstype) = mount::fat_filesystem(install_path) {
+        return install_to_fat(config, src_dir, install_path, &fstype);
+    }
+    if !config.naming.is_default() {
+        let naming = &config.naming;
+        let names: Vec<String> = [
+            (true, &naming.image),
+            (naming.install_config, &naming.config),
+            (naming.install_system_map, &naming.system_map),
+        ]
+        .iter()
+        .filter(|(install, _)| *install)
+        .map(|(_, template)| template.to_string())
+        .collect();
+        info!("Installing {} without 'make install'", names.join(", "));
+        let release = kernel_release(config, src_dir);
+        return copy_into_place(config, src_dir, install_path, &|template| {
+            template.render(&release)
+        });
+    }
+
     // make install (with INSTALL_PATH env)
+    let mut install = Command::new("make");
+    install
+        .arg("install")
+        .current_dir(src_dir)
+        .env("INSTALL_PATH", install_path);
     utils::exec_and_print_command(
-        Command::new("make")
-            .arg("install")
-            .current_dir(src_dir)
-            .env("INSTALL_PATH", install_path),
+        &mut config.privileged(install),
         format!(
             "\'make install\' in {:?} with env INSTALL_PATH={:?}",
             src_dir, install_path
         ),
-        &config,
+        config,
     )?;
     Ok(())
 }
 
-pub fn rebuild_portage_modules(config: &RunCmdConfig) -> Result<(), JanitorError> {
-    // emerge @module-rebuild
+/// Write the configured Pkgbase into the module directory of the kernel built in `src_dir`, which
+/// is how a `{pkgbase}` image is told apart from the kernels it replaced, by the janitor and by
+/// Arch's mkinitcpio alike
+pub fn record_pkgbase(
+    config: &RunCmdConfig,
+    src_dir: &Path,
+    module_path: &Path,
+) -> Result<(), JanitorError> {
+    let pkgbase = match &config.naming.pkgbase {
+        Some(pkgbase) => pkgbase,
+        None => return Ok(()),
+    };
+    let file = module_path
+        .join(kernel_release(config, src_dir))
+        .join(naming::PKGBASE_FILE);
+    config.write_file(&file, &format!("{}\n", pkgbase), 0o644)
+}
+
+/// The architectures whose kernels boot with a device tree from the bootloader
+const DTB_ARCHES: &[&str] = &["arm", "aarch64"];
+
+/// `make dtbs_install` into a directory named after the release in `dtb_install_path`, which is
+/// how the search tells whose device trees they are
+fn install_dtbs(
+    config: &RunCmdConfig,
+    src_dir: &Path,
+    dtb_install_path: &Path,
+) -> Result<(), JanitorError> {
+    if !DTB_ARCHES.contains(&std::env::consts::ARCH) {
+        warn!(
+            "DtbInstallPath is set but {} kernels don't use device trees, not installing them",
+            std::env::consts::ARCH
+        );
+        return Ok(());
+    }
+    let dtb_dir = dtb_install_path.join(kernel_release(config, src_dir));
+    let mut dtbs_install = Command::new("make");
+    dtbs_install
+        .arg("dtbs_install")
+        .current_dir(src_dir)
+        .env("INSTALL_DTBS_PATH", &dtb_dir);
     utils::exec_and_print_command(
-        Command::new("emerge").arg("@module-rebuild"),
-        format!("\'emerge @module-rebuild\'"),
-        &config,
-    )?;
+        &mut config.privileged(dtbs_install),
+        format!(
+            "\'make dtbs_install\' in {:?} with env INSTALL_DTBS_PATH={:?}",
+            src_dir, dtb_dir
+        ),
+        config,
+    )
+}
+
+/// Optimizes for the CPU the build runs on, the kernel might not boot on another one
+const MARCH_NATIVE: &str = "-march=native";
+
+/// Where `--rebuild` has `make install` and `make modules_install` put the files before they
+/// replace the installed ones
+const REBUILD_STAGING: &str = ".kernel-janitor-rebuild";
+
+/// Build and install the kernel in `src_dir` again, for after changing its config or the toolchain
+/// The files and modules are installed into directories next to the installed ones first and then
+/// renamed over them, so a failed build or install leaves the installed kernel alone
+/// Unless `incremental`, `make clean` runs first so nothing built by the old toolchain is linked in,
+/// an incremental rebuild only recompiles what the config change touched
+pub fn rebuild_kernel(
+    config: &RunCmdConfig,
+    src_dir: &Path,
+    previous_src_dir: Option<&Path>,
+    install_path: &Path,
+    module_path: &Path,
+    incremental: bool,
+) -> Result<(), JanitorError> {
+    if let Some(user) = &config.build_user {
+        warn_if_not_owned_by(src_dir, user);
+    }
+    let reused = match incremental {
+        true => reusable_objects(src_dir),
+        false => {
+            let mut clean = Command::new("make");
+            clean.arg("clean").current_dir(src_dir);
+            let (mut clean, as_user) = config.unprivileged(clean);
+            utils::exec_and_print_command(
+                &mut clean,
+                format!("'make clean' in {:?}{}", src_dir, as_user),
+                config,
+            )?;
+            0
+        }
+    };
+    let staging = install_path.join(REBUILD_STAGING);
+    let module_staging = module_path.join(REBUILD_STAGING);
+    config.create_dir_all(&staging)?;
+    config.create_dir_all(&module_staging)?;
+    if let Err(e) = compile_and_install(
+        config,
+        src_dir,
+        previous_src_dir,
+        &staging,
+        Some(&module_staging),
+        reused,
+    ) {
+        if !config.pretending() {
+            for dir in [&staging, &module_staging] {
+                if let Err(cleanup) = kernel::remove_path(dir, config) {
+                    warn!("Could not remove {:?}: {}", dir, cleanup);
+                }
+            }
+        }
+        return Err(e);
+    }
+    if config.pretending() {
+        info!(
+            "Would move the files in {:?} and the modules in {:?} over the installed ones",
+            staging, module_staging
+        );
+        return Ok(());
+    }
+    replace_installed(config, &staging, install_path)?;
+    replace_modules(config, &module_staging, module_path)
+}
+
+/// Swap the module trees `make modules_install` put into `staging` with the installed ones in
+/// `module_path`. The replaced tree is moved into `staging` and removed with it
+fn replace_modules(
+    config: &RunCmdConfig,
+    staging: &Path,
+    module_path: &Path,
+) -> Result<(), JanitorError> {
+    let staged_root = staging.join("lib/modules");
+    let staged = match config.fs.read_dir(&staged_root) {
+        Ok(staged) => staged,
+        Err(e) => {
+            debug!("No modules were installed into {:?}: {}", staged_root, e);
+            Vec::new()
+        }
+    };
+    signals::uninterruptible(|| -> Result<(), JanitorError> {
+        for tree in &staged {
+            let name = tree
+                .file_name()
+                .ok_or_else(|| JanitorErrorFrom!("{:?} has no file name", tree))?;
+            let installed = module_path.join(name);
+            if config.fs.disk_usage(&installed).is_ok() {
+                place_file(config, &installed, &staging.join(name), true)?;
+            }
+            place_file(config, tree, &installed, true)?;
+        }
+        Ok(())
+    })?;
+    kernel::remove_path(staging, config)?;
     Ok(())
 }
 
-pub fn gen_grub_cfg(config: &RunCmdConfig, install_path: &Path) -> Result<(), JanitorError> {
-    // grub-mkconfig -o $install_path/grub/grub.cfg
-    let grub_cfg_path = install_path.join("grub").join("grub.cfg");
-    utils::exec_and_print_command(
-        Command::new("grub-mkconfig").arg("-o").arg(&grub_cfg_path),
-        format!("\'grub-mkconfig -o {:?}\'", grub_cfg_path),
-        &config,
-    )?;
+/// Rename every file in `staging` into `install_path`, replacing the file with the same name
+/// Links like `vmlinuz` and the `.old` files installkernel made in the empty directory are left out,
+/// they'd point the installed ones at the wrong kernel
+fn replace_installed(
+    config: &RunCmdConfig,
+    staging: &Path,
+    install_path: &Path,
+) -> Result<(), JanitorError> {
+    let staged = config
+        .fs
+        .read_dir(staging)
+        .with_context(|| format!("failed to read {:?}", staging))?;
+    signals::uninterruptible(|| -> Result<(), JanitorError> {
+        for file in staged.iter().filter(|file| {
+            !config.fs.is_symlink(file) && file.extension().is_none_or(|ext| ext != "old")
+        }) {
+            let name = file
+                .file_name()
+                .ok_or_else(|| JanitorErrorFrom!("{:?} has no file name", file))?;
+            place_file(config, file, &install_path.join(name), true)?;
+        }
+        Ok(())
+    })?;
+    kernel::remove_path(staging, config)?;
     Ok(())
 }
 
+/// FAT allows none of these in a name
+const FAT_INVALID: &[char] = &['"', '*', '/', ':', '<', '>', '?', '\\', '|'];
+
+/// `name` with the characters FAT doesn't allow replaced, and without the trailing dots
+/// and spaces it would silently drop
+fn fat_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| match FAT_INVALID.contains(&c) || c.is_control() {
+            true => '_',
+            false => c,
+        })
+        .collect::<String>()
+        .trim_end_matches(['.', ' '])
+        .to_string()
+}
+
+/// Copy or rename through the escalation command when there is one, in memory when pretending
+fn place_file(
+    config: &RunCmdConfig,
+    from: &Path,
+    to: &Path,
+    rename: bool,
+) -> Result<(), JanitorError> {
+    let cmd_desc = match rename {
+        true => format!("move {:?} to {:?}", from, to),
+        false => format!("copy {:?} to {:?}", from, to),
+    };
+    if config.escalation.is_some() {
+        let mut cmd = Command::new(if rename { "mv" } else { "cp" });
+        cmd.arg("--").arg(from).arg(to);
+        return config.run_without_prompt(&mut config.privileged(cmd), &cmd_desc);
+    }
+    config.run_in_process(&cmd_desc, || {
+        let failed = || format!("failed to {}", cmd_desc);
+        match rename {
+            true => config.fs.rename(from, to).with_context(failed),
+            false => config.fs.copy(from, to).map(|_| ()).with_context(failed),
+        }
+    })
+}
+
+/// installkernel links `vmlinuz` to the new image and FAT can't hold links, so on an ESP the files
+/// are copied by name like `make install` would, keeping the ones they replace as `.old`
+fn install_to_fat(
+    config: &RunCmdConfig,
+    src_dir: &Path,
+    install_path: &Path,
+    fstype: &str,
+) -> Result<(), JanitorError> {
+    // Without long names not even vmlinuz-<release> fits, and the search couldn't find 8.3 names
+    if fstype == "msdos" {
+        return Err(JanitorError::new(
+            ErrorKind::Build,
+            format!(
+                "{} is mounted as msdos, which only allows 8.3 names. Mount it as vfat instead",
+                install_path.display()
+            ),
+        ));
+    }
+    info!(
+        "{} is on {}, installing without 'make install' since it can't hold symlinks",
+        install_path.display(),
+        fstype
+    );
+    let release = kernel_release(config, src_dir);
+    copy_into_place(config, src_dir, install_path, &|template| {
+        fat_filename(&template.render(&release))
+    })
+}
+
+/// Copy the image, System.map and .config into `install_path` with the names `name` makes from the
+/// configured templates, like `make install` would, keeping the ones they replace as `.old`
+fn copy_into_place(
+    config: &RunCmdConfig,
+    src_dir: &Path,
+    install_path: &Path,
+    name: &dyn Fn(&NameTemplate) -> String,
+) -> Result<(), JanitorError> {
+    let mut image_name = Command::new("make");
+    image_name.arg("-s").arg("image_name").current_dir(src_dir);
+    let image = src_dir.join(config.runner.output(&mut image_name)?.trim());
+    let files = [
+        (true, image, name(&config.naming.image)),
+        (
+            config.naming.install_system_map,
+            src_dir.join("System.map"),
+            name(&config.naming.system_map),
+        ),
+        (
+            config.naming.install_config,
+            src_dir.join(".config"),
+            name(&config.naming.config),
+        ),
+    ];
+    signals::uninterruptible(|| {
+        for (_, from, name) in files.iter().filter(|(install, _, _)| *install) {
+            let to = install_path.join(name);
+            if config.fs.disk_usage(&to).is_ok() {
+                place_file(
+                    config,
+                    &to,
+                    &to.with_file_name(format!("{}.old", name)),
+                    true,
+                )?;
+            }
+            place_file(config, from, &to, false)?;
+        }
+        Ok(())
+    })
+}
+
+/// The build user has to be able to write to the source tree
+fn warn_if_not_owned_by(src_dir: &Path, user: &BuildUser) {
+    match std::fs::metadata(src_dir) {
+        Ok(metadata) if metadata.uid() != user.uid => warn!(
+            "{:?} isn't owned by build user {}, the build will probably fail. Try \'chown -R {} {:?}\'",
+            src_dir, user.name, user.name, src_dir
+        ),
+        Ok(_) => (),
+        Err(e) => warn!("Could not check the owner of {:?}: {}", src_dir, e),
+    }
+}
+
+/// emerge @module-rebuild, or the `ModuleRebuildCommand` it was replaced with
+pub fn rebuild_portage_modules(config: &RunCmdConfig) -> Result<(), JanitorError> {
+    let mut rebuild = Command::new(&config.module_rebuild[0]);
+    rebuild.args(&config.module_rebuild[1..]);
+    // Fetching the sources of the modules can fail when a mirror is having a bad day
+    let cmd_desc = format!("'{}'", config.module_rebuild.join(" "));
+    config.confirm(&cmd_desc)?;
+    config.run_retried(&mut config.privileged(rebuild), &cmd_desc)
+}
+
+/// Check that the out-of-tree modules the system can't do without were built for the kernel
+/// in `src_dir`, before anything is done that makes it the one that boots
+/// and that the CriticalModules would load. Returns the modules that are missing or wouldn't load
+/// but don't stop the run, which leave it degraded
+pub fn check_modules(
+    config: &RunCmdConfig,
+    src_dir: &Path,
+    module_path: &Path,
+) -> Result<Vec<String>, JanitorError> {
+    if config.pretending() {
+        debug!("Nothing was built when pretending, not checking the modules");
+        return Ok(Vec::new());
+    }
+    let release = kernel_release(config, src_dir);
+    let module_dir = module_path.join(&release);
+    let mut missing: Vec<String> = modules::check_packaged(&module_dir)?
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+    missing.extend(modules::check_required(
+        &module_dir,
+        &config.required_modules,
+    )?);
+    for module in &missing {
+        summary::record_degraded(format!("{}.ko is missing", module));
+    }
+    // Ones that are missing already failed
+    let critical: Vec<String> = config
+        .critical_modules
+        .iter()
+        .filter(|module| !missing.contains(module))
+        .cloned()
+        .collect();
+    for module in modules::dry_run_load(config, &release, &critical) {
+        summary::record_degraded(format!("{}.ko wouldn't load", module));
+        missing.push(module);
+    }
+    // The kernel boots without firmware, the hardware just doesn't work, so it's only a warning
+    if let Err(e) = modules::check_firmware(config, &module_dir) {
+        warn!("Could not check the firmware of the new modules: {}", e);
+    }
+    Ok(missing)
+}
+
+/// Build the initramfs for the kernel that was just built in `src_dir`
+pub fn generate_initramfs(
+    config: &RunCmdConfig,
+    generator: &dyn InitramfsGenerator,
+    src_dir: &Path,
+) -> Result<(), JanitorError> {
+    let release = kernel_release(config, src_dir);
+    debug!(
+        "Generating the initramfs for {} with {}",
+        release,
+        generator.name()
+    );
+    generator.generate(config, &release, src_dir)
+}
+
+/// Regenerate the initramfs of every kernel left after cleanup except `newest`, which just got its own
+/// For after changing the generator's config, or when the kernels were installed before the janitor
+pub fn generate_retained_initramfs(
+    config: &RunCmdConfig,
+    generator: &dyn InitramfsGenerator,
+    inventory: &KernelInventory,
+    newest: &KernelVersion,
+) -> Result<(), JanitorError> {
+    let mut failures = Vec::new();
+    // Old versions share the initramfs of the release that replaced them
+    for kernel in inventory
+        .kernels()
+        .iter()
+        .filter(|k| !k.version.is_old() && &k.version != newest)
+    {
+        if signals::interrupted() {
+            return Err(JanitorError::new(
+                ErrorKind::Interrupted,
+                format!("Interrupted before the initramfs of {}", kernel.version),
+            ));
+        }
+        let release = match kernel
+            .vmlinuz_path
+            .as_deref()
+            .and_then(utils::paths::filename_from_path)
+        {
+            // An image named after its pkgbase only has the release in its module directory's name
+            Some(image) => match config.naming.image.release_of(&image).or_else(|| {
+                kernel
+                    .module_path
+                    .as_deref()
+                    .and_then(utils::paths::filename_from_path)
+            }) {
+                Some(release) => release,
+                None => continue,
+            },
+            None => continue,
+        };
+        let src_dir = match (&kernel.source_path, generator.needs_sources()) {
+            (Some(src_dir), _) => src_dir.as_path(),
+            (None, false) => Path::new(""),
+            (None, true) => {
+                warn!(
+                    "Skipping the initramfs of {}, {} needs its sources",
+                    release,
+                    generator.name()
+                );
+                continue;
+            }
+        };
+        debug!(
+            "Generating the initramfs for {} with {}",
+            release,
+            generator.name()
+        );
+        if let Err(e) = generator.generate(config, &release, src_dir) {
+            error!("Could not generate the initramfs of {}: {}", release, e);
+            failures.push(format!("{}: {}", release, e));
+        }
+    }
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(JanitorError::new(
+            ErrorKind::Build,
+            format!(
+                "Could not generate {} initramfs images:\n  {}",
+                failures.len(),
+                failures.join("\n  ")
+            ),
+        )),
+    }
+}
+
+/// What `uname -r` will say once the kernel built in `src_dir` is running
+/// Falls back to the source directory's name, e.g. before the first build or when pretending
+pub fn kernel_release(config: &RunCmdConfig, src_dir: &Path) -> String {
+    let mut make = Command::new("make");
+    make.arg("-s").arg("kernelrelease").current_dir(src_dir);
+    match config.runner.output(&mut make) {
+        Ok(release) if !release.trim().is_empty() => release.trim().to_string(),
+        res => {
+            if let Err(e) = res {
+                debug!("Could not get the kernel release from {:?}: {}", src_dir, e);
+            }
+            utils::paths::filename_from_path(src_dir)
+                .unwrap_or_default()
+                .trim_start_matches("linux-")
+                .to_string()
+        }
+    }
+}
+
+/// Add a boot entry for the newly built kernel and regenerate the bootloader config
+pub fn update_bootloader(
+    config: &RunCmdConfig,
+    bootloader: &dyn Bootloader,
+    newest_version: &KernelVersion,
+) -> Result<(), JanitorError> {
+    debug!("Updating {} for {}", bootloader.name(), newest_version);
+    bootloader.install_entry(config, newest_version)?;
+    bootloader.regenerate(config)?;
+    bootloader.validate(config, newest_version)
+}
+
 //  cleaning up old kernels and their related installed items
 pub fn cleanup_old_installs(
     cmd_config: &RunCmdConfig,
-    num_versions_to_keep: usize,
-    installed_kernels: Vec<InstalledKernel>,
-) -> Result<(), JanitorError> {
-    if installed_kernels.len() <= num_versions_to_keep {
-        Ok(println!(
-            "Configured to delete {} versions but there are only {} present. Skipping cleanup.",
-            num_versions_to_keep,
-            installed_kernels.len()
-        ))
+    policy: &dyn CleanupPolicy,
+    inventory: &mut KernelInventory,
+) -> Result<(), JanitorError> {
+    let mut to_delete = policy.select(inventory)?;
+    to_delete.retain(|version| !inventory.is_kept(version));
+    // Without it GRUB quietly boots the first entry instead, so it's only removed when asked to
+    if let Some(saved) = to_delete
+        .iter()
+        .find(|version| inventory.is_saved_default(version))
+        .cloned()
+    {
+        let keep = match cmd_config.interactive {
+            // Nobody is asked when pretending, the real run would ask before removing it
+            InteractiveStatus::On if cmd_config.pretending() => {
+                info!(
+                    "Would ask before deleting {}, it's the saved default boot entry",
+                    saved
+                );
+                true
+            }
+            InteractiveStatus::On => cmd_config
+                .confirm(&format!(
+                    "{} is the saved default boot entry, delete it anyway",
+                    saved
+                ))
+                .is_err(),
+            InteractiveStatus::Off => true,
+        };
+        if keep {
+            warn!(
+                "Keeping {}, it's the saved default boot entry. Set another default to remove it",
+                saved
+            );
+            to_delete.retain(|version| *version != saved);
+        }
+    }
+    events::emit(Event::CleanupPlanned {
+        policy: &policy.describe(),
+        versions: &to_delete,
+    });
+    if to_delete.is_empty() {
+        info!(
+            "Nothing to remove from the {} installed kernels with the policy to {}. Skipping cleanup.",
+            inventory.len(),
+            policy.describe()
+        );
+        Ok(())
     } else {
         // There's more installed kernels than there are to keep
         // The 'pretend' handling is dealt with in `kernel.uninstall`
-        let num_versions_to_delete = installed_kernels.len() - num_versions_to_keep;
-        utils::maybe_prompt_for_confirmation(
+        let num_versions_to_delete = to_delete.len();
+        cmd_config.confirm(&format!("Delete {} old kernels?", num_versions_to_delete))?;
+        // One kernel that can't be removed shouldn't keep the others around
+        let mut failures = Vec::new();
+        for (attempted, version) in to_delete.into_iter().enumerate() {
+            // Only stop between kernels, never in the middle of one
+            if signals::interrupted() {
+                failures.push(format!(
+                    "Interrupted after attempting {} of {} old kernels",
+                    attempted, num_versions_to_delete
+                ));
+                return Err(cleanup_failed(ErrorKind::Interrupted, failures));
+            }
+            match inventory.uninstall(&version, cmd_config) {
+                Ok(bytes) => summary::record_removed(version, bytes),
+                Err(e) => {
+                    error!("Could not remove kernel {}: {}", version, e);
+                    failures.push(format!("{}: {}", version, e));
+                }
+            }
+        }
+        match failures.is_empty() {
+            true => Ok(()),
+            false => Err(cleanup_failed(ErrorKind::Cleanup, failures)),
+        }
+    }
+}
+
+/// Run `make clean` in the source trees of old kernels that still have build artifacts
+/// Unlike `make distclean` this keeps the `.config` and what's needed to build external modules,
+/// so the kernels stay installed and their sources can be rebuilt later
+pub fn prune_sources(
+    cmd_config: &RunCmdConfig,
+    inventory: &KernelInventory,
+) -> Result<(), JanitorError> {
+    // A built tree always has its vmlinux at the top, `make clean` removes it
+    let to_prune: Vec<(&KernelVersion, &Path)> = inventory
+        .prunable_sources()
+        .into_iter()
+        .filter_map(|k| Some((&k.version, k.source_path.as_deref()?)))
+        .filter(|(_, src_dir)| cmd_config.fs.disk_usage(&src_dir.join("vmlinux")).is_ok())
+        .collect();
+    if to_prune.is_empty() {
+        info!("None of the old source trees have build artifacts left. Skipping pruning.");
+        return Ok(());
+    }
+    let num_to_prune = to_prune.len();
+    cmd_config.confirm(&format!("Clean {} old source trees?", num_to_prune))?;
+    let mut failures = Vec::new();
+    for (attempted, (version, src_dir)) in to_prune.into_iter().enumerate() {
+        if signals::interrupted() {
+            failures.push(format!(
+                "Interrupted after attempting {} of {} old source trees",
+                attempted, num_to_prune
+            ));
+            return Err(cleanup_failed(ErrorKind::Interrupted, failures));
+        }
+        let measure = || cmd_config.fs.disk_usage(src_dir).unwrap_or_default();
+        let before = measure();
+        let mut clean = Command::new("make");
+        clean.arg("clean").current_dir(src_dir);
+        let (mut clean, as_user) = cmd_config.unprivileged(clean);
+        match utils::exec_and_print_command(
+            &mut clean,
+            format!("\'make clean\' in {:?}{}", src_dir, as_user),
             cmd_config,
-            &format!("Delete {} old kernels?", num_versions_to_delete),
-        )?;
-        let removal_result: Result<_, _> = installed_kernels
-            .into_iter()
-            .take(num_versions_to_delete)
-            .map(|kernel| kernel.uninstall(&cmd_config.pretend))
-            .collect();
-        removal_result.map_err(|e| JanitorError::from(e))
+        ) {
+            Ok(()) => summary::record_pruned(version.clone(), before.saturating_sub(measure())),
+            Err(e) => {
+                error!("Could not clean the sources of {}: {}", version, e);
+                failures.push(format!("{}: {}", version, e));
+            }
+        }
+    }
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(cleanup_failed(ErrorKind::Cleanup, failures)),
     }
 }
 
+/// One error listing every kernel that couldn't be removed
+fn cleanup_failed(kind: ErrorKind, failures: Vec<String>) -> JanitorError {
+    JanitorError::new(
+        kind,
+        format!("Cleanup did not finish:\n  {}", failures.join("\n  ")),
+    )
+}
+
 // Useful for testing interactive action
 // https://stackoverflow.com/questions/28370126/how-can-i-test-stdin-and-stdout
 fn prompt_for_char<R>(mut reader: R) -> Result<char, JanitorError>
@@ -157,55 +1563,863 @@ where
         .ok_or(JanitorErrorFrom!("Could not parse input: {}", s))
 }
 
+/// What's listed for one kernel in the selection, like `[x] b) 5.10.1  132 B  [pinned]`
+fn selection_line(
+    inventory: &KernelInventory,
+    letter: char,
+    kernel: &InstalledKernel,
+    selected: bool,
+) -> Result<String, JanitorError> {
+    let markers = inventory.markers(kernel)?;
+    let line = format!(
+        "[{}] {}) {:<16}{:<12}{}",
+        if selected { 'x' } else { ' ' },
+        letter,
+        kernel.version.to_string(),
+        utils::format_bytes(inventory.size(kernel)?),
+        match markers.is_empty() {
+            true => String::new(),
+            false => format!("[{}]", markers.join(", ")),
+        }
+    );
+    Ok(line.trim_end().to_string())
+}
+
+/// Letters toggle the kernels they're listed with until an empty line, kernels cleanup keeps can't
+/// be picked. The kernels picked, oldest first
+fn select_kernels<R>(
+    reader: &mut R,
+    inventory: &KernelInventory,
+    choice_map: &BTreeMap<char, &InstalledKernel>,
+) -> Result<Vec<KernelVersion>, JanitorError>
+where
+    R: BufRead,
+{
+    let mut selected = BTreeSet::new();
+    loop {
+        println!("Installed kernels (newest first):");
+        for (letter, kernel) in choice_map {
+            let line = selection_line(inventory, *letter, kernel, selected.contains(letter))?;
+            println!("{}", line);
+        }
+        println!(
+            "Type the letters of the kernels to select or unselect them, an empty line when done:"
+        );
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        for letter in line.chars().filter(|c| c.is_alphabetic()) {
+            match choice_map.get(&letter) {
+                Some(kernel) if inventory.is_kept(&kernel.version) => {
+                    println!(
+                        "{} is pinned or the fallback, it can't be deleted",
+                        kernel.version
+                    )
+                }
+                Some(_) if !selected.remove(&letter) => {
+                    selected.insert(letter);
+                }
+                Some(_) => (),
+                None => println!("No kernel is listed as {}", letter),
+            }
+        }
+    }
+    let mut versions: Vec<KernelVersion> = selected
+        .iter()
+        .map(|letter| choice_map[letter].version.clone())
+        .collect();
+    versions.sort();
+    Ok(versions)
+}
+
 // Interactive deletion of kernels
 pub fn delete_interactive(
     cmd_config: &RunCmdConfig,
-    installed_kernels: Vec<InstalledKernel>,
+    inventory: &mut KernelInventory,
 ) -> Result<(), JanitorError> {
+    let stdio = std::io::stdin();
+    delete_selected(cmd_config, inventory, &mut stdio.lock())
+}
+
+/// `delete_interactive` with the answers read from `reader`
+fn delete_selected<R>(
+    cmd_config: &RunCmdConfig,
+    inventory: &mut KernelInventory,
+    reader: &mut R,
+) -> Result<(), JanitorError>
+where
+    R: BufRead,
+{
     // Zip up letters with kernels
     // If you have more than 26 kernels then you're kind of screwed
-    let mut choice_map: BTreeMap<char, InstalledKernel> = ('a'..='z')
-        .into_iter()
-        .zip(installed_kernels.into_iter().rev())
-        .collect();
-
-    println!("Listing installed kernels (oldest to newest)...");
-    for (letter, kernel) in choice_map.iter() {
-        println!("{}) {}", letter, kernel);
+    let choice_map: BTreeMap<char, &InstalledKernel> =
+        ('a'..='z').zip(inventory.kernels().iter().rev()).collect();
+    let to_delete = select_kernels(reader, inventory, &choice_map)?;
+    if to_delete.is_empty() {
+        info!("No kernels selected, nothing to delete");
+        return Ok(());
+    }
+    let mut total = 0;
+    for version in &to_delete {
+        if let Some(kernel) = inventory.by_version(version) {
+            total += inventory.size(kernel)?;
+        }
+    }
+    let saved_default = match to_delete.iter().find(|v| inventory.is_saved_default(v)) {
+        Some(version) => format!(", {} is the saved default boot entry", version),
+        None => String::new(),
+    };
+    println!(
+        "Delete {} ({} kernels, {} freed{})? (y/n)",
+        to_delete
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        to_delete.len(),
+        utils::format_bytes(total),
+        saved_default
+    );
+    if !prompt_for_char(&mut *reader)?.eq_ignore_ascii_case(&'y') {
+        return Err(JanitorError::from("Command declined"));
+    }
+    // One kernel that can't be removed shouldn't keep the others around
+    let mut failures = Vec::new();
+    for version in to_delete {
+        match inventory.uninstall(&version, cmd_config) {
+            Ok(bytes) => summary::record_removed(version, bytes),
+            Err(e) => {
+                error!("Could not remove kernel {}: {}", version, e);
+                failures.push(format!("{}: {}", version, e));
+            }
+        }
+    }
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(cleanup_failed(ErrorKind::Cleanup, failures)),
     }
-    let stdio = std::io::stdin();
-    let input = stdio.lock();
-    println!("Select a kernel to delete:");
-    let choice = prompt_for_char(input)?;
-
-    choice_map
-        .remove(&choice)
-        .ok_or(JanitorErrorFrom!("Could not find selected kernel"))?
-        .uninstall(&cmd_config.pretend)?;
-    Ok(())
 }
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::{
+        kernel::KernelSearch, policy::KeepNewest, runner::RecordingRunner, test_utils::inventory,
+    };
+    use std::{convert::TryFrom, path::PathBuf, time::Duration};
     /*
-    use crate::{kernel::KernelSearch, utils::tests::*};
+    use crate::{kernel::KernelSearch, test_utils::*};
 
     fn two_installed_kernels() {
-        cleanup_test_dir();
-        init_test_dir();
+        let test_dir = TestDir::new();
 
-        let dummy_install = InstalledKernel::create_test_version("5.4.97", false);
-        let dummy_install_old = InstalledKernel::create_test_version("5.4.97", true);
-        let install_path = get_test_install_pathbuf();
-        let module_path = get_test_module_pathbuf();
-        let src_path = get_test_src_pathbuf();
+        let dummy_install = InstalledKernel::create_test_version(&test_dir, "5.4.97", false);
+        let dummy_install_old = InstalledKernel::create_test_version(&test_dir, "5.4.97", true);
+        let install_path = test_dir.install_path();
+        let module_path = test_dir.module_path();
+        let src_path = test_dir.src_path();
 
-        let installed_kernels = KernelSearch::new(&install_path, &src_path, &module_path)
+        let installed_kernels = KernelSearch::new(&install_path, &[src_path.clone()], &module_path)
             .execute()
             .unwrap();
     }
     */
 
+    impl RunCmdConfig {
+        pub fn with_fs<F: FileSystem + 'static>(mut self, fs: F) -> RunCmdConfig {
+            self.fs = Arc::new(fs);
+            self
+        }
+    }
+
+    #[test]
+    fn cleanup_continues_after_failure() -> Result<(), JanitorError> {
+        // 5.4.97 has no module directory, so it can't be uninstalled
+        let memfs = MemoryFileSystem::new().with_dir(Path::new("/lib/modules/5.10.1-gentoo"));
+        let memfs = ["5.4.97", "5.10.1", "5.11.0"]
+            .iter()
+            .fold(memfs, |memfs, version| {
+                memfs
+                    .with_file(
+                        &PathBuf::from(format!("/boot/vmlinuz-{}-gentoo", version)),
+                        1,
+                    )
+                    .with_file(
+                        &PathBuf::from(format!("/boot/config-{}-gentoo", version)),
+                        1,
+                    )
+                    .with_file(
+                        &PathBuf::from(format!("/boot/System.map-{}-gentoo", version)),
+                        1,
+                    )
+                    .with_dir(&PathBuf::from(format!("/usr/src/linux-{}-gentoo", version)))
+            });
+        let mut inventory = KernelSearch::new(
+            Path::new("/boot"),
+            &[PathBuf::from("/usr/src")],
+            Path::new("/lib/modules"),
+        )
+        .with_filesystem(Arc::new(memfs.clone()))
+        .inventory()?;
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_fs(memfs.clone())
+            .with_removable_roots(vec![PathBuf::from("/")]);
+
+        let policy = KeepNewest { count: 1 };
+        let err = cleanup_old_installs(&cfg, &policy, &mut inventory).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Cleanup);
+        assert!(err.to_string().contains("\n  5.4.97-gentoo: "), "{}", err);
+        assert!(!memfs.exists(Path::new("/boot/vmlinuz-5.10.1-gentoo")));
+        Ok(())
+    }
+
+    #[test]
+    fn stage_errors_are_tagged() {
+        let res = run_stage(Stage::Bootloader, None, || -> Result<(), JanitorError> {
+            Err(std::io::Error::other("grub-mkconfig went away").into())
+        });
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::Bootloader);
+    }
+
+    /// Three complete kernels, 5.4.97, 5.10.1 and 5.11.0
+    fn three_installed() -> MemoryFileSystem {
+        let mut memfs = MemoryFileSystem::new();
+        for version in &["5.4.97", "5.10.1", "5.11.0"] {
+            memfs = memfs
+                .with_file(
+                    &PathBuf::from(format!("/boot/vmlinuz-{}-gentoo", version)),
+                    10,
+                )
+                .with_file(
+                    &PathBuf::from(format!("/boot/config-{}-gentoo", version)),
+                    1,
+                )
+                .with_file(
+                    &PathBuf::from(format!("/boot/System.map-{}-gentoo", version)),
+                    1,
+                )
+                .with_file(
+                    &PathBuf::from(format!("/usr/src/linux-{}-gentoo/Makefile", version)),
+                    100,
+                )
+                .with_dir(&PathBuf::from(format!("/lib/modules/{}-gentoo", version)));
+        }
+        memfs
+    }
+
+    fn search_in(memfs: &MemoryFileSystem) -> Result<KernelInventory, JanitorError> {
+        KernelSearch::new(
+            Path::new("/boot"),
+            &[PathBuf::from("/usr/src")],
+            Path::new("/lib/modules"),
+        )
+        .with_filesystem(Arc::new(memfs.clone()))
+        .inventory()
+    }
+
+    fn cleanup_config(memfs: &MemoryFileSystem) -> RunCmdConfig {
+        RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_fs(memfs.clone())
+            .with_removable_roots(vec![
+                PathBuf::from("/boot"),
+                PathBuf::from("/usr/src"),
+                PathBuf::from("/lib/modules"),
+            ])
+    }
+
+    #[test]
+    fn cleanup_in_memory() -> Result<(), JanitorError> {
+        let memfs = three_installed();
+        let search = || search_in(&memfs);
+        let cfg = cleanup_config(&memfs);
+
+        cleanup_old_installs(&cfg, &KeepNewest { count: 2 }, &mut search()?)?;
+        let remaining = search()?
+            .kernels()
+            .iter()
+            .map(|k| k.version.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(remaining, vec!["5.10.1-gentoo", "5.11.0-gentoo"]);
+        assert!(!memfs.exists(Path::new("/usr/src/linux-5.4.97-gentoo/Makefile")));
+        Ok(())
+    }
+
+    #[test]
+    fn cleanup_keeps_pinned_and_saved_default() -> Result<(), JanitorError> {
+        let memfs = three_installed();
+        let search = || search_in(&memfs);
+        let cfg = cleanup_config(&memfs);
+
+        let mut inventory = search()?;
+        inventory.pin(KernelVersion::try_from("5.4.97-gentoo").unwrap());
+        cleanup_old_installs(&cfg, &KeepNewest { count: 1 }, &mut inventory)?;
+        assert_eq!(inventory.len(), 2);
+        let remaining = search()?
+            .kernels()
+            .iter()
+            .map(|k| k.version.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(remaining, vec!["5.4.97-gentoo", "5.11.0-gentoo"]);
+        assert!(!memfs.exists(Path::new("/usr/src/linux-5.10.1-gentoo/Makefile")));
+
+        // Nobody is there to confirm removing the saved default, so it stays
+        let mut inventory =
+            search()?.with_saved_default(Some(KernelVersion::try_from("5.4.97-gentoo").unwrap()));
+        cleanup_old_installs(&cfg, &KeepNewest { count: 1 }, &mut inventory)?;
+        assert_eq!(inventory.len(), 2);
+        assert!(memfs.exists(Path::new("/boot/vmlinuz-5.4.97-gentoo")));
+
+        // Pretending plans what a real run does before it's asked, keeping it
+        let memfs = three_installed();
+        let cfg = RunCmdConfig {
+            pretend: PretendStatus::Pretend,
+            interactive: InteractiveStatus::On,
+            ..cleanup_config(&memfs)
+        };
+        let saved = KernelVersion::try_from("5.4.97-gentoo").unwrap();
+        let mut inventory = search_in(&memfs)?.with_saved_default(Some(saved.clone()));
+        cleanup_old_installs(&cfg, &KeepNewest { count: 1 }, &mut inventory)?;
+        assert!(inventory.kernels().iter().any(|k| k.version == saved));
+        assert!(!inventory
+            .kernels()
+            .iter()
+            .any(|k| k.version.to_string() == "5.10.1-gentoo"));
+        Ok(())
+    }
+
+    #[test]
+    fn copy_config_error_names_destination() {
+        let config = Path::new("/boot/config-5.11.8-gentoo");
+        let src_dir = Path::new("/usr/src/linux-5.11.8-gentoo");
+        let memfs = MemoryFileSystem::new().with_dir(src_dir);
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_fs(memfs.clone());
+        let err = copy_config(&cfg, config, src_dir).unwrap_err();
+        assert!(
+            err.to_string().contains(
+                "failed to copy /boot/config-5.11.8-gentoo to /usr/src/linux-5.11.8-gentoo/.config: "
+            ),
+            "{}",
+            err
+        );
+
+        let cfg = cfg.with_fs(memfs.with_file(config, 100));
+        copy_config(&cfg, config, src_dir).unwrap();
+        assert_eq!(cfg.fs.disk_usage(&src_dir.join(".config")).unwrap(), 100);
+        assert!(cfg
+            .fs
+            .disk_usage(&src_dir.join(".config.janitor-partial"))
+            .is_err());
+    }
+
+    #[test]
+    fn build_kernel_commands() -> Result<(), JanitorError> {
+        let runner = RecordingRunner::new().with_output("nproc", "8\n");
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone())
+            .with_env(CommandEnv::sanitized());
+        let src_dir = PathBuf::from("/usr/src/linux-5.11.8-gentoo");
+        build_kernel(&cfg, &src_dir, None, Path::new("/boot"))?;
+
+        assert_eq!(
+            runner.command_lines(),
+            vec![
+                "make olddefconfig",
+                "nproc",
+                "make -j 8",
+                "make modules_install",
+                "make install",
+            ]
+        );
+        let commands = runner.commands();
+        assert!(commands
+            .iter()
+            .filter(|c| c.program == "make")
+            .all(|c| c.current_dir.as_ref() == Some(&src_dir)));
+        assert!(commands
+            .last()
+            .unwrap()
+            .envs
+            .contains(&("INSTALL_PATH".to_string(), "/boot".to_string())));
+        assert!(commands
+            .iter()
+            .filter(|c| c.program == "make")
+            .all(|c| c.envs.contains(&("LC_ALL".to_string(), "C".to_string()))));
+        assert!(commands
+            .iter()
+            .all(|c| c.envs.iter().all(|(key, _)| key != "KCFLAGS")));
+
+        let runner = RecordingRunner::new().with_output("nproc", "8\n");
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone())
+            .with_march_native(true)
+            .with_max_load(Some(6.5));
+        build_kernel(&cfg, &src_dir, None, Path::new("/boot"))?;
+        let kcflags = ("KCFLAGS".to_string(), "-march=native".to_string());
+        let commands = runner.commands();
+        let make = commands
+            .iter()
+            .find(|c| c.args.first().map(String::as_str) == Some("-j"))
+            .unwrap();
+        assert!(make.envs.contains(&kcflags));
+        assert_eq!(make.args, vec!["-j", "8", "-l", "6.5"]);
+
+        // make.conf's MAKEOPTS replace -j$(nproc), and its niceness applies
+        let runner = RecordingRunner::new();
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone())
+            .with_make_conf(MakeConf {
+                makeopts: vec!["-j4".to_string(), "-l4".to_string()],
+                niceness: Some(10),
+            });
+        build_kernel(&cfg, &src_dir, None, Path::new("/boot"))?;
+        assert!(runner
+            .command_lines()
+            .contains(&"nice -n 10 make -j4 -l4".to_string()));
+        assert!(!runner.command_lines().iter().any(|c| c == "nproc"));
+        Ok(())
+    }
+
+    #[test]
+    fn source_version_matches() -> Result<(), JanitorError> {
+        let src_dir = Path::new("/usr/src/linux-5.12.0-gentoo");
+        let version = KernelVersion::try_from("5.12.0-gentoo-r1")?;
+        let makefile = |sublevel: &str| {
+            MemoryFileSystem::new().with_contents(
+                &src_dir.join("Makefile"),
+                &format!(
+                    "# SPDX-License-Identifier: GPL-2.0\nVERSION = 5\nPATCHLEVEL = 12\n\
+                     SUBLEVEL = {}\nEXTRAVERSION =\nNAME = Frozen Wasteland\n",
+                    sublevel
+                ),
+            )
+        };
+        check_source_version(&makefile("0"), src_dir, &version)?;
+
+        // 5.11.8 unpacked into the directory of 5.12.0
+        let memfs = MemoryFileSystem::new().with_contents(
+            &src_dir.join("Makefile"),
+            "VERSION = 5\nPATCHLEVEL = 11\nSUBLEVEL = 8\n",
+        );
+        let err = check_source_version(&memfs, src_dir, &version).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Build);
+        assert!(
+            err.to_string().contains("sources of 5.11.8, not 5.12.0"),
+            "{}",
+            err
+        );
+
+        let err = check_source_version(&makefile(""), src_dir, &version).unwrap_err();
+        assert!(
+            err.to_string().contains("doesn't say which version"),
+            "{}",
+            err
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn module_rebuild_retries() {
+        let runner = RecordingRunner::new().with_failure("emerge @module-rebuild");
+        let retry = RetryPolicy {
+            attempts: 3,
+            delay: Duration::ZERO,
+        };
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone())
+            .with_retry(retry);
+        assert!(rebuild_portage_modules(&cfg).is_err());
+        assert_eq!(runner.command_lines().len(), 3);
+
+        let retry = RetryPolicy {
+            attempts: 4,
+            delay: Duration::from_secs(30),
+        };
+        assert_eq!(retry.delay_after(1), Duration::from_secs(30));
+        assert_eq!(retry.delay_after(3), Duration::from_secs(120));
+    }
+
+    #[test]
+    fn module_rebuild_command() -> Result<(), JanitorError> {
+        let runner = RecordingRunner::new();
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone());
+        rebuild_portage_modules(&cfg)?;
+        let cfg = cfg.with_module_rebuild(
+            [
+                "emerge",
+                "--keep-going",
+                "--jobs=4",
+                "@module-rebuild",
+                "@x11-module-rebuild",
+            ]
+            .iter()
+            .map(|word| word.to_string())
+            .collect(),
+        );
+        rebuild_portage_modules(&cfg)?;
+        assert_eq!(
+            runner.command_lines(),
+            vec![
+                "emerge @module-rebuild",
+                "emerge --keep-going --jobs=4 @module-rebuild @x11-module-rebuild"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn build_then_install() -> Result<(), JanitorError> {
+        let test_dir = crate::test_utils::TestDir::new();
+        let src_dir = test_dir.src_path().join("linux-5.11.8-gentoo");
+        std::fs::create_dir_all(src_dir.join("arch/x86/boot"))?;
+        std::fs::write(src_dir.join(".config"), "CONFIG_EXT4_FS=y\n")?;
+
+        let runner = RecordingRunner::new().with_output("nproc", "8\n");
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone());
+        compile_kernel(&cfg, &src_dir, None)?;
+        assert_eq!(
+            runner.command_lines(),
+            vec!["make olddefconfig", "nproc", "make -j 8"]
+        );
+
+        let install = || -> Result<Vec<String>, JanitorError> {
+            let runner = RecordingRunner::new().with_output("make", "arch/x86/boot/bzImage\n");
+            let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+                .with_runner(runner.clone());
+            install_built_kernel(&cfg, &src_dir, &test_dir.install_path())?;
+            Ok(runner.command_lines())
+        };
+        // The recording runner didn't build anything
+        let err = install().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Build);
+        assert!(err.to_string().contains("--build-only first"), "{}", err);
+
+        let image = src_dir.join("arch/x86/boot/bzImage");
+        std::fs::write(&image, "")?;
+        let built = std::time::SystemTime::now();
+        std::fs::File::options()
+            .write(true)
+            .open(&image)?
+            .set_modified(built)?;
+        // Changed with menuconfig after the build
+        std::fs::File::options()
+            .write(true)
+            .open(src_dir.join(".config"))?
+            .set_modified(built + Duration::from_secs(60))?;
+        let err = install().unwrap_err();
+        assert!(err.to_string().contains("--build-only again"), "{}", err);
+
+        std::fs::File::options()
+            .write(true)
+            .open(&image)?
+            .set_modified(built + Duration::from_secs(120))?;
+        assert_eq!(
+            install()?,
+            vec!["make -s image_name", "make modules_install", "make install"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rebuild_replaces_installed_files() -> Result<(), JanitorError> {
+        let staging = Path::new("/boot").join(REBUILD_STAGING);
+        let memfs = MemoryFileSystem::new()
+            .with_file(Path::new("/boot/vmlinuz-5.11.8-gentoo"), 10)
+            .with_file(Path::new("/boot/System.map-5.11.8-gentoo"), 1)
+            .with_file(&staging.join("vmlinuz-5.11.8-gentoo"), 12)
+            .with_file(&staging.join("System.map-5.11.8-gentoo"), 2)
+            .with_file(&staging.join("config-5.11.8-gentoo"), 3)
+            // What installkernel made of the image it found in the empty directory
+            .with_file(&staging.join("vmlinuz-5.11.8-gentoo.old"), 12);
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_fs(memfs.clone())
+            .with_removable_roots(vec![PathBuf::from("/boot")]);
+        replace_installed(&cfg, &staging, Path::new("/boot"))?;
+
+        let size = |path: &str| memfs.disk_usage(Path::new(path)).ok();
+        assert_eq!(size("/boot/vmlinuz-5.11.8-gentoo"), Some(12));
+        assert_eq!(size("/boot/System.map-5.11.8-gentoo"), Some(2));
+        assert_eq!(size("/boot/config-5.11.8-gentoo"), Some(3));
+        assert_eq!(size("/boot/vmlinuz-5.11.8-gentoo.old"), None);
+        assert!(!memfs.exists(&staging));
+        Ok(())
+    }
+
+    #[test]
+    fn rebuild_replaces_installed_modules() -> Result<(), JanitorError> {
+        let staging = Path::new("/lib/modules").join(REBUILD_STAGING);
+        let memfs = MemoryFileSystem::new()
+            .with_file(Path::new("/lib/modules/5.11.8-gentoo/kernel/old.ko"), 10)
+            .with_file(Path::new("/lib/modules/5.10.1-gentoo/kernel/fs.ko"), 5)
+            .with_file(&staging.join("lib/modules/5.11.8-gentoo/kernel/new.ko"), 12);
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_fs(memfs.clone())
+            .with_removable_roots(vec![PathBuf::from("/lib/modules")]);
+        replace_modules(&cfg, &staging, Path::new("/lib/modules"))?;
+
+        assert!(memfs.exists(Path::new("/lib/modules/5.11.8-gentoo/kernel/new.ko")));
+        assert!(!memfs.exists(Path::new("/lib/modules/5.11.8-gentoo/kernel/old.ko")));
+        assert!(memfs.exists(Path::new("/lib/modules/5.10.1-gentoo/kernel/fs.ko")));
+        assert!(!memfs.exists(&staging));
+        Ok(())
+    }
+
+    #[test]
+    fn rebuild_from_scratch() -> Result<(), JanitorError> {
+        let test_dir = crate::test_utils::TestDir::new();
+        let install_path = test_dir.install_path();
+        let module_path = test_dir.module_path();
+        let src_dir = test_dir.src_path().join("linux-5.11.8-gentoo");
+        let rebuild = |incremental: bool| -> Result<Vec<String>, JanitorError> {
+            let runner = RecordingRunner::new().with_output("nproc", "8\n");
+            let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+                .with_runner(runner.clone())
+                .with_removable_roots(vec![install_path.clone(), module_path.clone()]);
+            rebuild_kernel(
+                &cfg,
+                &src_dir,
+                None,
+                &install_path,
+                &module_path,
+                incremental,
+            )?;
+            Ok(runner.command_lines())
+        };
+        let commands = rebuild(false)?;
+        assert_eq!(commands[..2], ["make clean", "make olddefconfig"]);
+        assert_eq!(commands.last().unwrap(), "make install");
+        assert!(!install_path.join(REBUILD_STAGING).exists());
+        assert!(!module_path.join(REBUILD_STAGING).exists());
+
+        let commands = rebuild(true)?;
+        assert_eq!(commands.first().unwrap(), "make olddefconfig");
+        Ok(())
+    }
+
+    #[test]
+    fn pretend_runs_nothing() -> Result<(), JanitorError> {
+        let runner = RecordingRunner::new();
+        let cfg = RunCmdConfig::new(PretendStatus::Pretend, InteractiveStatus::Off)
+            .with_runner(runner.clone());
+        let grub = crate::bootloader::Grub::new(Path::new("/boot"));
+        update_bootloader(&cfg, &grub, &KernelVersion::new(5, 11, 8, None, false))?;
+        rebuild_portage_modules(&cfg)?;
+        assert!(runner.commands().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn initramfs_for_built_release() -> Result<(), JanitorError> {
+        let runner = RecordingRunner::new().with_output("make", "5.11.8-gentoo-custom\n");
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone());
+        let dracut = crate::initramfs::InitramfsKind::Dracut
+            .create(Path::new("/boot"))
+            .unwrap();
+        generate_initramfs(
+            &cfg,
+            dracut.as_ref(),
+            Path::new("/usr/src/linux-5.11.8-gentoo"),
+        )?;
+        assert_eq!(
+            runner.command_lines(),
+            vec![
+                "make -s kernelrelease",
+                "dracut --force --kver 5.11.8-gentoo-custom /boot/initramfs-5.11.8-gentoo-custom.img"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn initramfs_for_retained_kernels() -> Result<(), JanitorError> {
+        let memfs = MemoryFileSystem::new()
+            .with_file(Path::new("/boot/vmlinuz-5.10.1-gentoo"), 10)
+            .with_file(Path::new("/boot/vmlinuz-5.10.1-gentoo.old"), 10)
+            .with_file(Path::new("/boot/vmlinuz-5.11.8-gentoo"), 10)
+            .with_file(Path::new("/boot/vmlinuz-5.4.97-gentoo"), 10)
+            .with_file(Path::new("/usr/src/linux-5.10.1-gentoo/Makefile"), 10)
+            .with_file(Path::new("/usr/src/linux-5.11.8-gentoo/Makefile"), 10)
+            .with_file(Path::new("/lib/modules/5.10.1-gentoo/kernel/kernel.ko"), 10);
+        let inventory = KernelSearch::new(
+            Path::new("/boot"),
+            &[PathBuf::from("/usr/src")],
+            Path::new("/lib/modules"),
+        )
+        .with_filesystem(Arc::new(memfs))
+        .inventory()?;
+        let newest = KernelVersion::try_from("5.11.8-gentoo")?;
+        let runner = RecordingRunner::new();
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone());
+        let create =
+            |kind: crate::initramfs::InitramfsKind| kind.create(Path::new("/boot")).unwrap();
+
+        let dracut = create(crate::initramfs::InitramfsKind::Dracut);
+        generate_retained_initramfs(&cfg, dracut.as_ref(), &inventory, &newest)?;
+        assert_eq!(
+            runner.command_lines(),
+            vec![
+                "dracut --force --kver 5.4.97-gentoo /boot/initramfs-5.4.97-gentoo.img",
+                "dracut --force --kver 5.10.1-gentoo /boot/initramfs-5.10.1-gentoo.img"
+            ]
+        );
+        // 5.4.97 has no sources left to run genkernel in
+        let genkernel = create(crate::initramfs::InitramfsKind::Genkernel);
+        generate_retained_initramfs(&cfg, genkernel.as_ref(), &inventory, &newest)?;
+        assert_eq!(
+            runner.command_lines()[2],
+            "genkernel --bootdir=/boot --kerneldir=/usr/src/linux-5.10.1-gentoo initramfs"
+        );
+        assert_eq!(runner.command_lines().len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn escalated_commands() -> Result<(), JanitorError> {
+        let runner = RecordingRunner::new().with_output("nproc", "2");
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone())
+            .with_escalation(Some(vec!["doas".to_string()]));
+        build_kernel(&cfg, Path::new("/usr/src/linux"), None, Path::new("/boot"))?;
+        rebuild_portage_modules(&cfg)?;
+
+        assert_eq!(
+            runner.command_lines(),
+            vec![
+                "make olddefconfig",
+                "nproc",
+                "make -j 2",
+                "doas env make modules_install",
+                "doas env INSTALL_PATH=/boot make install",
+                "doas env emerge @module-rebuild",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn place_file_pretends() -> Result<(), JanitorError> {
+        let from = Path::new("/usr/src/linux-5.11.8-gentoo/System.map");
+        let to = Path::new("/boot/System.map-5.11.8-gentoo");
+        let memfs = MemoryFileSystem::new()
+            .with_file(from, 100)
+            .with_dir(Path::new("/boot"));
+        let cfg = RunCmdConfig::new(PretendStatus::Pretend, InteractiveStatus::Off)
+            .with_fs(memfs.clone());
+        place_file(&cfg, from, to, true)?;
+        assert!(memfs.exists(from) && !memfs.exists(to));
+
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_fs(memfs.clone());
+        place_file(&cfg, from, to, true)?;
+        assert!(!memfs.exists(from) && memfs.exists(to));
+        Ok(())
+    }
+
+    #[test]
+    fn fat_filenames() {
+        assert_eq!(fat_filename("5.11.8-gentoo"), "5.11.8-gentoo");
+        assert_eq!(fat_filename("6.8.0-rc3-dirty:2?"), "6.8.0-rc3-dirty_2_");
+        assert_eq!(fat_filename("5.11.8-gentoo+. "), "5.11.8-gentoo+");
+    }
+
+    #[test]
+    fn prune_built_sources() -> Result<(), JanitorError> {
+        let mut memfs = MemoryFileSystem::new();
+        for version in &["5.4.97", "5.10.1", "5.11.0"] {
+            memfs = memfs
+                .with_file(
+                    &PathBuf::from(format!("/boot/vmlinuz-{}-gentoo", version)),
+                    10,
+                )
+                .with_file(
+                    &PathBuf::from(format!("/usr/src/linux-{}-gentoo/.config", version)),
+                    1,
+                );
+        }
+        memfs = memfs.with_dir(Path::new("/lib/modules"));
+        // 5.4.97 was cleaned already, 5.11.0 is built next
+        for version in &["5.10.1", "5.11.0"] {
+            memfs = memfs.with_file(
+                &PathBuf::from(format!("/usr/src/linux-{}-gentoo/vmlinux", version)),
+                100,
+            );
+        }
+        let inventory = KernelSearch::new(
+            Path::new("/boot"),
+            &[PathBuf::from("/usr/src")],
+            Path::new("/lib/modules"),
+        )
+        .with_filesystem(Arc::new(memfs.clone()))
+        .inventory()?;
+        let runner = RecordingRunner::new();
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_fs(memfs)
+            .with_runner(runner.clone());
+
+        prune_sources(&cfg, &inventory)?;
+        assert_eq!(runner.command_lines(), vec!["make clean"]);
+        assert_eq!(
+            runner.commands()[0].current_dir,
+            Some(PathBuf::from("/usr/src/linux-5.10.1-gentoo"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pretend_changes_nothing() -> Result<(), JanitorError> {
+        use crate::test_utils::TestDir;
+        let test_dir = TestDir::new();
+        let file = test_dir.path().join("state").join("pkgbase");
+        let runner = RecordingRunner::new();
+        // Interactive too, but there's nobody asked when pretending
+        let cfg = RunCmdConfig::new(PretendStatus::Pretend, InteractiveStatus::On)
+            .with_runner(runner.clone());
+        cfg.confirm("Delete 2 old kernels")?;
+        utils::exec_and_print_command(&mut Command::new("make"), "make".to_string(), &cfg)?;
+        cfg.run_without_prompt(&mut Command::new("grub-mkconfig"), "grub-mkconfig")?;
+        cfg.create_dir_all(&test_dir.path().join("staging"))?;
+        cfg.write_file(&file, "linux\n", 0o644)?;
+        let cfg = cfg.with_escalation(Some(vec!["sudo".to_string()]));
+        cfg.write_file(&file, "linux\n", 0o644)?;
+        assert!(runner.commands().is_empty());
+        assert!(!test_dir.path().join("staging").exists() && !file.exists());
+
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off);
+        cfg.write_file(&file, "linux\n", 0o640)?;
+        assert_eq!(std::fs::read_to_string(&file)?, "linux\n");
+        assert_eq!(
+            std::fs::metadata(&file)?.permissions().mode() & 0o777,
+            0o640
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn escalated_write_stages_privately() -> Result<(), JanitorError> {
+        let runner = RecordingRunner::new();
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_runner(runner.clone())
+            .with_escalation(Some(vec!["sudo".to_string()]));
+        cfg.write_file(
+            Path::new("/etc/portage/postsync.d/kernel-janitor"),
+            "#!/bin/sh\n",
+            0o755,
+        )?;
+        let commands = runner.commands();
+        assert_eq!(commands.len(), 1);
+        let staged = PathBuf::from(&commands[0].args[5]);
+        assert_eq!(commands[0].args[..5], ["env", "install", "-D", "-m", "755"]);
+        // In a directory of its own, which is gone again
+        let staging_dir = staged.parent().unwrap();
+        assert_ne!(staging_dir, std::env::temp_dir());
+        assert!(!staging_dir.exists());
+        Ok(())
+    }
+
     #[test]
     fn check_input_prompt() -> Result<(), JanitorError> {
         let input = b"a";
@@ -213,4 +2427,36 @@ mod test {
         assert_eq!(choice, 'a');
         Ok(())
     }
+
+    #[test]
+    fn delete_several() -> Result<(), JanitorError> {
+        let (mut inventory, memfs) = inventory();
+        inventory.pin(KernelVersion::try_from("5.12.0-gentoo").unwrap());
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off)
+            .with_fs(memfs.clone())
+            .with_removable_roots(vec![PathBuf::from("/")]);
+
+        // Newest first, so a is 5.12.0 and c is 5.10.1. a is pinned, z isn't listed and b is
+        // unselected again
+        let mut declined = &b"b c\na z b\nb\n\nn\n"[..];
+        assert!(delete_selected(&cfg, &mut inventory, &mut declined).is_err());
+        assert_eq!(inventory.len(), 4);
+
+        let mut input = &b"bc\n\ny\n"[..];
+        delete_selected(&cfg, &mut inventory, &mut input)?;
+        let remaining: Vec<_> = inventory
+            .kernels()
+            .iter()
+            .map(|k| k.version.to_string())
+            .collect();
+        assert_eq!(remaining, vec!["5.4.97-gentoo", "5.12.0-gentoo"]);
+        assert!(!memfs.exists(Path::new("/boot/vmlinuz-5.11.0-gentoo")));
+        assert!(!memfs.exists(Path::new("/usr/src/linux-5.10.1-gentoo")));
+        assert!(memfs.exists(Path::new("/lib/modules/5.4.97-gentoo")));
+
+        let mut nothing = &b"\n"[..];
+        delete_selected(&cfg, &mut inventory, &mut nothing)?;
+        assert_eq!(inventory.len(), 2);
+        Ok(())
+    }
 }
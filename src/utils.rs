@@ -1,13 +1,6 @@
-use std::{
-    io::{BufRead, BufReader},
-    process::Command,
-    thread,
-};
+use std::{process::Command, time::Duration};
 
-use crate::{
-    error::JanitorError,
-    update::{InteractiveStatus, PretendStatus, RunCmdConfig},
-};
+use crate::{error::JanitorError, update::RunCmdConfig, JanitorErrorFrom};
 
 pub fn user_is_root() -> Result<bool, JanitorError> {
     get_euid().map(|euid| euid == 0)
@@ -22,27 +15,20 @@ pub fn get_euid() -> Result<usize, JanitorError> {
         .map_err(|_| JanitorError::from(format!("Could not parse {} as usize", utf8_str)))
 }
 
-pub fn maybe_prompt_for_confirmation(
-    config: &RunCmdConfig,
-    cmd_desc: &str,
-) -> Result<(), JanitorError> {
-    if &config.interactive == &InteractiveStatus::Off {
-        return Ok(());
-    }
-    // Keep asking the user for input until they send something normal
-    loop {
-        println!("{}? (y/n)", cmd_desc);
-        let stdin = std::io::stdin();
-        let mut buf = String::new();
-        stdin.read_line(&mut buf)?;
-        let buf = buf.to_ascii_lowercase();
-        if buf.starts_with("y") {
-            return Ok(());
-        } else if buf.starts_with("n") {
-            return Err(JanitorError::from("Command declined"));
+/// Look up the uid and primary gid of a user by name
+pub fn lookup_user(name: &str) -> Result<(u32, u32), JanitorError> {
+    let id = |flag: &str| -> Result<u32, JanitorError> {
+        let output = Command::new("id").arg(flag).arg("--").arg(name).output()?;
+        if !output.status.success() {
+            return Err(JanitorError::from(format!("Unknown user {}", name)));
         }
-        println!("Could not understand {}", buf);
-    }
+        let utf8_str = String::from_utf8_lossy(&output.stdout);
+        utf8_str
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| JanitorError::from(format!("Could not parse {} as u32", utf8_str)))
+    };
+    Ok((id("-u")?, id("-g")?))
 }
 
 // Runs the command and prints both stdout/stderr to the console
@@ -51,54 +37,161 @@ pub fn exec_and_print_command(
     cmd_desc: String,
     cmd_config: &RunCmdConfig,
 ) -> Result<(), JanitorError> {
-    if &cmd_config.pretend == &PretendStatus::Pretend {
-        println!("Pretending to run {}", cmd_desc);
-        return Ok(());
+    exec_and_observe_command(cmd, cmd_desc, cmd_config, &|_| ())
+}
+
+/// `exec_and_print_command` that also hands each line of output to `observe`
+pub fn exec_and_observe_command(
+    cmd: &mut Command,
+    cmd_desc: String,
+    cmd_config: &RunCmdConfig,
+    observe: &(dyn Fn(&str) + Sync),
+) -> Result<(), JanitorError> {
+    cmd_config.confirm(&cmd_desc)?;
+    cmd_config.run_observed(cmd, &cmd_desc, observe)
+}
+
+/// Human readable size using binary units, e.g. `1.5 GiB`
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
     }
-    maybe_prompt_for_confirmation(&cmd_config, &cmd_desc)?;
-    println!("Running {}", cmd_desc);
-    let child = cmd
-        .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::piped())
-        .spawn()?;
-    let stdout = child.stdout.ok_or_else(|| {
-        JanitorError::from(format!(
-            "Could not capture standard output for {}",
-            cmd_desc
-        ))
-    })?;
-    let stderr = child.stderr.ok_or_else(|| {
-        JanitorError::from(format!("Could not capture standard error for {}", cmd_desc))
-    })?;
-
-    let out_thread = thread::spawn(move || {
-        let stdout_reader = BufReader::new(stdout);
-        stdout_reader
-            .lines()
-            .filter_map(Result::ok)
-            .for_each(|l| println!("{}", l));
-    });
-    let err_thread = thread::spawn(move || {
-        let stderr_reader = BufReader::new(stderr);
-        stderr_reader
-            .lines()
-            .filter_map(Result::ok)
-            .for_each(|l| println!("stderr: {}", l));
-    });
-    out_thread.join().expect("Could not join out_thread");
-    err_thread.join().expect("Could not join err_thread");
-    Ok(())
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+/// Short duration like `1h 2m 3s`, leading zero units are dropped
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, mins, secs) = (secs / 3600, (secs % 3600) / 60, secs % 60);
+    match (hours, mins) {
+        (0, 0) => format!("{}s", secs),
+        (0, _) => format!("{}m {}s", mins, secs),
+        _ => format!("{}h {}m {}s", hours, mins, secs),
+    }
+}
+
+/// How long ago something happened in its largest unit, like `3 days ago`
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    let (count, unit) = match secs {
+        s if s >= 86400 => (s / 86400, "day"),
+        s if s >= 3600 => (s / 3600, "hour"),
+        s if s >= 60 => (s / 60, "minute"),
+        _ => return "just now".to_string(),
+    };
+    match count {
+        1 => format!("1 {} ago", unit),
+        _ => format!("{} {}s ago", count, unit),
+    }
+}
+
+/// UTC date and time like `2021-03-20 14:05` from seconds since the epoch
+pub fn format_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let (hour, min) = ((secs % 86400) / 3600, (secs % 3600) / 60);
+    // Days to a civil date, from http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, hour, min)
+}
+
+/// Parse a duration like `90d`, units are s, m, h, d and w
+pub fn parse_duration(s: &str) -> Result<Duration, JanitorError> {
+    let invalid = || {
+        JanitorErrorFrom!(
+            "Invalid duration {}, expected a number followed by s, m, h, d or w like 90d",
+            s
+        )
+    };
+    let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let count: u64 = s[..split].parse().map_err(|_| invalid())?;
+    let unit_secs = match &s[split..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    Ok(Duration::from_secs(count * unit_secs))
 }
 
 pub mod paths {
     use std::{
         fs, io,
+        os::unix::fs::MetadataExt,
         path::{Path, PathBuf},
     };
+
+    use crate::debug;
+
+    /// Never removed, whatever the config says
+    const PROTECTED_PATHS: [&str; 2] = ["/", "/boot"];
+
+    fn refuse(path: &Path, reason: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Refusing to delete {:?}: {}", path, reason),
+        )
+    }
+
+    /// Errors unless `path` is strictly inside one of `roots`, isn't protected and isn't a mount point
+    /// Symlinks in the last component aren't followed since the link itself is what gets removed
+    pub fn check_removable(path: &Path, roots: &[PathBuf]) -> io::Result<()> {
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(p), Some(n)) if !p.as_os_str().is_empty() => (p, n),
+            _ => return Err(refuse(path, "not a path to a file or directory")),
+        };
+        let parent = fs::canonicalize(parent)?;
+        let resolved = parent.join(name);
+        // /boot may be a link to the ESP, its target is just as protected
+        let protected = |p: &&str| {
+            resolved == Path::new(p) || fs::canonicalize(p).is_ok_and(|real| resolved == real)
+        };
+        if PROTECTED_PATHS.iter().any(protected) {
+            return Err(refuse(path, "protected path"));
+        }
+        let inside_root = roots
+            .iter()
+            .filter_map(|root| fs::canonicalize(root).ok())
+            .any(|root| resolved.starts_with(&root) && resolved != root);
+        if !inside_root {
+            return Err(refuse(
+                path,
+                &format!("not inside any of the configured directories {:?}", roots),
+            ));
+        }
+        if fs::symlink_metadata(&resolved)?.dev() != fs::metadata(&parent)?.dev() {
+            return Err(refuse(path, "it's a mount point"));
+        }
+        Ok(())
+    }
+
+    /// `remove_dir_all` or `remove_file` after `check_removable` passed
+    pub fn safe_rm(path: &Path, roots: &[PathBuf]) -> io::Result<()> {
+        check_removable(path, roots)?;
+        match fs::symlink_metadata(path)?.is_dir() {
+            true => fs::remove_dir_all(path),
+            false => fs::remove_file(path),
+        }
+    }
     /// Finds all files with a prefix in a directory
     pub fn all_paths_with_prefix(prefix: &str, dir: &Path) -> io::Result<Vec<PathBuf>> {
         let paths: Vec<PathBuf> = fs::read_dir(dir)?
-            .into_iter()
             .filter_map(Result::ok)
             .map(|entry| entry.path())
             .filter(|path| {
@@ -120,10 +213,36 @@ pub mod paths {
         Some(os_str.to_string())
     }
 
+    /// The real location of a configured directory, so with /boot linked to /efi/EFI/gentoo
+    /// kernels are searched for, installed and deleted through the same path
+    /// Kept as it is when it can't be resolved, the search reports why
+    pub fn resolve_dir(path: &Path) -> PathBuf {
+        match fs::canonicalize(path) {
+            Ok(resolved) if resolved != path => {
+                debug!("{} resolves to {}", path.display(), resolved.display());
+                resolved
+            }
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// Total size in bytes of a file or everything under a directory
+    /// Symlinks are counted but not followed
+    pub fn disk_usage(path: &Path) -> io::Result<u64> {
+        let metadata = fs::symlink_metadata(path)?;
+        if !metadata.is_dir() {
+            return Ok(metadata.len());
+        }
+        let mut total = metadata.len();
+        for entry in fs::read_dir(path)? {
+            total += disk_usage(&entry?.path())?;
+        }
+        Ok(total)
+    }
+
     /// Finds all files with a prefix in a directory
     pub fn all_paths(dir: &Path) -> io::Result<Vec<PathBuf>> {
         let paths: Vec<PathBuf> = fs::read_dir(dir)?
-            .into_iter()
             .filter_map(Result::ok)
             .map(|entry| entry.path())
             .collect();
@@ -132,10 +251,11 @@ pub mod paths {
 }
 
 #[cfg(test)]
-pub mod tests {
+mod tests {
 
     use super::paths::*;
     use super::*;
+    use crate::test_utils::TestDir;
     use crate::update::*;
     use std::{
         fs, io,
@@ -143,98 +263,42 @@ pub mod tests {
         process::Command,
     };
 
-    fn create_dummy_files() -> io::Result<()> {
-        init_test_dir();
-        let test_dir = get_test_install_pathbuf();
-
-        let mut path_0 = test_dir.clone();
-        path_0.push("old-file.txt");
-        fs::write(path_0, "old-file")?;
-
-        let mut path_1 = test_dir.clone();
-        path_1.push("new-file.txt");
-        fs::write(path_1, "new-file")?;
-
+    fn create_dummy_files(dir: &Path) -> io::Result<()> {
+        fs::write(dir.join("old-file.txt"), "old-file")?;
+        fs::write(dir.join("new-file.txt"), "new-file")?;
         Ok(())
     }
 
-    pub fn init_test_dir() {
-        let test_dir = get_test_install_pathbuf();
-        let res = fs::create_dir_all(&test_dir);
-        assert!(res.is_ok(), "Could not init test dir {:?}", test_dir);
-    }
-
-    pub fn get_test_dir_string() -> String {
-        let thread = std::thread::current();
-        let thread_name = thread.name().unwrap_or("UnknownThreadName");
-        let thread_name_cleaned = thread_name.to_string().replace("::", "_");
-        format!("unit-test-temp/{}", thread_name_cleaned)
-    }
-
-    pub fn get_test_install_path_string() -> String {
-        format!("./{}", get_test_dir_string())
-    }
-    pub fn get_test_install_pathbuf() -> PathBuf {
-        PathBuf::from(get_test_install_path_string())
-    }
-
-    pub fn get_test_module_path_string() -> String {
-        format!("./{}/modules", get_test_dir_string())
-    }
-    pub fn get_test_module_pathbuf() -> PathBuf {
-        PathBuf::from(get_test_module_path_string())
-    }
-
-    pub fn get_test_src_path_string() -> String {
-        format!("./{}/src", get_test_dir_string())
-    }
-    pub fn get_test_src_pathbuf() -> PathBuf {
-        PathBuf::from(get_test_src_path_string())
-    }
-
-    pub fn cleanup_test_dir() {
-        let test_dir = get_test_install_pathbuf();
-        if !test_dir.exists() {
-            return;
-        }
-        let dir_entry = fs::read_dir(&test_dir);
-        assert!(dir_entry.is_ok(), "Could not read dir {:?}", test_dir);
-        let dir_entry = dir_entry.unwrap();
-
-        for entry in dir_entry {
-            assert!(entry.is_ok(), "Could open DirEntry {:?}", entry);
-            let path = entry.unwrap().path();
-
-            if path.is_file() {
-                println!("Attempting to delete file {:?}", path);
-                let res = fs::remove_file(&path);
-                assert!(res.is_ok(), "Could not delete file {:?}", path);
-            } else if path.is_dir() {
-                println!("Attempting to delete dir {:?}", path);
-                let res = fs::remove_dir_all(&path);
-                assert!(res.is_ok(), "Could not delete dir {:?}", path);
-            } else {
-                println!("Unknown file type {:?}", path);
-            }
-        }
-    }
     #[test]
-    fn test_setup_cleanup() {
-        init_test_dir();
-        cleanup_test_dir();
+    fn test_dir_is_removed() {
+        let test_dir = TestDir::new();
+        let other = TestDir::new();
+        assert_ne!(test_dir.path(), other.path());
+        let root = test_dir.path().to_path_buf();
+        assert!(test_dir.src_path().is_dir());
+        drop(test_dir);
+        assert!(!root.exists());
     }
     #[test]
     fn find_entries() {
-        init_test_dir();
-        let res = create_dummy_files();
+        let test_dir = TestDir::new();
+        let res = create_dummy_files(test_dir.path());
         assert!(res.is_ok());
 
-        let search_dir = get_test_install_pathbuf();
-        let res = all_paths_with_prefix("new", &search_dir);
+        let res = all_paths_with_prefix("new", test_dir.path());
         assert!(res.is_ok());
         assert_eq!(res.unwrap().len(), 1);
+    }
 
-        cleanup_test_dir();
+    #[test]
+    fn resolve_linked_dir() {
+        let test_dir = TestDir::new();
+        let linked = test_dir.path().join("efi-boot");
+        std::os::unix::fs::symlink(test_dir.install_path(), &linked).unwrap();
+        assert_eq!(resolve_dir(&linked), test_dir.install_path());
+        assert_eq!(resolve_dir(&test_dir.src_path()), test_dir.src_path());
+        let missing = test_dir.path().join("missing");
+        assert_eq!(resolve_dir(&missing), missing);
     }
 
     #[test]
@@ -248,36 +312,111 @@ pub mod tests {
 
     #[test]
     fn test_pretend_exec_command() {
-        let cfg = RunCmdConfig {
-            pretend: PretendStatus::Pretend,
-            interactive: InteractiveStatus::Off,
-        };
+        let cfg = RunCmdConfig::new(PretendStatus::Pretend, InteractiveStatus::Off);
         let res = exec_and_print_command(Command::new("ls").arg("-l"), "ls -l".to_string(), &cfg);
         assert!(res.is_ok(), "{}", res.unwrap_err());
     }
     #[test]
     fn test_exec_command() {
-        let cfg = RunCmdConfig {
-            pretend: PretendStatus::RunTheDamnThing,
-            interactive: InteractiveStatus::Off,
-        };
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off);
         let res = exec_and_print_command(Command::new("ls").arg("-l"), "ls -l".to_string(), &cfg);
         assert!(res.is_ok(), "{}", res.unwrap_err());
     }
 
     #[test]
     fn test_exec_command_err() {
-        let cfg = RunCmdConfig {
-            pretend: PretendStatus::RunTheDamnThing,
-            interactive: InteractiveStatus::Off,
-        };
+        // Failing commands are errors and carry their stderr
+        let cfg = RunCmdConfig::new(PretendStatus::RunTheDamnThing, InteractiveStatus::Off);
         let res = exec_and_print_command(
             Command::new("ls").arg("./IamNotaPathPleaseDontFindMe"),
-            "ls ./unit-test-temp/iamnotapathpleasedontfindme".to_string(),
+            "ls ./IamNotaPathPleaseDontFindMe".to_string(),
             &cfg,
         );
-        assert!(res.is_ok(), "{}", res.unwrap_err());
+        assert!(res.is_err());
+        let message = res.unwrap_err().to_string();
+        assert!(
+            message.contains("IamNotaPathPleaseDontFindMe"),
+            "{}",
+            message
+        );
     }
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(Duration::from_secs(59)), "just now");
+        assert_eq!(format_age(Duration::from_secs(3600)), "1 hour ago");
+        assert_eq!(
+            format_age(Duration::from_secs(3 * 86400 + 60)),
+            "3 days ago"
+        );
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00");
+        assert_eq!(format_timestamp(1_616_249_100), "2021-03-20 14:05");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00");
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(
+            parse_duration("90d").unwrap(),
+            Duration::from_secs(90 * 86400)
+        );
+        assert_eq!(
+            parse_duration("2w").unwrap(),
+            Duration::from_secs(14 * 86400)
+        );
+        assert!(parse_duration("90").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("3 days").is_err());
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_disk_usage() {
+        let test_dir = TestDir::new();
+        let dir = test_dir.path().join("usage");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), [0u8; 100]).unwrap();
+        fs::write(dir.join("b"), [0u8; 28]).unwrap();
+        let usage = disk_usage(&dir).unwrap();
+        assert!(usage >= 128, "{}", usage);
+    }
+
+    #[test]
+    fn test_safe_rm() {
+        let test_dir = TestDir::new();
+        let root = test_dir.path().join("safe-rm");
+        fs::create_dir_all(root.join("kernel/sub")).unwrap();
+        fs::write(root.join("vmlinuz"), "").unwrap();
+        let roots = vec![root.clone()];
+
+        assert!(safe_rm(&root, &roots).is_err(), "the root itself");
+        assert!(safe_rm(Path::new("/"), &roots).is_err());
+        assert!(safe_rm(Path::new("/boot"), &[PathBuf::from("/")]).is_err());
+        assert!(safe_rm(&root.join("../outside"), &roots).is_err());
+        assert!(check_removable(Path::new("/proc/self"), &[PathBuf::from("/")]).is_ok());
+        // /proc is its own filesystem
+        assert!(check_removable(Path::new("/proc"), &[PathBuf::from("/")]).is_err());
+
+        safe_rm(&root.join("kernel"), &roots).unwrap();
+        safe_rm(&root.join("vmlinuz"), &roots).unwrap();
+        assert!(!root.join("kernel").exists() && !root.join("vmlinuz").exists());
+    }
+
+    #[test]
+    fn test_lookup_user() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert!(lookup_user("iamnotauserpleasedontfindme").is_err());
+    }
+
     #[test]
     fn test_get_euid() {
         let euid = get_euid();
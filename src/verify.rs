@@ -0,0 +1,209 @@
+use crate::{
+    bootloader::Bootloader, doctor::Check, filesystem::FileSystem, inventory::KernelInventory,
+    kernel::InstalledKernel, naming::NameTemplate, utils,
+};
+
+// x86 boot protocol, see Documentation/x86/boot.rst in the kernel sources
+const SETUP_HEADER_MAGIC: &[u8; 4] = b"HdrS";
+const SETUP_HEADER_MAGIC_OFFSET: usize = 0x202;
+const KERNEL_VERSION_OFFSET: usize = 0x20e;
+
+/// The release a bzImage says it is, like `5.11.8-gentoo`
+/// None for images without an x86 setup header, those can't be told
+fn image_release(image: &[u8]) -> Option<String> {
+    if image.get(SETUP_HEADER_MAGIC_OFFSET..SETUP_HEADER_MAGIC_OFFSET + 4)? != SETUP_HEADER_MAGIC {
+        return None;
+    }
+    let offset = u16::from_le_bytes([
+        *image.get(KERNEL_VERSION_OFFSET)?,
+        *image.get(KERNEL_VERSION_OFFSET + 1)?,
+    ]);
+    if offset == 0 {
+        return None;
+    }
+    // The version string starts with the release, followed by who built it and when
+    let start = offset as usize + 0x200;
+    let version = image.get(start..image.len().min(start + 256))?;
+    String::from_utf8_lossy(version)
+        .split(|c: char| c == '\0' || c.is_whitespace())
+        .next()
+        .filter(|release| !release.is_empty())
+        .map(str::to_string)
+}
+
+/// Every expected file is there
+fn check_files(kernel: &InstalledKernel) -> Check {
+    let name = format!("{} files", kernel.version);
+    let missing = kernel.missing_files();
+    match missing.is_empty() {
+        true => Check::pass(&name, "all present".to_string()),
+        // Leftover modules with no image are what cleanup removes, sources alone are the next build
+        false if kernel.vmlinuz_path.is_none() && kernel.module_path.is_some() => Check::fail(
+            &name,
+            format!(
+                "modules without a kernel image, missing {}",
+                missing.join(", ")
+            ),
+            "remove the leftovers with --delete, or reinstall the kernel with make install",
+        ),
+        false => Check::warn(
+            &name,
+            format!("missing {}", missing.join(", ")),
+            "reinstall the kernel with make install, or ignore this if it's deliberate",
+        ),
+    }
+}
+
+/// The image is the release its name says it is
+fn check_image(
+    fs: &dyn FileSystem,
+    kernel: &InstalledKernel,
+    template: &NameTemplate,
+) -> Option<Check> {
+    let image = kernel.vmlinuz_path.as_ref()?;
+    let name = format!("{} image", kernel.version);
+    let expected = template.release_of(&utils::paths::filename_from_path(image)?)?;
+    let expected = expected.trim_end_matches(".old");
+    Some(
+        match fs.read(image).map(|contents| image_release(&contents)) {
+            Ok(Some(release)) if release == expected => {
+                Check::pass(&name, format!("is {}", release))
+            }
+            Ok(Some(release)) => Check::fail(
+                &name,
+                format!("{} contains {}", image.display(), release),
+                "the image was overwritten by another build, reinstall it with make install",
+            ),
+            Ok(None) => Check::pass(
+                &name,
+                "not a bzImage, its release can't be read".to_string(),
+            ),
+            Err(e) => Check::fail(
+                &name,
+                format!("failed to read {}: {}", image.display(), e),
+                "check the permissions of the install path",
+            ),
+        },
+    )
+}
+
+/// The installed System.map is the one of the build in the sources
+fn check_system_map(fs: &dyn FileSystem, kernel: &InstalledKernel) -> Option<Check> {
+    let installed = kernel.system_map_path.as_ref()?;
+    // Old versions share the sources with the current one, so the build there isn't theirs
+    if kernel.version.is_old() {
+        return None;
+    }
+    let built = kernel.source_path.as_ref()?.join("System.map");
+    if fs.disk_usage(&built).is_err() {
+        return None;
+    }
+    let name = format!("{} System.map", kernel.version);
+    Some(match (fs.read(installed), fs.read(&built)) {
+        (Ok(a), Ok(b)) if a == b => Check::pass(&name, "matches the build".to_string()),
+        (Ok(_), Ok(_)) => Check::fail(
+            &name,
+            format!("{} differs from {}", installed.display(), built.display()),
+            "the sources were rebuilt without installing, run make install",
+        ),
+        (Err(e), _) | (_, Err(e)) => Check::fail(
+            &name,
+            format!("failed to compare with {}: {}", built.display(), e),
+            "check the permissions of the install path and sources",
+        ),
+    })
+}
+
+/// Every file the boot entries load exists
+fn check_boot_entries(fs: &dyn FileSystem, bootloader: &dyn Bootloader) -> Check {
+    let name = format!("{} entries", bootloader.name());
+    match bootloader.referenced_files(fs) {
+        Ok(files) => {
+            let missing: Vec<String> = files
+                .iter()
+                .filter(|path| fs.disk_usage(path).is_err())
+                .map(|path| path.display().to_string())
+                .collect();
+            match missing.is_empty() {
+                true => Check::pass(&name, format!("all {} files exist", files.len())),
+                false => Check::fail(
+                    &name,
+                    format!("missing {}", missing.join(", ")),
+                    "regenerate the bootloader config, these entries won't boot",
+                ),
+            }
+        }
+        Err(e) => Check::fail(&name, e.to_string(), "check that /boot is mounted"),
+    }
+}
+
+/// Check every installed kernel and the boot entries, without changing anything
+/// `image_name` is the template the images are named with, for the release they should contain
+pub fn run(
+    fs: &dyn FileSystem,
+    inventory: &KernelInventory,
+    bootloader: Option<&dyn Bootloader>,
+    image_name: &NameTemplate,
+) -> Vec<Check> {
+    let mut checks = Vec::new();
+    for kernel in inventory.kernels() {
+        checks.push(check_files(kernel));
+        checks.extend(check_image(fs, kernel, image_name));
+        checks.extend(check_system_map(fs, kernel));
+    }
+    checks.extend(bootloader.map(|bootloader| check_boot_entries(fs, bootloader)));
+    checks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        doctor::CheckStatus, filesystem::MemoryFileSystem, kernel::KernelVersion, naming::Naming,
+    };
+    use std::path::Path;
+
+    /// Just enough of a bzImage for its version string to be found
+    fn fake_bz_image(version: &str) -> String {
+        let mut image = vec![0u8; 0x400];
+        image[SETUP_HEADER_MAGIC_OFFSET..SETUP_HEADER_MAGIC_OFFSET + 4]
+            .copy_from_slice(SETUP_HEADER_MAGIC);
+        image[KERNEL_VERSION_OFFSET..KERNEL_VERSION_OFFSET + 2]
+            .copy_from_slice(&0x100u16.to_le_bytes());
+        image[0x300..0x300 + version.len()].copy_from_slice(version.as_bytes());
+        String::from_utf8(image).unwrap()
+    }
+
+    #[test]
+    fn image_version() {
+        let image = Path::new("/boot/vmlinuz-5.11.8-gentoo");
+        let contents = fake_bz_image("5.11.8-gentoo (root@host) #1 SMP");
+        assert_eq!(
+            image_release(contents.as_bytes()).as_deref(),
+            Some("5.11.8-gentoo")
+        );
+        let memfs = MemoryFileSystem::new().with_contents(image, &contents);
+        let kernel = InstalledKernel::new(KernelVersion::new(5, 11, 8, None, false))
+            .with_vmlinuz_path(image.to_path_buf());
+        let template = Naming::default().image;
+        assert_eq!(
+            check_image(&memfs, &kernel, &template).unwrap().status,
+            CheckStatus::Pass
+        );
+
+        let memfs = memfs.with_contents(image, &fake_bz_image("5.10.1-gentoo (root@host) #1 SMP"));
+        assert_eq!(
+            check_image(&memfs, &kernel, &template).unwrap().status,
+            CheckStatus::Fail
+        );
+
+        assert_eq!(image_release(b"not a kernel"), None);
+        // Gone since the inventory was taken
+        assert_eq!(
+            check_image(&MemoryFileSystem::new(), &kernel, &template)
+                .unwrap()
+                .status,
+            CheckStatus::Fail
+        );
+    }
+}